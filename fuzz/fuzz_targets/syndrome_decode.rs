@@ -0,0 +1,20 @@
+//! Feeds random ESR_EL2 values into the syndrome decoders.
+//!
+//! The crate only builds for AArch64, run it on an AArch64 Linux host with
+//! `cargo fuzz run syndrome_decode`, which builds it with `std` through `--cfg fuzzing`.
+
+#![no_main]
+
+use arm_vcpu::decode::{DataAbortAccess, FaultStatus, SysRegAccess};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for chunk in data.chunks_exact(4) {
+        let esr = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as usize;
+        let iss = esr & ((1 << 25) - 1);
+
+        let _ = FaultStatus::from_iss(iss);
+        let _ = DataAbortAccess::decode(iss);
+        let _ = SysRegAccess::decode(iss);
+    }
+});
@@ -0,0 +1,290 @@
+//! Runs the built-in test guest of `arm_vcpu` under QEMU's EL2 and checks the VM exits it
+//! produces, catching regressions in exception.S and the world switch.
+//!
+//! Run it with `cargo run --release` from this directory, which needs `qemu-system-aarch64` in
+//! `PATH`. The harness boots at EL2 on a single core of the QEMU virt machine, maps the guest
+//! memory with stage-2 and reports the result through the QEMU exit code.
+//!
+//! With `--features record`, every VM exit is also recorded and the trace compared against
+//! `golden/test_guest.trace`, see `record.rs`.
+
+#![no_std]
+#![no_main]
+
+use core::arch::{asm, global_asm};
+use core::fmt::Write;
+
+use aarch64_cpu::registers::{DAIF, Writeable};
+use axaddrspace::{GuestPhysAddr, HostPhysAddr, HostVirtAddr};
+use axvcpu::{AxArchPerCpu, AxArchVCpu, AxVCpuExitReason, AxVCpuHal};
+
+use arm_vcpu::{
+    Aarch64PerCpu, Aarch64VCpu, Aarch64VCpuCreateConfig, Aarch64VCpuSetupConfig,
+    TEST_GUEST_HVC_ECHO, TEST_GUEST_HVC_HELLO, TEST_GUEST_MMIO_ADDR, TEST_GUEST_MMIO_VALUE,
+    test_guest_image,
+};
+
+#[cfg(feature = "record")]
+mod record;
+
+/// PL011 UART of the QEMU virt machine.
+const UART_BASE: usize = 0x0900_0000;
+/// GICv3 distributor of the QEMU virt machine.
+const GICD_BASE: usize = 0x0800_0000;
+/// SGI/PPI frame of the GICv3 redistributor of CPU 0.
+const GICR_SGI_BASE: usize = 0x080a_0000 + 0x1_0000;
+/// The PPI of the EL1 virtual timer.
+const VTIMER_IRQ: usize = 27;
+
+/// The IPA the guest memory is mapped at.
+const GUEST_IPA: usize = 0x4000_0000;
+/// The size of the guest memory, mapped by a single stage-2 level 2 block.
+const GUEST_RAM_SIZE: usize = 0x20_0000;
+/// The value returned to the MMIO read of the guest.
+const MMIO_READ_VALUE: u64 = 0x1234_5678;
+
+global_asm!(
+    "
+    .section .text.boot
+    .global _start
+_start:
+    ldr     x0, =__boot_stack_top
+    mov     sp, x0
+    ldr     x0, =__bss_start
+    ldr     x1, =__bss_end
+1:  cmp     x0, x1
+    b.hs    2f
+    str     xzr, [x0], #8
+    b       1b
+2:  bl      harness_main
+    b       .
+    "
+);
+
+#[repr(C, align(4096))]
+struct PageTable([u64; 512]);
+
+#[repr(C, align(0x200000))]
+struct GuestRam([u8; GUEST_RAM_SIZE]);
+
+static mut STAGE2_L0: PageTable = PageTable([0; 512]);
+static mut STAGE2_L1: PageTable = PageTable([0; 512]);
+static mut STAGE2_L2: PageTable = PageTable([0; 512]);
+static mut GUEST_RAM: GuestRam = GuestRam([0; GUEST_RAM_SIZE]);
+
+struct Uart;
+
+impl Write for Uart {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        const UARTFR_TXFF: u32 = 1 << 5;
+        for byte in s.bytes() {
+            unsafe {
+                while ((UART_BASE + 0x18) as *const u32).read_volatile() & UARTFR_TXFF != 0 {}
+                (UART_BASE as *mut u32).write_volatile(byte as u32);
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let _ = writeln!(Uart, "[{:5}] {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Exits QEMU with `code` through semihosting `SYS_EXIT`.
+fn exit_qemu(code: u32) -> ! {
+    const SYS_EXIT: u32 = 0x18;
+    const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+    let block = [ADP_STOPPED_APPLICATION_EXIT, code as u64];
+    unsafe {
+        asm!("hlt #0xf000", in("w0") SYS_EXIT, in("x1") &block, options(noreturn));
+    }
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    let _ = writeln!(Uart, "harness: {info}");
+    exit_qemu(1)
+}
+
+/// The harness owns the whole machine, no memory is handed out to the vCPU crate.
+struct HarnessHal;
+
+impl axaddrspace::AxMmHal for HarnessHal {
+    fn alloc_frame() -> Option<HostPhysAddr> {
+        None
+    }
+
+    fn dealloc_frame(_paddr: HostPhysAddr) {}
+
+    fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr {
+        HostVirtAddr::from(paddr.as_usize())
+    }
+
+    fn virt_to_phys(vaddr: HostVirtAddr) -> HostPhysAddr {
+        HostPhysAddr::from(vaddr.as_usize())
+    }
+}
+
+impl AxVCpuHal for HarnessHal {
+    type MmHal = Self;
+
+    /// Acknowledges the pending interrupt, which can only be the virtual timer of the guest.
+    ///
+    /// The timer interrupt is level-sensitive and stays asserted until the guest disables the
+    /// timer, so it is masked at the redistributor after the first occurrence.
+    fn irq_fetch() -> usize {
+        let iar: u64;
+        unsafe {
+            asm!("mrs {}, ICC_IAR1_EL1", out(reg) iar);
+            asm!("msr ICC_EOIR1_EL1, {}", in(reg) iar);
+        }
+        let intid = (iar & 0xff_ffff) as usize;
+        if intid < 32 {
+            gicr_write(0x180, 1 << intid); // GICR_ICENABLER0
+        }
+        intid
+    }
+
+    fn irq_hanlder() {
+        panic!("unexpected host IRQ");
+    }
+}
+
+fn gicr_write(offset: usize, value: u32) {
+    unsafe { ((GICR_SGI_BASE + offset) as *mut u32).write_volatile(value) }
+}
+
+/// Enables the GICv3 for the virtual timer PPI, in group 1 with the highest priority.
+fn init_gic() {
+    unsafe {
+        // GICD_CTLR: ARE_NS | EnableGrp1NS.
+        ((GICD_BASE) as *mut u32).write_volatile((1 << 4) | (1 << 1));
+        // GICR_WAKER: clear ProcessorSleep and wait for ChildrenAsleep to clear.
+        let waker = (GICR_SGI_BASE - 0x1_0000 + 0x14) as *mut u32;
+        waker.write_volatile(waker.read_volatile() & !(1 << 1));
+        while waker.read_volatile() & (1 << 2) != 0 {}
+
+        gicr_write(0x080, 1 << VTIMER_IRQ); // GICR_IGROUPR0
+        ((GICR_SGI_BASE + 0x400 + VTIMER_IRQ) as *mut u8).write_volatile(0); // GICR_IPRIORITYR
+        gicr_write(0x100, 1 << VTIMER_IRQ); // GICR_ISENABLER0
+
+        // ICC_SRE_EL2: SRE | Enable, ICC_PMR_EL1: all priorities, ICC_IGRPEN1_EL1: enabled.
+        asm!("msr ICC_SRE_EL2, {}", "isb", in(reg) 0b1001u64);
+        asm!("msr ICC_PMR_EL1, {}", in(reg) 0xffu64);
+        asm!("msr ICC_IGRPEN1_EL1, {}", "isb", in(reg) 1u64);
+    }
+}
+
+/// Copies the test guest into the guest memory and maps it at [`GUEST_IPA`], returning the
+/// stage-2 root table.
+fn init_guest_memory() -> HostPhysAddr {
+    // Stage-2 descriptors: table, or block with MemAttr = Normal WB, S2AP = RW, inner
+    // shareable and the access flag set.
+    const S2_TABLE: u64 = 0b11;
+    const S2_BLOCK_NORMAL_RW: u64 = 0b01 | (0b1111 << 2) | (0b11 << 6) | (0b11 << 8) | (1 << 10);
+
+    let image = test_guest_image();
+    unsafe {
+        let ram = &raw mut GUEST_RAM;
+        (*ram).0[..image.len()].copy_from_slice(image);
+
+        let (l0, l1, l2) = (&raw mut STAGE2_L0, &raw mut STAGE2_L1, &raw mut STAGE2_L2);
+        (*l0).0[(GUEST_IPA >> 39) & 0x1ff] = l1 as u64 | S2_TABLE;
+        (*l1).0[(GUEST_IPA >> 30) & 0x1ff] = l2 as u64 | S2_TABLE;
+        (*l2).0[(GUEST_IPA >> 21) & 0x1ff] = ram as u64 | S2_BLOCK_NORMAL_RW;
+        asm!("dsb ish", "ic iallu", "isb");
+
+        HostPhysAddr::from(l0 as usize)
+    }
+}
+
+/// Checks that `exit` is the `step`-th VM exit of the test guest and handles it.
+///
+/// Returns whether the guest is done.
+fn check_exit(step: usize, exit: AxVCpuExitReason, vcpu: &mut Aarch64VCpu<HarnessHal>) -> bool {
+    log::info!("exit {step}: {exit:x?}");
+    match (step, exit) {
+        (0, AxVCpuExitReason::Hypercall { nr, args }) => {
+            assert_eq!(nr, TEST_GUEST_HVC_HELLO);
+            assert_eq!(args[0], 0b01 << 2, "the guest must run at EL1");
+        }
+        (1, AxVCpuExitReason::MmioWrite { addr, width, data }) => {
+            assert_eq!(addr, GuestPhysAddr::from(TEST_GUEST_MMIO_ADDR));
+            assert_eq!(width.size(), 4);
+            assert_eq!(data, TEST_GUEST_MMIO_VALUE as u64);
+        }
+        (
+            2,
+            AxVCpuExitReason::MmioRead {
+                addr, width, reg, ..
+            },
+        ) => {
+            assert_eq!(addr, GuestPhysAddr::from(TEST_GUEST_MMIO_ADDR + 4));
+            assert_eq!(width.size(), 4);
+            vcpu.set_gpr(reg, MMIO_READ_VALUE as usize);
+        }
+        (3, AxVCpuExitReason::Hypercall { nr, args }) => {
+            assert_eq!(nr, TEST_GUEST_HVC_ECHO);
+            assert_eq!(
+                args[0], MMIO_READ_VALUE,
+                "the MMIO read value was not delivered"
+            );
+        }
+        (4, AxVCpuExitReason::ExternalInterrupt { vector }) => {
+            assert_eq!(
+                vector, VTIMER_IRQ as u64,
+                "the guest must be woken up from WFI"
+            );
+        }
+        (5, AxVCpuExitReason::SystemDown) => return true,
+        (step, exit) => panic!("unexpected exit {exit:x?} at step {step}"),
+    }
+    false
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn harness_main() -> ! {
+    log::set_logger(&Logger).unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+    #[cfg(feature = "record")]
+    record::init();
+    DAIF.write(DAIF::D::Masked + DAIF::A::Masked + DAIF::I::Masked + DAIF::F::Masked);
+
+    percpu::init();
+    percpu::init_percpu_reg(0);
+    init_gic();
+
+    let mut pcpu = Aarch64PerCpu::<HarnessHal>::new(0).unwrap();
+    pcpu.hardware_enable().unwrap();
+
+    let mut vcpu = Aarch64VCpu::<HarnessHal>::new(0, 0, Aarch64VCpuCreateConfig::default())
+        .expect("failed to create the vCPU");
+    vcpu.setup(Aarch64VCpuSetupConfig::default()).unwrap();
+    vcpu.set_ept_root(init_guest_memory()).unwrap();
+    vcpu.set_entry(GuestPhysAddr::from(GUEST_IPA)).unwrap();
+
+    for step in 0.. {
+        let exit = vcpu.run().expect("vCPU run failed");
+        #[cfg(feature = "record")]
+        record::returned(&exit);
+        if check_exit(step, exit, &mut vcpu) {
+            break;
+        }
+    }
+
+    #[cfg(feature = "record")]
+    record::check_golden();
+    log::info!("test guest passed");
+    exit_qemu(0)
+}
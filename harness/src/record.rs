@@ -0,0 +1,185 @@
+//! Recording of every VM exit of the test guest, compared against the golden trace in
+//! `golden/test_guest.trace`, with the `record` feature.
+//!
+//! The exits are recorded through the trace backend of the `tracing` feature of the crate,
+//! which sees the exits handled by the vCPU itself too, and tagged with the exit `run()`
+//! returns for them, if any. Any change of the exit path, e.g. a refactor of exception.rs or
+//! exception.S, that changes the trace is caught: the recorded trace is then printed in the
+//! format of the golden one, to be reviewed and committed if the change is intended.
+
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+
+use arm_vcpu::{TrapKind, VCpuTraceBackend, VCpuTraceEvent, set_trace_backend};
+use axvcpu::AxVCpuExitReason;
+
+use crate::Uart;
+
+/// The golden trace, one exit per line.
+const GOLDEN_TRACE: &str = include_str!("../golden/test_guest.trace");
+/// The maximum number of exits recorded.
+const MAX_EXITS: usize = 64;
+
+/// A VM exit of the test guest.
+#[derive(Clone, Copy)]
+struct RecordedExit {
+    kind: TrapKind,
+    /// `ESR_EL2` of the synchronous exceptions, 0 for the other kinds, for which it is stale.
+    esr: u64,
+    /// The exit returned by `run()`, `None` if the vCPU has handled it itself.
+    exit: Option<&'static str>,
+}
+
+struct ExitRecorder {
+    exits: UnsafeCell<([Option<RecordedExit>; MAX_EXITS], usize)>,
+}
+
+// The harness runs on a single core, and the backend is only called from the `run()` of the
+// vCPU, not from the IRQ handlers, so its calls never overlap even with the host IRQs
+// unmasked in the exits (`host_irqs_in_exits`).
+unsafe impl Sync for ExitRecorder {}
+
+impl ExitRecorder {
+    #[allow(clippy::mut_from_ref)]
+    fn exits(&self) -> &mut ([Option<RecordedExit>; MAX_EXITS], usize) {
+        unsafe { &mut *self.exits.get() }
+    }
+}
+
+impl VCpuTraceBackend for ExitRecorder {
+    fn record(&self, _mpidr: u64, _timestamp: u64, event: VCpuTraceEvent) {
+        let VCpuTraceEvent::Exit { kind, esr } = event else {
+            return;
+        };
+        let (exits, len) = self.exits();
+        assert!(*len < MAX_EXITS, "too many exits to record");
+        let esr = if kind == TrapKind::Synchronous {
+            esr
+        } else {
+            0
+        };
+        exits[*len] = Some(RecordedExit {
+            kind,
+            esr,
+            exit: None,
+        });
+        *len += 1;
+    }
+}
+
+static RECORDER: ExitRecorder = ExitRecorder {
+    exits: UnsafeCell::new(([None; MAX_EXITS], 0)),
+};
+
+/// Starts recording the exits.
+pub fn init() {
+    set_trace_backend(&RECORDER).unwrap();
+}
+
+/// Tags the last recorded exit with `exit`, returned by `run()`.
+pub fn returned(exit: &AxVCpuExitReason) {
+    let (exits, len) = RECORDER.exits();
+    if let Some(Some(last)) = len.checked_sub(1).map(|n| &mut exits[n]) {
+        if last.exit.is_none() {
+            last.exit = Some(exit_name(exit));
+        }
+    }
+}
+
+/// Returns the name of the variant of `exit`.
+fn exit_name(exit: &AxVCpuExitReason) -> &'static str {
+    match exit {
+        AxVCpuExitReason::Hypercall { .. } => "Hypercall",
+        AxVCpuExitReason::MmioRead { .. } => "MmioRead",
+        AxVCpuExitReason::MmioWrite { .. } => "MmioWrite",
+        AxVCpuExitReason::SysRegRead { .. } => "SysRegRead",
+        AxVCpuExitReason::SysRegWrite { .. } => "SysRegWrite",
+        AxVCpuExitReason::ExternalInterrupt { .. } => "ExternalInterrupt",
+        AxVCpuExitReason::NestedPageFault { .. } => "NestedPageFault",
+        AxVCpuExitReason::Halt => "Halt",
+        AxVCpuExitReason::CpuUp { .. } => "CpuUp",
+        AxVCpuExitReason::CpuDown { .. } => "CpuDown",
+        AxVCpuExitReason::SystemDown => "SystemDown",
+        AxVCpuExitReason::SendIPI { .. } => "SendIPI",
+        AxVCpuExitReason::FailEntry { .. } => "FailEntry",
+        AxVCpuExitReason::Nothing => "Nothing",
+        _ => "Other",
+    }
+}
+
+/// A line of a trace, formatted without allocating.
+struct TraceLine {
+    buf: [u8; 64],
+    len: usize,
+}
+
+impl TraceLine {
+    fn of(exit: &RecordedExit) -> Self {
+        let mut line = Self {
+            buf: [0; 64],
+            len: 0,
+        };
+        let _ = write!(
+            line,
+            "{:?} {:#010x} {}",
+            exit.kind,
+            exit.esr,
+            exit.exit.unwrap_or("-")
+        );
+        line
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+}
+
+impl Write for TraceLine {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(core::fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Compares the recorded exits with the golden trace, printing the recorded trace and
+/// panicking on the first difference.
+pub fn check_golden() {
+    let (exits, len) = RECORDER.exits();
+    let recorded = exits[..*len].iter().flatten().map(TraceLine::of);
+    let mut golden = GOLDEN_TRACE
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+    let mut mismatch = None;
+    for (n, line) in recorded.enumerate() {
+        match golden.next() {
+            Some(expected) if expected == line.as_str() => {}
+            expected => {
+                mismatch = Some((n, expected));
+                break;
+            }
+        }
+    }
+    if mismatch.is_none() {
+        mismatch = golden.next().map(|expected| (*len, Some(expected)));
+    }
+    let Some((n, expected)) = mismatch else {
+        log::info!("{len} exits matching the golden trace");
+        return;
+    };
+
+    let _ = writeln!(Uart, "recorded trace:");
+    for exit in exits[..*len].iter().flatten() {
+        let _ = writeln!(Uart, "{}", TraceLine::of(exit).as_str());
+    }
+    panic!(
+        "exit {n} differs from the golden trace, expected {:?}",
+        expected.unwrap_or("no more exits")
+    );
+}
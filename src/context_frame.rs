@@ -1,6 +1,21 @@
 use core::{arch::asm, fmt::Formatter};
 
 use aarch64_cpu::registers::*;
+use axaddrspace::device::SysRegAddr;
+use axerrno::{AxResult, ax_err};
+
+use crate::sysreg::{
+    SYSREG_ACTLR_EL1, SYSREG_AFSR0_EL1, SYSREG_AFSR1_EL1, SYSREG_AMAIR_EL1, SYSREG_CNTKCTL_EL1,
+    SYSREG_CNTP_CTL_EL0, SYSREG_CNTP_CVAL_EL0, SYSREG_CNTV_CTL_EL0, SYSREG_CNTV_CVAL_EL0,
+    SYSREG_CNTVOFF_EL2, SYSREG_CONTEXTIDR_EL1, SYSREG_CPACR_EL1, SYSREG_CSSELR_EL1, SYSREG_ELR_EL1,
+    SYSREG_ESR_EL1, SYSREG_FAR_EL1, SYSREG_MAIR_EL1, SYSREG_MDSCR_EL1, SYSREG_PAR_EL1,
+    SYSREG_SCTLR_EL1, SYSREG_SP_EL1, SYSREG_SPSR_EL1, SYSREG_TCR_EL1, SYSREG_TPIDR_EL0,
+    SYSREG_TPIDR_EL1, SYSREG_TPIDRRO_EL0, SYSREG_TTBR0_EL1, SYSREG_TTBR1_EL1, SYSREG_VBAR_EL1,
+};
+#[cfg(feature = "aarch32")]
+use crate::sysreg::{SYSREG_SPSR_ABT, SYSREG_SPSR_FIQ, SYSREG_SPSR_IRQ, SYSREG_SPSR_UND};
+#[cfg(feature = "vgic")]
+use crate::vgic::VgicState;
 
 /// A struct representing the AArch64 CPU context frame.
 ///
@@ -23,6 +38,8 @@ pub struct Aarch64ContextFrame {
     /// The exception link register, which stores the return address after an exception.
     pub elr: u64,
     /// The saved program status register, which holds the state of the program at the time of an exception.
+    ///
+    /// All the bits are preserved across VM exits, including the PAN, UAO, DIT and SSBS ones.
     pub spsr: u64,
 }
 
@@ -76,6 +93,93 @@ impl Aarch64ContextFrame {
         self.elr = pc as u64;
     }
 
+    /// Advances the exception program counter past the trapped instruction of `len` bytes, as
+    /// its execution would, including the `PSTATE.IT` of a T32 guest in an IT block, like
+    /// KVM's `kvm_adjust_itstate`.
+    pub fn skip_instruction(&mut self, len: usize) {
+        /// `SPSR.IT[1:0]`, bits [26:25], and `SPSR.IT[7:2]`, bits [15:10].
+        const SPSR_IT_MASK: u64 = (0b11 << 25) | (0b11_1111 << 10);
+
+        self.elr += len as u64;
+        let it = self.it_state();
+        if it == 0 {
+            return;
+        }
+        // The base condition is kept, the mask shifted, and the block ended after its last
+        // instruction.
+        let it = if it & 0b111 == 0 {
+            0
+        } else {
+            (it & 0b1110_0000) | ((it << 1) & 0b1_1111)
+        } as u64;
+        self.spsr = (self.spsr & !SPSR_IT_MASK) | ((it & 0b1111_1100) << 8) | ((it & 0b11) << 25);
+    }
+
+    /// Returns whether the AArch32 instruction which has trapped with the syndrome `esr` passes
+    /// its condition code check, like KVM's `kvm_condition_valid32`.
+    ///
+    /// The instructions failing it may trap anyway, they must then be skipped as `NOP`s. The
+    /// condition is the one of `ESR_EL2.COND` if `ESR_EL2.CV` is set, the one of the IT block
+    /// otherwise, and the exception classes from 0x10 are unconditional.
+    pub fn aarch32_condition_passed(&self, esr: u64) -> bool {
+        /// `ESR_EL2.CV`, bit [24], and `ESR_EL2.COND`, bits [23:20].
+        const ESR_CV: u64 = 1 << 24;
+        const ESR_COND_SHIFT: u64 = 20;
+
+        if !self.is_aarch32() || (esr >> 30) & 0b11 != 0 {
+            return true;
+        }
+        let cond = if esr & ESR_CV != 0 {
+            (esr >> ESR_COND_SHIFT) & 0b1111
+        } else {
+            match self.it_state() {
+                0 => return true,
+                it => (it >> 4) as u64,
+            }
+        };
+        let [n, z, c, v] = [31, 30, 29, 28].map(|bit| self.spsr & (1 << bit) != 0);
+        let passed = match cond >> 1 {
+            0b000 => z,
+            0b001 => c,
+            0b010 => n,
+            0b011 => v,
+            0b100 => c && !z,
+            0b101 => n == v,
+            0b110 => n == v && !z,
+            // AL, and the unconditional 0b1111.
+            _ => return true,
+        };
+        // The odd conditions are the negations of the even ones.
+        passed != (cond & 1 != 0)
+    }
+
+    /// Returns `PSTATE.IT` of a T32 guest, 0 outside of an IT block.
+    pub fn it_state(&self) -> u8 {
+        if !self.is_aarch32() {
+            return 0;
+        }
+        (((self.spsr >> 8) & 0b1111_1100) | ((self.spsr >> 25) & 0b11)) as u8
+    }
+
+    /// Returns the exception level the guest was running at when the exception was taken,
+    /// i.e. `SPSR.M[3:2]`, or EL0 for the User mode of AArch32 and EL1 for the other modes.
+    pub fn exception_level(&self) -> usize {
+        /// The AArch32 User mode, `SPSR.M[3:0]`.
+        const AARCH32_MODE_USR: u64 = 0b0000;
+
+        if self.is_aarch32() {
+            return (self.spsr & 0b1111 != AARCH32_MODE_USR) as usize;
+        }
+        ((self.spsr >> 2) & 0b11) as usize
+    }
+
+    /// Returns whether the guest was executing in AArch32 state when the exception was taken,
+    /// i.e. `SPSR.M[4]`, at EL0, or at EL1 for the vCPUs created with
+    /// [`Aarch64VCpuCreateConfig::aarch32_el1`](crate::Aarch64VCpuCreateConfig::aarch32_el1).
+    pub fn is_aarch32(&self) -> bool {
+        self.spsr & (1 << 4) != 0
+    }
+
     /// Sets the argument in register x0.
     ///
     /// # Arguments
@@ -134,6 +238,56 @@ impl Aarch64ContextFrame {
     }
 }
 
+/// The EL1 (and EL0) system register state of a guest.
+///
+/// It can be preset before the first run of a vCPU, see
+/// [`Aarch64VCpuSetupConfig::el1_state`](crate::Aarch64VCpuSetupConfig::el1_state), to resume a
+/// guest from a snapshot rather than cold booting it. Registers which are 32-bit wide are
+/// truncated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Aarch64El1State {
+    /// System Control Register (EL1).
+    pub sctlr_el1: u64,
+    /// Auxiliary Control Register (EL1).
+    pub actlr_el1: u64,
+    /// Architectural Feature Access Control Register.
+    pub cpacr_el1: u64,
+    /// Translation Table Base Register 0 (EL1).
+    pub ttbr0_el1: u64,
+    /// Translation Table Base Register 1 (EL1).
+    pub ttbr1_el1: u64,
+    /// Translation Control Register (EL1).
+    pub tcr_el1: u64,
+    /// Memory Attribute Indirection Register (EL1).
+    pub mair_el1: u64,
+    /// Auxiliary Memory Attribute Indirection Register (EL1).
+    pub amair_el1: u64,
+    /// Vector Base Address Register (EL1).
+    pub vbar_el1: u64,
+    /// Context ID Register (EL1).
+    pub contextidr_el1: u64,
+    /// Stack Pointer (EL1).
+    pub sp_el1: u64,
+    /// Exception Link Register (EL1).
+    pub elr_el1: u64,
+    /// Saved Program Status Register (EL1).
+    pub spsr_el1: u64,
+    /// Exception Syndrome Register (EL1).
+    pub esr_el1: u64,
+    /// Fault Address Register (EL1).
+    pub far_el1: u64,
+    /// Physical Address Register.
+    pub par_el1: u64,
+    /// EL0 Read/Write Software Thread ID Register.
+    pub tpidr_el0: u64,
+    /// EL1 Software Thread ID Register.
+    pub tpidr_el1: u64,
+    /// EL0 Read-Only Software Thread ID Register.
+    pub tpidrro_el0: u64,
+    /// Counter-timer Kernel Control Register.
+    pub cntkctl_el1: u64,
+}
+
 /// Represents the VM context for a guest virtual machine in a hypervisor environment.
 ///
 /// The `GuestSystemRegisters` structure contains various registers and states needed to manage
@@ -182,6 +336,26 @@ pub struct GuestSystemRegisters {
     tpidr_el0: u64,
     tpidr_el1: u64,
     tpidrro_el0: u64,
+    csselr_el1: u64,
+    afsr0_el1: u64,
+    afsr1_el1: u64,
+    mdscr_el1: u64,
+
+    // AArch32 EL1 only registers, switched if `HCR_EL2.RW` is clear
+    #[cfg(feature = "aarch32")]
+    dacr32_el2: u32,
+    #[cfg(feature = "aarch32")]
+    ifsr32_el2: u32,
+    #[cfg(feature = "aarch32")]
+    fpexc32_el2: u32,
+    #[cfg(feature = "aarch32")]
+    spsr_abt: u32,
+    #[cfg(feature = "aarch32")]
+    spsr_und: u32,
+    #[cfg(feature = "aarch32")]
+    spsr_irq: u32,
+    #[cfg(feature = "aarch32")]
+    spsr_fiq: u32,
 
     // hypervisor context
     pub hcr_el2: u64,
@@ -190,10 +364,15 @@ pub struct GuestSystemRegisters {
     hstr_el2: u64,
     pub pmcr_el0: u64,
     pub vtcr_el2: u64,
+    pub mdcr_el2: u64,
 
     // exception
     far_el2: u64,
     hpfar_el2: u64,
+
+    // GICv3 virtual CPU interface
+    #[cfg(feature = "vgic")]
+    pub vgic: VgicState,
 }
 
 impl GuestSystemRegisters {
@@ -206,6 +385,336 @@ impl GuestSystemRegisters {
         *self = GuestSystemRegisters::default()
     }
 
+    /// Returns whether the guest EL1 runs in AArch32 state, `HCR_EL2.RW` being clear.
+    #[cfg(feature = "aarch32")]
+    pub fn aarch32_el1(&self) -> bool {
+        /// `HCR_EL2.RW`, bit [31], set if EL1 is AArch64.
+        const HCR_EL2_RW: u64 = 1 << 31;
+        self.hcr_el2 & HCR_EL2_RW == 0
+    }
+
+    /// Loads `FPEXC32_EL2` of an AArch32 EL1 guest, which must be done before
+    /// `CPTR_EL2.TFP` is set since it traps the accesses of EL2 too.
+    #[cfg(feature = "aarch32")]
+    pub unsafe fn restore_fpexc32(&self) {
+        if self.aarch32_el1() {
+            unsafe { asm!("msr FPEXC32_EL2, {0:x}", in(reg) self.fpexc32_el2) };
+        }
+    }
+
+    /// Clears the AArch32 only registers of the guest EL1, as on a reset of the PE.
+    #[cfg(feature = "aarch32")]
+    pub fn reset_aarch32_regs(&mut self) {
+        self.dacr32_el2 = 0;
+        self.ifsr32_el2 = 0;
+        self.fpexc32_el2 = 0;
+        self.spsr_abt = 0;
+        self.spsr_und = 0;
+        self.spsr_irq = 0;
+        self.spsr_fiq = 0;
+    }
+
+    /// Returns the EL1 system register state held in the structure.
+    pub fn el1_state(&self) -> Aarch64El1State {
+        Aarch64El1State {
+            sctlr_el1: self.sctlr_el1 as u64,
+            actlr_el1: self.actlr_el1,
+            cpacr_el1: self.cpacr_el1 as u64,
+            ttbr0_el1: self.ttbr0_el1,
+            ttbr1_el1: self.ttbr1_el1,
+            tcr_el1: self.tcr_el1,
+            mair_el1: self.mair_el1,
+            amair_el1: self.amair_el1,
+            vbar_el1: self.vbar_el1,
+            contextidr_el1: self.contextidr_el1 as u64,
+            sp_el1: self.sp_el1,
+            elr_el1: self.elr_el1,
+            spsr_el1: self.spsr_el1 as u64,
+            esr_el1: self.esr_el1 as u64,
+            far_el1: self.far_el1,
+            par_el1: self.par_el1,
+            tpidr_el0: self.tpidr_el0,
+            tpidr_el1: self.tpidr_el1,
+            tpidrro_el0: self.tpidrro_el0,
+            cntkctl_el1: self.cntkctl_el1 as u64,
+        }
+    }
+
+    /// Replaces the EL1 system register state held in the structure, it is loaded into the
+    /// hardware by the next [`restore`](Self::restore).
+    pub fn set_el1_state(&mut self, state: &Aarch64El1State) {
+        self.sctlr_el1 = state.sctlr_el1 as u32;
+        self.actlr_el1 = state.actlr_el1;
+        self.cpacr_el1 = state.cpacr_el1 as u32;
+        self.ttbr0_el1 = state.ttbr0_el1;
+        self.ttbr1_el1 = state.ttbr1_el1;
+        self.tcr_el1 = state.tcr_el1;
+        self.mair_el1 = state.mair_el1;
+        self.amair_el1 = state.amair_el1;
+        self.vbar_el1 = state.vbar_el1;
+        self.contextidr_el1 = state.contextidr_el1 as u32;
+        self.sp_el1 = state.sp_el1;
+        self.elr_el1 = state.elr_el1;
+        self.spsr_el1 = state.spsr_el1 as u32;
+        self.esr_el1 = state.esr_el1 as u32;
+        self.far_el1 = state.far_el1;
+        self.par_el1 = state.par_el1;
+        self.tpidr_el0 = state.tpidr_el0;
+        self.tpidr_el1 = state.tpidr_el1;
+        self.tpidrro_el0 = state.tpidrro_el0;
+        self.cntkctl_el1 = state.cntkctl_el1 as u32;
+    }
+
+    /// Disables the EL1 timers of the guest and clears their comparators, as on a reset.
+    pub fn reset_timers(&mut self) {
+        self.cntp_ctl_el0 = 0;
+        self.cntv_ctl_el0 = 0;
+        self.cntp_cval_el0 = 0;
+        self.cntv_cval_el0 = 0;
+        self.cntp_tval_el0 = 0;
+        self.cntv_tval_el0 = 0;
+    }
+
+    /// Makes the guest take an exception with the syndrome `esr` to EL1 at the next entry, for
+    /// the instruction at `pc`, as if it had not trapped to EL2.
+    ///
+    /// The exception is taken with all of `DAIF` masked, and `PSTATE.PAN` set if
+    /// `SCTLR_EL1.SPAN` is cleared. As on a real exception entry, `PSTATE.DIT` is kept and
+    /// `UAO` cleared, the whole PSTATE of the guest being saved into `SPSR_EL1`.
+    ///
+    /// An AArch32 EL1 takes the prefetch and data aborts in the Abort mode, with their fault
+    /// status in `IFSR` or `DFSR`, and the other exceptions in the Undefined mode.
+    pub fn inject_el1_sync(&mut self, ctx: &mut Aarch64ContextFrame, esr: u64, pc: u64) {
+        const SCTLR_EL1_SPAN: u32 = 1 << 23;
+        const PSTATE_PAN: u64 = 1 << 22;
+        const PSTATE_DIT: u64 = 1 << 24;
+        const PSTATE_DAIF: u64 = 0b1111 << 6;
+        const PSTATE_EL1H: u64 = 0b0101;
+
+        if self.aarch32_el1() {
+            self.inject_aarch32_sync(ctx, esr, pc);
+            return;
+        }
+        // The vector offset of the synchronous exceptions, depending on the source.
+        let offset = if ctx.is_aarch32() {
+            0x600
+        } else {
+            match ctx.spsr & 0b1111 {
+                PSTATE_EL1H => 0x200,
+                // EL1t
+                0b0100 => 0x000,
+                _ => 0x400,
+            }
+        };
+        self.esr_el1 = esr as u32;
+        self.elr_el1 = pc;
+        self.spsr_el1 = ctx.spsr as u32;
+        let mut pstate = PSTATE_EL1H | PSTATE_DAIF | (ctx.spsr & (PSTATE_PAN | PSTATE_DIT));
+        if self.sctlr_el1 & SCTLR_EL1_SPAN == 0 {
+            pstate |= PSTATE_PAN;
+        }
+        ctx.spsr = pstate;
+        ctx.elr = self.vbar_el1 + offset;
+    }
+
+    /// Makes an AArch32 EL1 guest take the exception of [`Self::inject_el1_sync`], like KVM's
+    /// `enter_exception32`.
+    ///
+    /// The CPSR of the guest is saved into the SPSR of the mode, and the return address into
+    /// its LR, in the AArch64 view of the registers. The exception is taken with the IRQs
+    /// masked, the asynchronous aborts too in the Abort mode, in the state and endianness
+    /// selected by `SCTLR.TE` and `SCTLR.EE`, to the vectors selected by `SCTLR.V`.
+    fn inject_aarch32_sync(&mut self, ctx: &mut Aarch64ContextFrame, esr: u64, pc: u64) {
+        const MODE_ABT: u64 = 0b1_0111;
+        const MODE_UND: u64 = 0b1_1011;
+        /// `NZCVQ`, `DIT`, `GE`, `A` and `F`, bits [31:27], [21], [19:16], [8] and [6].
+        const CPSR_KEPT: u64 = (0b1_1111 << 27) | (1 << 21) | (0b1111 << 16) | (1 << 8) | (1 << 6);
+        const CPSR_PAN: u64 = 1 << 22;
+        const CPSR_E: u64 = 1 << 9;
+        const CPSR_A: u64 = 1 << 8;
+        const CPSR_I: u64 = 1 << 7;
+        const CPSR_T: u64 = 1 << 5;
+        const SCTLR_TE: u32 = 1 << 30;
+        const SCTLR_EE: u32 = 1 << 25;
+        const SCTLR_SPAN: u32 = 1 << 23;
+        const SCTLR_V: u32 = 1 << 13;
+        const TTBCR_EAE: u64 = 1 << 31;
+        /// `LR_abt` and `LR_und`, in the AArch64 view of the registers.
+        const LR_ABT: usize = 20;
+        const LR_UND: usize = 22;
+
+        let thumb = ctx.spsr & CPSR_T != 0;
+        // The mode, vector offset and return address offset, depending on the class.
+        let (mode, offset, lr_offset) = match (esr >> 26) & 0x3f {
+            0x20 | 0x21 => (MODE_ABT, 0x0c, 4),
+            0x24 | 0x25 => (MODE_ABT, 0x10, 8),
+            _ => (MODE_UND, 0x04, if thumb { 2 } else { 4 }),
+        };
+        let lr = (pc + lr_offset) as u32 as u64;
+        if mode == MODE_ABT {
+            let fsr = aarch32_fsr(esr, self.tcr_el1 & TTBCR_EAE != 0);
+            if offset == 0x0c {
+                self.ifsr32_el2 = fsr;
+            } else {
+                self.esr_el1 = fsr;
+            }
+            self.spsr_abt = ctx.spsr as u32;
+            ctx.gpr[LR_ABT] = lr;
+        } else {
+            self.spsr_und = ctx.spsr as u32;
+            ctx.gpr[LR_UND] = lr;
+        }
+
+        let mut cpsr = mode | CPSR_I | (ctx.spsr & CPSR_KEPT);
+        if mode == MODE_ABT {
+            cpsr |= CPSR_A;
+        }
+        if self.sctlr_el1 & SCTLR_SPAN == 0 {
+            cpsr |= CPSR_PAN;
+        } else {
+            cpsr |= ctx.spsr & CPSR_PAN;
+        }
+        if self.sctlr_el1 & SCTLR_EE != 0 {
+            cpsr |= CPSR_E;
+        }
+        if self.sctlr_el1 & SCTLR_TE != 0 {
+            cpsr |= CPSR_T;
+        }
+        ctx.spsr = cpsr;
+        let base = if self.sctlr_el1 & SCTLR_V != 0 {
+            0xffff_0000
+        } else {
+            self.vbar_el1 as u32 as u64
+        };
+        ctx.elr = base + offset;
+    }
+
+    /// Returns the physical counter value the virtual timer fires at with the virtual counter
+    /// offset `cntvoff_el2`, if it is enabled and its interrupt is not masked.
+    pub fn vtimer_deadline(&self, cntvoff_el2: u64) -> Option<u64> {
+        const CNTV_CTL_ENABLE: u32 = 1 << 0;
+        const CNTV_CTL_IMASK: u32 = 1 << 1;
+        let enabled = self.cntv_ctl_el0 & (CNTV_CTL_ENABLE | CNTV_CTL_IMASK) == CNTV_CTL_ENABLE;
+        enabled.then(|| self.cntv_cval_el0.wrapping_add(cntvoff_el2))
+    }
+
+    /// Returns the saved value of the system register `addr`, if it is one of the migrated
+    /// registers of [`VCPU_STATE_DESCRIPTOR`](crate::VCPU_STATE_DESCRIPTOR).
+    pub fn migrated_sysreg(&self, addr: SysRegAddr) -> Option<u64> {
+        Some(match addr {
+            SYSREG_SP_EL1 => self.sp_el1,
+            SYSREG_ELR_EL1 => self.elr_el1,
+            SYSREG_SPSR_EL1 => self.spsr_el1 as u64,
+            SYSREG_SCTLR_EL1 => self.sctlr_el1 as u64,
+            SYSREG_ACTLR_EL1 => self.actlr_el1,
+            SYSREG_CPACR_EL1 => self.cpacr_el1 as u64,
+            SYSREG_TTBR0_EL1 => self.ttbr0_el1,
+            SYSREG_TTBR1_EL1 => self.ttbr1_el1,
+            SYSREG_TCR_EL1 => self.tcr_el1,
+            SYSREG_ESR_EL1 => self.esr_el1 as u64,
+            SYSREG_FAR_EL1 => self.far_el1,
+            SYSREG_PAR_EL1 => self.par_el1,
+            SYSREG_MAIR_EL1 => self.mair_el1,
+            SYSREG_AMAIR_EL1 => self.amair_el1,
+            SYSREG_VBAR_EL1 => self.vbar_el1,
+            SYSREG_CONTEXTIDR_EL1 => self.contextidr_el1 as u64,
+            SYSREG_TPIDR_EL0 => self.tpidr_el0,
+            SYSREG_TPIDR_EL1 => self.tpidr_el1,
+            SYSREG_TPIDRRO_EL0 => self.tpidrro_el0,
+            SYSREG_CNTKCTL_EL1 => self.cntkctl_el1 as u64,
+            SYSREG_CNTV_CTL_EL0 => self.cntv_ctl_el0 as u64,
+            SYSREG_CNTV_CVAL_EL0 => self.cntv_cval_el0,
+            SYSREG_CNTVOFF_EL2 => self.cntvoff_el2,
+            SYSREG_CNTP_CTL_EL0 => self.cntp_ctl_el0 as u64,
+            SYSREG_CNTP_CVAL_EL0 => self.cntp_cval_el0,
+            SYSREG_CSSELR_EL1 => self.csselr_el1,
+            SYSREG_AFSR0_EL1 => self.afsr0_el1,
+            SYSREG_AFSR1_EL1 => self.afsr1_el1,
+            SYSREG_MDSCR_EL1 => self.mdscr_el1,
+            #[cfg(feature = "aarch32")]
+            SYSREG_SPSR_ABT => self.spsr_abt as u64,
+            #[cfg(feature = "aarch32")]
+            SYSREG_SPSR_UND => self.spsr_und as u64,
+            #[cfg(feature = "aarch32")]
+            SYSREG_SPSR_IRQ => self.spsr_irq as u64,
+            #[cfg(feature = "aarch32")]
+            SYSREG_SPSR_FIQ => self.spsr_fiq as u64,
+            _ => return None,
+        })
+    }
+
+    /// Replaces the saved value of the migrated system register `addr`, it is loaded into the
+    /// hardware by the next [`restore`](Self::restore).
+    ///
+    /// Returns `false` if `addr` is not one of the migrated registers.
+    pub fn set_migrated_sysreg(&mut self, addr: SysRegAddr, value: u64) -> bool {
+        match addr {
+            SYSREG_SP_EL1 => self.sp_el1 = value,
+            SYSREG_ELR_EL1 => self.elr_el1 = value,
+            SYSREG_SPSR_EL1 => self.spsr_el1 = value as u32,
+            SYSREG_SCTLR_EL1 => self.sctlr_el1 = value as u32,
+            SYSREG_ACTLR_EL1 => self.actlr_el1 = value,
+            SYSREG_CPACR_EL1 => self.cpacr_el1 = value as u32,
+            SYSREG_TTBR0_EL1 => self.ttbr0_el1 = value,
+            SYSREG_TTBR1_EL1 => self.ttbr1_el1 = value,
+            SYSREG_TCR_EL1 => self.tcr_el1 = value,
+            SYSREG_ESR_EL1 => self.esr_el1 = value as u32,
+            SYSREG_FAR_EL1 => self.far_el1 = value,
+            SYSREG_PAR_EL1 => self.par_el1 = value,
+            SYSREG_MAIR_EL1 => self.mair_el1 = value,
+            SYSREG_AMAIR_EL1 => self.amair_el1 = value,
+            SYSREG_VBAR_EL1 => self.vbar_el1 = value,
+            SYSREG_CONTEXTIDR_EL1 => self.contextidr_el1 = value as u32,
+            SYSREG_TPIDR_EL0 => self.tpidr_el0 = value,
+            SYSREG_TPIDR_EL1 => self.tpidr_el1 = value,
+            SYSREG_TPIDRRO_EL0 => self.tpidrro_el0 = value,
+            SYSREG_CNTKCTL_EL1 => self.cntkctl_el1 = value as u32,
+            SYSREG_CNTV_CTL_EL0 => self.cntv_ctl_el0 = value as u32,
+            SYSREG_CNTV_CVAL_EL0 => self.cntv_cval_el0 = value,
+            SYSREG_CNTVOFF_EL2 => self.cntvoff_el2 = value,
+            SYSREG_CNTP_CTL_EL0 => self.cntp_ctl_el0 = value as u32,
+            SYSREG_CNTP_CVAL_EL0 => self.cntp_cval_el0 = value,
+            SYSREG_CSSELR_EL1 => self.csselr_el1 = value,
+            SYSREG_AFSR0_EL1 => self.afsr0_el1 = value,
+            SYSREG_AFSR1_EL1 => self.afsr1_el1 = value,
+            SYSREG_MDSCR_EL1 => self.mdscr_el1 = value,
+            #[cfg(feature = "aarch32")]
+            SYSREG_SPSR_ABT => self.spsr_abt = value as u32,
+            #[cfg(feature = "aarch32")]
+            SYSREG_SPSR_UND => self.spsr_und = value as u32,
+            #[cfg(feature = "aarch32")]
+            SYSREG_SPSR_IRQ => self.spsr_irq = value as u32,
+            #[cfg(feature = "aarch32")]
+            SYSREG_SPSR_FIQ => self.spsr_fiq = value as u32,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Emulates a write of the guest to one of the virtual memory control registers trapped by
+    /// `HCR_EL2.TVM`, or to `VBAR_EL1`, the value is loaded into the hardware by the next
+    /// [`restore`](Self::restore).
+    ///
+    /// Returns `false` if `addr` is not one of them.
+    pub fn write_vm_control(&mut self, addr: SysRegAddr, value: u64) -> bool {
+        match addr {
+            SYSREG_SCTLR_EL1 => self.sctlr_el1 = value as u32,
+            SYSREG_TTBR0_EL1 => self.ttbr0_el1 = value,
+            SYSREG_TTBR1_EL1 => self.ttbr1_el1 = value,
+            SYSREG_TCR_EL1 => self.tcr_el1 = value,
+            SYSREG_ESR_EL1 => self.esr_el1 = value as u32,
+            SYSREG_FAR_EL1 => self.far_el1 = value,
+            SYSREG_MAIR_EL1 => self.mair_el1 = value,
+            SYSREG_AMAIR_EL1 => self.amair_el1 = value,
+            SYSREG_CONTEXTIDR_EL1 => self.contextidr_el1 = value as u32,
+            SYSREG_VBAR_EL1 => self.vbar_el1 = value,
+            SYSREG_AFSR0_EL1 => self.afsr0_el1 = value,
+            SYSREG_AFSR1_EL1 => self.afsr1_el1 = value,
+            _ => return false,
+        }
+        true
+    }
+
     /// Stores the current values of all relevant registers into the `GuestSystemRegisters` structure.
     ///
     /// This method uses inline assembly to read the values of various system registers
@@ -216,6 +725,7 @@ impl GuestSystemRegisters {
             asm!("mrs {0}, CNTV_CVAL_EL0", out(reg) self.cntv_cval_el0);
             asm!("mrs {0:x}, CNTKCTL_EL1", out(reg) self.cntkctl_el1);
             asm!("mrs {0:x}, CNTP_CTL_EL0", out(reg) self.cntp_ctl_el0);
+            asm!("mrs {0}, CNTP_CVAL_EL0", out(reg) self.cntp_cval_el0);
             asm!("mrs {0:x}, CNTV_CTL_EL0", out(reg) self.cntv_ctl_el0);
             asm!("mrs {0:x}, CNTP_TVAL_EL0", out(reg) self.cntp_tval_el0);
             asm!("mrs {0:x}, CNTV_TVAL_EL0", out(reg) self.cntv_tval_el0);
@@ -243,12 +753,30 @@ impl GuestSystemRegisters {
             asm!("mrs {0}, TPIDR_EL0", out(reg) self.tpidr_el0);
             asm!("mrs {0}, TPIDR_EL1", out(reg) self.tpidr_el1);
             asm!("mrs {0}, TPIDRRO_EL0", out(reg) self.tpidrro_el0);
+            asm!("mrs {0}, CSSELR_EL1", out(reg) self.csselr_el1);
+            asm!("mrs {0}, AFSR0_EL1", out(reg) self.afsr0_el1);
+            asm!("mrs {0}, AFSR1_EL1", out(reg) self.afsr1_el1);
+            asm!("mrs {0}, MDSCR_EL1", out(reg) self.mdscr_el1);
 
             asm!("mrs {0}, PMCR_EL0", out(reg) self.pmcr_el0);
             asm!("mrs {0}, VTCR_EL2", out(reg) self.vtcr_el2);
             asm!("mrs {0}, VTTBR_EL2", out(reg) self.vttbr_el2);
             asm!("mrs {0}, HCR_EL2", out(reg) self.hcr_el2);
+            asm!("mrs {0}, MDCR_EL2", out(reg) self.mdcr_el2);
             asm!("mrs {0}, ACTLR_EL1", out(reg) self.actlr_el1);
+            #[cfg(feature = "aarch32")]
+            if self.aarch32_el1() {
+                asm!("mrs {0:x}, DACR32_EL2", out(reg) self.dacr32_el2);
+                asm!("mrs {0:x}, IFSR32_EL2", out(reg) self.ifsr32_el2);
+                // No longer trapped by `CPTR_EL2.TFP` once the guest has exited.
+                asm!("mrs {0:x}, FPEXC32_EL2", out(reg) self.fpexc32_el2);
+                asm!("mrs {0:x}, SPSR_abt", out(reg) self.spsr_abt);
+                asm!("mrs {0:x}, SPSR_und", out(reg) self.spsr_und);
+                asm!("mrs {0:x}, SPSR_irq", out(reg) self.spsr_irq);
+                asm!("mrs {0:x}, SPSR_fiq", out(reg) self.spsr_fiq);
+            }
+            #[cfg(feature = "vgic")]
+            self.vgic.store();
             // println!("save sctlr {:x}", self.sctlr_el1);
         }
     }
@@ -262,11 +790,25 @@ impl GuestSystemRegisters {
     /// Each system register is restored with its corresponding value from the `GuestSystemRegisters`, ensuring
     /// that the virtual machine or thread resumes execution with the correct context.
     pub unsafe fn restore(&self) {
+        /// `CNTHCTL_EL2.EL1PCEN`, bit [1], set if the EL1 physical timer is not trapped.
+        const CNTHCTL_EL2_EL1PCEN: u64 = 1 << 1;
         unsafe {
             asm!("msr CNTV_CVAL_EL0, {0}", in(reg) self.cntv_cval_el0);
             asm!("msr CNTKCTL_EL1, {0:x}", in (reg) self.cntkctl_el1);
             asm!("msr CNTV_CTL_EL0, {0:x}", in (reg) self.cntv_ctl_el0);
-            asm!("msr CNTHCTL_EL2, {0}", in(reg) self.cnthctl_el2);
+            // The physical timer is only the guest's if it accesses it without trapping.
+            if self.cnthctl_el2 & CNTHCTL_EL2_EL1PCEN != 0 {
+                asm!("msr CNTP_CVAL_EL0, {0}", in(reg) self.cntp_cval_el0);
+                asm!("msr CNTP_CTL_EL0, {0:x}", in(reg) self.cntp_ctl_el0);
+            }
+            // The event stream of the host, `EVNTEN`, `EVNTDIR`, `EVNTI` and `EVNTIS`, bits
+            // [7:2] and [17], keeps running, the vCPU only sets the timer traps.
+            const CNTHCTL_EL2_EVNT_MASK: u64 = 0xfc | (1 << 17);
+            let host_cnthctl_el2: u64;
+            asm!("mrs {0}, CNTHCTL_EL2", out(reg) host_cnthctl_el2);
+            let cnthctl_el2 = (host_cnthctl_el2 & CNTHCTL_EL2_EVNT_MASK)
+                | (self.cnthctl_el2 & !CNTHCTL_EL2_EVNT_MASK);
+            asm!("msr CNTHCTL_EL2, {0}", in(reg) cnthctl_el2);
             // The restoration of SP_EL0 is done in `exception_return_el2`,
             // which move the value from `self.ctx.sp_el0` to `SP_EL0`.
             // asm!("msr SP_EL0, {0}", in(reg) self.sp_el0);
@@ -288,15 +830,278 @@ impl GuestSystemRegisters {
             asm!("msr TPIDR_EL0, {0}", in(reg) self.tpidr_el0);
             asm!("msr TPIDR_EL1, {0}", in(reg) self.tpidr_el1);
             asm!("msr TPIDRRO_EL0, {0}", in(reg) self.tpidrro_el0);
+            asm!("msr CSSELR_EL1, {0}", in(reg) self.csselr_el1);
+            asm!("msr AFSR0_EL1, {0}", in(reg) self.afsr0_el1);
+            asm!("msr AFSR1_EL1, {0}", in(reg) self.afsr1_el1);
+            asm!("msr MDSCR_EL1, {0}", in(reg) self.mdscr_el1);
 
             asm!("msr PMCR_EL0, {0}", in(reg) self.pmcr_el0);
             asm!("msr ACTLR_EL1, {0}", in(reg) self.actlr_el1);
+            #[cfg(feature = "aarch32")]
+            if self.aarch32_el1() {
+                // `FPEXC32_EL2` is loaded by `restore_fpexc32`, before the FP/SIMD traps are set.
+                asm!("msr DACR32_EL2, {0:x}", in(reg) self.dacr32_el2);
+                asm!("msr IFSR32_EL2, {0:x}", in(reg) self.ifsr32_el2);
+                asm!("msr SPSR_abt, {0:x}", in(reg) self.spsr_abt);
+                asm!("msr SPSR_und, {0:x}", in(reg) self.spsr_und);
+                asm!("msr SPSR_irq, {0:x}", in(reg) self.spsr_irq);
+                asm!("msr SPSR_fiq, {0:x}", in(reg) self.spsr_fiq);
+            }
 
             asm!("msr VTCR_EL2, {0}", in(reg) self.vtcr_el2);
             asm!("msr VTTBR_EL2, {0}", in(reg) self.vttbr_el2);
             asm!("msr HCR_EL2, {0}", in(reg) self.hcr_el2);
+            asm!("msr MDCR_EL2, {0}", in(reg) self.mdcr_el2);
             asm!("msr VMPIDR_EL2, {0}", in(reg) self.vmpidr_el2);
             asm!("msr CNTVOFF_EL2, {0}", in(reg) self.cntvoff_el2);
+            #[cfg(feature = "vgic")]
+            self.vgic.restore();
         }
     }
 }
+
+/// Returns the AArch32 `DFSR` or `IFSR` of the abort with the syndrome `esr`, in the
+/// long-descriptor format if `lpae`, in the short-descriptor one otherwise.
+///
+/// The fault statuses with no short-descriptor encoding are reported as synchronous external
+/// aborts.
+fn aarch32_fsr(esr: u64, lpae: bool) -> u32 {
+    const FSR_WNR: u32 = 1 << 11;
+    const FSR_LPAE: u32 = 1 << 9;
+    const ESR_WNR: u64 = 1 << 6;
+
+    let fsc = (esr & 0x3f) as u32;
+    // Only the data aborts report the written ones.
+    let is_data = (esr >> 26) & 0x3f >= 0x24;
+    let wnr = if is_data && esr & ESR_WNR != 0 {
+        FSR_WNR
+    } else {
+        0
+    };
+    if lpae {
+        return FSR_LPAE | wnr | fsc;
+    }
+    let fs = match fsc {
+        // Translation, Access flag and Permission faults, levels 1 and 2.
+        0b00_0101 => 0b0_0101,
+        0b00_0110 => 0b0_0111,
+        0b00_1001 => 0b0_0011,
+        0b00_1010 => 0b0_0110,
+        0b00_1101 => 0b0_1101,
+        0b00_1110 => 0b0_1111,
+        // Alignment and Debug.
+        0b10_0001 => 0b0_0001,
+        0b10_0010 => 0b0_0010,
+        _ => 0b0_1000,
+    };
+    // `FS[4]` is bit [10].
+    wnr | ((fs & 0b1_0000) << 6) | (fs & 0b1111)
+}
+
+/// Checks that the guest can be entered with `pstate` set by the VMM, i.e. that its `M[4:0]`
+/// field is an exception level and stack pointer, or a mode, of the register width of the EL1
+/// of the guest.
+///
+/// An AArch64 EL1 is entered in EL1h, EL1t or EL0t, an AArch32 one, if `aarch32_el1`, in
+/// any of the User, FIQ, IRQ, Supervisor, Abort, Undefined and System modes. Returns
+/// `InvalidInput` otherwise, the entry would be an illegal exception return.
+pub(crate) fn validate_guest_pstate(pstate: u64, aarch32_el1: bool) -> AxResult {
+    let valid = match (aarch32_el1, pstate & 0b1_1111) {
+        // EL0t, EL1t and EL1h.
+        (false, 0b0_0000 | 0b0_0100 | 0b0_0101) => true,
+        // usr, fiq, irq, svc, abt, und and sys.
+        (true, 0b1_0000..=0b1_0011 | 0b1_0111 | 0b1_1011 | 0b1_1111) => true,
+        _ => false,
+    };
+    if !valid {
+        return ax_err!(InvalidInput, "PSTATE.M is not a mode of the guest EL1");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSTATE_EL0T: u64 = 0b0000;
+    const PSTATE_EL1H: u64 = 0b0101;
+    const PSTATE_SSBS: u64 = 1 << 12;
+    const PSTATE_PAN: u64 = 1 << 22;
+    const PSTATE_UAO: u64 = 1 << 23;
+    const PSTATE_DIT: u64 = 1 << 24;
+    const PSTATE_EMERGING: u64 = PSTATE_SSBS | PSTATE_PAN | PSTATE_UAO | PSTATE_DIT;
+    const SCTLR_EL1_SPAN: u32 = 1 << 23;
+    const VBAR_EL1: u64 = 0x4008_0000;
+    const HCR_EL2_RW: u64 = 1 << 31;
+    const CPSR_SVC: u64 = 0b1_0011;
+    const CPSR_T: u64 = 1 << 5;
+    const ESR_UNDEF: u64 = 1 << 25;
+
+    fn guest(spsr: u64, sctlr_el1: u32) -> (GuestSystemRegisters, Aarch64ContextFrame) {
+        let regs = GuestSystemRegisters {
+            sctlr_el1,
+            vbar_el1: VBAR_EL1,
+            hcr_el2: HCR_EL2_RW,
+            ..Default::default()
+        };
+        let ctx = Aarch64ContextFrame {
+            spsr,
+            ..Default::default()
+        };
+        (regs, ctx)
+    }
+
+    #[test]
+    fn synthetic_spsr_exception_level() {
+        let (_, ctx) = guest(PSTATE_EL1H | PSTATE_EMERGING, 0);
+        assert_eq!(ctx.exception_level(), 1);
+        assert!(!ctx.is_aarch32());
+        let (_, ctx) = guest(PSTATE_EL0T | PSTATE_EMERGING, 0);
+        assert_eq!(ctx.exception_level(), 0);
+        assert!(!ctx.is_aarch32());
+    }
+
+    #[test]
+    fn injected_exception_saves_the_whole_pstate() {
+        for spsr in [
+            PSTATE_EL1H | PSTATE_EMERGING,
+            PSTATE_EL0T | PSTATE_DIT | PSTATE_SSBS,
+        ] {
+            let (mut regs, mut ctx) = guest(spsr, SCTLR_EL1_SPAN);
+            regs.inject_el1_sync(&mut ctx, 0, 0x1000);
+            assert_eq!(regs.spsr_el1 as u64, spsr);
+            assert_eq!(regs.elr_el1, 0x1000);
+        }
+    }
+
+    #[test]
+    fn injected_exception_keeps_dit_and_clears_uao() {
+        let (mut regs, mut ctx) = guest(PSTATE_EL1H | PSTATE_EMERGING, SCTLR_EL1_SPAN);
+        regs.inject_el1_sync(&mut ctx, 0, 0);
+        assert_eq!(ctx.spsr & PSTATE_DIT, PSTATE_DIT);
+        assert_eq!(ctx.spsr & PSTATE_UAO, 0);
+
+        let (mut regs, mut ctx) = guest(PSTATE_EL1H | PSTATE_UAO, SCTLR_EL1_SPAN);
+        regs.inject_el1_sync(&mut ctx, 0, 0);
+        assert_eq!(ctx.spsr & (PSTATE_DIT | PSTATE_UAO), 0);
+    }
+
+    #[test]
+    fn injected_exception_pan() {
+        // `SCTLR_EL1.SPAN` clear: PAN is set on the exception entry.
+        let (mut regs, mut ctx) = guest(PSTATE_EL1H, 0);
+        regs.inject_el1_sync(&mut ctx, 0, 0);
+        assert_eq!(ctx.spsr & PSTATE_PAN, PSTATE_PAN);
+        // `SCTLR_EL1.SPAN` set: PAN is kept, either way.
+        for pan in [0, PSTATE_PAN] {
+            let (mut regs, mut ctx) = guest(PSTATE_EL1H | pan, SCTLR_EL1_SPAN);
+            regs.inject_el1_sync(&mut ctx, 0, 0);
+            assert_eq!(ctx.spsr & PSTATE_PAN, pan);
+        }
+    }
+
+    #[test]
+    fn injected_exception_mode_and_vector() {
+        let (mut regs, mut ctx) = guest(PSTATE_EL1H | PSTATE_EMERGING, 0);
+        regs.inject_el1_sync(&mut ctx, 0x9600_0000, 0);
+        assert_eq!(ctx.spsr & 0b1111, PSTATE_EL1H);
+        assert_eq!(ctx.spsr & (0b1111 << 6), 0b1111 << 6);
+        assert_eq!(ctx.elr, VBAR_EL1 + 0x200);
+        assert_eq!(regs.esr_el1, 0x9600_0000);
+
+        let (mut regs, mut ctx) = guest(PSTATE_EL0T | PSTATE_EMERGING, 0);
+        regs.inject_el1_sync(&mut ctx, 0, 0);
+        assert_eq!(ctx.spsr & 0b1111, PSTATE_EL1H);
+        assert_eq!(ctx.elr, VBAR_EL1 + 0x400);
+    }
+
+    #[test]
+    fn aarch32_undef_entry() {
+        const IT_ELSE: u64 = 1 << 10;
+        let (mut regs, mut ctx) = guest(CPSR_SVC | CPSR_T | IT_ELSE, 0);
+        regs.hcr_el2 = 0;
+        regs.inject_el1_sync(&mut ctx, ESR_UNDEF, 0x8000);
+        assert_eq!(regs.spsr_und as u64, CPSR_SVC | CPSR_T | IT_ELSE);
+        assert_eq!(ctx.gpr[22], 0x8002);
+        // The Undefined mode in the A32 state, IRQs masked and out of the IT block.
+        assert_eq!(ctx.spsr & !(1 << 22), 0b1_1011 | (1 << 7));
+        assert_eq!(ctx.elr, VBAR_EL1 + 0x04);
+    }
+
+    #[test]
+    fn aarch32_data_abort_entry() {
+        const SCTLR_V: u32 = 1 << 13;
+        // A write with a level 1 translation fault.
+        let esr = (0x24 << 26) | (1 << 6) | 0b00_0101;
+        let (mut regs, mut ctx) = guest(CPSR_SVC, SCTLR_EL1_SPAN);
+        regs.hcr_el2 = 0;
+        regs.inject_el1_sync(&mut ctx, esr, 0x8000);
+        assert_eq!(regs.esr_el1, (1 << 11) | 0b0_0101);
+        assert_eq!(regs.spsr_abt as u64, CPSR_SVC);
+        assert_eq!(ctx.gpr[20], 0x8008);
+        assert_eq!(ctx.spsr, 0b1_0111 | (1 << 8) | (1 << 7));
+        assert_eq!(ctx.elr, VBAR_EL1 + 0x10);
+
+        // The long-descriptor format, and the high vectors.
+        let (mut regs, mut ctx) = guest(CPSR_SVC, SCTLR_V);
+        regs.hcr_el2 = 0;
+        regs.tcr_el1 = 1 << 31;
+        regs.inject_el1_sync(&mut ctx, esr, 0x8000);
+        assert_eq!(regs.esr_el1, (1 << 11) | (1 << 9) | 0b00_0101);
+        assert_eq!(ctx.elr, 0xffff_0010);
+    }
+
+    #[test]
+    fn guest_pstate_modes() {
+        for mode in [PSTATE_EL0T, 0b0100, PSTATE_EL1H] {
+            assert!(validate_guest_pstate(0x3c0 | mode, false).is_ok());
+            assert!(validate_guest_pstate(0x1c0 | mode, true).is_err());
+        }
+        // EL2h, and the AArch32 Supervisor mode for an AArch64 EL1.
+        assert!(validate_guest_pstate(0b1001, false).is_err());
+        assert!(validate_guest_pstate(CPSR_SVC, false).is_err());
+        assert!(validate_guest_pstate(CPSR_SVC | CPSR_T, true).is_ok());
+        // The Hyp and Monitor modes.
+        assert!(validate_guest_pstate(0b1_1010, true).is_err());
+        assert!(validate_guest_pstate(0b1_0110, true).is_err());
+    }
+
+    #[test]
+    fn it_block_advance() {
+        // `ITT EQ`: two instructions, then out of the block.
+        let (_, mut ctx) = guest(CPSR_SVC | CPSR_T | (1 << 10), 0);
+        assert_eq!(ctx.it_state(), 0b0000_0100);
+        ctx.skip_instruction(2);
+        assert_eq!(ctx.it_state(), 0b0000_1000);
+        assert_eq!(ctx.spsr, CPSR_SVC | CPSR_T | (1 << 11));
+        ctx.skip_instruction(4);
+        assert_eq!(ctx.it_state(), 0);
+        assert_eq!(ctx.spsr, CPSR_SVC | CPSR_T);
+        assert_eq!(ctx.elr, 6);
+
+        // `ITT NE`, the low bit of the condition is shifted in from the mask.
+        let it: u64 = 0b0001_1100;
+        let (_, mut ctx) = guest(CPSR_SVC | CPSR_T | ((it >> 2) << 10), 0);
+        ctx.skip_instruction(2);
+        assert_eq!(ctx.it_state(), 0b0001_1000);
+    }
+
+    #[test]
+    fn aarch32_condition_check() {
+        const CPSR_Z: u64 = 1 << 30;
+        const ESR_CV: u64 = 1 << 24;
+        // An `MCR` with the condition NE.
+        let esr = (0x03 << 26) | ESR_CV | (0b0001 << 20);
+        let (_, ctx) = guest(CPSR_SVC | CPSR_Z, 0);
+        assert!(!ctx.aarch32_condition_passed(esr));
+        let (_, ctx) = guest(CPSR_SVC, 0);
+        assert!(ctx.aarch32_condition_passed(esr));
+        // No condition in the syndrome, the one of the IT block: EQ.
+        let (_, ctx) = guest(CPSR_SVC | CPSR_T | (1 << 10), 0);
+        assert!(!ctx.aarch32_condition_passed(0x03 << 26));
+        assert!(ctx.aarch32_condition_passed(0x12 << 26));
+        // AArch64 instructions are unconditional.
+        let (_, ctx) = guest(PSTATE_EL1H, 0);
+        assert!(ctx.aarch32_condition_passed(esr));
+    }
+}
@@ -0,0 +1,234 @@
+//! Detection of optional architectural features on the host CPU.
+//!
+//! The ID registers are read directly each time a query is made, they are cheap to read
+//! and identical on all PEs we care about.
+
+/// Reads the AArch64 Processor Feature Register 0 (ID_AA64PFR0_EL1).
+#[inline(always)]
+fn id_aa64pfr0_el1() -> u64 {
+    let val: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, ID_AA64PFR0_EL1", out(reg) val);
+    }
+    val
+}
+
+/// Reads the AArch64 Processor Feature Register 1 (ID_AA64PFR1_EL1).
+#[inline(always)]
+fn id_aa64pfr1_el1() -> u64 {
+    let val: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, ID_AA64PFR1_EL1", out(reg) val);
+    }
+    val
+}
+
+/// Reads the AArch64 Debug Feature Register 0 (ID_AA64DFR0_EL1).
+#[inline(always)]
+fn id_aa64dfr0_el1() -> u64 {
+    let val: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, ID_AA64DFR0_EL1", out(reg) val);
+    }
+    val
+}
+
+/// Reads the AArch64 Memory Model Feature Register 0 (ID_AA64MMFR0_EL1).
+#[inline(always)]
+fn id_aa64mmfr0_el1() -> u64 {
+    let val: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, ID_AA64MMFR0_EL1", out(reg) val);
+    }
+    val
+}
+
+/// Reads the AArch64 Memory Model Feature Register 1 (ID_AA64MMFR1_EL1).
+#[inline(always)]
+fn id_aa64mmfr1_el1() -> u64 {
+    let val: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, ID_AA64MMFR1_EL1", out(reg) val);
+    }
+    val
+}
+
+/// Reads the AArch64 Memory Model Feature Register 2 (ID_AA64MMFR2_EL1).
+#[inline(always)]
+fn id_aa64mmfr2_el1() -> u64 {
+    let val: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, ID_AA64MMFR2_EL1", out(reg) val);
+    }
+    val
+}
+
+/// Extracts a 4-bit ID register field starting at `shift`.
+#[inline(always)]
+const fn id_field(reg: u64, shift: u32) -> u64 {
+    (reg >> shift) & 0xf
+}
+
+/// Returns the physical address range implemented by the host, in the encoding of
+/// `VTCR_EL2.PS` (0b0000 for 32 bits ... 0b0101 for 48 bits, 0b0110 for 52 bits).
+///
+/// See ID_AA64MMFR0_EL1.PARange, bits [3:0].
+pub fn host_parange() -> u64 {
+    const ID_AA64MMFR0_PARANGE_SHIFT: u32 = 0;
+    id_field(id_aa64mmfr0_el1(), ID_AA64MMFR0_PARANGE_SHIFT)
+}
+
+/// Returns whether the host EL1 can run in AArch32 state.
+///
+/// See ID_AA64PFR0_EL1.EL1, bits [7:4], 0b0010 if AArch32 is supported.
+#[cfg(feature = "aarch32")]
+pub fn has_aarch32_el1() -> bool {
+    const ID_AA64PFR0_EL1_SHIFT: u32 = 4;
+    id_field(id_aa64pfr0_el1(), ID_AA64PFR0_EL1_SHIFT) >= 2
+}
+
+/// Returns whether the host implements FEAT_RAS, i.e. whether the syndrome of the virtual
+/// SErrors can be set with `VSESR_EL2`.
+///
+/// See ID_AA64PFR0_EL1.RAS, bits [31:28].
+pub fn has_feat_ras() -> bool {
+    const ID_AA64PFR0_RAS_SHIFT: u32 = 28;
+    id_field(id_aa64pfr0_el1(), ID_AA64PFR0_RAS_SHIFT) >= 1
+}
+
+/// Returns whether the host implements FEAT_S2FWB, i.e. whether `HCR_EL2.FWB` can be used
+/// to force stage-2 memory attributes over the stage-1 ones.
+///
+/// See ID_AA64MMFR2_EL1.FWB, bits [43:40].
+pub fn has_feat_s2fwb() -> bool {
+    const ID_AA64MMFR2_FWB_SHIFT: u32 = 40;
+    id_field(id_aa64mmfr2_el1(), ID_AA64MMFR2_FWB_SHIFT) >= 1
+}
+
+/// Returns whether the host implements FEAT_NV2, i.e. whether the EL2 register accesses of a
+/// guest hypervisor can be redirected to memory.
+///
+/// See ID_AA64MMFR2_EL1.NV, bits [27:24].
+#[cfg(feature = "nested")]
+pub fn has_feat_nv2() -> bool {
+    const ID_AA64MMFR2_NV_SHIFT: u32 = 24;
+    id_field(id_aa64mmfr2_el1(), ID_AA64MMFR2_NV_SHIFT) >= 2
+}
+
+/// Returns whether the host implements FEAT_FGT, the fine-grained traps (`HFGRTR_EL2`,
+/// `HFGWTR_EL2` ...).
+///
+/// See ID_AA64MMFR0_EL1.FGT, bits [59:56].
+pub fn has_feat_fgt() -> bool {
+    const ID_AA64MMFR0_FGT_SHIFT: u32 = 56;
+    id_field(id_aa64mmfr0_el1(), ID_AA64MMFR0_FGT_SHIFT) >= 1
+}
+
+/// Returns whether the host implements FEAT_LOR (Limited Ordering Regions).
+///
+/// See ID_AA64MMFR1_EL1.LO, bits [19:16].
+pub fn has_feat_lor() -> bool {
+    const ID_AA64MMFR1_LO_SHIFT: u32 = 16;
+    id_field(id_aa64mmfr1_el1(), ID_AA64MMFR1_LO_SHIFT) >= 1
+}
+
+/// Returns whether the host implements FEAT_XNX, i.e. whether stage-2 execute-never can be
+/// controlled separately for EL0 and EL1.
+///
+/// See ID_AA64MMFR1_EL1.XNX, bits [31:28].
+pub fn has_feat_xnx() -> bool {
+    const ID_AA64MMFR1_XNX_SHIFT: u32 = 28;
+    id_field(id_aa64mmfr1_el1(), ID_AA64MMFR1_XNX_SHIFT) >= 1
+}
+
+/// Returns whether the host implements the system register interface of GICv3 or later, with
+/// its virtualization extensions.
+///
+/// See ID_AA64PFR0_EL1.GIC, bits [27:24].
+#[cfg(feature = "vgic")]
+pub fn has_gicv3_sysregs() -> bool {
+    const ID_AA64PFR0_GIC_SHIFT: u32 = 24;
+    id_field(id_aa64pfr0_el1(), ID_AA64PFR0_GIC_SHIFT) >= 1
+}
+
+/// Returns whether the host implements FEAT_MPAM, in any version.
+///
+/// See ID_AA64PFR0_EL1.MPAM, bits [43:40], and ID_AA64PFR1_EL1.MPAM_frac, bits [19:16].
+pub fn has_feat_mpam() -> bool {
+    const ID_AA64PFR0_MPAM_SHIFT: u32 = 40;
+    const ID_AA64PFR1_MPAM_FRAC_SHIFT: u32 = 16;
+    id_field(id_aa64pfr0_el1(), ID_AA64PFR0_MPAM_SHIFT) >= 1
+        || id_field(id_aa64pfr1_el1(), ID_AA64PFR1_MPAM_FRAC_SHIFT) >= 1
+}
+
+/// Returns whether the host implements FEAT_TRF (self-hosted trace filtering, `TRFCR_ELx`).
+///
+/// See ID_AA64DFR0_EL1.TraceFilt, bits [43:40].
+pub fn has_feat_trf() -> bool {
+    const ID_AA64DFR0_TRACEFILT_SHIFT: u32 = 40;
+    id_field(id_aa64dfr0_el1(), ID_AA64DFR0_TRACEFILT_SHIFT) >= 1
+}
+
+/// Returns whether the host implements the PMUv3 architecture, in any version.
+///
+/// See ID_AA64DFR0_EL1.PMUVer, bits [11:8], 0b1111 being an IMPLEMENTATION DEFINED PMU.
+pub fn has_feat_pmuv3() -> bool {
+    matches!(pmu_version(), 1..=0xe)
+}
+
+/// Returns whether the host implements FEAT_PMUv3p1, which provides `MDCR_EL2.HPMD`.
+pub fn has_feat_pmuv3p1() -> bool {
+    matches!(pmu_version(), 4..=0xe)
+}
+
+/// Returns whether the host implements FEAT_PMUv3p5, which provides `MDCR_EL2.HCCD`.
+pub fn has_feat_pmuv3p5() -> bool {
+    matches!(pmu_version(), 6..=0xe)
+}
+
+/// Returns ID_AA64DFR0_EL1.PMUVer, bits [11:8].
+fn pmu_version() -> u64 {
+    const ID_AA64DFR0_PMUVER_SHIFT: u32 = 8;
+    id_field(id_aa64dfr0_el1(), ID_AA64DFR0_PMUVER_SHIFT)
+}
+
+/// Returns whether the host implements FEAT_TRBE (Trace Buffer Extension).
+///
+/// See ID_AA64DFR0_EL1.TraceBuffer, bits [47:44].
+pub fn has_feat_trbe() -> bool {
+    const ID_AA64DFR0_TRACEBUFFER_SHIFT: u32 = 44;
+    id_field(id_aa64dfr0_el1(), ID_AA64DFR0_TRACEBUFFER_SHIFT) >= 1
+}
+
+/// Returns whether the host implements FEAT_PAN (Privileged Access Never).
+///
+/// See ID_AA64MMFR1_EL1.PAN, bits [23:20].
+pub fn has_feat_pan() -> bool {
+    const ID_AA64MMFR1_PAN_SHIFT: u32 = 20;
+    id_field(id_aa64mmfr1_el1(), ID_AA64MMFR1_PAN_SHIFT) >= 1
+}
+
+/// Returns whether the host implements FEAT_UAO (User Access Override).
+///
+/// See ID_AA64MMFR2_EL1.UAO, bits [7:4].
+pub fn has_feat_uao() -> bool {
+    const ID_AA64MMFR2_UAO_SHIFT: u32 = 4;
+    id_field(id_aa64mmfr2_el1(), ID_AA64MMFR2_UAO_SHIFT) >= 1
+}
+
+/// Returns whether the host implements FEAT_DIT (Data Independent Timing).
+///
+/// See ID_AA64PFR0_EL1.DIT, bits [51:48].
+pub fn has_feat_dit() -> bool {
+    const ID_AA64PFR0_DIT_SHIFT: u32 = 48;
+    id_field(id_aa64pfr0_el1(), ID_AA64PFR0_DIT_SHIFT) >= 1
+}
+
+/// Returns whether the host implements FEAT_SSBS (Speculative Store Bypass Safe) with the
+/// `MSR`/`MRS` instructions to access PSTATE.SSBS.
+///
+/// See ID_AA64PFR1_EL1.SSBS, bits [7:4].
+pub fn has_feat_ssbs2() -> bool {
+    const ID_AA64PFR1_SSBS_SHIFT: u32 = 4;
+    id_field(id_aa64pfr1_el1(), ID_AA64PFR1_SSBS_SHIFT) >= 2
+}
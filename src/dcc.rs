@@ -0,0 +1,138 @@
+//! Emulation of the Debug Communications Channel (DCC).
+//!
+//! The DCC is the channel between the PE and an external debugger, used by `earlycon=dcc`,
+//! `hvc_dcc` and JTAG-style consoles. Under the hypervisor there is no debugger draining it,
+//! so a guest polling `MDCCSR_EL0.TXfull` would hang forever. When DCC emulation is enabled,
+//! the debug registers are trapped (`MDCR_EL2.TDA`) and the channel is backed by the host
+//! instead.
+
+use axaddrspace::device::SysRegAddr;
+
+use crate::sysreg::{
+    SYSREG_DBGDTR_EL0, SYSREG_DBGDTRRX_EL0, SYSREG_DBGDTRTX_EL0, SYSREG_MDCCSR_EL0,
+    SYSREG_MDSCR_EL1, is_debug_sysreg,
+};
+
+/// Maximum length of a line of guest output buffered by [`DccBackend::Log`].
+const DCC_LINE_MAX: usize = 128;
+
+/// `MDCCSR_EL0.RXfull`, bit [30].
+const MDCCSR_EL0_RXFULL: u64 = 1 << 30;
+
+/// Where the data transmitted by the guest through the DCC goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DccBackend {
+    /// The output of the guest is written to the host log, line by line.
+    Log,
+    /// Each word written to `DBGDTRTX_EL0` is reported to the VMM as an
+    /// [`AxVCpuExitReason::SysRegWrite`] to [`SYSREG_DBGDTRTX_EL0`](crate::SYSREG_DBGDTRTX_EL0),
+    /// the rare writes to the half-duplex `DBGDTR_EL0` are reported as they are.
+    ///
+    /// Input can be provided to the guest with [`Aarch64VCpu::dcc_push_rx`].
+    ///
+    /// [`AxVCpuExitReason::SysRegWrite`]: axvcpu::AxVCpuExitReason::SysRegWrite
+    /// [`Aarch64VCpu::dcc_push_rx`]: crate::Aarch64VCpu::dcc_push_rx
+    Exit,
+}
+
+/// The emulated DCC of a vCPU.
+///
+/// The transmit side is always ready (`MDCCSR_EL0.TXfull` reads as 0), the receive side only
+/// holds data pushed by the VMM. The other debug registers are trapped as well, they are
+/// emulated as RAZ/WI, except `MDSCR_EL1` which keeps the value written by the guest.
+#[derive(Clone, Debug)]
+pub struct VirtDcc {
+    backend: DccBackend,
+    line: [u8; DCC_LINE_MAX],
+    line_len: usize,
+    rx: Option<u32>,
+    mdscr_el1: u64,
+}
+
+impl VirtDcc {
+    /// Creates an empty channel with the given backend.
+    pub const fn new(backend: DccBackend) -> Self {
+        Self {
+            backend,
+            line: [0; DCC_LINE_MAX],
+            line_len: 0,
+            rx: None,
+            mdscr_el1: 0,
+        }
+    }
+
+    /// Makes `data` available to the guest in `DBGDTRRX_EL0`.
+    ///
+    /// Returns `false` if the guest has not read the previous data yet.
+    pub fn push_rx(&mut self, data: u32) -> bool {
+        if self.rx.is_some() {
+            return false;
+        }
+        self.rx = Some(data);
+        true
+    }
+
+    /// Returns the `MDSCR_EL1` written by the guest.
+    pub fn mdscr_el1(&self) -> u64 {
+        self.mdscr_el1
+    }
+
+    /// Emulates a read of a debug register.
+    ///
+    /// Returns `None` if `addr` is not a debug register.
+    pub fn read(&mut self, addr: SysRegAddr) -> Option<u64> {
+        match addr {
+            SYSREG_MDCCSR_EL0 => Some(if self.rx.is_some() {
+                MDCCSR_EL0_RXFULL
+            } else {
+                0
+            }),
+            SYSREG_DBGDTR_EL0 | SYSREG_DBGDTRRX_EL0 => {
+                Some(self.rx.take().unwrap_or_default() as u64)
+            }
+            SYSREG_MDSCR_EL1 => Some(self.mdscr_el1),
+            _ if is_debug_sysreg(addr) => Some(0),
+            _ => None,
+        }
+    }
+
+    /// Emulates a write to a debug register, `mpidr` identifies the vCPU in the host log.
+    ///
+    /// Returns `false` if `addr` is not a debug register, or if the write has to be reported to
+    /// the VMM.
+    pub fn write(&mut self, addr: SysRegAddr, value: u64, mpidr: u64) -> bool {
+        match (addr, self.backend) {
+            (SYSREG_DBGDTRTX_EL0 | SYSREG_DBGDTR_EL0, DccBackend::Exit) => false,
+            (SYSREG_DBGDTRTX_EL0, DccBackend::Log) => {
+                self.log_byte(value as u8, mpidr);
+                true
+            }
+            // Both words of the half-duplex register are transmitted, the low one first.
+            (SYSREG_DBGDTR_EL0, DccBackend::Log) => {
+                self.log_byte(value as u8, mpidr);
+                self.log_byte((value >> 32) as u8, mpidr);
+                true
+            }
+            (SYSREG_MDSCR_EL1, _) => {
+                self.mdscr_el1 = value;
+                true
+            }
+            _ => is_debug_sysreg(addr),
+        }
+    }
+
+    /// Buffers a byte transmitted by the guest, flushing the line to the host log on newlines.
+    fn log_byte(&mut self, byte: u8, mpidr: u64) {
+        if byte != b'\n' && byte != b'\r' {
+            self.line[self.line_len] = byte;
+            self.line_len += 1;
+        }
+        if byte == b'\n' || self.line_len == DCC_LINE_MAX {
+            info!(
+                "[vCPU {mpidr:#x} DCC] {}",
+                self.line[..self.line_len].escape_ascii()
+            );
+            self.line_len = 0;
+        }
+    }
+}
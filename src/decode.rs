@@ -0,0 +1,735 @@
+//! Decoders for the Instruction Specific Syndrome (ISS) reported in `ESR_EL2`.
+//!
+//! The decoders only work on raw syndrome values and never touch the hardware registers, so
+//! they can be exercised by the fuzzing harness in `fuzz/`. The syndromes are (indirectly)
+//! controlled by the guest, decoding must never panic.
+
+use axaddrspace::device::{AccessWidth, SysRegAddr};
+
+/// The fault status code of a data or instruction abort, from ISS.xFSC[5:2].
+///
+/// The lowest two bits, which hold the translation table level the fault occurred at, are
+/// ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultStatus {
+    /// Address size fault.
+    AddressSize,
+    /// Translation fault.
+    Translation,
+    /// Access flag fault.
+    AccessFlag,
+    /// Permission fault.
+    Permission,
+    /// Any other fault (synchronous external abort, alignment fault, TLB conflict ...).
+    Other,
+}
+
+impl FaultStatus {
+    /// Decodes the fault status code from the ISS of a data or instruction abort.
+    pub const fn from_iss(iss: usize) -> Self {
+        match (iss & 0b111111) >> 2 {
+            0b0000 => Self::AddressSize,
+            0b0001 => Self::Translation,
+            0b0010 => Self::AccessFlag,
+            0b0011 => Self::Permission,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Returns the level of the translation tables a translation, access flag or permission fault
+/// is reported at, from the ISS of a data or instruction abort (ISS.xFSC[1:0]).
+///
+/// Returns `None` for the other faults, whose xFSC[1:0] is no level.
+pub const fn fault_level(iss: usize) -> Option<u8> {
+    match FaultStatus::from_iss(iss) {
+        FaultStatus::Translation | FaultStatus::AccessFlag | FaultStatus::Permission => {
+            Some((iss & 0b11) as u8)
+        }
+        _ => None,
+    }
+}
+
+/// Returns whether the data abort with the given ISS is caused by a cache maintenance
+/// instruction (ISS.CM), which has no valid instruction syndrome.
+pub const fn is_cache_maintenance(iss: usize) -> bool {
+    const ISS_DA_CM: usize = 1 << 8;
+    iss & ISS_DA_CM != 0
+}
+
+/// The access that caused a data abort, as described by a valid instruction syndrome.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataAbortAccess {
+    /// The size of the access (ISS.SAS).
+    pub width: AccessWidth,
+    /// The index of the transferred register (ISS.SRT), 31 is the zero register.
+    pub reg: usize,
+    /// The width of the transferred register (ISS.SF).
+    pub reg_width: AccessWidth,
+    /// Whether the access is a write (ISS.WnR).
+    pub write: bool,
+    /// Whether the loaded value is sign-extended (ISS.SSE).
+    pub sign_ext: bool,
+    /// Whether the access has acquire or release semantics (ISS.AR), i.e. is an `LDAR`,
+    /// `STLR` or one of their variants.
+    pub acquire_release: bool,
+}
+
+impl DataAbortAccess {
+    /// Decodes the access from the ISS of a data abort.
+    ///
+    /// Returns `None` if the instruction syndrome is not valid (ISS.ISV is clear), e.g. for
+    /// load/store pairs or accesses with writeback.
+    pub fn decode(iss: usize) -> Option<Self> {
+        const ISS_DA_ISV: usize = 1 << 24;
+        const ISS_DA_SSE: usize = 1 << 21;
+        const ISS_DA_SF: usize = 1 << 15;
+        const ISS_DA_AR: usize = 1 << 14;
+        const ISS_DA_WNR: usize = 1 << 6;
+
+        if iss & ISS_DA_ISV == 0 {
+            return None;
+        }
+
+        let width = AccessWidth::try_from(1usize << ((iss >> 22) & 0b11)).ok()?;
+        let reg_width =
+            AccessWidth::try_from(if iss & ISS_DA_SF != 0 { 8usize } else { 4 }).ok()?;
+
+        Some(Self {
+            width,
+            reg: (iss >> 16) & 0b11111,
+            reg_width,
+            write: iss & ISS_DA_WNR != 0,
+            sign_ext: iss & ISS_DA_SSE != 0,
+            acquire_release: iss & ISS_DA_AR != 0,
+        })
+    }
+}
+
+/// The base register update of a load or store with writeback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Writeback {
+    /// The index of the base register (Rn), 31 is the stack pointer.
+    pub base: usize,
+    /// The offset added to the base register.
+    pub offset: i64,
+}
+
+/// An MMIO access decoded from the faulting instruction, for the data aborts with no valid
+/// instruction syndrome.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodedMmioInsn {
+    /// A load or store of a single general-purpose register, the pre-indexed and post-indexed
+    /// forms update the base register.
+    Single {
+        /// The access.
+        access: DataAbortAccess,
+        /// The update of the base register, if any.
+        writeback: Option<Writeback>,
+    },
+    /// A load or store of a pair of general-purpose registers (`LDP`, `STP`, `LDPSW`, `LDNP`,
+    /// `STNP`).
+    Pair {
+        /// The access of the first register (Rt), at the lower address.
+        access: DataAbortAccess,
+        /// The index of the second register (Rt2), accessed right after the first one.
+        reg2: usize,
+        /// The index of the base register (Rn), 31 is the stack pointer.
+        base: usize,
+        /// The offset of the first register from the base register.
+        offset: i64,
+        /// Whether the base register is updated, by the pair offset of the indexed forms.
+        writeback: Option<Writeback>,
+    },
+    /// `DC ZVA`, zeroing a whole block of `DCZID_EL0.BS`.
+    DcZva,
+}
+
+/// Sign-extends the `bits` low bits of `value`.
+const fn sign_extend(value: u32, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (((value as u64) << shift) as i64) >> shift
+}
+
+impl DecodedMmioInsn {
+    /// Decodes the A64 instruction `insn`, returning `None` if it is not a load or store of
+    /// general-purpose registers, or `DC ZVA`.
+    ///
+    /// The SIMD&FP, exclusive, atomic and tag forms are not decoded.
+    pub fn decode(insn: u32) -> Option<Self> {
+        const DC_ZVA_MASK: u32 = 0xffff_ffe0;
+        const DC_ZVA: u32 = 0xd50b_7420;
+        /// `V`, set for the SIMD&FP registers.
+        const LDST_V: u32 = 1 << 26;
+
+        if insn & DC_ZVA_MASK == DC_ZVA {
+            return Some(Self::DcZva);
+        }
+        if insn & LDST_V != 0 {
+            return None;
+        }
+        let rt = (insn & 0b11111) as usize;
+        let rn = ((insn >> 5) & 0b11111) as usize;
+        match (insn >> 27) & 0b111 {
+            0b111 => Self::decode_single(insn, rt, rn),
+            0b101 => Self::decode_pair(insn, rt, rn),
+            _ => None,
+        }
+    }
+
+    /// Decodes the loads and stores of a single register, with an immediate or register
+    /// offset.
+    fn decode_single(insn: u32, rt: usize, rn: usize) -> Option<Self> {
+        let size = insn >> 30;
+        let (write, sign_ext, reg_64) = match ((insn >> 22) & 0b11, size) {
+            (0b00, _) => (true, false, size == 3),
+            (0b01, _) => (false, false, size == 3),
+            // `PRFM` for size 3, the other ones sign-extend to 64 bits.
+            (0b10, 0..=2) => (false, true, true),
+            (0b11, 0..=1) => (false, true, false),
+            _ => return None,
+        };
+
+        let writeback = match (insn >> 24) & 0b11 {
+            // Unsigned immediate offset.
+            0b01 => None,
+            0b00 if insn & (1 << 21) == 0 => match (insn >> 10) & 0b11 {
+                // Post-indexed and pre-indexed.
+                0b01 | 0b11 => Some(Writeback {
+                    base: rn,
+                    offset: sign_extend((insn >> 12) & 0x1ff, 9),
+                }),
+                // Unscaled and unprivileged.
+                _ => None,
+            },
+            // Register offset.
+            0b00 if (insn >> 10) & 0b11 == 0b10 => None,
+            _ => return None,
+        };
+
+        Some(Self::Single {
+            access: DataAbortAccess {
+                width: AccessWidth::try_from(1usize << size).ok()?,
+                reg: rt,
+                reg_width: if reg_64 {
+                    AccessWidth::Qword
+                } else {
+                    AccessWidth::Dword
+                },
+                write,
+                sign_ext,
+                acquire_release: false,
+            },
+            writeback,
+        })
+    }
+
+    /// Decodes the loads and stores of a pair of registers.
+    fn decode_pair(insn: u32, rt: usize, rn: usize) -> Option<Self> {
+        const LDST_PAIR_L: u32 = 1 << 22;
+
+        if insn & (1 << 25) != 0 {
+            return None;
+        }
+        let load = insn & LDST_PAIR_L != 0;
+        let (width, reg_width, sign_ext) = match (insn >> 30, load) {
+            (0b00, _) => (AccessWidth::Dword, AccessWidth::Dword, false),
+            (0b01, true) => (AccessWidth::Dword, AccessWidth::Qword, true),
+            (0b10, _) => (AccessWidth::Qword, AccessWidth::Qword, false),
+            // `STGP` and unallocated.
+            _ => return None,
+        };
+        let scale = if width == AccessWidth::Qword { 3 } else { 2 };
+        let imm = sign_extend((insn >> 15) & 0x7f, 7) << scale;
+
+        let (offset, writeback) = match (insn >> 23) & 0b11 {
+            // Non-temporal and signed offset.
+            0b00 | 0b10 => (imm, false),
+            // Post-indexed.
+            0b01 => (0, true),
+            // Pre-indexed.
+            _ => (imm, true),
+        };
+
+        Some(Self::Pair {
+            access: DataAbortAccess {
+                width,
+                reg: rt,
+                reg_width,
+                write: !load,
+                sign_ext,
+                acquire_release: false,
+            },
+            reg2: ((insn >> 10) & 0b11111) as usize,
+            base: rn,
+            offset,
+            writeback: writeback.then_some(Writeback {
+                base: rn,
+                offset: imm,
+            }),
+        })
+    }
+}
+
+#[cfg(feature = "aarch32")]
+impl DecodedMmioInsn {
+    /// Decodes the A32 instruction `insn`, returning `None` if it is not a load or store of a
+    /// general-purpose register with no offset register writeback, or `LDRD`/`STRD`.
+    ///
+    /// The registers are the AArch32 ones, see [`aarch32_reg_view`], the PC is only accepted
+    /// as the base register of the forms with no writeback.
+    pub fn decode_a32(insn: u32) -> Option<Self> {
+        const A32_P: u32 = 1 << 24;
+        const A32_U: u32 = 1 << 23;
+        const A32_W: u32 = 1 << 21;
+        const A32_L: u32 = 1 << 20;
+
+        // The unconditional instructions (`PLD`...).
+        if insn >> 28 == 0b1111 {
+            return None;
+        }
+        let rn = ((insn >> 16) & 0xf) as usize;
+        let rt = ((insn >> 12) & 0xf) as usize;
+        // The post-indexed forms (`P` clear), including the unprivileged ones, and the
+        // pre-indexed ones update the base register.
+        let writeback = insn & A32_P == 0 || insn & A32_W != 0;
+        let load = insn & A32_L != 0;
+        let (access, imm) = match (insn >> 25) & 0b111 {
+            // LDR, STR, LDRB and STRB (immediate).
+            0b010 => {
+                let width = if insn & (1 << 22) != 0 {
+                    AccessWidth::Byte
+                } else {
+                    AccessWidth::Dword
+                };
+                (a32_access(width, rt, !load, false), insn & 0xfff)
+            }
+            // The extra loads and stores, with an immediate or register offset.
+            0b000 if insn & 0x90 == 0x90 && (insn >> 5) & 0b11 != 0 => {
+                let immediate = insn & (1 << 22) != 0;
+                if !immediate && (writeback || ((insn >> 5) & 0b10 != 0 && !load)) {
+                    // The offset register is not decoded, nor the one of `LDRD` and `STRD`.
+                    return None;
+                }
+                let imm = ((insn >> 4) & 0xf0) | (insn & 0xf);
+                match ((insn >> 5) & 0b11, load) {
+                    (0b01, _) => (a32_access(AccessWidth::Word, rt, !load, false), imm),
+                    (0b10, true) => (a32_access(AccessWidth::Byte, rt, false, true), imm),
+                    (0b11, true) => (a32_access(AccessWidth::Word, rt, false, true), imm),
+                    // LDRD (0b10) and STRD (0b11), of an even register and the next one.
+                    (op2, false) => {
+                        // The post-indexed form with `W` set is UNPREDICTABLE.
+                        if !rt.is_multiple_of(2)
+                            || rt == 14
+                            || (writeback && rn == 15)
+                            || (insn & A32_P == 0 && insn & A32_W != 0)
+                        {
+                            return None;
+                        }
+                        let imm = if insn & A32_U != 0 {
+                            imm as i64
+                        } else {
+                            -(imm as i64)
+                        };
+                        return Some(Self::Pair {
+                            access: a32_access(AccessWidth::Dword, rt, op2 == 0b11, false),
+                            reg2: rt + 1,
+                            base: rn,
+                            offset: if insn & A32_P != 0 { imm } else { 0 },
+                            writeback: writeback.then_some(Writeback {
+                                base: rn,
+                                offset: imm,
+                            }),
+                        });
+                    }
+                    // Excluded by the guard.
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        if rt == 15 || (writeback && rn == 15) {
+            return None;
+        }
+        let offset = if insn & A32_U != 0 {
+            imm as i64
+        } else {
+            -(imm as i64)
+        };
+        Some(Self::Single {
+            access,
+            writeback: writeback.then_some(Writeback { base: rn, offset }),
+        })
+    }
+
+    /// Decodes the 32-bit T32 instruction `insn`, its first halfword in the upper half,
+    /// returning `None` if it is not a load or store of a general-purpose register with an
+    /// immediate or register offset, or `LDRD`/`STRD` with an immediate offset.
+    ///
+    /// The 16-bit loads and stores are always described by a valid instruction syndrome. The
+    /// registers are the AArch32 ones, see [`aarch32_reg_view`].
+    pub fn decode_t32(insn: u32) -> Option<Self> {
+        let rn = ((insn >> 16) & 0xf) as usize;
+        let rt = ((insn >> 12) & 0xf) as usize;
+        if insn >> 25 == 0b111_0100 && insn & (1 << 22) != 0 {
+            return Self::decode_t32_pair(insn, rt, rn);
+        }
+        if insn >> 25 != 0b111_1100 || rt == 15 {
+            return None;
+        }
+        let load = insn & (1 << 20) != 0;
+        let signed = insn & (1 << 24) != 0;
+        if signed && !load {
+            return None;
+        }
+        let width = match (insn >> 21) & 0b11 {
+            0b00 => AccessWidth::Byte,
+            0b01 => AccessWidth::Word,
+            0b10 if !signed => AccessWidth::Dword,
+            _ => return None,
+        };
+        let access = a32_access(width, rt, !load, signed);
+
+        // The 12-bit immediate offset and literal forms, with no writeback.
+        if insn & (1 << 23) != 0 || rn == 15 {
+            return Some(Self::Single {
+                access,
+                writeback: None,
+            });
+        }
+        let writeback = match (insn >> 8) & 0b1111 {
+            // Negative offset, unprivileged.
+            0b1100 | 0b1110 => None,
+            // Post-indexed and pre-indexed, `P`, `U` and `W` in bits [10:8].
+            0b1001 | 0b1011 | 0b1101 | 0b1111 => {
+                let imm = (insn & 0xff) as i64;
+                Some(Writeback {
+                    base: rn,
+                    offset: if insn & (1 << 9) != 0 { imm } else { -imm },
+                })
+            }
+            // Register offset.
+            _ if (insn >> 6) & 0x3f == 0 => None,
+            _ => return None,
+        };
+        Some(Self::Single { access, writeback })
+    }
+
+    /// Decodes the T32 `LDRD` and `STRD` (immediate).
+    fn decode_t32_pair(insn: u32, rt: usize, rn: usize) -> Option<Self> {
+        const T32_P: u32 = 1 << 24;
+        const T32_W: u32 = 1 << 21;
+
+        let rt2 = ((insn >> 8) & 0xf) as usize;
+        // `P` and `W` both clear encode the exclusives and table branches.
+        if insn & (T32_P | T32_W) == 0 || rt == 15 || rt2 == 15 || rn == 15 {
+            return None;
+        }
+        let imm = ((insn & 0xff) << 2) as i64;
+        let imm = if insn & (1 << 23) != 0 { imm } else { -imm };
+        let writeback = insn & T32_W != 0;
+        Some(Self::Pair {
+            access: a32_access(AccessWidth::Dword, rt, insn & (1 << 20) == 0, false),
+            reg2: rt2,
+            base: rn,
+            offset: if insn & T32_P != 0 { imm } else { 0 },
+            writeback: writeback.then_some(Writeback {
+                base: rn,
+                offset: imm,
+            }),
+        })
+    }
+
+    /// Returns the instruction with its AArch32 registers replaced by their AArch64 view in
+    /// the mode `mode`, see [`aarch32_reg_view`], `None` if one has none.
+    pub fn to_aarch64_view(self, mode: u64) -> Option<Self> {
+        let view = |reg| aarch32_reg_view(reg, mode);
+        let view_writeback = |writeback: Option<Writeback>| match writeback {
+            Some(writeback) => {
+                view(writeback.base).map(|base| Some(Writeback { base, ..writeback }))
+            }
+            None => Some(None),
+        };
+        Some(match self {
+            Self::Single { access, writeback } => Self::Single {
+                access: DataAbortAccess {
+                    reg: view(access.reg)?,
+                    ..access
+                },
+                writeback: view_writeback(writeback)?,
+            },
+            Self::Pair {
+                access,
+                reg2,
+                base,
+                offset,
+                writeback,
+            } => Self::Pair {
+                access: DataAbortAccess {
+                    reg: view(access.reg)?,
+                    ..access
+                },
+                reg2: view(reg2)?,
+                base: view(base)?,
+                offset,
+                writeback: view_writeback(writeback)?,
+            },
+            Self::DcZva => Self::DcZva,
+        })
+    }
+}
+
+/// Returns the access of a 32-bit register of an A32 or T32 load or store.
+#[cfg(feature = "aarch32")]
+const fn a32_access(
+    width: AccessWidth,
+    reg: usize,
+    write: bool,
+    sign_ext: bool,
+) -> DataAbortAccess {
+    DataAbortAccess {
+        width,
+        reg,
+        reg_width: AccessWidth::Dword,
+        write,
+        sign_ext,
+        acquire_release: false,
+    }
+}
+
+/// Returns whether the T32 instruction starting with the halfword `hw1` is 32-bit wide.
+#[cfg(feature = "aarch32")]
+pub const fn is_t32_wide(hw1: u16) -> bool {
+    matches!(hw1 >> 11, 0b11101..=0b11111)
+}
+
+/// Returns the index of the AArch64 register holding the AArch32 register `reg`, from `r0` to
+/// `r14`, in the AArch32 mode `mode` (`SPSR.M[4:0]`), as in the AArch64 view of the register
+/// file. Returns `None` for the PC and the modes of EL2 and EL3.
+#[cfg(feature = "aarch32")]
+pub const fn aarch32_reg_view(reg: usize, mode: u64) -> Option<usize> {
+    const MODE_USR: u64 = 0b1_0000;
+    const MODE_FIQ: u64 = 0b1_0001;
+    const MODE_IRQ: u64 = 0b1_0010;
+    const MODE_SVC: u64 = 0b1_0011;
+    const MODE_ABT: u64 = 0b1_0111;
+    const MODE_UND: u64 = 0b1_1011;
+    const MODE_SYS: u64 = 0b1_1111;
+
+    // The `(r13, r14)` of each mode, the FIQ mode also banks `r8`-`r12` in `x24`-`x28`.
+    let (sp, lr) = match mode & 0b1_1111 {
+        MODE_USR | MODE_SYS => (13, 14),
+        MODE_FIQ => (29, 30),
+        MODE_IRQ => (17, 16),
+        MODE_SVC => (19, 18),
+        MODE_ABT => (21, 20),
+        MODE_UND => (23, 22),
+        _ => return None,
+    };
+    match reg {
+        0..=7 => Some(reg),
+        8..=12 if mode & 0b1_1111 == MODE_FIQ => Some(reg + 16),
+        8..=12 => Some(reg),
+        13 => Some(sp),
+        14 => Some(lr),
+        _ => None,
+    }
+}
+
+/// A load-exclusive or store-exclusive instruction of the guest, see
+/// [`Aarch64ExitInfo::exclusive`](crate::Aarch64ExitInfo::exclusive).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExclusiveAccess {
+    /// A load-exclusive (`LDXR`, `LDAXR` and their byte and halfword forms).
+    Load,
+    /// A store-exclusive (`STXR`, `STLXR` and their byte and halfword forms).
+    Store {
+        /// The index of the register receiving the status (Rs), 0 for success.
+        status_reg: usize,
+    },
+}
+
+impl ExclusiveAccess {
+    /// Decodes the A64 instruction `insn`, returning `None` if it is not a load-exclusive or
+    /// store-exclusive of a single register.
+    pub const fn decode(insn: u32) -> Option<Self> {
+        const LDST_EXCLUSIVE_MASK: u32 = 0x3f << 24;
+        const LDST_EXCLUSIVE: u32 = 0x08 << 24;
+        /// `o2`, set for the ordered non-exclusive forms (`LDAR`, `STLR`).
+        const LDST_O2: u32 = 1 << 23;
+        const LDST_L: u32 = 1 << 22;
+        /// `o1`, set for the pair forms.
+        const LDST_O1: u32 = 1 << 21;
+
+        if insn & LDST_EXCLUSIVE_MASK != LDST_EXCLUSIVE || insn & (LDST_O2 | LDST_O1) != 0 {
+            return None;
+        }
+        if insn & LDST_L != 0 {
+            return Some(Self::Load);
+        }
+        Some(Self::Store {
+            status_reg: ((insn >> 16) & 0b11111) as usize,
+        })
+    }
+}
+
+/// A trapped `MSR`/`MRS` access, decoded from the ISS of an exception with EC 0x18.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SysRegAccess {
+    /// The accessed system register.
+    ///
+    /// The numbering follows the ISS layout, formatted as `<op0><op2><op1><CRn>00000<CRm>0`.
+    pub addr: SysRegAddr,
+    /// The index of the transferred register (ISS.Rt), 31 is the zero register.
+    pub reg: usize,
+    /// Whether the access is a write (`MSR`) or a read (`MRS`).
+    pub write: bool,
+}
+
+impl SysRegAccess {
+    /// Decodes the access from the ISS of a trapped `MSR`/`MRS` instruction.
+    pub const fn decode(iss: usize) -> Self {
+        const ISS_SYSREG_ADDR: usize = (0xfff << 10) | (0xf << 1);
+        const ISS_SYSREG_DIRECTION_READ: usize = 0b1;
+
+        Self {
+            addr: SysRegAddr::new(iss & ISS_SYSREG_ADDR),
+            reg: (iss >> 5) & 0b11111,
+            write: iss & ISS_SYSREG_DIRECTION_READ == 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn access(
+        width: AccessWidth,
+        reg: usize,
+        reg_width: AccessWidth,
+        write: bool,
+        sign_ext: bool,
+    ) -> DataAbortAccess {
+        DataAbortAccess {
+            width,
+            reg,
+            reg_width,
+            write,
+            sign_ext,
+            acquire_release: false,
+        }
+    }
+
+    #[test]
+    fn pair_signed_offset() {
+        // ldp x1, x2, [x3, #16]
+        assert_eq!(
+            DecodedMmioInsn::decode(0xa941_0861),
+            Some(DecodedMmioInsn::Pair {
+                access: access(AccessWidth::Qword, 1, AccessWidth::Qword, false, false),
+                reg2: 2,
+                base: 3,
+                offset: 16,
+                writeback: None,
+            })
+        );
+        // ldpsw x0, x1, [x2]
+        assert_eq!(
+            DecodedMmioInsn::decode(0x6940_0440),
+            Some(DecodedMmioInsn::Pair {
+                access: access(AccessWidth::Dword, 0, AccessWidth::Qword, false, true),
+                reg2: 1,
+                base: 2,
+                offset: 0,
+                writeback: None,
+            })
+        );
+    }
+
+    #[test]
+    fn pair_pre_index() {
+        // stp w1, w2, [sp, #-8]!
+        assert_eq!(
+            DecodedMmioInsn::decode(0x29bf_0be1),
+            Some(DecodedMmioInsn::Pair {
+                access: access(AccessWidth::Dword, 1, AccessWidth::Dword, true, false),
+                reg2: 2,
+                base: 31,
+                offset: -8,
+                writeback: Some(Writeback {
+                    base: 31,
+                    offset: -8
+                }),
+            })
+        );
+    }
+
+    #[test]
+    fn pair_post_index() {
+        // ldp x0, x1, [x2], #32
+        assert_eq!(
+            DecodedMmioInsn::decode(0xa8c2_0440),
+            Some(DecodedMmioInsn::Pair {
+                access: access(AccessWidth::Qword, 0, AccessWidth::Qword, false, false),
+                reg2: 1,
+                base: 2,
+                offset: 0,
+                writeback: Some(Writeback {
+                    base: 2,
+                    offset: 32
+                }),
+            })
+        );
+    }
+
+    #[test]
+    fn single_pre_and_post_index() {
+        // ldr x0, [x1, #8]!
+        assert_eq!(
+            DecodedMmioInsn::decode(0xf840_8c20),
+            Some(DecodedMmioInsn::Single {
+                access: access(AccessWidth::Qword, 0, AccessWidth::Qword, false, false),
+                writeback: Some(Writeback { base: 1, offset: 8 }),
+            })
+        );
+        // str w2, [x3], #-4
+        assert_eq!(
+            DecodedMmioInsn::decode(0xb81f_c462),
+            Some(DecodedMmioInsn::Single {
+                access: access(AccessWidth::Dword, 2, AccessWidth::Dword, true, false),
+                writeback: Some(Writeback {
+                    base: 3,
+                    offset: -4
+                }),
+            })
+        );
+    }
+
+    #[test]
+    fn dc_zva() {
+        // dc zva, x5
+        assert_eq!(
+            DecodedMmioInsn::decode(0xd50b_7425),
+            Some(DecodedMmioInsn::DcZva)
+        );
+        // dc civac, x5
+        assert_eq!(DecodedMmioInsn::decode(0xd50b_7e25), None);
+    }
+
+    #[test]
+    fn simd_pair_not_decoded() {
+        // ldp q0, q1, [x0]
+        assert_eq!(DecodedMmioInsn::decode(0xad40_0400), None);
+    }
+
+    #[test]
+    fn fault_levels() {
+        // Translation fault at level 3, permission fault at level 1.
+        assert_eq!(fault_level(0b00_0111), Some(3));
+        assert_eq!(fault_level(0b00_1101), Some(1));
+        // Synchronous external abort, alignment fault.
+        assert_eq!(fault_level(0b01_0000), None);
+        assert_eq!(fault_level(0b10_0001), None);
+    }
+}
@@ -0,0 +1,155 @@
+//! The registry of the errata of the host CPUs which need workarounds for correct
+//! virtualization, keyed on `MIDR_EL1`.
+//!
+//! The errata of each physical CPU are detected by `hardware_enable`, as the cores of a
+//! heterogeneous (big.LITTLE) host may differ. The workarounds are applied by the world
+//! switch and the TLB maintenance of the vCPUs running on it.
+
+use aarch64_cpu::registers::{MIDR_EL1, Readable};
+
+/// A workaround needed by some host CPUs, see [`HostErratum`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErratumWorkaround {
+    /// Speculative address translations of EL1 may use the stage-2 context of the previous
+    /// VM, allocating corrupted TLB entries: the stage-2 context of the guest is loaded and
+    /// synchronized before its EL1 translation registers.
+    SpeculativeAt,
+    /// A TLB invalidation followed by a `DSB` may not invalidate all the entries: the last
+    /// invalidation of each sequence is repeated after the barrier.
+    RepeatTlbi,
+}
+
+impl ErratumWorkaround {
+    const fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// A CPU erratum with the range of the affected cores.
+#[derive(Clone, Copy, Debug)]
+pub struct HostErratum {
+    /// The name of the erratum, e.g. `"Cortex-A76 #1165522"`.
+    pub name: &'static str,
+    /// The implementer code, `MIDR_EL1.Implementer`.
+    implementer: u64,
+    /// The part number, `MIDR_EL1.PartNum`.
+    part: u64,
+    /// The affected revisions, as `(variant << 4) | revision`.
+    revisions: (u64, u64),
+    /// The workaround of the erratum.
+    pub workaround: ErratumWorkaround,
+}
+
+impl HostErratum {
+    /// Returns whether the core identified by `midr` is affected.
+    const fn affects(&self, midr: u64) -> bool {
+        let implementer = (midr >> 24) & 0xff;
+        let part = (midr >> 4) & 0xfff;
+        let revision = ((midr >> 16) & 0xf0) | (midr & 0xf);
+        implementer == self.implementer
+            && part == self.part
+            && self.revisions.0 <= revision
+            && revision <= self.revisions.1
+    }
+}
+
+/// The `MIDR_EL1.Implementer` code of Arm.
+const MIDR_IMPLEMENTER_ARM: u64 = 0x41;
+/// The `MIDR_EL1.PartNum` of the Arm cores with errata.
+const MIDR_PART_CORTEX_A55: u64 = 0xd05;
+const MIDR_PART_CORTEX_A57: u64 = 0xd07;
+const MIDR_PART_CORTEX_A72: u64 = 0xd08;
+const MIDR_PART_CORTEX_A76: u64 = 0xd0b;
+const MIDR_PART_CORTEX_A510: u64 = 0xd46;
+
+/// All the revisions of a core.
+const ALL_REVISIONS: (u64, u64) = (0x00, 0xff);
+
+const fn arm_erratum(
+    name: &'static str,
+    part: u64,
+    revisions: (u64, u64),
+    workaround: ErratumWorkaround,
+) -> HostErratum {
+    HostErratum {
+        name,
+        implementer: MIDR_IMPLEMENTER_ARM,
+        part,
+        revisions,
+        workaround,
+    }
+}
+
+/// The known errata, the revisions are `rXpY` encoded as `0xXY`.
+pub const HOST_ERRATA: &[HostErratum] = &[
+    arm_erratum(
+        "Cortex-A55 #1530923",
+        MIDR_PART_CORTEX_A55,
+        (0x00, 0x20),
+        ErratumWorkaround::SpeculativeAt,
+    ),
+    arm_erratum(
+        "Cortex-A57 #1319537",
+        MIDR_PART_CORTEX_A57,
+        ALL_REVISIONS,
+        ErratumWorkaround::SpeculativeAt,
+    ),
+    arm_erratum(
+        "Cortex-A72 #1319367",
+        MIDR_PART_CORTEX_A72,
+        ALL_REVISIONS,
+        ErratumWorkaround::SpeculativeAt,
+    ),
+    arm_erratum(
+        "Cortex-A76 #1165522",
+        MIDR_PART_CORTEX_A76,
+        (0x00, 0x30),
+        ErratumWorkaround::SpeculativeAt,
+    ),
+    arm_erratum(
+        "Cortex-A76 #1286807",
+        MIDR_PART_CORTEX_A76,
+        (0x00, 0x30),
+        ErratumWorkaround::RepeatTlbi,
+    ),
+    arm_erratum(
+        "Cortex-A510 #2441009",
+        MIDR_PART_CORTEX_A510,
+        (0x00, 0x11),
+        ErratumWorkaround::RepeatTlbi,
+    ),
+];
+
+/// The set of [`ErratumWorkaround::bit`]s needed by the current physical CPU.
+#[percpu::def_percpu]
+static HOST_WORKAROUNDS: u32 = 0;
+
+/// Returns the errata of the current physical CPU.
+pub fn host_errata() -> impl Iterator<Item = &'static HostErratum> {
+    let midr = MIDR_EL1.get();
+    HOST_ERRATA
+        .iter()
+        .filter(move |erratum| erratum.affects(midr))
+}
+
+/// Disables the workarounds of the current physical CPU, which goes offline.
+pub fn clear_host_errata() {
+    unsafe { HOST_WORKAROUNDS.write_current_raw(0) };
+}
+
+/// Detects the errata of the current physical CPU and enables their workarounds.
+pub fn detect_host_errata() {
+    let mut workarounds = 0;
+    for erratum in host_errata() {
+        info!("Applying the workaround of {}", erratum.name);
+        workarounds |= erratum.workaround.bit();
+    }
+    // Safety: the workarounds are only read on the current CPU, which doesn't run a vCPU.
+    unsafe { HOST_WORKAROUNDS.write_current_raw(workarounds) };
+}
+
+/// Returns whether the current physical CPU needs `workaround`.
+pub fn needs_workaround(workaround: ErratumWorkaround) -> bool {
+    // Safety: only written by `detect_host_errata` on the current CPU.
+    unsafe { HOST_WORKAROUNDS.read_current_raw() & workaround.bit() != 0 }
+}
@@ -1,38 +1,119 @@
 use crate::TrapFrame;
+use crate::cpu_feature::has_feat_xnx;
+use crate::decode::{
+    DataAbortAccess, FaultStatus, SysRegAccess, fault_level, is_cache_maintenance,
+};
 use crate::exception_utils::{
-    exception_class, exception_class_value, exception_data_abort_access_is_write,
-    exception_data_abort_access_reg, exception_data_abort_access_reg_width,
-    exception_data_abort_access_width, exception_data_abort_handleable,
-    exception_data_abort_is_permission_fault, exception_data_abort_is_translate_fault,
-    exception_esr, exception_fault_addr, exception_next_instruction_step, exception_sysreg_addr,
-    exception_sysreg_direction_write, exception_sysreg_gpr,
+    exception_class, exception_class_value, exception_esr, exception_fault_addr, exception_iss,
+    exception_next_instruction_step,
 };
+use crate::exit::{Stage2Fault, UnhandledExit};
+use crate::heartbeat::{GuestHeartbeat, handle_heartbeat_call};
+use crate::hvstats::{GuestHvStats, handle_hv_stats_call};
+use crate::policy::{ExitPolicies, PolicyExit, PolicyOutcome};
+use crate::psci::{PsciState, handle_psci_call};
+use crate::pvlock::{PvLockState, handle_pvlock_call};
+use crate::smc::{SMCCC_64, SMCCC_NUM_REGS, SMCCC_RET_NOT_SUPPORTED, SmcForwardPolicy, smc_call};
+use crate::stats::{Aarch64VCpuStats, count_event};
+use crate::symbol::Symbolizer;
+use crate::trap_stack::{NestedTrapGuard, dump_nested_traps};
+use crate::vendor_hyp::handle_vendor_hyp_call;
+use crate::wfe::WfeSpinDetector;
 
-use aarch64_cpu::registers::{ESR_EL2, HCR_EL2, Readable, SCTLR_EL1, VTCR_EL2, VTTBR_EL2};
-use axaddrspace::{
-    GuestPhysAddr,
-    device::{AccessWidth, SysRegAddr},
-};
-use axerrno::{AxError, AxResult};
+use aarch64_cpu::registers::{ESR_EL2, FAR_EL2, HCR_EL2, Readable, SCTLR_EL1, VTCR_EL2, VTTBR_EL2};
+use axaddrspace::{GuestPhysAddr, MappingFlags};
+use axerrno::AxResult;
 use axvcpu::AxVCpuExitReason;
 use log::error;
 
 numeric_enum_macro::numeric_enum! {
 #[repr(u8)]
-#[derive(Debug)]
+/// The kind of an exception taken to EL2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TrapKind {
+    /// Synchronous exception.
     Synchronous = 0,
+    /// IRQ.
     Irq = 1,
+    /// FIQ.
     Fiq = 2,
+    /// SError.
     SError = 3,
 }
 }
 
+/// The per-vCPU state and configuration used by the exception handlers.
+#[derive(Debug, Default)]
+pub struct ExceptionState {
+    /// Counters of guest misbehaviour events.
+    pub stats: Aarch64VCpuStats,
+    /// Which SMCs of the guest are forwarded to EL3.
+    pub smc_forward: SmcForwardPolicy,
+    /// The psci state of the vCPU.
+    pub psci: PsciState,
+    /// The state of the paravirtual "vCPU is preempted" interface, if it is offered.
+    pub pvlock: Option<PvLockState>,
+    /// The statistics queried by the `HV_STATS` hypercall, if it is offered.
+    pub hv_stats: Option<GuestHvStats>,
+    /// The heartbeat state, if the heartbeat hypercall is offered to the guest.
+    pub heartbeat: Option<GuestHeartbeat>,
+    /// The `WFE` spin loop detection state, if `WFE` is trapped.
+    pub wfe_spin: Option<WfeSpinDetector>,
+    /// Annotates the guest addresses of the diagnostics with their symbols.
+    pub symbolizer: Symbolizer,
+    /// How the `DC IVAC`s of the guest to the pages it can't write are handled.
+    pub dc_ivac: DcIvacPolicy,
+    /// The IPA of a `DC IVAC` upgraded to a clean and invalidate, performed by the vCPU once
+    /// the exception is handled.
+    pub dc_civac: Option<GuestPhysAddr>,
+    /// The IPA and VA of a data abort to be emulated from its instruction, which has no valid
+    /// syndrome.
+    pub undecoded_abort: Option<(GuestPhysAddr, usize)>,
+    /// The stage-2 fault the last exit is caused by, set by the abort handlers.
+    pub stage2_fault: Option<Stage2Fault>,
+    /// The exception the last exit is caused by, if no handler could handle it.
+    pub unhandled: Option<UnhandledExit>,
+    /// Set on the first FP/SIMD access of the guest, whose registers are then loaded by the
+    /// vCPU before resuming it.
+    pub fp_access: bool,
+    /// Set by the handlers of the exits which are completed without the VMM, the guest is then
+    /// re-entered right away by `run()`.
+    pub resume: bool,
+    /// Set by the handler of a `WFE` yielding to the VMM, which `run()` resumes instead if
+    /// [`MaskableExit::Wfe`](crate::MaskableExit::Wfe) is masked.
+    pub wfe_yield: bool,
+    /// The security policies deciding on the exits and forwarded `SMC`s.
+    pub policies: ExitPolicies,
+}
+
+/// How the `DC IVAC`s (invalidate by VA to the PoC) of the guest to the pages it can only read
+/// are handled, see [`Aarch64VCpuSetupConfig::dc_ivac_readonly`].
+///
+/// Such pages are typically shared by the host or other VMs, an invalidation without clean
+/// would discard the data they have written and not yet written back.
+///
+/// [`Aarch64VCpuSetupConfig::dc_ivac_readonly`]: crate::Aarch64VCpuSetupConfig::dc_ivac_readonly
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DcIvacPolicy {
+    /// The invalidation is upgraded to a clean and invalidate (`DC CIVAC`) of the line, as the
+    /// architecture allows, and completed in EL2.
+    #[default]
+    CleanInvalidate,
+    /// The stage-2 permission fault is reported as an [`AxVCpuExitReason::NestedPageFault`]
+    /// for a write, e.g. to unshare the page. The instruction is re-executed once the VMM
+    /// resumes the vCPU.
+    Report,
+}
+
 /// Equals to [`TrapKind::Synchronous`], used in exception.S.
 const EXCEPTION_SYNC: usize = TrapKind::Synchronous as usize;
 /// Equals to [`TrapKind::Irq`], used in exception.S.
 const EXCEPTION_IRQ: usize = TrapKind::Irq as usize;
+/// Equals to [`TrapKind::SError`], used in exception.S.
+const EXCEPTION_SERROR: usize = TrapKind::SError as usize;
 
+/// Where an exception is taken from, the `source` of the entries of the vector table in
+/// exception.S.
 #[repr(u8)]
 #[derive(Debug)]
 #[allow(unused)]
@@ -47,6 +128,7 @@ core::arch::global_asm!(
     include_str!("exception.S"),
     exception_sync = const EXCEPTION_SYNC,
     exception_irq = const EXCEPTION_IRQ,
+    exception_serror = const EXCEPTION_SERROR,
 );
 
 /// Handles synchronous exceptions that occur during the execution of a guest VM.
@@ -54,86 +136,150 @@ core::arch::global_asm!(
 /// This function examines the exception class (EC) to determine the cause of the exception
 /// and then handles it accordingly.
 ///
-/// Currently we just handle exception type including data abort (`DataAbortLowerEL`) and hypervisor call (`HVC64)`.
+/// Currently we just handle exception type including data abort (`DataAbortLowerEL`), instruction
+/// abort (`InstrAbortLowerEL`) and hypervisor call (`HVC64` and `HVC32`).
 ///
 /// # Arguments
 ///
 /// * `ctx` - A mutable reference to the `TrapFrame`, which contains the saved state of the guest VM's CPU registers at the time of the exception.
+/// * `state` - The exception handling state and configuration of the vCPU.
 ///
 /// # Returns
 ///
 /// An `AxResult` containing an `AxVCpuExitReason` indicating the reason for the VM exit.
 /// This could be due to a hypervisor call (`Hypercall`) or other reasons such as data aborts.
 ///
-/// # Panics
-///
-/// If an unhandled exception class is encountered, the function will panic, outputting
-/// details about the exception including the instruction pointer, faulting address, exception
-/// syndrome register (ESR), and system control registers.
+/// The AArch32 instructions failing their condition code check are skipped, see
+/// [`TrapFrame::aarch32_condition_passed`].
 ///
-pub fn handle_exception_sync(ctx: &mut TrapFrame) -> AxResult<AxVCpuExitReason> {
+/// An unhandled exception class, or an abort which is neither a translation nor a permission
+/// fault, is logged with the details of the exception and reported as an
+/// [`AxVCpuExitReason::FailEntry`], see [`UnhandledExit`].
+pub fn handle_exception_sync(
+    ctx: &mut TrapFrame,
+    state: &mut ExceptionState,
+) -> AxResult<AxVCpuExitReason> {
+    // A conditional AArch32 instruction may trap even if it fails its condition code check.
+    if !ctx.aarch32_condition_passed(exception_esr() as u64) {
+        ctx.skip_instruction(exception_next_instruction_step());
+        state.resume = true;
+        return Ok(AxVCpuExitReason::Nothing);
+    }
     match exception_class() {
-        Some(ESR_EL2::EC::Value::DataAbortLowerEL) => {
-            let elr = ctx.exception_pc();
-            let val = elr + exception_next_instruction_step();
-            ctx.set_exception_pc(val);
-            handle_data_abort(ctx)
-        }
-        Some(ESR_EL2::EC::Value::HVC64) => {
-            // The `#imm`` argument when triggering a hvc call, currently not used.
-            let _hvc_arg_imm16 = ESR_EL2.read(ESR_EL2::ISS);
-
-            // Is this a psci call?
-            //
-            // By convention, a psci call can use either the `hvc` or the `smc` instruction.
-            // NimbOS uses `hvc`, `ArceOS` use `hvc` too when running on QEMU.
-            if let Some(result) = handle_psci_call(ctx) {
-                return result;
+        Some(ESR_EL2::EC::Value::DataAbortLowerEL) => handle_data_abort(ctx, state),
+        Some(ESR_EL2::EC::Value::InstrAbortLowerEL) => handle_instruction_abort(ctx, state),
+        Some(ESR_EL2::EC::Value::HVC32) => {
+            // Like for `SMC32`, the upper halves of x0-x7 are UNKNOWN.
+            for reg in &mut ctx.gpr[..8] {
+                *reg = *reg as u32 as u64;
             }
-
-            // We assume that guest VM triggers HVC through a `hvc #0`` instruction.
-            // And arm64 hcall implementation uses `x0` to specify the hcall number.
-            // For more details on the hypervisor call (HVC) mechanism and the use of general-purpose registers,
-            // refer to the [Linux Kernel documentation on KVM ARM hypervisor ABI](https://github.com/torvalds/linux/blob/master/Documentation/virt/kvm/arm/hyp-abi.rst).
-            Ok(AxVCpuExitReason::Hypercall {
-                nr: ctx.gpr[0],
-                args: [
-                    ctx.gpr[1], ctx.gpr[2], ctx.gpr[3], ctx.gpr[4], ctx.gpr[5], ctx.gpr[6],
-                ],
-            })
+            handle_hvc(ctx, state)
         }
+        Some(ESR_EL2::EC::Value::HVC64) => handle_hvc(ctx, state),
         Some(ESR_EL2::EC::Value::TrappedMsrMrs) => handle_system_register(ctx),
+        Some(ESR_EL2::EC::Value::TrappedWFIorWFE) => Ok(handle_wfe(ctx, state)),
         Some(ESR_EL2::EC::Value::SMC64) => {
-            let elr = ctx.exception_pc();
-            let val = elr + exception_next_instruction_step();
-            ctx.set_exception_pc(val);
-            handle_smc64_exception(ctx)
+            ctx.skip_instruction(exception_next_instruction_step());
+            handle_smc64_exception(ctx, state)
         }
-        _ => {
-            panic!(
-                "handler not presents for EC_{} @ipa 0x{:x}, @pc 0x{:x}, @esr 0x{:x},
-                @sctlr_el1 0x{:x}, @vttbr_el2 0x{:x}, @vtcr_el2: {:#x} hcr: {:#x} ctx:{}",
-                exception_class_value(),
-                exception_fault_addr()?,
-                (*ctx).exception_pc(),
-                exception_esr(),
-                SCTLR_EL1.get() as usize,
-                VTTBR_EL2.get() as usize,
-                VTCR_EL2.get() as usize,
-                HCR_EL2.get() as usize,
-                ctx
-            );
+        Some(ESR_EL2::EC::Value::TrappedFP) => {
+            // The access is executed again once the registers of the guest are loaded.
+            state.fp_access = true;
+            state.resume = true;
+            Ok(AxVCpuExitReason::Nothing)
+        }
+        Some(ESR_EL2::EC::Value::SMC32) => {
+            ctx.skip_instruction(exception_next_instruction_step());
+            // An AArch32 EL1 passes and gets the SMCCC registers in r0-r7, the upper halves of
+            // x0-x7 are UNKNOWN.
+            for reg in &mut ctx.gpr[..8] {
+                *reg = *reg as u32 as u64;
+            }
+            handle_smc64_exception(ctx, state)
         }
+        _ => Ok(unhandled_exit(ctx, state, "handler not presents")),
     }
 }
 
-fn handle_data_abort(context_frame: &mut TrapFrame) -> AxResult<AxVCpuExitReason> {
+/// Handles an `HVC` of the guest, from AArch64 or AArch32.
+fn handle_hvc(ctx: &mut TrapFrame, state: &mut ExceptionState) -> AxResult<AxVCpuExitReason> {
+    // The `#imm`` argument when triggering a hvc call, currently not used.
+    let _hvc_arg_imm16 = ESR_EL2.read(ESR_EL2::ISS);
+
+    // Is this a psci call?
+    //
+    // By convention, a psci call can use either the `hvc` or the `smc` instruction.
+    // NimbOS uses `hvc`, `ArceOS` use `hvc` too when running on QEMU.
+    if let Some(result) = handle_psci_call(ctx, state) {
+        return result;
+    }
+    if let Some(exit) = handle_vendor_hyp_call(ctx, state) {
+        return Ok(exit);
+    }
+    if let Some(exit) = state
+        .pvlock
+        .as_mut()
+        .and_then(|pv| handle_pvlock_call(ctx, pv))
+    {
+        return Ok(exit);
+    }
+    if let Some(exit) = state
+        .hv_stats
+        .as_ref()
+        .and_then(|stats| handle_hv_stats_call(ctx, stats))
+    {
+        return Ok(exit);
+    }
+    if let Some(exit) = state
+        .heartbeat
+        .as_mut()
+        .and_then(|heartbeat| handle_heartbeat_call(ctx, heartbeat))
+    {
+        return Ok(exit);
+    }
+
+    // We assume that guest VM triggers HVC through a `hvc #0`` instruction.
+    // And arm64 hcall implementation uses `x0` to specify the hcall number.
+    // For more details on the hypervisor call (HVC) mechanism and the use of general-purpose registers,
+    // refer to the [Linux Kernel documentation on KVM ARM hypervisor ABI](https://github.com/torvalds/linux/blob/master/Documentation/virt/kvm/arm/hyp-abi.rst).
+    Ok(AxVCpuExitReason::Hypercall {
+        nr: ctx.gpr[0],
+        args: [
+            ctx.gpr[1], ctx.gpr[2], ctx.gpr[3], ctx.gpr[4], ctx.gpr[5], ctx.gpr[6],
+        ],
+    })
+}
+
+/// Logs the synchronous exception the guest has taken and no handler can handle, and returns
+/// the exit reporting it to the VMM.
+fn unhandled_exit(ctx: &TrapFrame, state: &mut ExceptionState, what: &str) -> AxVCpuExitReason {
+    error!(
+        "{} for EC_{} @far 0x{:x}, @pc {}, @lr {}, @esr 0x{:x},
+        @sctlr_el1 0x{:x}, @vttbr_el2 0x{:x}, @vtcr_el2: {:#x} hcr: {:#x} ctx:{}",
+        what,
+        exception_class_value(),
+        FAR_EL2.get(),
+        state.symbolizer.addr(ctx.exception_pc() as u64),
+        state.symbolizer.addr(ctx.gpr[30]),
+        exception_esr(),
+        SCTLR_EL1.get() as usize,
+        VTTBR_EL2.get() as usize,
+        VTCR_EL2.get() as usize,
+        HCR_EL2.get() as usize,
+        ctx
+    );
+    let unhandled = UnhandledExit::capture(TrapKind::Synchronous, ctx);
+    state.unhandled = Some(unhandled);
+    unhandled.exit_reason()
+}
+
+fn handle_data_abort(
+    context_frame: &mut TrapFrame,
+    state: &mut ExceptionState,
+) -> AxResult<AxVCpuExitReason> {
     let addr = exception_fault_addr()?;
-    let access_width = exception_data_abort_access_width();
-    let is_write = exception_data_abort_access_is_write();
-    //let sign_ext = exception_data_abort_access_is_sign_ext();
-    let reg = exception_data_abort_access_reg();
-    let reg_width = exception_data_abort_access_reg_width();
+    let iss = exception_iss();
+    state.stage2_fault = fault_level(iss).map(|level| Stage2Fault { ipa: addr, level });
 
     trace!(
         "Data fault @{:?}, ELR {:#x}, esr: 0x{:x}",
@@ -142,46 +288,178 @@ fn handle_data_abort(context_frame: &mut TrapFrame) -> AxResult<AxVCpuExitReason
         exception_esr(),
     );
 
-    let width = match AccessWidth::try_from(access_width) {
-        Ok(access_width) => access_width,
-        Err(_) => return Err(AxError::InvalidInput),
-    };
+    if is_cache_maintenance(iss) {
+        return handle_cache_maintenance_abort(context_frame, state, addr, iss);
+    }
 
-    let reg_width = match AccessWidth::try_from(reg_width) {
-        Ok(reg_width) => reg_width,
-        Err(_) => return Err(AxError::InvalidInput),
+    let Some(access) = DataAbortAccess::decode(iss) else {
+        // The A32 and T32 instructions are only decoded with the `aarch32` feature.
+        let decodable = cfg!(feature = "aarch32") || !context_frame.is_aarch32();
+        if FaultStatus::from_iss(iss) == FaultStatus::Translation && decodable {
+            // Decoded from the instruction by the vCPU, which can read the guest memory.
+            state.undecoded_abort = Some((addr, FAR_EL2.get() as usize));
+            return Ok(AxVCpuExitReason::Nothing);
+        }
+        return Ok(unhandled_exit(
+            context_frame,
+            state,
+            "Core data abort not handleable",
+        ));
     };
 
-    if !exception_data_abort_handleable() {
-        panic!(
-            "Core data abort not handleable {:#x}, esr {:#x}",
+    match FaultStatus::from_iss(iss) {
+        FaultStatus::Translation => {}
+        FaultStatus::Permission => {
+            // Let the VMM decide, the faulting instruction is re-executed once it returns.
+            let access_flags = if access.write {
+                MappingFlags::WRITE
+            } else {
+                MappingFlags::READ
+            };
+            return Ok(AxVCpuExitReason::NestedPageFault { addr, access_flags });
+        }
+        _ => {
+            return Ok(unhandled_exit(
+                context_frame,
+                state,
+                "Core data abort is not translate fault",
+            ));
+        }
+    }
+
+    // The access is going to be emulated by the VMM, skip the faulting instruction.
+    context_frame.skip_instruction(exception_next_instruction_step());
+
+    Ok(mmio_exit(context_frame, addr, &access))
+}
+
+/// Returns the MMIO exit emulating `access` at `addr`.
+pub fn mmio_exit(
+    ctx: &TrapFrame,
+    addr: GuestPhysAddr,
+    access: &DataAbortAccess,
+) -> AxVCpuExitReason {
+    if access.write {
+        return AxVCpuExitReason::MmioWrite {
             addr,
-            exception_esr()
-        );
+            width: access.width,
+            data: ctx.gpr(access.reg) as u64,
+        };
+    }
+    AxVCpuExitReason::MmioRead {
+        addr,
+        width: access.width,
+        reg: access.reg,
+        reg_width: access.reg_width,
+        signed_ext: access.sign_ext,
     }
+}
 
-    if !exception_data_abort_is_translate_fault() {
-        if exception_data_abort_is_permission_fault() {
-            return Err(AxError::Unsupported);
-        } else {
-            panic!("Core data abort is not translate fault {:#x}", addr,);
+/// Handles the data aborts caused by the cache maintenance instructions of the guest.
+///
+/// Only `DC IVAC` needs the write permission, so a permission fault means it targets a page
+/// the guest can only read, it is handled according to the [`DcIvacPolicy`]. The maintenance
+/// of unmapped IPAs, MMIO regions in particular, has no effect and is skipped, like KVM does.
+fn handle_cache_maintenance_abort(
+    ctx: &mut TrapFrame,
+    state: &mut ExceptionState,
+    addr: GuestPhysAddr,
+    iss: usize,
+) -> AxResult<AxVCpuExitReason> {
+    match (FaultStatus::from_iss(iss), state.dc_ivac) {
+        (FaultStatus::Translation, _) => {
+            state.stats.skipped_cache_maintenance =
+                state.stats.skipped_cache_maintenance.saturating_add(1);
+        }
+        (FaultStatus::Permission, DcIvacPolicy::CleanInvalidate) => state.dc_civac = Some(addr),
+        (FaultStatus::Permission, DcIvacPolicy::Report) => {
+            return Ok(AxVCpuExitReason::NestedPageFault {
+                addr,
+                access_flags: MappingFlags::WRITE,
+            });
+        }
+        _ => {
+            return Ok(unhandled_exit(
+                ctx,
+                state,
+                "Core data abort is not translate fault",
+            ));
         }
     }
 
-    if is_write {
-        return Ok(AxVCpuExitReason::MmioWrite {
-            addr,
-            width,
-            data: context_frame.gpr(reg) as u64,
-        });
-    }
-    Ok(AxVCpuExitReason::MmioRead {
+    ctx.skip_instruction(exception_next_instruction_step());
+    state.resume = true;
+    Ok(AxVCpuExitReason::Nothing)
+}
+
+/// Handles instruction aborts taken from the guest, which are caused by stage-2 translation or
+/// permission faults on instruction fetches.
+///
+/// They are reported as [`AxVCpuExitReason::NestedPageFault`] with [`MappingFlags::EXECUTE`],
+/// so that they can be told apart from read/write faults. If the host implements FEAT_XNX,
+/// stage 2 can deny execution at EL0 and EL1 separately, [`MappingFlags::USER`] is then added
+/// for fetches from guest EL0.
+///
+/// The PC is not advanced, the instruction is fetched again once the VMM resumes the vCPU.
+fn handle_instruction_abort(
+    ctx: &mut TrapFrame,
+    state: &mut ExceptionState,
+) -> AxResult<AxVCpuExitReason> {
+    let addr = exception_fault_addr()?;
+    state.stage2_fault = fault_level(exception_iss()).map(|level| Stage2Fault { ipa: addr, level });
+
+    trace!(
+        "Instruction fault @{:?}, ELR {:#x}, esr: 0x{:x}",
         addr,
-        width,
-        reg,
-        reg_width,
-        signed_ext: false,
-    })
+        ctx.exception_pc(),
+        exception_esr(),
+    );
+
+    let mut access_flags = MappingFlags::EXECUTE;
+    if has_feat_xnx() && ctx.exception_level() == 0 {
+        access_flags |= MappingFlags::USER;
+    }
+
+    Ok(AxVCpuExitReason::NestedPageFault { addr, access_flags })
+}
+
+/// Handles a trapped `WFI` or `WFE`.
+///
+/// The `WFI`s, only trapped if requested, are reported as [`AxVCpuExitReason::Halt`] and
+/// resume at the next instruction. The `WFIT`s complete right away, as woken up spuriously,
+/// since the VMM wouldn't wake the vCPU up on their timeout.
+///
+/// The `WFE`s, only trapped if spin loop detection is enabled, complete as if woken up by a
+/// spurious event. If they are part of a spin loop, they are reported as
+/// [`AxVCpuExitReason::Nothing`], giving the VMM the opportunity to schedule another vCPU,
+/// unless masked, otherwise the guest is resumed right away.
+fn handle_wfe(ctx: &mut TrapFrame, state: &mut ExceptionState) -> AxVCpuExitReason {
+    /// `ISS.TI`, bits [1:0], the trapped instruction.
+    const ISS_TI_MASK: usize = 0b11;
+    const ISS_TI_WFI: usize = 0b00;
+    const ISS_TI_WFIT: usize = 0b10;
+
+    let pc = ctx.exception_pc();
+    ctx.skip_instruction(exception_next_instruction_step());
+
+    match exception_iss() & ISS_TI_MASK {
+        ISS_TI_WFI => {
+            state.stats.wfi_halts = state.stats.wfi_halts.saturating_add(1);
+            return AxVCpuExitReason::Halt;
+        }
+        ISS_TI_WFIT => {
+            state.resume = true;
+            return AxVCpuExitReason::Nothing;
+        }
+        _ => {}
+    }
+    if state.wfe_spin.as_mut().is_some_and(|spin| spin.record(pc)) {
+        state.stats.wfe_yields = state.stats.wfe_yields.saturating_add(1);
+        state.wfe_yield = true;
+    } else {
+        state.resume = true;
+    }
+    AxVCpuExitReason::Nothing
 }
 
 /// Handles a system register access exception.
@@ -196,91 +474,118 @@ fn handle_data_abort(context_frame: &mut TrapFrame) -> AxResult<AxVCpuExitReason
 /// * `AxResult<AxVCpuExitReason>` - An `AxResult` containing an `AxVCpuExitReason` indicating
 ///   whether the operation was a read or write and the relevant details.
 fn handle_system_register(context_frame: &mut TrapFrame) -> AxResult<AxVCpuExitReason> {
-    let iss = ESR_EL2.read(ESR_EL2::ISS);
+    let SysRegAccess { addr, reg, write } = SysRegAccess::decode(exception_iss());
 
-    let addr = exception_sysreg_addr(iss.try_into().unwrap());
-    let elr = context_frame.exception_pc();
-    let val = elr + exception_next_instruction_step();
-    let write = exception_sysreg_direction_write(iss);
-    let reg = exception_sysreg_gpr(iss) as usize;
-    context_frame.set_exception_pc(val);
+    context_frame.skip_instruction(exception_next_instruction_step());
     if write {
         return Ok(AxVCpuExitReason::SysRegWrite {
-            addr: SysRegAddr::new(addr),
-            value: context_frame.gpr(reg as usize) as u64,
+            addr,
+            value: context_frame.gpr(reg) as u64,
         });
     }
-    Ok(AxVCpuExitReason::SysRegRead {
-        addr: SysRegAddr::new(addr),
-        reg,
-    })
+    Ok(AxVCpuExitReason::SysRegRead { addr, reg })
 }
 
-/// Handles HVC or SMC exceptions that serve as psci (Power State Coordination Interface) calls.
-///
-/// A hvc or smc call with the function in range 0x8000_0000..=0x8000_001F  (when the 32-bit
-/// hvc/smc calling convention is used) or 0xC000_0000..=0xC000_001F (when the 64-bit hvc/smc
-/// calling convention is used) is a psci call. This function handles them all.
+/// Handles SMC (Secure Monitor Call) exceptions, trapped by `HCR_EL2.TSC`, from AArch64 or
+/// AArch32.
 ///
-/// Returns `None` if the HVC is not a psci call.
-fn handle_psci_call(ctx: &mut TrapFrame) -> Option<AxResult<AxVCpuExitReason>> {
-    const PSCI_FN_RANGE_32: core::ops::RangeInclusive<u64> = 0x8400_0000..=0x8400_001F;
-    const PSCI_FN_RANGE_64: core::ops::RangeInclusive<u64> = 0xC400_0000..=0xC400_001F;
-
-    const PSCI_FN_VERSION: u64 = 0x0;
-    const _PSCI_FN_CPU_SUSPEND: u64 = 0x1;
-    const PSCI_FN_CPU_OFF: u64 = 0x2;
-    const PSCI_FN_CPU_ON: u64 = 0x3;
-    const _PSCI_FN_MIGRATE: u64 = 0x5;
-    const PSCI_FN_SYSTEM_OFF: u64 = 0x8;
-    const _PSCI_FN_SYSTEM_RESET: u64 = 0x9;
-    const PSCI_FN_END: u64 = 0x1f;
-
-    let fn_ = ctx.gpr[0];
-    let fn_offset = if PSCI_FN_RANGE_32.contains(&fn_) {
-        Some(fn_ - PSCI_FN_RANGE_32.start())
-    } else if PSCI_FN_RANGE_64.contains(&fn_) {
-        Some(fn_ - PSCI_FN_RANGE_64.start())
+/// This function will judge if the SMC call is a PSCI call, if so, it will handle it as a PSCI call.
+/// Otherwise, it will forward the SMC call to the ATF, if allowed by [`SmcForwardPolicy`].
+fn handle_smc64_exception(
+    ctx: &mut TrapFrame,
+    state: &mut ExceptionState,
+) -> AxResult<AxVCpuExitReason> {
+    // Is this a psci call?
+    if let Some(result) = handle_psci_call(ctx, state) {
+        result
+    } else if let Some(exit) = handle_vendor_hyp_call(ctx, state) {
+        Ok(exit)
+    } else if let Some(exit) = state
+        .pvlock
+        .as_mut()
+        .and_then(|pv| handle_pvlock_call(ctx, pv))
+    {
+        Ok(exit)
+    } else if let Some(exit) = state
+        .hv_stats
+        .as_ref()
+        .and_then(|stats| handle_hv_stats_call(ctx, stats))
+    {
+        Ok(exit)
+    } else if let Some(exit) = state
+        .heartbeat
+        .as_mut()
+        .and_then(|heartbeat| handle_heartbeat_call(ctx, heartbeat))
+    {
+        Ok(exit)
     } else {
-        None
-    };
-
-    match fn_offset {
-        Some(PSCI_FN_CPU_OFF) => Some(Ok(AxVCpuExitReason::CpuDown { _state: ctx.gpr[1] })),
-        Some(PSCI_FN_CPU_ON) => Some(Ok(AxVCpuExitReason::CpuUp {
-            target_cpu: ctx.gpr[1],
-            entry_point: GuestPhysAddr::from(ctx.gpr[2] as usize),
-            arg: ctx.gpr[3],
-        })),
-        Some(PSCI_FN_SYSTEM_OFF) => Some(Ok(AxVCpuExitReason::SystemDown)),
-        // We just forward these request to the ATF directly.
-        Some(PSCI_FN_VERSION..PSCI_FN_END) => None,
-        _ => None,
+        forward_smc(ctx, state)
     }
 }
 
-/// Handles SMC (Secure Monitor Call) exceptions.
+/// Forwards an SMC of the guest to EL3 and writes the results back to the guest registers.
 ///
-/// This function will judge if the SMC call is a PSCI call, if so, it will handle it as a PSCI call.
-/// Otherwise, it will forward the SMC call to the ATF directly.
-fn handle_smc64_exception(ctx: &mut TrapFrame) -> AxResult<AxVCpuExitReason> {
-    // Is this a psci call?
-    if let Some(result) = handle_psci_call(ctx) {
-        result
+/// Calls which are not allowed by the [`SmcForwardPolicy`] of the vCPU return `NOT_SUPPORTED`.
+/// For the SMC32 calling convention, only the lower 32 bits of the registers are passed and
+/// returned, as the upper ones are not defined.
+fn forward_smc(ctx: &mut TrapFrame, state: &mut ExceptionState) -> AxResult<AxVCpuExitReason> {
+    // The function ID is in w0, the upper bits of x0 must be ignored.
+    let fn_id = ctx.gpr[0] as u32;
+
+    if !state.smc_forward.allows(fn_id) {
+        if count_event(&mut state.stats.denied_smc) {
+            warn!(
+                "Denied smc {fn_id:#x} @pc {:#x} ({} times)",
+                ctx.exception_pc(),
+                state.stats.denied_smc
+            );
+        }
+        ctx.set_argument(SMCCC_RET_NOT_SUPPORTED as usize);
+        return Ok(AxVCpuExitReason::Nothing);
+    }
+
+    let call = AxVCpuExitReason::Hypercall {
+        nr: ctx.gpr[0],
+        args: [
+            ctx.gpr[1], ctx.gpr[2], ctx.gpr[3], ctx.gpr[4], ctx.gpr[5], ctx.gpr[6],
+        ],
+    };
+    match state.policies.apply(Some(PolicyExit::Smc), call, ctx) {
+        PolicyOutcome::Allowed {
+            rewritten: false, ..
+        } => {}
+        PolicyOutcome::Allowed { exit, .. } => return Ok(exit),
+        PolicyOutcome::Denied => return Ok(AxVCpuExitReason::Nothing),
+    }
+
+    let mask = if fn_id & SMCCC_64 != 0 {
+        u64::MAX
     } else {
-        // We just forward the SMC call to the ATF directly.
-        // The args are from lower EL, so it is safe to call the ATF.
-        (ctx.gpr[0], ctx.gpr[1], ctx.gpr[2], ctx.gpr[3]) =
-            unsafe { crate::smc::smc_call(ctx.gpr[0], ctx.gpr[1], ctx.gpr[2], ctx.gpr[3]) };
-        Ok(AxVCpuExitReason::Nothing)
+        u32::MAX as u64
+    };
+
+    let mut regs = [0; SMCCC_NUM_REGS];
+    for (reg, gpr) in regs.iter_mut().zip(ctx.gpr.iter()) {
+        *reg = gpr & mask;
     }
+    // The args are from lower EL and only fast calls of allowed services get here, the
+    // host state is not kept in any of the registers used by the call.
+    unsafe { smc_call(&mut regs) };
+    for (gpr, reg) in ctx.gpr.iter_mut().zip(regs.iter()) {
+        *gpr = reg & mask;
+    }
+
+    Ok(AxVCpuExitReason::Nothing)
 }
 
 /// Handles IRQ exceptions that occur from the current exception level.
 /// Dispatches IRQs to the appropriate handler provided by the underlying host OS,
 /// which is registered at [`crate::pcpu::IRQ_HANDLER`] during `Aarch64PerCpu::new()`.
+///
+/// The handler may unmask the IRQs, the nested ones are bounded by [`NestedTrapGuard`].
 #[unsafe(no_mangle)]
-fn current_el_irq_handler(_tf: &mut TrapFrame) {
+fn current_el_irq_handler(tf: &mut TrapFrame, kind: TrapKind, _source: TrapSource) {
+    let _nested = NestedTrapGuard::enter(tf, kind);
     unsafe { crate::pcpu::IRQ_HANDLER.current_ref_raw() }
         .get()
         .unwrap()()
@@ -288,7 +593,8 @@ fn current_el_irq_handler(_tf: &mut TrapFrame) {
 
 /// Handles synchronous exceptions that occur from the current exception level.
 #[unsafe(no_mangle)]
-fn current_el_sync_handler(tf: &mut TrapFrame) {
+fn current_el_sync_handler(tf: &mut TrapFrame, kind: TrapKind, _source: TrapSource) {
+    let _nested = NestedTrapGuard::enter(tf, kind);
     let esr = ESR_EL2.extract();
     let ec = ESR_EL2.read(ESR_EL2::EC);
     let iss = ESR_EL2.read(ESR_EL2::ISS);
@@ -296,6 +602,7 @@ fn current_el_sync_handler(tf: &mut TrapFrame) {
     error!("ESR_EL2: {:#x}", esr.get());
     error!("Exception Class: {ec:#x}");
     error!("Instruction Specific Syndrome: {iss:#x}");
+    dump_nested_traps();
 
     panic!(
         "Unhandled synchronous exception from current EL: {:#x?}",
@@ -345,6 +652,7 @@ unsafe extern "C" fn vmexit_trampoline() -> ! {
         // Curretly `sp` points to the base address of `Aarch64VCpu.ctx`, which stores guest's `TrapFrame`.
         "add x9, sp, 34 * 8", // Skip the exception frame.
         // Currently `x9` points to `&Aarch64VCpu.host_stack_top`, see `run_guest()` in vcpu.rs.
+        record_exit_vector_time!(),
         "ldr x10, [x9]", // Get `host_stack_top` value from `&Aarch64VCpu.host_stack_top`.
         "mov sp, x10",   // Set `sp` as the host stack top.
         restore_regs_from_stack!(), // Restore host function context frame.
@@ -355,6 +663,7 @@ unsafe extern "C" fn vmexit_trampoline() -> ! {
 /// Deal with invalid aarch64 exception.
 #[unsafe(no_mangle)]
 fn invalid_exception_el2(tf: &mut TrapFrame, kind: TrapKind, source: TrapSource) {
+    dump_nested_traps();
     panic!(
         "Invalid exception {:?} from {:?}:\n{:#x?}",
         kind, source, tf
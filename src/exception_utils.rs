@@ -3,6 +3,8 @@ use axaddrspace::GuestPhysAddr;
 use axerrno::{AxResult, ax_err};
 use tock_registers::interfaces::*;
 
+use crate::decode::FaultStatus;
+
 /// Retrieves the Exception Syndrome Register (ESR) value from EL2.
 ///
 /// # Returns
@@ -111,6 +113,35 @@ fn translate_far_to_hpfar(far: usize) -> AxResult<usize> {
     }
 }
 
+/// Translates the guest virtual address `va` with the stage-1 translation regime of the guest,
+/// which must still be loaded, like a read at EL1.
+///
+/// Returns `None` if the translation faults.
+pub fn translate_guest_va(va: usize) -> Option<GuestPhysAddr> {
+    try_translate_guest_va(va).ok()
+}
+
+/// Translates the guest virtual address `va` like [`translate_guest_va`].
+///
+/// Returns the fault status (`PAR_EL1.FST`) of a stage-1 fault, for an abort to be injected
+/// into the guest, or `None` if the translation table walk faulted at stage 2.
+pub fn try_translate_guest_va(va: usize) -> Result<GuestPhysAddr, Option<u64>> {
+    const PAR_EL1_PA_MASK: u64 = 0x000f_ffff_ffff_f000;
+    /// `PAR_EL1.S`, bit [9], of an aborted translation: the fault was at stage 2.
+    const PAR_EL1_S: u64 = 1 << 9;
+
+    let par = PAR_EL1.get();
+    arm_at!("s1e1r", va);
+    let tmp = PAR_EL1.get();
+    PAR_EL1.set(par);
+    if (tmp & PAR_EL1::F::TranslationAborted.value) != 0 {
+        return Err((tmp & PAR_EL1_S == 0).then_some((tmp >> 1) & 0b11_1111));
+    }
+    Ok(GuestPhysAddr::from(
+        (tmp & PAR_EL1_PA_MASK) as usize | (va & 0xfff),
+    ))
+}
+
 /// Retrieves the fault address that caused an exception.
 ///
 /// This function returns the Guest Physical Address (GPA) that caused the
@@ -170,29 +201,6 @@ pub fn exception_iss() -> usize {
     ESR_EL2.read(ESR_EL2::ISS) as usize
 }
 
-#[inline(always)]
-pub fn exception_sysreg_direction_write(iss: u64) -> bool {
-    const ESR_ISS_SYSREG_DIRECTION: u64 = 0b1;
-    (iss & ESR_ISS_SYSREG_DIRECTION) == 0
-}
-
-#[inline(always)]
-pub fn exception_sysreg_gpr(iss: u64) -> u64 {
-    const ESR_ISS_SYSREG_REG_OFF: u64 = 5;
-    const ESR_ISS_SYSREG_REG_LEN: u64 = 5;
-    const ESR_ISS_SYSREG_REG_MASK: u64 = (1 << ESR_ISS_SYSREG_REG_LEN) - 1;
-    (iss >> ESR_ISS_SYSREG_REG_OFF) & ESR_ISS_SYSREG_REG_MASK
-}
-
-/// The numbering of `SystemReg` follows the order specified in the Instruction Set Specification (ISS),
-/// formatted as `<op0><op2><op1><CRn>00000<CRm>0`.
-/// (Op0[21..20] + Op2[19..17] + Op1[16..14] + CRn[13..10]) + CRm[4..1]
-#[inline(always)]
-pub const fn exception_sysreg_addr(iss: usize) -> usize {
-    const ESR_ISS_SYSREG_ADDR: usize = (0xfff << 10) | (0xf << 1);
-    iss & ESR_ISS_SYSREG_ADDR
-}
-
 /// Checks if the data abort exception was caused by a permission fault.
 ///
 /// # Returns
@@ -200,67 +208,7 @@ pub const fn exception_sysreg_addr(iss: usize) -> usize {
 /// - `false` otherwise.
 #[inline(always)]
 pub fn exception_data_abort_is_permission_fault() -> bool {
-    (exception_iss() & 0b111111 & (0xf << 2)) == 12
-}
-
-/// Determines the access width of a data abort exception.
-///
-/// # Returns
-/// The access width in bytes (1, 2, 4, or 8 bytes).
-#[inline(always)]
-pub fn exception_data_abort_access_width() -> usize {
-    1 << ((exception_iss() >> 22) & 0b11)
-}
-
-/// Determines the DA can be handled
-#[inline(always)]
-pub fn exception_data_abort_handleable() -> bool {
-    (!(exception_iss() & (1 << 10)) | (exception_iss() & (1 << 24))) != 0
-}
-
-#[inline(always)]
-pub fn exception_data_abort_is_translate_fault() -> bool {
-    (exception_iss() & 0b111111 & (0xf << 2)) == 4
-}
-
-/// Checks if the data abort exception was caused by a write access.
-///
-/// # Returns
-/// - `true` if the exception was caused by a write access.
-/// - `false` if it was caused by a read access.
-#[inline(always)]
-pub fn exception_data_abort_access_is_write() -> bool {
-    (exception_iss() & (1 << 6)) != 0
-}
-
-/// Retrieves the register index involved in a data abort exception.
-///
-/// # Returns
-/// The index of the register (0-31) involved in the access.
-#[inline(always)]
-pub fn exception_data_abort_access_reg() -> usize {
-    (exception_iss() >> 16) & 0b11111
-}
-
-/// Determines the width of the register involved in a data abort exception.
-///
-/// # Returns
-/// The width of the register in bytes (4 or 8 bytes).
-#[allow(unused)]
-#[inline(always)]
-pub fn exception_data_abort_access_reg_width() -> usize {
-    4 + 4 * ((exception_iss() >> 15) & 1)
-}
-
-/// Checks if the data accessed during a data abort exception is sign-extended.
-///
-/// # Returns
-/// - `true` if the data is sign-extended.
-/// - `false` otherwise.
-#[allow(unused)]
-#[inline(always)]
-pub fn exception_data_abort_access_is_sign_ext() -> bool {
-    ((exception_iss() >> 21) & 1) != 0
+    FaultStatus::from_iss(exception_iss()) == FaultStatus::Permission
 }
 
 /// Macro to save the host function context to the stack.
@@ -287,6 +235,27 @@ macro_rules! save_regs_to_stack {
     };
 }
 
+/// Macro to record the physical counter in `Aarch64VCpu.exit_vector_time`, right after the
+/// guest context has been saved on a VM exit.
+///
+/// `x9` must point to `Aarch64VCpu.host_stack_top`, the field follows it. `x11` is clobbered.
+#[cfg(feature = "exit-latency")]
+macro_rules! record_exit_vector_time {
+    () => {
+        "
+        mrs     x11, cntpct_el0
+        str     x11, [x9, 8]"
+    };
+}
+
+/// Does nothing without the `exit-latency` feature.
+#[cfg(not(feature = "exit-latency"))]
+macro_rules! record_exit_vector_time {
+    () => {
+        ""
+    };
+}
+
 /// Macro to restore the host function context from the stack.
 ///
 /// This macro restores the values of the callee-saved general-purpose registers (`x19` to `x30`) from the stack.
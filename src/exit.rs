@@ -0,0 +1,384 @@
+//! Information about the VM exits of a vCPU, complementing [`axvcpu::AxVCpuExitReason`].
+
+use core::ops::RangeInclusive;
+
+use aarch64_cpu::registers::{ESR_EL2, FAR_EL2, Readable};
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use axvcpu::AxVCpuExitReason;
+
+use crate::TrapFrame;
+use crate::decode::ExclusiveAccess;
+use crate::exception::TrapKind;
+use crate::smc::SMCCC_RET_NOT_SUPPORTED;
+use crate::stage2::level_shift;
+
+/// Information about a VM exit, see [`Aarch64VCpu::last_exit`].
+///
+/// [`Aarch64VCpu::last_exit`]: crate::Aarch64VCpu::last_exit
+#[derive(Clone, Copy, Debug)]
+pub struct Aarch64ExitInfo {
+    /// The kind of the exception the exit is caused by.
+    pub kind: TrapKind,
+    /// The value of `ESR_EL2`, only meaningful for synchronous exceptions.
+    pub esr: u64,
+    /// The value of the physical counter (`CNTPCT_EL0`) when the exit happened.
+    pub timestamp: u64,
+    /// The exception level of the guest the exit was taken from, 0 or 1 (`SPSR_EL2.M[3:2]`).
+    pub el: u8,
+    /// The stage-2 fault the exit is caused by, if any, i.e. for the MMIO and nested page
+    /// fault exits caused by a translation, access flag or permission fault, the only ones
+    /// reported at a level of the tables.
+    pub stage2_fault: Option<Stage2Fault>,
+    /// The exclusive access of the MMIO exits caused by a load-exclusive or store-exclusive,
+    /// if the instruction could be read.
+    ///
+    /// Every exit clears the exclusive monitor of the guest, so an exclusive sequence touching
+    /// an MMIO region can't complete natively. A store-exclusive emulated by the VMM reports
+    /// success, its status register is already cleared. A store-exclusive replayed with
+    /// [`Aarch64VCpu::replay_mmio_access`] fails, the guest then restarts the sequence.
+    ///
+    /// [`Aarch64VCpu::replay_mmio_access`]: crate::Aarch64VCpu::replay_mmio_access
+    pub exclusive: Option<ExclusiveAccess>,
+    /// Whether the MMIO access of the exit has acquire or release semantics (`LDAR`, `STLR`
+    /// and their variants).
+    ///
+    /// The guest relies on the ordering of such an access with its other memory accesses,
+    /// e.g. a release store to a doorbell register after filling a descriptor ring: the VMM
+    /// must complete the effects of the access in order with the ones of the previous exits,
+    /// e.g. after the posted MMIO writes of the `exit-ring` feature.
+    pub acquire_release: bool,
+    /// The exception the vCPU couldn't handle, if the exit is reported as an
+    /// [`AxVCpuExitReason::FailEntry`].
+    pub unhandled: Option<UnhandledExit>,
+    /// The physical SError the exit is caused by, if its kind is [`TrapKind::SError`].
+    pub serror: Option<GuestSError>,
+}
+
+/// A physical SError (asynchronous abort) taken while the guest ran, see
+/// [`Aarch64ExitInfo::serror`].
+///
+/// It is reported to the VMM as an [`AxVCpuExitReason::FailEntry`] whose
+/// `hardware_entry_failure_reason` is `ESR_EL2`, with the SError exception class (0x2f). The
+/// VMM decides from the syndrome whether the error is contained: it can then reflect it into
+/// the guest with [`Aarch64VCpu::inject_serror`] and resume it, or stop the VM otherwise.
+///
+/// [`Aarch64VCpu::inject_serror`]: crate::Aarch64VCpu::inject_serror
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestSError {
+    /// The syndrome, `ESR_EL2`.
+    pub esr: u64,
+    /// The address of the instruction the guest was interrupted at, `ELR_EL2`.
+    pub elr: u64,
+}
+
+impl GuestSError {
+    /// `ESR_ELx.IDS`, bit [24], set if the syndrome is IMPLEMENTATION DEFINED.
+    const IDS: u64 = 1 << 24;
+    /// `ESR_ELx.DFSC` of the asynchronous SErrors, whose `AET` is valid with FEAT_RAS.
+    const DFSC_ASYNC: u64 = 0b01_0001;
+
+    /// Captures the SError the guest has just taken.
+    pub(crate) fn capture(ctx: &TrapFrame) -> Self {
+        Self {
+            esr: ESR_EL2.get(),
+            elr: ctx.exception_pc() as u64,
+        }
+    }
+
+    /// Returns whether the syndrome is IMPLEMENTATION DEFINED, the other fields then have no
+    /// architectural meaning (`IDS`).
+    pub const fn imp_def(&self) -> bool {
+        self.esr & Self::IDS != 0
+    }
+
+    /// Returns the error type (`AET`, bits [12:10]) of the architected syndromes of FEAT_RAS:
+    /// 0b000 uncontainable, 0b001 unrecoverable, 0b010 restartable, 0b011 recoverable and
+    /// 0b110 corrected.
+    pub const fn error_type(&self) -> Option<u8> {
+        if self.imp_def() || self.esr & 0x3f != Self::DFSC_ASYNC {
+            return None;
+        }
+        Some(((self.esr >> 10) & 0b111) as u8)
+    }
+
+    /// Returns whether the error is contained, i.e. restartable, recoverable or corrected,
+    /// according to [`error_type`](Self::error_type).
+    pub const fn contained(&self) -> bool {
+        matches!(self.error_type(), Some(0b010 | 0b011 | 0b110))
+    }
+
+    /// Returns the exit reporting the SError to the VMM.
+    pub(crate) fn exit_reason(&self) -> AxVCpuExitReason {
+        AxVCpuExitReason::FailEntry {
+            hardware_entry_failure_reason: self.esr,
+        }
+    }
+}
+
+/// An exception of the guest the vCPU has no handler for, or denies, see
+/// [`Aarch64ExitInfo::unhandled`].
+///
+/// It is reported to the VMM as an [`AxVCpuExitReason::FailEntry`] whose
+/// `hardware_entry_failure_reason` packs `ec` and `iss` as in `ESR_EL2`, instead of panicking
+/// the hypervisor: the VMM can stop the offending VM only, or emulate the instruction and
+/// resume it. The PC of the guest is not advanced, so resuming it without emulation takes the
+/// same exception again.
+#[derive(Clone, Copy, Debug)]
+pub struct UnhandledExit {
+    /// The kind of the exception.
+    pub kind: TrapKind,
+    /// The exception class, `ESR_EL2.EC`.
+    pub ec: u8,
+    /// The instruction specific syndrome, `ESR_EL2.ISS`.
+    pub iss: u32,
+    /// The faulting virtual address, `FAR_EL2`, only meaningful for the aborts.
+    pub far: u64,
+    /// The address of the instruction taking the exception, `ELR_EL2`.
+    pub elr: u64,
+    /// Whether the exception is taken from AArch32 EL0, see
+    /// [`Aarch64VCpuSetupConfig::deny_aarch32_el0`].
+    ///
+    /// [`Aarch64VCpuSetupConfig::deny_aarch32_el0`]: crate::Aarch64VCpuSetupConfig::deny_aarch32_el0
+    pub aarch32: bool,
+    /// The registers of the guest when it took the exception.
+    pub ctx: TrapFrame,
+}
+
+impl UnhandledExit {
+    /// Captures the exception the guest has just taken.
+    pub(crate) fn capture(kind: TrapKind, ctx: &TrapFrame) -> Self {
+        Self {
+            kind,
+            ec: ESR_EL2.read(ESR_EL2::EC) as u8,
+            iss: ESR_EL2.read(ESR_EL2::ISS) as u32,
+            far: FAR_EL2.get(),
+            elr: ctx.exception_pc() as u64,
+            aarch32: ctx.is_aarch32(),
+            ctx: *ctx,
+        }
+    }
+
+    /// Returns the exit reporting the exception to the VMM.
+    pub(crate) fn exit_reason(&self) -> AxVCpuExitReason {
+        AxVCpuExitReason::FailEntry {
+            hardware_entry_failure_reason: ((self.ec as u64) << 26) | self.iss as u64,
+        }
+    }
+}
+
+/// A stage-2 fault of the guest, see [`Aarch64ExitInfo::stage2_fault`].
+///
+/// It tells the VMM how large a block it can map the faulting IPA with, e.g. to back the
+/// guest memory with 2MiB or 1GiB blocks from the first touch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stage2Fault {
+    /// The faulting IPA.
+    pub ipa: GuestPhysAddr,
+    /// The level of the stage-2 tables the fault is reported at: the level of the missing
+    /// entry for translation faults, the one of the mapping for access flag and permission
+    /// faults.
+    pub level: u8,
+}
+
+impl Stage2Fault {
+    /// Returns the size of the largest block the IPA can be mapped by without replacing the
+    /// existing stage-2 tables, with the 4KiB granule: 1GiB for faults at levels 0 and 1,
+    /// 2MiB at level 2 and 4KiB at level 3.
+    ///
+    /// The VMM must still check that the block fits in the memory region of the guest and is
+    /// backed by a contiguous and as aligned host region.
+    pub const fn block_size(&self) -> usize {
+        // There are no level 0 blocks with the 4KiB granule.
+        let level = if self.level == 0 { 1 } else { self.level };
+        1 << level_shift(level as usize)
+    }
+
+    /// Returns the IPA of the block of [`Stage2Fault::block_size`] containing the faulting IPA.
+    pub fn block_base(&self) -> GuestPhysAddr {
+        GuestPhysAddr::from(self.ipa.as_usize() & !(self.block_size() - 1))
+    }
+}
+
+/// Timestamps of the exit path of a VM exit, in physical counter (`CNTPCT_EL0`) ticks, see
+/// [`Aarch64VCpu::last_exit_timestamps`].
+///
+/// `handler - vector` is the cost of the world switch, `vmm_return - handler` the one of the
+/// exit handling of the vCPU, and `reentry - vmm_return` the time spent in the VMM.
+///
+/// [`Aarch64VCpu::last_exit_timestamps`]: crate::Aarch64VCpu::last_exit_timestamps
+#[cfg(feature = "exit-latency")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Aarch64ExitTimestamps {
+    /// The exception vector has saved the guest registers.
+    pub vector: u64,
+    /// The exit handler of the vCPU is entered, the same as [`Aarch64ExitInfo::timestamp`].
+    pub handler: u64,
+    /// `run()` has returned the exit to the VMM, 0 if the exit has been handled by the vCPU
+    /// itself.
+    pub vmm_return: u64,
+    /// The guest is re-entered.
+    pub reentry: u64,
+}
+
+/// VM exits which can be masked, see [`Aarch64ExitMask`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaskableExit {
+    /// [`AxVCpuExitReason::Hypercall`], completed with `SMCCC_RET_NOT_SUPPORTED` (-1) in `x0`.
+    Hypercall,
+    /// [`AxVCpuExitReason::MmioRead`], reading as zero.
+    MmioRead,
+    /// [`AxVCpuExitReason::MmioWrite`], ignored.
+    MmioWrite,
+    /// [`AxVCpuExitReason::SysRegRead`], reading as zero.
+    SysRegRead,
+    /// [`AxVCpuExitReason::SysRegWrite`], ignored.
+    SysRegWrite,
+    /// [`AxVCpuExitReason::Halt`], the vCPU is resumed immediately, as a spurious wake-up.
+    Halt,
+    /// [`AxVCpuExitReason::SendIPI`], the IPI is dropped.
+    SendIpi,
+    /// The yields of the `WFE` spin loops, reported as [`AxVCpuExitReason::Nothing`], the vCPU
+    /// is resumed immediately, see [`Aarch64VCpuSetupConfig::wfe_spin`].
+    ///
+    /// [`Aarch64VCpuSetupConfig::wfe_spin`]: crate::Aarch64VCpuSetupConfig::wfe_spin
+    Wfe,
+}
+
+impl MaskableExit {
+    const fn bit(self) -> u32 {
+        1 << self as u32
+    }
+
+    fn of(exit: &AxVCpuExitReason) -> Option<Self> {
+        Some(match exit {
+            AxVCpuExitReason::Hypercall { .. } => Self::Hypercall,
+            AxVCpuExitReason::MmioRead { .. } => Self::MmioRead,
+            AxVCpuExitReason::MmioWrite { .. } => Self::MmioWrite,
+            AxVCpuExitReason::SysRegRead { .. } => Self::SysRegRead,
+            AxVCpuExitReason::SysRegWrite { .. } => Self::SysRegWrite,
+            AxVCpuExitReason::Halt => Self::Halt,
+            AxVCpuExitReason::SendIPI { .. } => Self::SendIpi,
+            _ => return None,
+        })
+    }
+}
+
+/// Maximum number of hypercall ranges an [`Aarch64ExitMask`] can hold.
+const MAX_MASKED_HYPERCALL_RANGES: usize = 4;
+
+/// A hypercall range completed in EL2, with the value returned in `x0`.
+#[derive(Clone, Copy, Debug)]
+struct MaskedHypercalls {
+    first: u64,
+    last: u64,
+    ret: u64,
+}
+
+/// The VM exits which are handled in EL2 with a default policy instead of being returned by
+/// `run()`, see [`Aarch64VCpuSetupConfig::exit_mask`] and [`Aarch64VCpu::set_exit_mask`].
+///
+/// Exits which have to be handled by the VMM to make progress (interrupts, nested page
+/// faults, psci power management) can't be masked.
+///
+/// Exits can also be masked only when they are taken from guest EL0, for the paravirtual
+/// interfaces which must only be reachable by the guest kernel, see
+/// [`Aarch64ExitMask::set_from_el0`].
+///
+/// [`Aarch64VCpuSetupConfig::exit_mask`]: crate::Aarch64VCpuSetupConfig::exit_mask
+/// [`Aarch64VCpu::set_exit_mask`]: crate::Aarch64VCpu::set_exit_mask
+#[derive(Clone, Debug, Default)]
+pub struct Aarch64ExitMask {
+    exits: u32,
+    el0_exits: u32,
+    hypercalls: [Option<MaskedHypercalls>; MAX_MASKED_HYPERCALL_RANGES],
+}
+
+impl Aarch64ExitMask {
+    /// Masks or unmasks all the exits of `kind`.
+    pub fn set(&mut self, kind: MaskableExit, masked: bool) -> &mut Self {
+        if masked {
+            self.exits |= kind.bit();
+        } else {
+            self.exits &= !kind.bit();
+        }
+        self
+    }
+
+    /// Returns whether all the exits of `kind` are masked.
+    pub fn is_masked(&self, kind: MaskableExit) -> bool {
+        self.exits & kind.bit() != 0
+    }
+
+    /// Masks or unmasks the exits of `kind` taken from guest EL0, in addition to the ones
+    /// masked by [`Aarch64ExitMask::set`].
+    ///
+    /// E.g. an MMIO paravirtual interface can be hidden from the user space of the guest by
+    /// masking the MMIO exits from EL0. `HVC` and `SMC` are undefined at EL0, so the
+    /// hypercalls never come from EL0.
+    pub fn set_from_el0(&mut self, kind: MaskableExit, masked: bool) -> &mut Self {
+        if masked {
+            self.el0_exits |= kind.bit();
+        } else {
+            self.el0_exits &= !kind.bit();
+        }
+        self
+    }
+
+    /// Returns whether the exits of `kind` taken from the guest exception level `el` are
+    /// masked.
+    pub fn is_masked_from(&self, kind: MaskableExit, el: u8) -> bool {
+        self.is_masked(kind) || (el == 0 && self.el0_exits & kind.bit() != 0)
+    }
+
+    /// Masks the hypercalls whose number is in `nrs`, e.g. a diagnostic range, which are
+    /// completed with `ret` in `x0`.
+    ///
+    /// Up to 4 ranges can be masked.
+    pub fn mask_hypercalls(&mut self, nrs: RangeInclusive<u64>, ret: u64) -> AxResult {
+        let Some(slot) = self.hypercalls.iter_mut().find(|slot| slot.is_none()) else {
+            return ax_err!(NoMemory, "too many masked hypercall ranges");
+        };
+        *slot = Some(MaskedHypercalls {
+            first: *nrs.start(),
+            last: *nrs.end(),
+            ret,
+        });
+        Ok(())
+    }
+
+    /// Handles `exit`, taken from the guest exception level `el`, with the default policy if
+    /// it is masked.
+    ///
+    /// Returns `false` if the exit is not masked and must be returned to the VMM.
+    pub(crate) fn handle(&self, exit: &AxVCpuExitReason, el: u8, ctx: &mut TrapFrame) -> bool {
+        if let AxVCpuExitReason::Hypercall { nr, .. } = exit {
+            let mut ranges = self.hypercalls.iter().flatten();
+            if let Some(range) = ranges.find(|r| (r.first..=r.last).contains(nr)) {
+                ctx.set_argument(range.ret as usize);
+                return true;
+            }
+        }
+
+        match MaskableExit::of(exit) {
+            Some(kind) if self.is_masked_from(kind, el) => {}
+            _ => return false,
+        }
+        complete_in_el2(exit, SMCCC_RET_NOT_SUPPORTED, ctx);
+        true
+    }
+}
+
+/// Completes `exit` without the VMM: hypercalls return `ret` in `x0`, reads return zero, and
+/// the other exits are dropped.
+pub(crate) fn complete_in_el2(exit: &AxVCpuExitReason, ret: u64, ctx: &mut TrapFrame) {
+    match *exit {
+        AxVCpuExitReason::Hypercall { .. } => ctx.set_argument(ret as usize),
+        AxVCpuExitReason::MmioRead { reg, .. } | AxVCpuExitReason::SysRegRead { reg, .. } => {
+            if reg != 31 {
+                ctx.set_gpr(reg, 0);
+            }
+        }
+        _ => {}
+    }
+}
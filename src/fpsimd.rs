@@ -0,0 +1,94 @@
+//! Lazy switching of the FP/SIMD registers between the host and the guest.
+//!
+//! The guest is entered with its FP/SIMD accesses trapped (`CPTR_EL2.TFP`). Its first access
+//! traps, the host registers are then saved and the guest ones loaded, and the guest resumes
+//! with the accesses untrapped. The registers are switched back when `run()` returns to the VMM,
+//! so a guest never using the FP/SIMD registers costs no switch at all.
+//!
+//! The exit path of the vCPU is built for the soft-float targets and doesn't touch the
+//! registers, so they stay loaded while the exits are handled in EL2.
+
+use core::arch::asm;
+
+/// `CPTR_EL2.TFP`, bit [10], traps the FP/SIMD (and SVE) accesses of EL1 and EL0.
+pub const CPTR_EL2_TFP: u64 = 1 << 10;
+
+/// The FP/SIMD registers of a context, `Q0`-`Q31`, `FPCR` and `FPSR`.
+#[repr(C, align(16))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FpSimdState {
+    /// `Q0`-`Q31`.
+    pub q: [u128; 32],
+    /// `FPCR`.
+    pub fpcr: u64,
+    /// `FPSR`.
+    pub fpsr: u64,
+}
+
+impl FpSimdState {
+    /// Saves the registers of the current CPU, which must not be trapped.
+    pub unsafe fn save(&mut self) {
+        unsafe {
+            asm!(
+                ".arch_extension fp",
+                ".arch_extension simd",
+                "stp q0, q1, [{0}, #0x000]",
+                "stp q2, q3, [{0}, #0x020]",
+                "stp q4, q5, [{0}, #0x040]",
+                "stp q6, q7, [{0}, #0x060]",
+                "stp q8, q9, [{0}, #0x080]",
+                "stp q10, q11, [{0}, #0x0a0]",
+                "stp q12, q13, [{0}, #0x0c0]",
+                "stp q14, q15, [{0}, #0x0e0]",
+                "stp q16, q17, [{0}, #0x100]",
+                "stp q18, q19, [{0}, #0x120]",
+                "stp q20, q21, [{0}, #0x140]",
+                "stp q22, q23, [{0}, #0x160]",
+                "stp q24, q25, [{0}, #0x180]",
+                "stp q26, q27, [{0}, #0x1a0]",
+                "stp q28, q29, [{0}, #0x1c0]",
+                "stp q30, q31, [{0}, #0x1e0]",
+                "mrs {1}, fpcr",
+                "mrs {2}, fpsr",
+                "stp {1}, {2}, [{0}, #0x200]",
+                in(reg) self as *mut Self,
+                out(reg) _,
+                out(reg) _,
+                options(nostack),
+            );
+        }
+    }
+
+    /// Loads the registers into the current CPU, which must not be trapped.
+    pub unsafe fn restore(&self) {
+        unsafe {
+            asm!(
+                ".arch_extension fp",
+                ".arch_extension simd",
+                "ldp q0, q1, [{0}, #0x000]",
+                "ldp q2, q3, [{0}, #0x020]",
+                "ldp q4, q5, [{0}, #0x040]",
+                "ldp q6, q7, [{0}, #0x060]",
+                "ldp q8, q9, [{0}, #0x080]",
+                "ldp q10, q11, [{0}, #0x0a0]",
+                "ldp q12, q13, [{0}, #0x0c0]",
+                "ldp q14, q15, [{0}, #0x0e0]",
+                "ldp q16, q17, [{0}, #0x100]",
+                "ldp q18, q19, [{0}, #0x120]",
+                "ldp q20, q21, [{0}, #0x140]",
+                "ldp q22, q23, [{0}, #0x160]",
+                "ldp q24, q25, [{0}, #0x180]",
+                "ldp q26, q27, [{0}, #0x1a0]",
+                "ldp q28, q29, [{0}, #0x1c0]",
+                "ldp q30, q31, [{0}, #0x1e0]",
+                "ldp {1}, {2}, [{0}, #0x200]",
+                "msr fpcr, {1}",
+                "msr fpsr, {2}",
+                in(reg) self as *const Self,
+                out(reg) _,
+                out(reg) _,
+                options(nostack, readonly),
+            );
+        }
+    }
+}
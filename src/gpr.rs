@@ -0,0 +1,94 @@
+//! A writable view of the general-purpose registers of a stopped guest, which tracks the
+//! registers modified by the VMM between an exit and the next entry.
+
+use axaddrspace::device::AccessWidth;
+
+use crate::TrapFrame;
+
+/// Returns the number of bits of an access of `width`.
+const fn width_bits(width: AccessWidth) -> u32 {
+    match width {
+        AccessWidth::Byte => 8,
+        AccessWidth::Word => 16,
+        AccessWidth::Dword => 32,
+        AccessWidth::Qword => 64,
+    }
+}
+
+/// Returns the low `width` of `value`.
+pub(crate) const fn truncate(value: u64, width: AccessWidth) -> u64 {
+    match width_bits(width) {
+        64 => value,
+        bits => value & ((1 << bits) - 1),
+    }
+}
+
+/// Returns the value of a register of `reg_width` loaded with the `width` value `value`, as
+/// the load instructions do: the value is zero or sign-extended to the register, and the
+/// upper 32 bits of `xn` are cleared by the loads to `wn`.
+pub(crate) const fn loaded_value(
+    value: u64,
+    width: AccessWidth,
+    reg_width: AccessWidth,
+    sign_extend: bool,
+) -> u64 {
+    let value = truncate(value, width);
+    let value = if sign_extend {
+        let shift = 64 - width_bits(width);
+        (((value << shift) as i64) >> shift) as u64
+    } else {
+        value
+    };
+    truncate(value, reg_width)
+}
+
+/// The general-purpose registers `x0` to `x30` of the guest, see
+/// [`Aarch64VCpu::exit_gprs`].
+///
+/// The registers whose value is changed through the view are recorded in the mask returned by
+/// [`Aarch64VCpu::modified_gprs`] (bit `n` for `xn`), along with the ones changed by the other
+/// setters of the vCPU, until the next exit. Debugging tools can then log exactly what the VMM
+/// changed before the guest is re-entered.
+///
+/// [`Aarch64VCpu::exit_gprs`]: crate::Aarch64VCpu::exit_gprs
+/// [`Aarch64VCpu::modified_gprs`]: crate::Aarch64VCpu::modified_gprs
+pub struct ExitGprs<'a> {
+    ctx: &'a mut TrapFrame,
+    modified: &'a mut u32,
+}
+
+impl<'a> ExitGprs<'a> {
+    pub(crate) fn new(ctx: &'a mut TrapFrame, modified: &'a mut u32) -> Self {
+        Self { ctx, modified }
+    }
+
+    /// Returns the value of `xn`, 0 for `n` 31 (`xzr`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is above 31.
+    pub fn get(&self, n: usize) -> u64 {
+        self.ctx.gpr(n) as u64
+    }
+
+    /// Sets the value of `xn`, the writes to `xzr` (`n` 31) are ignored.
+    ///
+    /// Writing the current value of a register doesn't count as a modification.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is above 31.
+    pub fn set(&mut self, n: usize, value: u64) {
+        if self.get(n) != value {
+            self.ctx.set_gpr(n, value as usize);
+            if n < 31 {
+                *self.modified |= 1 << n;
+            }
+        }
+    }
+
+    /// Returns the mask of the registers modified since the last exit.
+    pub fn modified(&self) -> u32 {
+        *self.modified
+    }
+}
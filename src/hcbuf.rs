@@ -0,0 +1,81 @@
+//! Guest buffers passed to the hypercalls whose payload doesn't fit in the argument
+//! registers, e.g. log buffers or descriptor lists.
+//!
+//! The guest passes the IPA of the buffer and its length in two consecutive arguments of the
+//! `HVC`, and keeps the buffer mapped and unmodified until the call returns. The VMM gets it
+//! with [`Aarch64VCpu::hypercall_buffer`], which validates the whole range up front, then
+//! copies it from or to host memory in chunks of any size with
+//! [`Aarch64VCpu::read_guest_buffer`] and [`Aarch64VCpu::write_guest_buffer`]. The buffer may
+//! span discontiguous host pages, each page is translated through stage-2 on each copy.
+//!
+//! [`Aarch64VCpu::hypercall_buffer`]: crate::Aarch64VCpu::hypercall_buffer
+//! [`Aarch64VCpu::read_guest_buffer`]: crate::Aarch64VCpu::read_guest_buffer
+//! [`Aarch64VCpu::write_guest_buffer`]: crate::Aarch64VCpu::write_guest_buffer
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+
+/// The maximum length of a guest buffer, bounding the work of a hypercall.
+pub const HYPERCALL_BUFFER_MAX: usize = 1 << 20;
+
+/// The size of the pages a guest buffer is translated by.
+const PAGE_SIZE: usize = 0x1000;
+
+/// A guest buffer described by the arguments of a hypercall, see the module documentation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestBuffer {
+    addr: GuestPhysAddr,
+    len: usize,
+}
+
+impl GuestBuffer {
+    /// Describes the buffer of `len` bytes at `addr`.
+    ///
+    /// Returns `InvalidInput` if it is longer than [`HYPERCALL_BUFFER_MAX`] or wraps around
+    /// the address space.
+    pub(crate) fn new(addr: GuestPhysAddr, len: usize) -> AxResult<Self> {
+        if len > HYPERCALL_BUFFER_MAX {
+            return ax_err!(InvalidInput, "hypercall buffer too large");
+        }
+        if addr.as_usize().checked_add(len).is_none() {
+            return ax_err!(InvalidInput, "hypercall buffer wraps around");
+        }
+        Ok(Self { addr, len })
+    }
+
+    /// Returns the IPA of the buffer.
+    pub fn addr(&self) -> GuestPhysAddr {
+        self.addr
+    }
+
+    /// Returns the length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the IPA and length of the chunks of the buffer within a page, from `offset`
+    /// and for at most `len` bytes.
+    pub(crate) fn chunks(
+        &self,
+        offset: usize,
+        len: usize,
+    ) -> impl Iterator<Item = (GuestPhysAddr, usize)> {
+        let start = self.addr.as_usize() + offset.min(self.len);
+        let end = start + len.min(self.len - offset.min(self.len));
+        let mut ipa = start;
+        core::iter::from_fn(move || {
+            if ipa >= end {
+                return None;
+            }
+            let size = (ipa | (PAGE_SIZE - 1)).saturating_add(1).min(end) - ipa;
+            let chunk = (GuestPhysAddr::from(ipa), size);
+            ipa += size;
+            Some(chunk)
+        })
+    }
+}
@@ -0,0 +1,48 @@
+//! The heartbeat hypercall through which a guest proves it is alive, a watchdog needing no
+//! device model.
+//!
+//! `HEARTBEAT` is a 64-bit fast call in the vendor specific hypervisor service range, taking
+//! no argument and returning `SUCCESS` in `x0`. The guest calls it periodically, e.g. from a
+//! watchdog driver, and the VMM polls [`Aarch64VCpu::ticks_since_heartbeat`] to detect the
+//! hung vCPUs.
+//!
+//! [`Aarch64VCpu::ticks_since_heartbeat`]: crate::Aarch64VCpu::ticks_since_heartbeat
+
+use aarch64_cpu::registers::{CNTPCT_EL0, Readable};
+use axvcpu::AxVCpuExitReason;
+
+use crate::TrapFrame;
+
+/// `HEARTBEAT`, records that the guest is alive.
+const HEARTBEAT: u32 = 0xc600_0040;
+
+const HEARTBEAT_RET_SUCCESS: u64 = 0;
+
+/// The heartbeat state of a vCPU.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GuestHeartbeat {
+    /// The physical counter value at the last heartbeat, `None` until the first one.
+    pub last: Option<u64>,
+}
+
+impl GuestHeartbeat {
+    /// Returns the physical counter ticks elapsed since the last heartbeat.
+    pub fn ticks_since(&self) -> Option<u64> {
+        self.last.map(|last| CNTPCT_EL0.get().wrapping_sub(last))
+    }
+}
+
+/// Handles the `HEARTBEAT` calls of the guest, through `HVC` or `SMC`.
+///
+/// Returns `None` if the call is not one.
+pub fn handle_heartbeat_call(
+    ctx: &mut TrapFrame,
+    heartbeat: &mut GuestHeartbeat,
+) -> Option<AxVCpuExitReason> {
+    if ctx.gpr[0] as u32 != HEARTBEAT {
+        return None;
+    }
+    heartbeat.last = Some(CNTPCT_EL0.get());
+    ctx.set_argument(HEARTBEAT_RET_SUCCESS as usize);
+    Some(AxVCpuExitReason::Nothing)
+}
@@ -0,0 +1,63 @@
+//! The diagnostic hypercall through which a cooperative guest queries its own virtualization
+//! overhead: its exit counts and stolen time.
+//!
+//! `HV_STATS` is a 64-bit fast call in the vendor specific hypervisor service range, selecting
+//! the statistic in `x1`. It returns `SUCCESS` in `x0` and the value in `x1`, or
+//! `INVALID_PARAMETER` for an unknown statistic.
+
+use axvcpu::AxVCpuExitReason;
+
+use crate::TrapFrame;
+use crate::exception::TrapKind;
+
+/// `HV_STATS`, returns the statistic selected by `x1`.
+const HV_STATS: u32 = 0xc600_0030;
+
+/// The statistics of `HV_STATS`, the exit counts by [`TrapKind`] come first.
+const HV_STATS_EXITS_SYNC: u64 = TrapKind::Synchronous as u64;
+const HV_STATS_EXITS_SERROR: u64 = TrapKind::SError as u64;
+/// The stolen time, in generic timer ticks.
+const HV_STATS_STOLEN_TICKS: u64 = 4;
+
+const HV_STATS_RET_SUCCESS: u64 = 0;
+const HV_STATS_RET_INVALID_PARAMETER: u64 = -3i64 as u64;
+
+/// The virtualization overhead statistics of a vCPU, as seen by the guest.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GuestHvStats {
+    /// The number of exits of each [`TrapKind`], including the ones handled in EL2.
+    pub exits: [u64; 4],
+    /// The time the vCPU has spent out of the guest between an exit and the next entry, in
+    /// generic timer ticks.
+    pub stolen_ticks: u64,
+}
+
+impl GuestHvStats {
+    /// Records an exit of `kind`.
+    pub fn record_exit(&mut self, kind: TrapKind) {
+        let count = &mut self.exits[kind as usize];
+        *count = count.saturating_add(1);
+    }
+}
+
+/// Handles the `HV_STATS` calls of the guest, through `HVC` or `SMC`.
+///
+/// Returns `None` if the call is not one.
+pub fn handle_hv_stats_call(ctx: &mut TrapFrame, stats: &GuestHvStats) -> Option<AxVCpuExitReason> {
+    if ctx.gpr[0] as u32 != HV_STATS {
+        return None;
+    }
+    let value = match ctx.gpr[1] {
+        stat @ HV_STATS_EXITS_SYNC..=HV_STATS_EXITS_SERROR => Some(stats.exits[stat as usize]),
+        HV_STATS_STOLEN_TICKS => Some(stats.stolen_ticks),
+        _ => None,
+    };
+    match value {
+        Some(value) => {
+            ctx.set_argument(HV_STATS_RET_SUCCESS as usize);
+            ctx.set_gpr(1, value as usize);
+        }
+        None => ctx.set_argument(HV_STATS_RET_INVALID_PARAMETER as usize),
+    }
+    Some(AxVCpuExitReason::Nothing)
+}
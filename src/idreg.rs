@@ -0,0 +1,193 @@
+//! Virtualization of the feature ID registers (`HCR_EL2.TID3`), presenting the guest with a
+//! reduced feature set, e.g. the common subset of the hosts a VM migrates between.
+//!
+//! The trapped space is the one of `S3_0_C0_C<CRm>_<op2>` with `CRm` from 1 to 7: the AArch32
+//! ID registers, `MVFR<n>_EL1` and the `ID_AA64*_EL1` registers. All the reads of the guest
+//! return the values of [`VirtIdRegs`], initialized from the host with the features the vCPU
+//! doesn't virtualize hidden, and overridden by the VMM with [`Aarch64VCpu::set_id_reg`].
+//!
+//! [`Aarch64VCpu::set_id_reg`]: crate::Aarch64VCpu::set_id_reg
+
+use core::arch::asm;
+
+use axaddrspace::device::SysRegAddr;
+
+use crate::sysreg::{sysreg_addr, sysreg_encoding};
+
+/// The number of registers of the trapped space, 8 per `CRm`.
+const ID_REGS: usize = 7 * 8;
+
+/// `ID_AA64PFR0_EL1`, AArch64 Processor Feature Register 0.
+pub const SYSREG_ID_AA64PFR0_EL1: SysRegAddr = sysreg_addr(3, 0, 0, 4, 0);
+/// `ID_AA64PFR1_EL1`, AArch64 Processor Feature Register 1.
+pub const SYSREG_ID_AA64PFR1_EL1: SysRegAddr = sysreg_addr(3, 0, 0, 4, 1);
+/// `ID_AA64DFR0_EL1`, AArch64 Debug Feature Register 0.
+pub const SYSREG_ID_AA64DFR0_EL1: SysRegAddr = sysreg_addr(3, 0, 0, 5, 0);
+/// `ID_AA64ISAR0_EL1`, AArch64 Instruction Set Attribute Register 0.
+pub const SYSREG_ID_AA64ISAR0_EL1: SysRegAddr = sysreg_addr(3, 0, 0, 6, 0);
+/// `ID_AA64ISAR1_EL1`, AArch64 Instruction Set Attribute Register 1.
+pub const SYSREG_ID_AA64ISAR1_EL1: SysRegAddr = sysreg_addr(3, 0, 0, 6, 1);
+/// `ID_AA64ISAR2_EL1`, AArch64 Instruction Set Attribute Register 2.
+pub const SYSREG_ID_AA64ISAR2_EL1: SysRegAddr = sysreg_addr(3, 0, 0, 6, 2);
+/// `ID_AA64MMFR0_EL1`, AArch64 Memory Model Feature Register 0.
+pub const SYSREG_ID_AA64MMFR0_EL1: SysRegAddr = sysreg_addr(3, 0, 0, 7, 0);
+/// `ID_AA64MMFR1_EL1`, AArch64 Memory Model Feature Register 1.
+pub const SYSREG_ID_AA64MMFR1_EL1: SysRegAddr = sysreg_addr(3, 0, 0, 7, 1);
+/// `ID_AA64MMFR2_EL1`, AArch64 Memory Model Feature Register 2.
+pub const SYSREG_ID_AA64MMFR2_EL1: SysRegAddr = sysreg_addr(3, 0, 0, 7, 2);
+
+/// The fields hidden from the guest by default, as `(register, shift)` of 4-bit fields: the
+/// vCPU only switches the FP/SIMD registers, not the SVE and SME ones, and contains MPAM and
+/// the trace buffer of the guest rather than virtualizing them.
+const HIDDEN_FIELDS: [(SysRegAddr, u32); 5] = [
+    // ID_AA64PFR0_EL1.SVE, bits [35:32].
+    (SYSREG_ID_AA64PFR0_EL1, 32),
+    // ID_AA64PFR0_EL1.MPAM, bits [43:40].
+    (SYSREG_ID_AA64PFR0_EL1, 40),
+    // ID_AA64PFR1_EL1.MPAM_frac, bits [19:16].
+    (SYSREG_ID_AA64PFR1_EL1, 16),
+    // ID_AA64PFR1_EL1.SME, bits [27:24].
+    (SYSREG_ID_AA64PFR1_EL1, 24),
+    // ID_AA64DFR0_EL1.TraceBuffer, bits [47:44].
+    (SYSREG_ID_AA64DFR0_EL1, 44),
+];
+
+/// Generates the reader of the registers of the trapped space, which are distinct
+/// instructions, indexed by `(CRm - 1) * 8 + op2`.
+macro_rules! id_reg_reader {
+    ($read:ident, [$($n:literal => $reg:literal),* $(,)?]) => {
+        fn $read(n: usize) -> u64 {
+            let value: u64;
+            match n {
+                $($n => unsafe { asm!(concat!("mrs {}, ", $reg), out(reg) value) },)*
+                _ => unreachable!(),
+            }
+            value
+        }
+    };
+}
+
+id_reg_reader!(read_host_id_reg, [
+    0 => "S3_0_C0_C1_0", 1 => "S3_0_C0_C1_1", 2 => "S3_0_C0_C1_2", 3 => "S3_0_C0_C1_3",
+    4 => "S3_0_C0_C1_4", 5 => "S3_0_C0_C1_5", 6 => "S3_0_C0_C1_6", 7 => "S3_0_C0_C1_7",
+    8 => "S3_0_C0_C2_0", 9 => "S3_0_C0_C2_1", 10 => "S3_0_C0_C2_2", 11 => "S3_0_C0_C2_3",
+    12 => "S3_0_C0_C2_4", 13 => "S3_0_C0_C2_5", 14 => "S3_0_C0_C2_6", 15 => "S3_0_C0_C2_7",
+    16 => "S3_0_C0_C3_0", 17 => "S3_0_C0_C3_1", 18 => "S3_0_C0_C3_2", 19 => "S3_0_C0_C3_3",
+    20 => "S3_0_C0_C3_4", 21 => "S3_0_C0_C3_5", 22 => "S3_0_C0_C3_6", 23 => "S3_0_C0_C3_7",
+    24 => "S3_0_C0_C4_0", 25 => "S3_0_C0_C4_1", 26 => "S3_0_C0_C4_2", 27 => "S3_0_C0_C4_3",
+    28 => "S3_0_C0_C4_4", 29 => "S3_0_C0_C4_5", 30 => "S3_0_C0_C4_6", 31 => "S3_0_C0_C4_7",
+    32 => "S3_0_C0_C5_0", 33 => "S3_0_C0_C5_1", 34 => "S3_0_C0_C5_2", 35 => "S3_0_C0_C5_3",
+    36 => "S3_0_C0_C5_4", 37 => "S3_0_C0_C5_5", 38 => "S3_0_C0_C5_6", 39 => "S3_0_C0_C5_7",
+    40 => "S3_0_C0_C6_0", 41 => "S3_0_C0_C6_1", 42 => "S3_0_C0_C6_2", 43 => "S3_0_C0_C6_3",
+    44 => "S3_0_C0_C6_4", 45 => "S3_0_C0_C6_5", 46 => "S3_0_C0_C6_6", 47 => "S3_0_C0_C6_7",
+    48 => "S3_0_C0_C7_0", 49 => "S3_0_C0_C7_1", 50 => "S3_0_C0_C7_2", 51 => "S3_0_C0_C7_3",
+    52 => "S3_0_C0_C7_4", 53 => "S3_0_C0_C7_5", 54 => "S3_0_C0_C7_6", 55 => "S3_0_C0_C7_7",
+]);
+
+/// Returns the index of `addr` in the trapped space, if it belongs to it.
+const fn id_reg_index(addr: SysRegAddr) -> Option<usize> {
+    match sysreg_encoding(addr) {
+        (3, 0, 0, crm @ 1..=7, op2) => Some((crm - 1) * 8 + op2),
+        _ => None,
+    }
+}
+
+/// The values of the ID registers presented to the guest.
+#[derive(Clone, Debug)]
+pub struct VirtIdRegs {
+    regs: [u64; ID_REGS],
+}
+
+impl VirtIdRegs {
+    /// Reads the ID registers of the current CPU, then hides the features the vCPU doesn't
+    /// virtualize.
+    pub fn from_host() -> Self {
+        let mut regs = [0; ID_REGS];
+        for (n, reg) in regs.iter_mut().enumerate() {
+            *reg = read_host_id_reg(n);
+        }
+        Self::sanitized(regs)
+    }
+
+    /// Returns the registers of the values `regs` of the trapped space, with the features the
+    /// vCPU doesn't virtualize hidden.
+    fn sanitized(regs: [u64; ID_REGS]) -> Self {
+        let mut id_regs = Self { regs };
+        for (addr, shift) in HIDDEN_FIELDS {
+            if let Some(n) = id_reg_index(addr) {
+                id_regs.regs[n] &= !(0xf << shift);
+            }
+        }
+        id_regs
+    }
+
+    /// Returns the value of `addr` read by the guest, or `None` if it is not a trapped ID
+    /// register.
+    pub fn read(&self, addr: SysRegAddr) -> Option<u64> {
+        id_reg_index(addr).map(|n| self.regs[n])
+    }
+
+    /// Sets the value of `addr` read by the guest, returns `false` if it is not a trapped ID
+    /// register.
+    pub fn set(&mut self, addr: SysRegAddr, value: u64) -> bool {
+        let Some(n) = id_reg_index(addr) else {
+            return false;
+        };
+        self.regs[n] = value;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `ID_AA64PFR0_EL1.SVE` and `MPAM`, bits [35:32] and [43:40].
+    const PFR0_HIDDEN: u64 = (0xf << 32) | (0xf << 40);
+    /// `ID_AA64PFR1_EL1.MPAM_frac` and `SME`, bits [19:16] and [27:24].
+    const PFR1_HIDDEN: u64 = (0xf << 16) | (0xf << 24);
+    /// `ID_AA64DFR0_EL1.TraceBuffer`, bits [47:44].
+    const DFR0_HIDDEN: u64 = 0xf << 44;
+
+    #[test]
+    fn hidden_fields_are_cleared() {
+        let regs = VirtIdRegs::sanitized([u64::MAX; ID_REGS]);
+        assert_eq!(regs.read(SYSREG_ID_AA64PFR0_EL1), Some(!PFR0_HIDDEN));
+        assert_eq!(regs.read(SYSREG_ID_AA64PFR1_EL1), Some(!PFR1_HIDDEN));
+        assert_eq!(regs.read(SYSREG_ID_AA64DFR0_EL1), Some(!DFR0_HIDDEN));
+        // The other registers are kept.
+        assert_eq!(regs.read(SYSREG_ID_AA64ISAR0_EL1), Some(u64::MAX));
+        assert_eq!(regs.read(SYSREG_ID_AA64MMFR2_EL1), Some(u64::MAX));
+    }
+
+    #[test]
+    fn registers_are_indexed_by_encoding() {
+        let mut values = [0; ID_REGS];
+        for (n, value) in values.iter_mut().enumerate() {
+            *value = n as u64;
+        }
+        let regs = VirtIdRegs::sanitized(values);
+        // `(CRm - 1) * 8 + op2`.
+        assert_eq!(regs.read(sysreg_addr(3, 0, 0, 1, 0)), Some(0));
+        assert_eq!(regs.read(SYSREG_ID_AA64ISAR2_EL1), Some(42));
+        assert_eq!(regs.read(sysreg_addr(3, 0, 0, 7, 7)), Some(55));
+    }
+
+    #[test]
+    fn set_only_in_the_trapped_space() {
+        let mut regs = VirtIdRegs::sanitized([0; ID_REGS]);
+        // Written as is, the hidden fields included.
+        assert!(regs.set(SYSREG_ID_AA64PFR0_EL1, PFR0_HIDDEN | 1));
+        assert_eq!(regs.read(SYSREG_ID_AA64PFR0_EL1), Some(PFR0_HIDDEN | 1));
+
+        // `MIDR_EL1`, `CTR_EL0` and a register of another `op1` are not trapped.
+        for addr in [
+            sysreg_addr(3, 0, 0, 0, 0),
+            sysreg_addr(3, 3, 0, 0, 1),
+            sysreg_addr(3, 1, 0, 4, 0),
+        ] {
+            assert!(!regs.set(addr, 1));
+            assert_eq!(regs.read(addr), None);
+        }
+    }
+}
@@ -1,20 +1,148 @@
-#![no_std]
-#![feature(doc_cfg)]
+#![cfg_attr(not(fuzzing), no_std)]
+#![cfg_attr(docsrs, feature(doc_cfg))]
 #![doc = include_str!("../README.md")]
 
 #[macro_use]
 extern crate log;
 
 mod context_frame;
+mod cpu_feature;
+mod dcc;
+#[cfg(not(fuzzing))]
+mod decode;
+/// Syndrome decoders, only public for the fuzzing harness.
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod decode;
+mod errata;
 #[macro_use]
 mod exception_utils;
 mod exception;
+mod exit;
+mod fpsimd;
+mod gpr;
+mod hcbuf;
+mod heartbeat;
+mod hvstats;
+mod idreg;
+mod mmu;
+mod mpam;
+#[cfg(feature = "nested")]
+mod nv2;
 mod pcpu;
+#[cfg(feature = "vpmu")]
+mod pmu;
+mod pmu_filter;
+mod policy;
+mod power_mmio;
+mod psci;
+mod pstate;
+mod pvlock;
+#[cfg(feature = "exit-ring")]
+mod ring;
+#[cfg(feature = "run-queue")]
+mod runq;
 mod smc;
+mod stage2;
+mod state;
+mod stats;
+#[cfg(feature = "switch-checks")]
+mod switch_check;
+mod symbol;
+mod sysreg;
+#[cfg(feature = "test-guest")]
+mod test_guest;
+mod tlb;
+#[cfg(feature = "tracing")]
+mod trace;
+mod trap_stack;
 mod vcpu;
+mod vendor_hyp;
+#[cfg(feature = "vgic")]
+mod vgic;
+mod vmid;
+mod vmstate;
+mod watch;
+mod wfe;
 
-pub use self::pcpu::Aarch64PerCpu;
+pub use self::context_frame::Aarch64El1State;
+pub use self::cpu_feature::has_feat_s2fwb;
+pub use self::dcc::DccBackend;
+pub use self::decode::ExclusiveAccess;
+pub use self::errata::{ErratumWorkaround, HOST_ERRATA, HostErratum, host_errata};
+pub use self::exception::{DcIvacPolicy, TrapKind};
+#[cfg(feature = "exit-latency")]
+#[cfg_attr(docsrs, doc(cfg(feature = "exit-latency")))]
+pub use self::exit::Aarch64ExitTimestamps;
+pub use self::exit::{
+    Aarch64ExitInfo, Aarch64ExitMask, GuestSError, MaskableExit, Stage2Fault, UnhandledExit,
+};
+pub use self::gpr::ExitGprs;
+pub use self::hcbuf::{GuestBuffer, HYPERCALL_BUFFER_MAX};
+pub use self::idreg::{
+    SYSREG_ID_AA64DFR0_EL1, SYSREG_ID_AA64ISAR0_EL1, SYSREG_ID_AA64ISAR1_EL1,
+    SYSREG_ID_AA64ISAR2_EL1, SYSREG_ID_AA64MMFR0_EL1, SYSREG_ID_AA64MMFR1_EL1,
+    SYSREG_ID_AA64MMFR2_EL1, SYSREG_ID_AA64PFR0_EL1, SYSREG_ID_AA64PFR1_EL1,
+};
+pub use self::mmu::Aarch64GuestMmu;
+pub use self::mpam::MpamPartition;
+#[cfg(feature = "nested")]
+#[cfg_attr(docsrs, doc(cfg(feature = "nested")))]
+pub use self::nv2::{
+    VNCR_CNTVOFF_EL2, VNCR_ELR_EL1, VNCR_ESR_EL1, VNCR_HCR_EL2, VNCR_HSTR_EL2, VNCR_SCTLR_EL1,
+    VNCR_SPSR_EL1, VNCR_TPIDR_EL2, VNCR_VBAR_EL1, VNCR_VMPIDR_EL2, VNCR_VNCR_EL2, VNCR_VPIDR_EL2,
+    VNCR_VTCR_EL2, VNCR_VTTBR_EL2, VncrPage,
+};
+pub use self::pcpu::{Aarch64PerCpu, IrqClassifier, IrqTarget};
+#[cfg(feature = "vpmu")]
+#[cfg_attr(docsrs, doc(cfg(feature = "vpmu")))]
+pub use self::pmu::CycleCounterFuzz;
+pub use self::policy::{ExitPolicy, ExitVerdict, PolicyExit};
+pub use self::power_mmio::PowerMmioReg;
+pub use self::psci::{ResetStormLimit, SystemEvent};
+#[cfg(feature = "exit-ring")]
+#[cfg_attr(docsrs, doc(cfg(feature = "exit-ring")))]
+pub use self::ring::{
+    EXIT_RING_ENTRIES, ExitRing, ExitRingConsumer, ExitRingEntry, ExitRingProducer,
+};
+#[cfg(feature = "run-queue")]
+#[cfg_attr(docsrs, doc(cfg(feature = "run-queue")))]
+pub use self::runq::{RUN_QUEUE_CAPACITY, RunQueue, request_vcpu_switch};
+pub use self::smc::{SMCCC_OWNER_OEM, SMCCC_OWNER_SIP, SmcForwardPolicy, SmcccError};
+pub use self::stage2::Stage2MemAttr;
+pub use self::state::{
+    ResetStateDeviation, VCPU_SNAPSHOT_MAGIC, VCPU_STATE_DESCRIPTOR, VCPU_STATE_VERSION,
+    VCpuSnapshot, VCpuStateDescriptor, VCpuStateReg,
+};
+pub use self::stats::Aarch64VCpuStats;
+pub use self::symbol::{GuestSymbol, GuestSymbolizer};
+pub use self::sysreg::{
+    CodeMaintenancePolicy, ImpDefSysRegPolicy, SYSREG_DBGDTR_EL0, SYSREG_DBGDTRTX_EL0,
+    SYSREG_DC_CVAU, SYSREG_DC_ZVA, SYSREG_IC_IALLU, SYSREG_IC_IALLUIS, SYSREG_IC_IVAU,
+    SysRegEmulation, SysRegEntry, sysreg_addr,
+};
+#[cfg(feature = "test-guest")]
+#[cfg_attr(docsrs, doc(cfg(feature = "test-guest")))]
+pub use self::test_guest::{
+    TEST_GUEST_HVC_ECHO, TEST_GUEST_HVC_HELLO, TEST_GUEST_MMIO_ADDR, TEST_GUEST_MMIO_VALUE,
+    test_guest_image,
+};
+pub use self::tlb::TlbScope;
+#[cfg(feature = "tracing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
+pub use self::trace::{VCpuTraceBackend, VCpuTraceEvent, set_trace_backend};
+pub use self::trap_stack::{MAX_NESTED_TRAPS, nested_trap_depth};
 pub use self::vcpu::{Aarch64VCpu, Aarch64VCpuCreateConfig, Aarch64VCpuSetupConfig};
+pub use self::vendor_hyp::{
+    ARCEOS_HYP_UUID, VENDOR_HYP_FEATURE_FEATURES, VENDOR_HYP_FEATURE_HEARTBEAT,
+    VENDOR_HYP_FEATURE_HV_STATS, VENDOR_HYP_FEATURE_PV_LOCK,
+};
+#[cfg(feature = "vgic")]
+#[cfg_attr(docsrs, doc(cfg(feature = "vgic")))]
+pub use self::vgic::{IrqLatencyStats, LatencySamples, VirqState};
+pub use self::vmstate::Aarch64VmArchState;
+pub use self::watch::WatchedSysReg;
+pub use self::wfe::WfeSpinPolicy;
 
 /// context frame for aarch64
 pub type TrapFrame = context_frame::Aarch64ContextFrame;
@@ -0,0 +1,65 @@
+//! Tracking of the stage-1 MMU configuration of the guest.
+
+use crate::context_frame::Aarch64El1State;
+
+/// A mirror of the registers controlling the stage-1 translation of the guest, kept up to date
+/// by trapping their writes (`HCR_EL2.TVM`), see [`Aarch64VCpu::guest_mmu`].
+///
+/// Unlike the EL1 state saved at VM exits, every change made by the guest is observed, and
+/// counted in [`generation`](Self::generation).
+///
+/// [`Aarch64VCpu::guest_mmu`]: crate::Aarch64VCpu::guest_mmu
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Aarch64GuestMmu {
+    /// System Control Register (EL1), which holds the MMU enable (`M`) bit.
+    pub sctlr_el1: u64,
+    /// Translation Table Base Register 0 (EL1).
+    pub ttbr0_el1: u64,
+    /// Translation Table Base Register 1 (EL1).
+    pub ttbr1_el1: u64,
+    /// Translation Control Register (EL1).
+    pub tcr_el1: u64,
+    /// Memory Attribute Indirection Register (EL1).
+    pub mair_el1: u64,
+    /// The number of times the guest has changed any of the registers above.
+    pub generation: u64,
+}
+
+impl Aarch64GuestMmu {
+    /// Creates the mirror of the registers held in `state`.
+    pub fn new(state: &Aarch64El1State) -> Self {
+        let mut mmu = Self::default();
+        mmu.sync(state);
+        mmu.generation = 0;
+        mmu
+    }
+
+    /// Updates the mirror from `state`, bumping the generation if anything changed.
+    pub fn sync(&mut self, state: &Aarch64El1State) {
+        let (sctlr_el1, ttbr0_el1, ttbr1_el1, tcr_el1, mair_el1) = (
+            state.sctlr_el1,
+            state.ttbr0_el1,
+            state.ttbr1_el1,
+            state.tcr_el1,
+            state.mair_el1,
+        );
+        if (sctlr_el1, ttbr0_el1, ttbr1_el1, tcr_el1, mair_el1)
+            != (
+                self.sctlr_el1,
+                self.ttbr0_el1,
+                self.ttbr1_el1,
+                self.tcr_el1,
+                self.mair_el1,
+            )
+        {
+            *self = Self {
+                sctlr_el1,
+                ttbr0_el1,
+                ttbr1_el1,
+                tcr_el1,
+                mair_el1,
+                generation: self.generation.wrapping_add(1),
+            };
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! Containment of the guests on hosts implementing FEAT_MPAM (Memory System Resource
+//! Partitioning and Monitoring).
+//!
+//! The guest MPAM registers are trapped and emulated as RAZ/WI, so that the guest can't change
+//! the resource partitioning of the host. The traffic of the guest is tagged with the
+//! partition configured for its vCPU, or with the default partition (PARTID 0).
+
+use core::arch::asm;
+
+/// `MPAM2_EL2.TRAPMPAM1EL1`, bit [48], traps the accesses to `MPAM1_EL1`.
+const MPAM2_EL2_TRAPMPAM1EL1: u64 = 1 << 48;
+/// `MPAM2_EL2.TRAPMPAM0EL1`, bit [49], traps the accesses to `MPAM0_EL1`.
+const MPAM2_EL2_TRAPMPAM0EL1: u64 = 1 << 49;
+/// `MPAM2_EL2.TIDR`, bit [58], traps the accesses to `MPAMIDR_EL1`.
+const MPAM2_EL2_TIDR: u64 = 1 << 58;
+
+/// The MPAM partition the memory traffic of a guest is tagged with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MpamPartition {
+    /// The partition ID, for both the data and the instruction accesses.
+    pub partid: u16,
+    /// The performance monitoring group, for both the data and the instruction accesses.
+    pub pmg: u8,
+}
+
+impl MpamPartition {
+    /// The value of `MPAM0_EL1` and `MPAM1_EL1` selecting the partition.
+    const fn mpam_el1(self) -> u64 {
+        let (partid, pmg) = (self.partid as u64, self.pmg as u64);
+        (pmg << 40) | (pmg << 32) | (partid << 16) | partid
+    }
+}
+
+/// Traps the guest MPAM registers and tags the traffic of the guest with `partition`, keeping
+/// the partition of the host (EL2) itself.
+///
+/// # Safety
+///
+/// The host must implement FEAT_MPAM.
+pub unsafe fn load_guest_mpam(partition: MpamPartition) {
+    unsafe {
+        let mpam2_el2: u64;
+        asm!("mrs {}, S3_4_C10_C5_0", out(reg) mpam2_el2); // MPAM2_EL2
+        let traps = MPAM2_EL2_TRAPMPAM1EL1 | MPAM2_EL2_TRAPMPAM0EL1 | MPAM2_EL2_TIDR;
+        asm!("msr S3_4_C10_C5_0, {}", in(reg) mpam2_el2 | traps);
+        asm!("msr S3_0_C10_C5_0, {}", in(reg) partition.mpam_el1()); // MPAM1_EL1
+        asm!("msr S3_0_C10_C5_1, {}", in(reg) partition.mpam_el1()); // MPAM0_EL1
+    }
+}
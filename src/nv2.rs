@@ -0,0 +1,211 @@
+//! The memory-backed virtual EL2 registers of a guest hypervisor (FEAT_NV2), with the
+//! experimental `nested` feature, as a building block of a nested virtualization subsystem
+//! in the VMM.
+//!
+//! Once a [`VncrPage`] is attached with [`Aarch64VCpu::set_vncr_page`], the guest runs with
+//! `HCR_EL2.{NV, NV2}`: its hypervisor runs at EL1 but reads EL2 from `CurrentEL`, and most of
+//! its accesses to the EL2 registers, and to the EL1 ones through the `_EL12` aliases, are
+//! turned into loads and stores to the page, without trapping. The other EL2 registers are
+//! reported as [`AxVCpuExitReason::SysRegRead`] and [`AxVCpuExitReason::SysRegWrite`], and
+//! the trapped `ERET`s as unhandled exits, for the VMM to emulate. The EL2 timers are not
+//! emulated, their accesses are UNDEFINED. `HCR_EL2.NV1` is left cleared, only guest
+//! hypervisors running with `HCR_EL2.E2H` (VHE) are supported.
+//!
+//! The VMM supplies the values of the registers with [`VncrPage::write`] and observes the
+//! ones of the guest with [`VncrPage::read`], while the vCPU is stopped. The registers
+//! written by the guest are tracked at each exit against a shadow copy of the page, see
+//! [`VncrPage::dirty`].
+//!
+//! [`Aarch64VCpu::set_vncr_page`]: crate::Aarch64VCpu::set_vncr_page
+//! [`AxVCpuExitReason::SysRegRead`]: axvcpu::AxVCpuExitReason::SysRegRead
+//! [`AxVCpuExitReason::SysRegWrite`]: axvcpu::AxVCpuExitReason::SysRegWrite
+
+/// The number of registers of the page.
+const VNCR_REGS: usize = 0x1000 / 8;
+
+/// The offset of `VTTBR_EL2` in the page.
+pub const VNCR_VTTBR_EL2: usize = 0x020;
+/// The offset of `VTCR_EL2` in the page.
+pub const VNCR_VTCR_EL2: usize = 0x040;
+/// The offset of `VMPIDR_EL2` in the page.
+pub const VNCR_VMPIDR_EL2: usize = 0x050;
+/// The offset of `CNTVOFF_EL2` in the page.
+pub const VNCR_CNTVOFF_EL2: usize = 0x060;
+/// The offset of `HCR_EL2` in the page.
+pub const VNCR_HCR_EL2: usize = 0x078;
+/// The offset of `HSTR_EL2` in the page.
+pub const VNCR_HSTR_EL2: usize = 0x080;
+/// The offset of `VPIDR_EL2` in the page.
+pub const VNCR_VPIDR_EL2: usize = 0x088;
+/// The offset of `TPIDR_EL2` in the page.
+pub const VNCR_TPIDR_EL2: usize = 0x090;
+/// The offset of `VNCR_EL2` in the page.
+pub const VNCR_VNCR_EL2: usize = 0x0b0;
+/// The offset of `SCTLR_EL1` (`SCTLR_EL12`) in the page.
+pub const VNCR_SCTLR_EL1: usize = 0x110;
+/// The offset of `ESR_EL1` (`ESR_EL12`) in the page.
+pub const VNCR_ESR_EL1: usize = 0x138;
+/// The offset of `SPSR_EL1` (`SPSR_EL12`) in the page.
+pub const VNCR_SPSR_EL1: usize = 0x160;
+/// The offset of `ELR_EL1` (`ELR_EL12`) in the page.
+pub const VNCR_ELR_EL1: usize = 0x230;
+/// The offset of `VBAR_EL1` (`VBAR_EL12`) in the page.
+pub const VNCR_VBAR_EL1: usize = 0x250;
+
+/// The page the virtual EL2 registers of a guest hypervisor are stored in, pointed to by
+/// `VNCR_EL2`, with the state tracking the writes of the guest.
+///
+/// The registers are indexed by their byte offset in the page, see the `VNCR_*` constants.
+#[repr(C, align(4096))]
+pub struct VncrPage {
+    /// The page accessed by the guest, first so that it is the one `VNCR_EL2` points to.
+    regs: [u64; VNCR_REGS],
+    /// The values of `regs` at the last exit, or as last written by the VMM.
+    shadow: [u64; VNCR_REGS],
+    /// The registers written by the guest since the last [`VncrPage::clear_dirty`], one bit
+    /// per register.
+    dirty: [u64; VNCR_REGS / 64],
+}
+
+impl VncrPage {
+    /// Creates a page with all the registers cleared.
+    pub const fn new() -> Self {
+        Self {
+            regs: [0; VNCR_REGS],
+            shadow: [0; VNCR_REGS],
+            dirty: [0; VNCR_REGS / 64],
+        }
+    }
+
+    /// Returns the value of the register at `offset`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` is not 8-byte aligned or out of the page.
+    pub fn read(&self, offset: usize) -> u64 {
+        let n = reg_index(offset);
+        // Written by the guest behind the back of the compiler.
+        unsafe { core::ptr::read_volatile(&self.regs[n]) }
+    }
+
+    /// Sets the value of the register at `offset`, which doesn't count as a write of the
+    /// guest.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` is not 8-byte aligned or out of the page.
+    pub fn write(&mut self, offset: usize, value: u64) {
+        let n = reg_index(offset);
+        unsafe { core::ptr::write_volatile(&mut self.regs[n], value) };
+        self.shadow[n] = value;
+    }
+
+    /// Returns the offsets of the registers the guest changed since the last
+    /// [`VncrPage::clear_dirty`], as observed at the exits.
+    ///
+    /// A register the guest changed then restored between two exits is not reported.
+    pub fn dirty(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..VNCR_REGS)
+            .filter(|n| self.dirty[n / 64] & (1 << (n % 64)) != 0)
+            .map(|n| n * 8)
+    }
+
+    /// Clears the record of the registers changed by the guest.
+    pub fn clear_dirty(&mut self) {
+        self.dirty = [0; VNCR_REGS / 64];
+    }
+
+    /// Records the registers changed by the guest since the last entry, after an exit.
+    pub(crate) fn track_dirty(&mut self) {
+        for n in 0..VNCR_REGS {
+            let value = unsafe { core::ptr::read_volatile(&self.regs[n]) };
+            if value != self.shadow[n] {
+                self.shadow[n] = value;
+                self.dirty[n / 64] |= 1 << (n % 64);
+            }
+        }
+    }
+
+    /// Returns the address `VNCR_EL2` is programmed with.
+    pub(crate) fn base(&self) -> u64 {
+        self.regs.as_ptr() as u64
+    }
+}
+
+impl Default for VncrPage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the index of the register at `offset`.
+fn reg_index(offset: usize) -> usize {
+    assert!(
+        offset % 8 == 0 && offset < VNCR_REGS * 8,
+        "invalid VNCR offset {offset:#x}"
+    );
+    offset / 8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stores `value` at `offset` like the guest does, bypassing the shadow.
+    fn guest_write(page: &mut VncrPage, offset: usize, value: u64) {
+        page.regs[reg_index(offset)] = value;
+    }
+
+    #[test]
+    fn guest_writes_are_tracked() {
+        let mut page = VncrPage::new();
+        guest_write(&mut page, VNCR_ELR_EL1, 0x8000);
+        guest_write(&mut page, VNCR_HCR_EL2, 1);
+        page.track_dirty();
+        // In offset order, across the words of the bitmap.
+        assert!(page.dirty().eq([VNCR_HCR_EL2, VNCR_ELR_EL1]));
+        assert_eq!(page.read(VNCR_ELR_EL1), 0x8000);
+
+        // Still dirty at the next exits, until cleared.
+        page.track_dirty();
+        assert_eq!(page.dirty().count(), 2);
+        page.clear_dirty();
+        assert_eq!(page.dirty().count(), 0);
+    }
+
+    #[test]
+    fn vmm_writes_are_not_tracked() {
+        let mut page = VncrPage::new();
+        page.write(VNCR_VTTBR_EL2, 0x4000_0000);
+        page.track_dirty();
+        assert_eq!(page.dirty().count(), 0);
+        assert_eq!(page.read(VNCR_VTTBR_EL2), 0x4000_0000);
+
+        // The guest writing the value of the VMM back doesn't change it either.
+        guest_write(&mut page, VNCR_VTTBR_EL2, 0x4000_0000);
+        page.track_dirty();
+        assert_eq!(page.dirty().count(), 0);
+    }
+
+    #[test]
+    fn restored_registers_are_not_reported() {
+        let mut page = VncrPage::new();
+        page.write(VNCR_SCTLR_EL1, 0x30d0_0800);
+        guest_write(&mut page, VNCR_SCTLR_EL1, 0x30d0_0801);
+        guest_write(&mut page, VNCR_SCTLR_EL1, 0x30d0_0800);
+        page.track_dirty();
+        assert_eq!(page.dirty().count(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn misaligned_offset() {
+        VncrPage::new().read(VNCR_HCR_EL2 + 4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn offset_out_of_the_page() {
+        VncrPage::new().write(0x1000, 0);
+    }
+}
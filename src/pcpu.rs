@@ -1,10 +1,13 @@
 use core::{cell::OnceCell, marker::PhantomData};
 
 use aarch64_cpu::registers::*;
-use axerrno::AxResult;
+use axerrno::{AxResult, ax_err};
 use axvcpu::{AxArchPerCpu, AxVCpuHal};
 use tock_registers::interfaces::ReadWriteable;
 
+use crate::errata::{clear_host_errata, detect_host_errata};
+use crate::vmid::check_vmid_cpu;
+
 /// Per-CPU data. A pointer to this struct is loaded into TP when a CPU starts. This structure
 #[repr(C)]
 #[repr(align(4096))]
@@ -14,9 +17,29 @@ pub struct Aarch64PerCpu<H: AxVCpuHal> {
     _phantom: PhantomData<H>,
 }
 
+/// The `cpu_id` of the [`Aarch64PerCpu`] of this CPU.
+#[percpu::def_percpu]
+static CPU_ID: usize = 0;
+
 #[percpu::def_percpu]
 static ORI_EXCEPTION_VECTOR_BASE: usize = 0;
 
+/// `HCR_EL2` of the host before `hardware_enable`, restored by `hardware_disable`.
+#[percpu::def_percpu]
+static ORI_HCR_EL2: u64 = 0;
+
+/// The address of the vCPU bound to this CPU, 0 if none, see [`Aarch64PerCpu::offline`].
+#[percpu::def_percpu]
+static RESIDENT_VCPU: usize = 0;
+
+/// The host address of the PV `preempted` flag of the vCPU bound to this CPU, 0 if none.
+#[percpu::def_percpu]
+static RESIDENT_PV_PREEMPTED: usize = 0;
+
+/// The address of the last vCPU entered on this CPU, 0 if none.
+#[percpu::def_percpu]
+static LAST_ENTERED_VCPU: usize = 0;
+
 /// IRQ handler registered by underlying host OS during per-cpu initialization,
 /// for dispatching IRQs to the host OS.
 ///
@@ -24,12 +47,136 @@ static ORI_EXCEPTION_VECTOR_BASE: usize = 0;
 #[percpu::def_percpu]
 pub static IRQ_HANDLER: OnceCell<&(dyn Fn() + Send + Sync)> = OnceCell::new();
 
+/// The target of a physical IRQ taken while a guest runs, see [`IrqClassifier`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqTarget {
+    /// The IRQ is the host's, e.g. its timer tick or an IPI, and the classifier has handled
+    /// and completed it: the guest is entered again right away.
+    Host,
+    /// The IRQ is reported to the VMM as an
+    /// [`AxVCpuExitReason::ExternalInterrupt`](axvcpu::AxVCpuExitReason::ExternalInterrupt),
+    /// e.g. to be injected into the guest, or for a host IRQ needing `run()` to return.
+    Guest,
+}
+
+/// Classifies the physical IRQs taken while the guests run on a CPU, registered with
+/// [`Aarch64PerCpu::set_irq_classifier`].
+///
+/// Without one, all of them are reported as
+/// [`AxVCpuExitReason::ExternalInterrupt`](axvcpu::AxVCpuExitReason::ExternalInterrupt).
+pub trait IrqClassifier: Send + Sync {
+    /// Returns the target of the IRQ `vector`, acknowledged by `irq_fetch` of the HAL, and
+    /// handles and completes (EOIs) it if it is the host's.
+    ///
+    /// Called in the exit path with all the exceptions masked and the EL1 registers of the
+    /// guest loaded: it must neither reschedule nor touch the vCPU.
+    fn classify(&self, vector: usize) -> IrqTarget;
+}
+
+/// The IRQ classifier registered for this CPU, see [`Aarch64PerCpu::set_irq_classifier`].
+#[percpu::def_percpu]
+static IRQ_CLASSIFIER: Option<&'static dyn IrqClassifier> = None;
+
 unsafe extern "C" {
     fn exception_vector_base_vcpu();
 }
 
+/// Returns the `cpu_id` of the current CPU.
+pub(crate) fn current_cpu_id() -> usize {
+    unsafe { CPU_ID.read_current_raw() }
+}
+
+/// Records that the vCPU at `vcpu` is bound to the current CPU, with the host address of its PV
+/// `preempted` flag if it has one.
+pub(crate) fn set_resident_vcpu(vcpu: usize, pv_preempted: Option<*mut u64>) {
+    unsafe {
+        RESIDENT_VCPU.write_current_raw(vcpu);
+        RESIDENT_PV_PREEMPTED.write_current_raw(pv_preempted.map_or(0, |flag| flag as usize));
+    }
+}
+
+/// Returns the IRQ classifier registered for the current CPU, if any.
+pub(crate) fn irq_classifier() -> Option<&'static dyn IrqClassifier> {
+    unsafe { *IRQ_CLASSIFIER.current_ref_raw() }
+}
+
+/// Records that the vCPU at `vcpu` is entered on the current CPU, returning the address of the
+/// previously entered one, 0 if none.
+pub(crate) fn swap_last_entered_vcpu(vcpu: usize) -> usize {
+    unsafe {
+        let last = LAST_ENTERED_VCPU.read_current_raw();
+        LAST_ENTERED_VCPU.write_current_raw(vcpu);
+        last
+    }
+}
+
+/// Returns the address of the last vCPU entered on the current CPU, 0 if none.
+pub(crate) fn last_entered_vcpu() -> usize {
+    unsafe { LAST_ENTERED_VCPU.read_current_raw() }
+}
+
+/// Records that the vCPU at `vcpu` is unbound from the current CPU, if it is the bound one.
+pub(crate) fn clear_resident_vcpu(vcpu: usize) {
+    if unsafe { RESIDENT_VCPU.read_current_raw() } == vcpu {
+        set_resident_vcpu(0, None);
+    }
+}
+
+impl<H: AxVCpuHal> Aarch64PerCpu<H> {
+    /// Registers the classifier telling the IRQs of the host from the ones of the guests
+    /// among the IRQs taken while the vCPUs run on the current CPU, or removes it.
+    ///
+    /// Must be called on the CPU of this per-CPU data, after `new()` and outside of the `run()`
+    /// of a vCPU.
+    pub fn set_irq_classifier(&mut self, classifier: Option<&'static dyn IrqClassifier>) {
+        unsafe { *IRQ_CLASSIFIER.current_ref_mut_raw() = classifier };
+    }
+
+    /// Tears down the virtualization state of the current CPU, which the host is about to
+    /// hot-remove.
+    ///
+    /// The vCPU still bound to this CPU, if any, is unbound: its PV `preempted` flag is set, and
+    /// it can be bound to another CPU and run there, its guest state being saved at each exit.
+    /// Virtualization is then disabled like `hardware_disable` does, and the per-CPU state of the
+    /// crate tied to the vCPUs, i.e. the bound vCPU, the pending run queue switch and the errata
+    /// workarounds, is dropped. `hardware_enable` detects the errata again if the CPU comes back
+    /// online. The IRQ handler and classifier stay registered, they are only called while a
+    /// vCPU runs, i.e. once virtualization is enabled again.
+    ///
+    /// Must be called on the CPU going offline, outside of the `run()` of a vCPU and with
+    /// preemption disabled. Returns `BadState` if virtualization is not enabled on it.
+    pub fn offline(&mut self) -> AxResult {
+        if !self.is_enabled() {
+            return ax_err!(BadState, "virtualization is not enabled on this CPU");
+        }
+        let vcpu = unsafe { RESIDENT_VCPU.read_current_raw() };
+        if vcpu != 0 {
+            let flag = unsafe { RESIDENT_PV_PREEMPTED.read_current_raw() } as *mut u64;
+            if !flag.is_null() {
+                // The guest only ever reads the flag, see `Aarch64VCpu::unbind`.
+                unsafe { flag.write_volatile(1u64.to_le()) };
+            }
+            info!(
+                "CPU {} going offline, unbinding vCPU @{vcpu:#x}",
+                self.cpu_id
+            );
+        }
+        // The host pointer to the flag is not kept across the offline period.
+        set_resident_vcpu(0, None);
+
+        self.hardware_disable()?;
+        VTTBR_EL2.set(0);
+        #[cfg(feature = "run-queue")]
+        let _ = crate::runq::take_switch_target();
+        clear_host_errata();
+        Ok(())
+    }
+}
+
 impl<H: AxVCpuHal> AxArchPerCpu for Aarch64PerCpu<H> {
     fn new(cpu_id: usize) -> AxResult<Self> {
+        check_vmid_cpu(cpu_id)?;
+        unsafe { CPU_ID.write_current_raw(cpu_id) };
         // Register IRQ handler for this CPU.
         let _ = unsafe { IRQ_HANDLER.current_ref_mut_raw() }
             .set(&|| H::irq_hanlder())
@@ -42,19 +189,27 @@ impl<H: AxVCpuHal> AxArchPerCpu for Aarch64PerCpu<H> {
     }
 
     fn is_enabled(&self) -> bool {
-        HCR_EL2.is_set(HCR_EL2::VM)
+        // Not `HCR_EL2.VM`, which is cleared while running guests with stage-2 disabled.
+        VBAR_EL2.get() == exception_vector_base_vcpu as usize as u64
     }
 
     fn hardware_enable(&mut self) -> AxResult {
         // First we save origin `exception_vector_base`.
         // Safety:
         // Todo: take care of `preemption`
-        unsafe { ORI_EXCEPTION_VECTOR_BASE.write_current_raw(VBAR_EL2.get() as usize) }
+        unsafe {
+            ORI_EXCEPTION_VECTOR_BASE.write_current_raw(VBAR_EL2.get() as usize);
+            ORI_HCR_EL2.write_current_raw(HCR_EL2.get());
+        }
 
         // Set current `VBAR_EL2` to `exception_vector_base_vcpu`
         // defined in this crate.
         VBAR_EL2.set(exception_vector_base_vcpu as usize as _);
 
+        // No vCPU is bound yet, whatever the CPU ran before going offline.
+        set_resident_vcpu(0, None);
+        detect_host_errata();
+
         HCR_EL2.modify(
             HCR_EL2::VM::Enable
                 + HCR_EL2::RW::EL1IsAarch64
@@ -85,7 +240,8 @@ impl<H: AxVCpuHal> AxArchPerCpu for Aarch64PerCpu<H> {
         // Todo: take care of `preemption`
         VBAR_EL2.set(unsafe { ORI_EXCEPTION_VECTOR_BASE.read_current_raw() } as _);
 
-        HCR_EL2.set(HCR_EL2::VM::Disable.into());
+        // The host value has `HCR_EL2.VM` cleared, as the host doesn't run with stage-2.
+        HCR_EL2.set(unsafe { ORI_HCR_EL2.read_current_raw() });
         Ok(())
     }
 }
@@ -0,0 +1,224 @@
+//! A lightweight virtual PMU, only exposing the cycle counter.
+//!
+//! The PMU registers are trapped (`MDCR_EL2.TPM`) and emulated: the guest sees a PMUv3 with
+//! no event counter (`PMCR_EL0.N` = 0) and its own cycle counter, which is carried by the
+//! hardware `PMCCNTR_EL0` while the vCPU runs and saved while it doesn't. The cycles spent in
+//! EL2 are never counted, whatever the guest filter.
+//!
+//! As the cycle counter provides a high resolution timer to cross-VM timing side channels on
+//! shared cores, its values can also be quantized and fuzzed, see [`CycleCounterFuzz`].
+
+use core::arch::asm;
+
+use aarch64_cpu::registers::{CNTPCT_EL0, Readable};
+
+use axaddrspace::device::SysRegAddr;
+
+use crate::sysreg::{
+    SYSREG_PMCCFILTR_EL0, SYSREG_PMCCNTR_EL0, SYSREG_PMCNTENCLR_EL0, SYSREG_PMCNTENSET_EL0,
+    SYSREG_PMCR_EL0, SYSREG_PMOVSCLR_EL0, SYSREG_PMOVSSET_EL0, SYSREG_PMSELR_EL0,
+    SYSREG_PMUSERENR_EL0, SYSREG_PMXEVTYPER_EL0, is_pmu_sysreg,
+};
+
+/// `PMCR_EL0.E`, bit [0], enables the counters.
+const PMCR_EL0_E: u64 = 1 << 0;
+/// `PMCR_EL0.C`, bit [2], resets the cycle counter when written with 1.
+const PMCR_EL0_C: u64 = 1 << 2;
+/// `PMCR_EL0.D`, bit [3], makes the cycle counter count every 64 cycles.
+const PMCR_EL0_D: u64 = 1 << 3;
+/// `PMCR_EL0.LC`, bit [6], makes the cycle counter overflow at 64 bits.
+const PMCR_EL0_LC: u64 = 1 << 6;
+/// The bits of `PMCR_EL0` the guest can set.
+const PMCR_EL0_MASK: u64 = PMCR_EL0_E | PMCR_EL0_D | PMCR_EL0_LC;
+
+/// The cycle counter bit, [31], of `PMCNTEN{SET,CLR}_EL0` and `PMOVS{SET,CLR}_EL0`.
+const PMU_CYCLE_COUNTER: u64 = 1 << 31;
+/// `PMCCFILTR_EL0.P` and `U`, bits [31:30], the only filter bits of the guest, stopping the
+/// counting at EL1 and EL0.
+const PMCCFILTR_EL0_MASK: u64 = 0b11 << 30;
+/// `PMSELR_EL0.SEL`, bits [4:0], the value 31 selects the cycle counter.
+const PMSELR_EL0_SEL_MASK: u64 = 0b11111;
+const PMSELR_EL0_SEL_CYCLE: u64 = 31;
+/// `PMUSERENR_EL0.{ER,CR,SW,EN}`, bits [3:0].
+const PMUSERENR_EL0_MASK: u64 = 0b1111;
+/// `PMUSERENR_EL0.EN`, bit [0], enables all the EL0 accesses.
+const PMUSERENR_EL0_EN: u64 = 1 << 0;
+/// `PMUSERENR_EL0.CR`, bit [2], enables the EL0 reads of the cycle counter.
+const PMUSERENR_EL0_CR: u64 = 1 << 2;
+/// `PMUSERENR_EL0.ER`, bit [3], enables the EL0 reads of the event counters and accesses to
+/// `PMSELR_EL0`.
+const PMUSERENR_EL0_ER: u64 = 1 << 3;
+
+/// The degradation of the cycle counter values read by the guest, mitigating the timing side
+/// channels, see
+/// [`Aarch64VCpuSetupConfig::pmu_cycle_fuzz`](crate::Aarch64VCpuSetupConfig::pmu_cycle_fuzz).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CycleCounterFuzz {
+    /// The granule the values are rounded down to, in cycles, rounded up to a power of two.
+    pub quantum: u64,
+    /// Whether a random offset within the granule is added to the values, which stay
+    /// monotonic.
+    pub jitter: bool,
+}
+
+/// The cycle counter only virtual PMU of a vCPU.
+///
+/// The overflow interrupt is not implemented (`PMINTENSET_EL1` is RAZ/WI), the overflow
+/// flag is. The `PMUSERENR_EL0` of the guest is loaded in the hardware while it runs, which
+/// then makes the EL0 accesses it doesn't enable trap to EL1 of the guest, and checked again
+/// on the trapped EL0 accesses, see [`VirtPmu::el0_access_allowed`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VirtPmu {
+    fuzz: Option<CycleCounterFuzz>,
+    /// The state of the xorshift generator of the jitter, never 0.
+    rng: u64,
+    /// The last fuzzed value read by the guest, which the next ones can't be below.
+    last_read: u64,
+    pmcr_el0: u64,
+    pmccntr_el0: u64,
+    pmccfiltr_el0: u64,
+    pmselr_el0: u64,
+    pmuserenr_el0: u64,
+    /// The `PMUSERENR_EL0` of the host, while the guest one is loaded.
+    host_pmuserenr_el0: u64,
+    /// `PMCNTENSET_EL0.C`.
+    enabled: bool,
+    /// `PMOVSSET_EL0.C`.
+    overflow: bool,
+}
+
+impl VirtPmu {
+    /// Creates the virtual PMU, the cycle counter values read by the guest are degraded by
+    /// `fuzz`, if any.
+    pub fn new(fuzz: Option<CycleCounterFuzz>) -> Self {
+        Self {
+            fuzz: fuzz.map(|fuzz| CycleCounterFuzz {
+                quantum: fuzz.quantum.max(1).next_power_of_two(),
+                ..fuzz
+            }),
+            rng: CNTPCT_EL0.get() | 1,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the cycle counter value read by the guest.
+    fn read_cycle_counter(&mut self) -> u64 {
+        let Some(fuzz) = self.fuzz else {
+            return self.pmccntr_el0;
+        };
+        let mut value = self.pmccntr_el0 & !(fuzz.quantum - 1);
+        if fuzz.jitter {
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+            value += self.rng & (fuzz.quantum - 1);
+        }
+        self.last_read = self.last_read.max(value);
+        self.last_read
+    }
+
+    /// Loads the cycle counter of the vCPU in the hardware, before entering the guest.
+    ///
+    /// # Safety
+    ///
+    /// The host must implement PMUv3, and must not use the cycle counter.
+    pub unsafe fn load(&mut self) {
+        unsafe {
+            asm!("mrs {}, PMUSERENR_EL0", out(reg) self.host_pmuserenr_el0);
+            asm!("msr PMUSERENR_EL0, {}", in(reg) self.pmuserenr_el0);
+            asm!("msr PMCNTENCLR_EL0, {}", in(reg) PMU_CYCLE_COUNTER);
+            asm!("msr PMOVSCLR_EL0, {}", in(reg) PMU_CYCLE_COUNTER);
+            asm!("msr PMCCFILTR_EL0, {}", in(reg) self.pmccfiltr_el0 & PMCCFILTR_EL0_MASK);
+            asm!("msr PMCCNTR_EL0, {}", in(reg) self.pmccntr_el0);
+            asm!("msr PMCR_EL0, {}", in(reg) self.pmcr_el0);
+            if self.enabled {
+                asm!("msr PMCNTENSET_EL0, {}", in(reg) PMU_CYCLE_COUNTER);
+            }
+        }
+    }
+
+    /// Saves the cycle counter of the vCPU and stops it, after exiting the guest.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`VirtPmu::load`].
+    pub unsafe fn save(&mut self) {
+        unsafe {
+            asm!("msr PMCNTENCLR_EL0, {}", in(reg) PMU_CYCLE_COUNTER);
+            asm!("isb");
+            asm!("mrs {}, PMCCNTR_EL0", out(reg) self.pmccntr_el0);
+            let pmovsset_el0: u64;
+            asm!("mrs {}, PMOVSSET_EL0", out(reg) pmovsset_el0);
+            self.overflow |= pmovsset_el0 & PMU_CYCLE_COUNTER != 0;
+            // The guest one is only written through the emulation, it is not read back.
+            asm!("msr PMUSERENR_EL0, {}", in(reg) self.host_pmuserenr_el0);
+        }
+    }
+
+    /// Returns whether the guest `PMUSERENR_EL0` enables the EL0 read, or `write`, of `addr`.
+    ///
+    /// The EL0 accesses it doesn't enable are UNDEFINED, the guest must take an Undefined
+    /// Instruction exception instead of the access being emulated.
+    pub fn el0_access_allowed(&self, addr: SysRegAddr, write: bool) -> bool {
+        if self.pmuserenr_el0 & PMUSERENR_EL0_EN != 0 {
+            return addr != SYSREG_PMUSERENR_EL0 || !write;
+        }
+        match (addr, write) {
+            (SYSREG_PMUSERENR_EL0, false) => true,
+            (SYSREG_PMCCNTR_EL0, false) => self.pmuserenr_el0 & PMUSERENR_EL0_CR != 0,
+            (SYSREG_PMSELR_EL0, _) => self.pmuserenr_el0 & PMUSERENR_EL0_ER != 0,
+            _ => false,
+        }
+    }
+
+    /// Emulates a read of a PMU register, the ones of the event counters read as zero.
+    ///
+    /// Returns `None` if `addr` is not a PMU register.
+    pub fn read(&mut self, addr: SysRegAddr) -> Option<u64> {
+        let cycle_counter = |set: bool| if set { PMU_CYCLE_COUNTER } else { 0 };
+        Some(match addr {
+            SYSREG_PMCR_EL0 => self.pmcr_el0,
+            SYSREG_PMCCNTR_EL0 => self.read_cycle_counter(),
+            SYSREG_PMCCFILTR_EL0 => self.pmccfiltr_el0,
+            SYSREG_PMXEVTYPER_EL0 if self.pmselr_el0 == PMSELR_EL0_SEL_CYCLE => self.pmccfiltr_el0,
+            SYSREG_PMSELR_EL0 => self.pmselr_el0,
+            SYSREG_PMUSERENR_EL0 => self.pmuserenr_el0,
+            SYSREG_PMCNTENSET_EL0 | SYSREG_PMCNTENCLR_EL0 => cycle_counter(self.enabled),
+            SYSREG_PMOVSSET_EL0 | SYSREG_PMOVSCLR_EL0 => cycle_counter(self.overflow),
+            _ if is_pmu_sysreg(addr) => 0,
+            _ => return None,
+        })
+    }
+
+    /// Emulates a write to a PMU register, the ones of the event counters ignore writes.
+    ///
+    /// Returns `false` if `addr` is not a PMU register.
+    pub fn write(&mut self, addr: SysRegAddr, value: u64) -> bool {
+        let cycle_counter = value & PMU_CYCLE_COUNTER != 0;
+        match addr {
+            SYSREG_PMCR_EL0 => {
+                self.pmcr_el0 = value & PMCR_EL0_MASK;
+                if value & PMCR_EL0_C != 0 {
+                    self.pmccntr_el0 = 0;
+                    self.last_read = 0;
+                }
+            }
+            SYSREG_PMCCNTR_EL0 => {
+                self.pmccntr_el0 = value;
+                self.last_read = 0;
+            }
+            SYSREG_PMCCFILTR_EL0 => self.pmccfiltr_el0 = value & PMCCFILTR_EL0_MASK,
+            SYSREG_PMXEVTYPER_EL0 if self.pmselr_el0 == PMSELR_EL0_SEL_CYCLE => {
+                self.pmccfiltr_el0 = value & PMCCFILTR_EL0_MASK
+            }
+            SYSREG_PMSELR_EL0 => self.pmselr_el0 = value & PMSELR_EL0_SEL_MASK,
+            SYSREG_PMUSERENR_EL0 => self.pmuserenr_el0 = value & PMUSERENR_EL0_MASK,
+            SYSREG_PMCNTENSET_EL0 => self.enabled |= cycle_counter,
+            SYSREG_PMCNTENCLR_EL0 => self.enabled &= !cycle_counter,
+            SYSREG_PMOVSSET_EL0 => self.overflow |= cycle_counter,
+            SYSREG_PMOVSCLR_EL0 => self.overflow &= !cycle_counter,
+            _ => return is_pmu_sysreg(addr),
+        }
+        true
+    }
+}
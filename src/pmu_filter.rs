@@ -0,0 +1,267 @@
+//! The host PMU left to the guest, with its counters kept from counting at EL2.
+//!
+//! The counters the guest programs count the hypervisor execution if their `NSH` filter bit is
+//! set, unless `MDCR_EL2.HPMD` (FEAT_PMUv3p1) stops the event counters and `MDCR_EL2.HCCD`
+//! (FEAT_PMUv3p5) the cycle counter at EL2. On the PMUv3 hosts lacking either, the PMU
+//! registers are trapped (`MDCR_EL2.TPM`) and accessed on behalf of the guest by
+//! [`FilteredPmu`], which clears the EL2 filter bits of the event types and of the cycle counter
+//! filter the guest writes.
+//!
+//! The accesses are performed at EL2, which also sees the event counters reserved to the host
+//! (`MDCR_EL2.HPMN` and above), so they are emulated as EL1 sees them: the host counters are
+//! RAZ/WI, also in the counter bitmaps, and `PMCR_EL0.N` reads as `HPMN`.
+
+use core::arch::asm;
+
+use axaddrspace::device::SysRegAddr;
+
+use crate::sysreg::sysreg_encoding;
+
+/// `PMEVTYPER<n>_EL0.NSH` and `SH`, bits [27] and [24], count at Non-secure and Secure EL2.
+/// `PMCCFILTR_EL0` has the same layout.
+const PMEVTYPER_EL2: u64 = (1 << 27) | (1 << 24);
+/// `PMCR_EL0.P`, bit [1], resets the event counters when written with 1.
+const PMCR_EL0_P: u64 = 1 << 1;
+/// `PMCR_EL0.N`, bits [15:11], the number of event counters.
+const PMCR_EL0_N_SHIFT: u32 = 11;
+const PMCR_EL0_N_MASK: u64 = 0b11111 << PMCR_EL0_N_SHIFT;
+/// `PMSELR_EL0.SEL`, bits [4:0].
+const PMSELR_EL0_SEL_MASK: u64 = 0b11111;
+/// The `PMSELR_EL0.SEL` value of the cycle counter, and its bit in the counter bitmaps.
+const CYCLE_COUNTER: u64 = 31;
+
+/// The PMU registers, decoded from their encoding.
+#[derive(Clone, Copy)]
+enum PmuReg {
+    Pmcr,
+    CntEnSet,
+    CntEnClr,
+    OvsClr,
+    SwInc,
+    Selr,
+    Ceid0,
+    Ceid1,
+    Ccntr,
+    XevTyper,
+    XevCntr,
+    UserEnr,
+    OvsSet,
+    IntEnSet,
+    IntEnClr,
+    Mmir,
+    EvCntr(u64),
+    EvTyper(u64),
+}
+
+impl PmuReg {
+    fn decode(addr: SysRegAddr) -> Option<Self> {
+        Some(match sysreg_encoding(addr) {
+            (3, 3, 9, 12, 0) => Self::Pmcr,
+            (3, 3, 9, 12, 1) => Self::CntEnSet,
+            (3, 3, 9, 12, 2) => Self::CntEnClr,
+            (3, 3, 9, 12, 3) => Self::OvsClr,
+            (3, 3, 9, 12, 4) => Self::SwInc,
+            (3, 3, 9, 12, 5) => Self::Selr,
+            (3, 3, 9, 12, 6) => Self::Ceid0,
+            (3, 3, 9, 12, 7) => Self::Ceid1,
+            (3, 3, 9, 13, 0) => Self::Ccntr,
+            (3, 3, 9, 13, 1) => Self::XevTyper,
+            (3, 3, 9, 13, 2) => Self::XevCntr,
+            (3, 3, 9, 14, 0) => Self::UserEnr,
+            (3, 3, 9, 14, 3) => Self::OvsSet,
+            (3, 0, 9, 14, 1) => Self::IntEnSet,
+            (3, 0, 9, 14, 2) => Self::IntEnClr,
+            (3, 0, 9, 14, 6) => Self::Mmir,
+            // `n` is CRm[1:0]:op2, 31 being `PMCCFILTR_EL0` for the types.
+            (3, 3, 14, crm @ 8..=11, op2) => Self::EvCntr((((crm & 0b11) << 3) | op2) as u64),
+            (3, 3, 14, crm @ 12..=15, op2) => Self::EvTyper((((crm & 0b11) << 3) | op2) as u64),
+            _ => return None,
+        })
+    }
+}
+
+/// Runs `f` with the counter `n` selected in `PMSELR_EL0`, which is then restored.
+unsafe fn with_selected<T>(n: u64, f: impl FnOnce() -> T) -> T {
+    let pmselr_el0: u64;
+    unsafe {
+        asm!("mrs {}, PMSELR_EL0", out(reg) pmselr_el0);
+        asm!("msr PMSELR_EL0, {}", "isb", in(reg) n);
+    }
+    let value = f();
+    unsafe { asm!("msr PMSELR_EL0, {}", in(reg) pmselr_el0) };
+    value
+}
+
+/// The host PMU as seen by the guest EL1, see the [module documentation](self).
+#[derive(Clone, Copy, Debug)]
+pub struct FilteredPmu {
+    /// `MDCR_EL2.HPMN`, the number of event counters of the guest.
+    hpmn: u64,
+}
+
+impl FilteredPmu {
+    /// Creates the PMU of a guest owning the event counters below `MDCR_EL2.HPMN` of
+    /// `mdcr_el2`.
+    pub const fn new(mdcr_el2: u64) -> Self {
+        Self {
+            hpmn: mdcr_el2 & PMSELR_EL0_SEL_MASK,
+        }
+    }
+
+    /// Returns the bits of the counters of the guest in the counter bitmaps.
+    fn counters(&self) -> u64 {
+        ((1 << self.hpmn) - 1) | (1 << CYCLE_COUNTER)
+    }
+
+    /// Returns whether the event counter `n` belongs to the guest.
+    fn is_guest_counter(&self, n: u64) -> bool {
+        n < self.hpmn
+    }
+
+    /// Returns the event counter selected by the guest, `CYCLE_COUNTER` included, if it may
+    /// access it.
+    unsafe fn selected(&self) -> Option<u64> {
+        let pmselr_el0: u64;
+        unsafe { asm!("mrs {}, PMSELR_EL0", out(reg) pmselr_el0) };
+        let n = pmselr_el0 & PMSELR_EL0_SEL_MASK;
+        (n == CYCLE_COUNTER || self.is_guest_counter(n)).then_some(n)
+    }
+
+    /// Performs a read of the guest from the PMU register `addr`.
+    ///
+    /// Returns `None` if `addr` is not a PMU register.
+    ///
+    /// # Safety
+    ///
+    /// The host must implement PMUv3.
+    pub unsafe fn read(&self, addr: SysRegAddr) -> Option<u64> {
+        let reg = PmuReg::decode(addr)?;
+        let counters = self.counters();
+        let value: u64;
+        unsafe {
+            match reg {
+                PmuReg::Pmcr => {
+                    let pmcr_el0: u64;
+                    asm!("mrs {}, PMCR_EL0", out(reg) pmcr_el0);
+                    value = (pmcr_el0 & !PMCR_EL0_N_MASK) | (self.hpmn << PMCR_EL0_N_SHIFT);
+                }
+                PmuReg::CntEnSet | PmuReg::CntEnClr => {
+                    asm!("mrs {}, PMCNTENSET_EL0", out(reg) value);
+                    return Some(value & counters);
+                }
+                PmuReg::OvsClr | PmuReg::OvsSet => {
+                    asm!("mrs {}, PMOVSSET_EL0", out(reg) value);
+                    return Some(value & counters);
+                }
+                PmuReg::IntEnSet | PmuReg::IntEnClr => {
+                    asm!("mrs {}, PMINTENSET_EL1", out(reg) value);
+                    return Some(value & counters);
+                }
+                PmuReg::Selr => asm!("mrs {}, PMSELR_EL0", out(reg) value),
+                PmuReg::Ceid0 => asm!("mrs {}, PMCEID0_EL0", out(reg) value),
+                PmuReg::Ceid1 => asm!("mrs {}, PMCEID1_EL0", out(reg) value),
+                PmuReg::Ccntr => asm!("mrs {}, PMCCNTR_EL0", out(reg) value),
+                PmuReg::UserEnr => asm!("mrs {}, PMUSERENR_EL0", out(reg) value),
+                // Write-only, and `PMMIR_EL1` is only implemented from FEAT_PMUv3p4 on.
+                PmuReg::SwInc | PmuReg::Mmir => value = 0,
+                PmuReg::XevTyper => {
+                    value = match self.selected() {
+                        Some(_) => {
+                            let typer: u64;
+                            asm!("mrs {}, PMXEVTYPER_EL0", out(reg) typer);
+                            typer
+                        }
+                        None => 0,
+                    };
+                }
+                PmuReg::XevCntr => {
+                    value = match self.selected() {
+                        Some(n) if n != CYCLE_COUNTER => {
+                            let cntr: u64;
+                            asm!("mrs {}, PMXEVCNTR_EL0", out(reg) cntr);
+                            cntr
+                        }
+                        _ => 0,
+                    };
+                }
+                PmuReg::EvCntr(n) if self.is_guest_counter(n) => {
+                    value = with_selected(n, || {
+                        let cntr: u64;
+                        asm!("mrs {}, PMXEVCNTR_EL0", out(reg) cntr);
+                        cntr
+                    });
+                }
+                PmuReg::EvTyper(CYCLE_COUNTER) => asm!("mrs {}, PMCCFILTR_EL0", out(reg) value),
+                PmuReg::EvTyper(n) if self.is_guest_counter(n) => {
+                    value = with_selected(n, || {
+                        let typer: u64;
+                        asm!("mrs {}, PMXEVTYPER_EL0", out(reg) typer);
+                        typer
+                    });
+                }
+                PmuReg::EvCntr(_) | PmuReg::EvTyper(_) => value = 0,
+            }
+        }
+        Some(value)
+    }
+
+    /// Performs a write of the guest to the PMU register `addr`, never letting the counters
+    /// count at EL2.
+    ///
+    /// Returns `false` if `addr` is not a PMU register.
+    ///
+    /// # Safety
+    ///
+    /// The host must implement PMUv3.
+    pub unsafe fn write(&self, addr: SysRegAddr, value: u64) -> bool {
+        let Some(reg) = PmuReg::decode(addr) else {
+            return false;
+        };
+        let counters = value & self.counters();
+        let typer = value & !PMEVTYPER_EL2;
+        unsafe {
+            match reg {
+                PmuReg::Pmcr => {
+                    // `P` would reset the host counters too.
+                    if value & PMCR_EL0_P != 0 {
+                        for n in 0..self.hpmn {
+                            with_selected(n, || asm!("msr PMXEVCNTR_EL0, xzr"));
+                        }
+                    }
+                    asm!("msr PMCR_EL0, {}", in(reg) value & !PMCR_EL0_P);
+                }
+                PmuReg::CntEnSet => asm!("msr PMCNTENSET_EL0, {}", in(reg) counters),
+                PmuReg::CntEnClr => asm!("msr PMCNTENCLR_EL0, {}", in(reg) counters),
+                PmuReg::OvsSet => asm!("msr PMOVSSET_EL0, {}", in(reg) counters),
+                PmuReg::OvsClr => asm!("msr PMOVSCLR_EL0, {}", in(reg) counters),
+                PmuReg::IntEnSet => asm!("msr PMINTENSET_EL1, {}", in(reg) counters),
+                PmuReg::IntEnClr => asm!("msr PMINTENCLR_EL1, {}", in(reg) counters),
+                PmuReg::SwInc => asm!("msr PMSWINC_EL0, {}", in(reg) counters),
+                PmuReg::Selr => asm!("msr PMSELR_EL0, {}", in(reg) value),
+                PmuReg::Ccntr => asm!("msr PMCCNTR_EL0, {}", in(reg) value),
+                PmuReg::UserEnr => asm!("msr PMUSERENR_EL0, {}", in(reg) value),
+                // Read-only.
+                PmuReg::Ceid0 | PmuReg::Ceid1 | PmuReg::Mmir => {}
+                PmuReg::XevTyper => {
+                    if self.selected().is_some() {
+                        asm!("msr PMXEVTYPER_EL0, {}", in(reg) typer);
+                    }
+                }
+                PmuReg::XevCntr => {
+                    if self.selected().is_some_and(|n| n != CYCLE_COUNTER) {
+                        asm!("msr PMXEVCNTR_EL0, {}", in(reg) value);
+                    }
+                }
+                PmuReg::EvCntr(n) if self.is_guest_counter(n) => {
+                    with_selected(n, || asm!("msr PMXEVCNTR_EL0, {}", in(reg) value));
+                }
+                PmuReg::EvTyper(CYCLE_COUNTER) => asm!("msr PMCCFILTR_EL0, {}", in(reg) typer),
+                PmuReg::EvTyper(n) if self.is_guest_counter(n) => {
+                    with_selected(n, || asm!("msr PMXEVTYPER_EL0, {}", in(reg) typer));
+                }
+                PmuReg::EvCntr(_) | PmuReg::EvTyper(_) => {}
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,166 @@
+//! Security policy callbacks, deciding on the VM exits of a vCPU before they reach the VMM.
+//!
+//! The VMM registers [`ExitPolicy`]s for some kinds of exits with
+//! [`Aarch64VCpu::add_exit_policy`]. They are invoked in their registration order on every
+//! such exit, and on every `SMC` about to be forwarded to EL3, and can let it through, rewrite
+//! it, or deny it. Auditing policies log the exits and let them through.
+//!
+//! [`Aarch64VCpu::add_exit_policy`]: crate::Aarch64VCpu::add_exit_policy
+
+use core::fmt;
+
+use axerrno::{AxResult, ax_err};
+use axvcpu::AxVCpuExitReason;
+
+use crate::TrapFrame;
+use crate::exit::complete_in_el2;
+use crate::smc::SmcccError;
+
+/// The kinds of exits the [`ExitPolicy`]s are registered for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyExit {
+    /// [`AxVCpuExitReason::Hypercall`], i.e. the `HVC`s not handled by the vCPU itself.
+    Hypercall,
+    /// The `SMC`s allowed to be forwarded to EL3 by the
+    /// [`SmcForwardPolicy`](crate::SmcForwardPolicy), presented to the policies as an
+    /// [`AxVCpuExitReason::Hypercall`], before they are forwarded.
+    ///
+    /// A policy rewriting them returns the rewritten exit to the VMM instead of forwarding
+    /// the `SMC`.
+    Smc,
+    /// [`AxVCpuExitReason::MmioRead`].
+    MmioRead,
+    /// [`AxVCpuExitReason::MmioWrite`].
+    MmioWrite,
+    /// [`AxVCpuExitReason::SysRegRead`].
+    SysRegRead,
+    /// [`AxVCpuExitReason::SysRegWrite`].
+    SysRegWrite,
+    /// [`AxVCpuExitReason::SendIPI`].
+    SendIpi,
+    /// [`AxVCpuExitReason::Halt`].
+    Halt,
+}
+
+impl PolicyExit {
+    const fn bit(self) -> u32 {
+        1 << self as u32
+    }
+
+    fn of(exit: &AxVCpuExitReason) -> Option<Self> {
+        Some(match exit {
+            AxVCpuExitReason::Hypercall { .. } => Self::Hypercall,
+            AxVCpuExitReason::MmioRead { .. } => Self::MmioRead,
+            AxVCpuExitReason::MmioWrite { .. } => Self::MmioWrite,
+            AxVCpuExitReason::SysRegRead { .. } => Self::SysRegRead,
+            AxVCpuExitReason::SysRegWrite { .. } => Self::SysRegWrite,
+            AxVCpuExitReason::SendIPI { .. } => Self::SendIpi,
+            AxVCpuExitReason::Halt => Self::Halt,
+            _ => return None,
+        })
+    }
+}
+
+/// The decision of an [`ExitPolicy`] on an exit.
+#[derive(Debug)]
+pub enum ExitVerdict {
+    /// The exit goes on, to the next policies and the VMM.
+    Allow,
+    /// The exit is replaced, e.g. to scrub its arguments, and goes on to the next policies
+    /// and the VMM.
+    Rewrite(AxVCpuExitReason),
+    /// The exit is completed in EL2 and the guest resumes, the next policies are not invoked.
+    ///
+    /// The hypercalls and `SMC`s return the error in `x0`. The other exits are completed as
+    /// if they were masked, see [`MaskableExit`](crate::MaskableExit): reads return zero,
+    /// writes, IPIs and halts are dropped.
+    Deny(SmcccError),
+}
+
+/// A security policy deciding on the exits of a vCPU, see [`Aarch64VCpu::add_exit_policy`].
+///
+/// It is called on the exit path of the vCPU, with the guest registers in `ctx` (the PC is
+/// already past the trapped instruction), and must neither block nor allocate.
+///
+/// [`Aarch64VCpu::add_exit_policy`]: crate::Aarch64VCpu::add_exit_policy
+pub trait ExitPolicy: Send + Sync {
+    /// Decides on `exit`, of the kind `kind`.
+    fn check(&self, kind: PolicyExit, exit: &AxVCpuExitReason, ctx: &TrapFrame) -> ExitVerdict;
+}
+
+/// Maximum number of policies a vCPU can hold.
+const MAX_EXIT_POLICIES: usize = 4;
+
+/// The outcome of the policies on an exit.
+pub enum PolicyOutcome {
+    /// The exit goes on to the VMM, unchanged or not.
+    Allowed {
+        /// The exit, as rewritten by the policies.
+        exit: AxVCpuExitReason,
+        /// Whether a policy has rewritten the exit.
+        rewritten: bool,
+    },
+    /// The exit has been completed in EL2.
+    Denied,
+}
+
+/// The policies registered for a vCPU, with the kinds of exits they decide on.
+#[derive(Clone, Copy, Default)]
+pub struct ExitPolicies {
+    policies: [Option<(u32, &'static dyn ExitPolicy)>; MAX_EXIT_POLICIES],
+}
+
+impl ExitPolicies {
+    /// Registers `policy` for the exits of `kinds`, after the already registered ones.
+    pub fn add(&mut self, kinds: &[PolicyExit], policy: &'static dyn ExitPolicy) -> AxResult {
+        let Some(slot) = self.policies.iter_mut().find(|slot| slot.is_none()) else {
+            return ax_err!(NoMemory, "too many exit policies");
+        };
+        let kinds = kinds.iter().fold(0, |kinds, kind| kinds | kind.bit());
+        *slot = Some((kinds, policy));
+        Ok(())
+    }
+
+    /// Unregisters all the policies.
+    pub fn clear(&mut self) {
+        self.policies = Default::default();
+    }
+
+    /// Applies the policies to `exit`, of the kind `kind` or the one of `exit` if `None`.
+    pub fn apply(
+        &self,
+        kind: Option<PolicyExit>,
+        mut exit: AxVCpuExitReason,
+        ctx: &mut TrapFrame,
+    ) -> PolicyOutcome {
+        let mut rewritten = false;
+        for (kinds, policy) in self.policies.iter().flatten() {
+            let Some(kind) = kind.or_else(|| PolicyExit::of(&exit)) else {
+                break;
+            };
+            if kinds & kind.bit() == 0 {
+                continue;
+            }
+            match policy.check(kind, &exit, ctx) {
+                ExitVerdict::Allow => {}
+                ExitVerdict::Rewrite(new) => {
+                    exit = new;
+                    rewritten = true;
+                }
+                ExitVerdict::Deny(error) => {
+                    complete_in_el2(&exit, error.code(), ctx);
+                    return PolicyOutcome::Denied;
+                }
+            }
+        }
+        PolicyOutcome::Allowed { exit, rewritten }
+    }
+}
+
+impl fmt::Debug for ExitPolicies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.policies.iter().flatten().map(|(kinds, _)| kinds))
+            .finish()
+    }
+}
@@ -0,0 +1,43 @@
+//! Translation of the guest writes to the power and reset registers of the platform into psci
+//! system events, for legacy guests rebooting or powering off without psci.
+//!
+//! Such guests, e.g. with a watchdog-based reboot, write to the registers they know of the
+//! physical platform. The VMM describes them with [`PowerMmioReg`]s, see
+//! [`Aarch64VCpuSetupConfig::power_mmio`], and the matching writes are reported as
+//! [`AxVCpuExitReason::SystemDown`] instead of [`AxVCpuExitReason::MmioWrite`], exactly like
+//! the psci `SYSTEM_OFF` and `SYSTEM_RESET` calls.
+//!
+//! [`Aarch64VCpuSetupConfig::power_mmio`]: crate::Aarch64VCpuSetupConfig::power_mmio
+//! [`AxVCpuExitReason::SystemDown`]: axvcpu::AxVCpuExitReason::SystemDown
+//! [`AxVCpuExitReason::MmioWrite`]: axvcpu::AxVCpuExitReason::MmioWrite
+
+use axaddrspace::GuestPhysAddr;
+
+use crate::psci::SystemEvent;
+
+/// A power or reset register of the platform, see [`power_mmio`](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PowerMmioReg {
+    /// The IPA of the register.
+    pub addr: GuestPhysAddr,
+    /// The bits of the written value compared with `value`, e.g. a trigger bit or a key field.
+    pub mask: u64,
+    /// The value of the `mask` bits requesting the event.
+    pub value: u64,
+    /// The requested event, [`SystemEvent::Off`] or [`SystemEvent::Reset`], which is still
+    /// subject to the [`ResetStormLimit`](crate::ResetStormLimit).
+    pub event: SystemEvent,
+}
+
+/// Returns the system event requested by the guest writing `data` at `addr`, if any.
+///
+/// The other writes, e.g. the watchdog kicks, are left to the VMM as regular MMIO writes.
+pub fn power_mmio_event(
+    regs: &[PowerMmioReg],
+    addr: GuestPhysAddr,
+    data: u64,
+) -> Option<SystemEvent> {
+    regs.iter()
+        .find(|reg| reg.addr == addr && data & reg.mask == reg.value)
+        .map(|reg| reg.event)
+}
@@ -0,0 +1,380 @@
+//! Handling of the psci (Power State Coordination Interface) calls of the guest.
+
+use aarch64_cpu::registers::{CNTFRQ_EL0, CNTPCT_EL0, Readable};
+use axaddrspace::GuestPhysAddr;
+use axerrno::AxResult;
+use axvcpu::AxVCpuExitReason;
+
+use crate::TrapFrame;
+use crate::exception::ExceptionState;
+use crate::stats::count_event;
+
+const PSCI_FN_RANGE_32: core::ops::RangeInclusive<u64> = 0x8400_0000..=0x8400_001F;
+const PSCI_FN_RANGE_64: core::ops::RangeInclusive<u64> = 0xC400_0000..=0xC400_001F;
+
+const PSCI_FN_VERSION: u64 = 0x0;
+const PSCI_FN_CPU_SUSPEND: u64 = 0x1;
+const PSCI_FN_CPU_OFF: u64 = 0x2;
+const PSCI_FN_CPU_ON: u64 = 0x3;
+const PSCI_FN_AFFINITY_INFO: u64 = 0x4;
+const _PSCI_FN_MIGRATE: u64 = 0x5;
+const PSCI_FN_MIGRATE_INFO_TYPE: u64 = 0x6;
+const PSCI_FN_SYSTEM_OFF: u64 = 0x8;
+const PSCI_FN_SYSTEM_RESET: u64 = 0x9;
+const PSCI_FN_FEATURES: u64 = 0xa;
+const PSCI_FN_CPU_FREEZE: u64 = 0xb;
+const PSCI_FN_CPU_DEFAULT_SUSPEND: u64 = 0xc;
+const PSCI_FN_STAT_RESIDENCY: u64 = 0x10;
+const PSCI_FN_STAT_COUNT: u64 = 0x11;
+const PSCI_FN_SYSTEM_RESET2: u64 = 0x12;
+const PSCI_FN_SYSTEM_OFF2: u64 = 0x15;
+
+const PSCI_RET_SUCCESS: u64 = 0;
+const PSCI_RET_NOT_SUPPORTED: u64 = -1i64 as u64;
+const PSCI_RET_INVALID_PARAMETERS: u64 = -2i64 as u64;
+const PSCI_RET_ALREADY_ON: u64 = -4i64 as u64;
+
+/// The psci version implemented for the guests, 1.3, the first one with `SYSTEM_OFF2`.
+const PSCI_VERSION_1_3: u64 = 0x1_0003;
+/// The `HIBERNATE_OFF` type of `SYSTEM_OFF2`, the only one defined, also its bit in the types
+/// returned by `PSCI_FEATURES`.
+const PSCI_OFF_TYPE_HIBERNATE_OFF: u64 = 0x1;
+/// The `AFFINITY_INFO` state of a running vCPU.
+const PSCI_AFFINITY_ON: u64 = 0;
+/// The `MIGRATE_INFO_TYPE` of a system with no Trusted OS to migrate.
+const PSCI_TOS_NOT_PRESENT_MP: u64 = 2;
+
+/// The affinity fields of MPIDR_EL1 (Aff3, Aff2, Aff1 and Aff0).
+const MPIDR_AFFINITY_MASK: u64 = 0xff_00ff_ffff;
+
+/// Maximum number of distinct power states statistics are kept for.
+const PSCI_STAT_MAX_STATES: usize = 4;
+
+/// Statistics of a power state, for `PSCI_STAT_RESIDENCY` and `PSCI_STAT_COUNT`.
+#[derive(Clone, Copy, Debug, Default)]
+struct PowerStateStat {
+    power_state: u32,
+    count: u64,
+    residency_ticks: u64,
+}
+
+/// A system power event requested by the guest, reported as [`AxVCpuExitReason::SystemDown`],
+/// see [`Aarch64VCpu::last_system_event`](crate::Aarch64VCpu::last_system_event).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SystemEvent {
+    /// `SYSTEM_OFF` or `SYSTEM_OFF2`.
+    Off,
+    /// `SYSTEM_RESET` or `SYSTEM_RESET2`, the VMM is expected to reboot the VM.
+    Reset,
+    /// A reset beyond the [`ResetStormLimit`], the VMM should throttle or stop the VM rather
+    /// than reboot it right away.
+    ResetStorm,
+}
+
+/// The rate of guest resets beyond which they are reported as [`SystemEvent::ResetStorm`],
+/// catching guests stuck in a crash/reboot loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResetStormLimit {
+    /// The maximum number of resets in a window.
+    pub max_resets: u32,
+    /// The length of the window, in microseconds.
+    pub window_us: u64,
+}
+
+/// The per-vCPU psci state.
+#[derive(Debug, Default)]
+pub struct PsciState {
+    /// The virtual MPIDR_EL1 of the vCPU, identifying it in `target_cpu` arguments.
+    pub mpidr: u64,
+    /// Statistics of the power states the vCPU has entered.
+    ///
+    /// Only the first [`PSCI_STAT_MAX_STATES`] distinct power states are accounted for, which
+    /// is plenty for regular guests.
+    stats: [Option<PowerStateStat>; PSCI_STAT_MAX_STATES],
+    /// The power state the vCPU is suspended in and the counter value it has entered it at.
+    suspended: Option<(u32, u64)>,
+    /// The reset rate beyond which resets are reported as storms.
+    pub reset_storm_limit: Option<ResetStormLimit>,
+    /// The counter value the current reset window has started at, and the resets in it.
+    reset_window: (u64, u32),
+    /// The last system power event requested by the guest.
+    pub last_system_event: Option<SystemEvent>,
+}
+
+impl PsciState {
+    fn stat_mut(&mut self, power_state: u32) -> Option<&mut PowerStateStat> {
+        let idx = self
+            .stats
+            .iter()
+            .position(|s| matches!(s, Some(s) if s.power_state == power_state))
+            .or_else(|| self.stats.iter().position(|s| s.is_none()))?;
+        Some(self.stats[idx].get_or_insert(PowerStateStat {
+            power_state,
+            ..Default::default()
+        }))
+    }
+
+    fn stat(&self, power_state: u32) -> PowerStateStat {
+        self.stats
+            .iter()
+            .flatten()
+            .find(|s| s.power_state == power_state)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Records that the vCPU enters `power_state`.
+    fn suspend(&mut self, power_state: u32) {
+        if let Some(stat) = self.stat_mut(power_state) {
+            stat.count += 1;
+        }
+        self.suspended = Some((power_state, CNTPCT_EL0.get()));
+    }
+
+    /// Records that the vCPU is resumed, accounting the residency of the power state it was
+    /// suspended in, if any.
+    pub fn resume(&mut self) {
+        if let Some((power_state, since)) = self.suspended.take() {
+            let ticks = CNTPCT_EL0.get().wrapping_sub(since);
+            if let Some(stat) = self.stat_mut(power_state) {
+                stat.residency_ticks += ticks;
+            }
+        }
+    }
+
+    /// Drops the state left by the guest, before it restarts on a reset of the vCPU.
+    ///
+    /// The statistics and the reset window are kept, they account for the whole life of the
+    /// vCPU.
+    pub fn reset_guest_state(&mut self) {
+        self.suspended = None;
+        self.last_system_event = None;
+    }
+
+    /// Records a reset of the guest, returning whether it exceeds the reset storm limit.
+    fn record_reset(&mut self) -> bool {
+        let Some(limit) = self.reset_storm_limit else {
+            return false;
+        };
+        let now = CNTPCT_EL0.get();
+        let (start, count) = &mut self.reset_window;
+        if ticks_to_us(now.wrapping_sub(*start)) > limit.window_us {
+            (*start, *count) = (now, 0);
+        }
+        *count = count.saturating_add(1);
+        *count > limit.max_resets
+    }
+
+    /// Returns whether `target_cpu` designates this vCPU.
+    fn is_self(&self, target_cpu: u64) -> bool {
+        target_cpu & MPIDR_AFFINITY_MASK == self.mpidr & MPIDR_AFFINITY_MASK
+    }
+}
+
+/// Returns whether the psci function at `fn_offset` is implemented, for `PSCI_FEATURES`.
+fn is_implemented(fn_offset: u64) -> bool {
+    matches!(
+        fn_offset,
+        PSCI_FN_VERSION
+            | PSCI_FN_CPU_SUSPEND
+            | PSCI_FN_CPU_OFF
+            | PSCI_FN_CPU_ON
+            | PSCI_FN_AFFINITY_INFO
+            | PSCI_FN_MIGRATE_INFO_TYPE
+            | PSCI_FN_SYSTEM_OFF
+            | PSCI_FN_SYSTEM_RESET
+            | PSCI_FN_FEATURES
+            | PSCI_FN_CPU_FREEZE
+            | PSCI_FN_CPU_DEFAULT_SUSPEND
+            | PSCI_FN_STAT_RESIDENCY
+            | PSCI_FN_STAT_COUNT
+            | PSCI_FN_SYSTEM_RESET2
+            | PSCI_FN_SYSTEM_OFF2
+    )
+}
+
+/// Returns the offset of the psci function `fn_` in the 32-bit or 64-bit psci range, `None` if
+/// it is not a psci function.
+fn psci_fn_offset(fn_: u64) -> Option<u64> {
+    if PSCI_FN_RANGE_32.contains(&fn_) {
+        Some(fn_ - PSCI_FN_RANGE_32.start())
+    } else if PSCI_FN_RANGE_64.contains(&fn_) {
+        Some(fn_ - PSCI_FN_RANGE_64.start())
+    } else {
+        None
+    }
+}
+
+/// Converts generic timer ticks to microseconds.
+fn ticks_to_us(ticks: u64) -> u64 {
+    let freq = CNTFRQ_EL0.get().max(1);
+    (ticks as u128 * 1_000_000 / freq as u128) as u64
+}
+
+/// Records the system power event `event` requested by the guest, `SystemEvent::Reset` becoming
+/// `SystemEvent::ResetStorm` beyond the [`ResetStormLimit`], and returns its exit.
+pub fn system_event(
+    ctx: &TrapFrame,
+    state: &mut ExceptionState,
+    event: SystemEvent,
+) -> AxVCpuExitReason {
+    let event = match event {
+        SystemEvent::Off => {
+            state.stats.system_offs = state.stats.system_offs.saturating_add(1);
+            SystemEvent::Off
+        }
+        SystemEvent::Reset | SystemEvent::ResetStorm => {
+            state.stats.system_resets = state.stats.system_resets.saturating_add(1);
+            if state.psci.record_reset() {
+                warn!(
+                    "Guest reset storm, {} resets so far @pc {:#x}",
+                    state.stats.system_resets,
+                    ctx.exception_pc()
+                );
+                SystemEvent::ResetStorm
+            } else {
+                SystemEvent::Reset
+            }
+        }
+    };
+    state.psci.last_system_event = Some(event);
+    AxVCpuExitReason::SystemDown
+}
+
+/// Handles HVC or SMC exceptions that serve as psci (Power State Coordination Interface) calls.
+///
+/// A hvc or smc call with the function in range 0x8000_0000..=0x8000_001F  (when the 32-bit
+/// hvc/smc calling convention is used) or 0xC000_0000..=0xC000_001F (when the 64-bit hvc/smc
+/// calling convention is used) is a psci call. This function handles them all, none of them is
+/// forwarded to the firmware.
+///
+/// The guest sees psci 1.3. `CPU_ON` and `CPU_OFF` are reported as [`AxVCpuExitReason::CpuUp`]
+/// and [`AxVCpuExitReason::CpuDown`], except `CPU_ON` of the calling vCPU which gets
+/// `ALREADY_ON`. On a `CpuUp`, the VMM boots the target vCPU with
+/// [`Aarch64VCpu::boot_secondary`](crate::Aarch64VCpu::boot_secondary) and completes the call
+/// with `set_return_value()`. `AFFINITY_INFO` of the calling vCPU returns `ON`, the state of the
+/// other vCPUs is only known to the VMM, so the call is reported as an
+/// [`AxVCpuExitReason::Hypercall`] the VMM answers with `set_return_value()`. There is no
+/// Trusted OS to migrate, so `MIGRATE_INFO_TYPE` returns 2 and `MIGRATE` is not supported.
+///
+/// `CPU_SUSPEND` is emulated as a `WFI`, like KVM does: the vCPU halts and is resumed at the
+/// next instruction. The time spent and the number of entries of each power state are kept
+/// for `PSCI_STAT_RESIDENCY` and `PSCI_STAT_COUNT`, which only know about the calling vCPU and
+/// return 0 for other ones.
+///
+/// `CPU_FREEZE` and `CPU_DEFAULT_SUSPEND` are emulated as `WFI`s too, and reported as
+/// supported by `PSCI_FEATURES`, whatever the firmware supports. They don't name a power
+/// state, so they are left out of the statistics.
+///
+/// `SYSTEM_OFF` and `SYSTEM_RESET` (and their `2` variants) are all reported as
+/// [`AxVCpuExitReason::SystemDown`], the [`SystemEvent`] telling them apart, and counted in the
+/// vCPU statistics. Resets beyond the [`ResetStormLimit`] are reported as
+/// [`SystemEvent::ResetStorm`], so a VMM only checking for `SystemDown` stops such a guest.
+///
+/// `PSCI_FEATURES` returns success for the functions above, with no feature flag set, i.e. the
+/// original `CPU_SUSPEND` power state format, and `NOT_SUPPORTED` for the other ones, which
+/// get `NOT_SUPPORTED` when called too. For `SYSTEM_OFF2`, it returns the `HIBERNATE_OFF`
+/// type, the only one `SYSTEM_OFF2` accepts, the other ones get `INVALID_PARAMETERS`.
+///
+/// Returns `None` if the HVC is not a psci call.
+pub fn handle_psci_call(
+    ctx: &mut TrapFrame,
+    state: &mut ExceptionState,
+) -> Option<AxResult<AxVCpuExitReason>> {
+    let fn_ = ctx.gpr[0];
+    let fn_offset = psci_fn_offset(fn_);
+
+    match fn_offset {
+        Some(PSCI_FN_CPU_SUSPEND) => {
+            state.psci.suspend(ctx.gpr[1] as u32);
+            ctx.set_argument(PSCI_RET_SUCCESS as usize);
+            Some(Ok(AxVCpuExitReason::Halt))
+        }
+        Some(PSCI_FN_CPU_FREEZE) | Some(PSCI_FN_CPU_DEFAULT_SUSPEND) => {
+            ctx.set_argument(PSCI_RET_SUCCESS as usize);
+            Some(Ok(AxVCpuExitReason::Halt))
+        }
+        Some(PSCI_FN_VERSION) => {
+            ctx.set_argument(PSCI_VERSION_1_3 as usize);
+            Some(Ok(AxVCpuExitReason::Nothing))
+        }
+        Some(PSCI_FN_FEATURES) => {
+            let ret = match psci_fn_offset(ctx.gpr[1]) {
+                // The supported types.
+                Some(PSCI_FN_SYSTEM_OFF2) => PSCI_OFF_TYPE_HIBERNATE_OFF,
+                Some(offset) if is_implemented(offset) => PSCI_RET_SUCCESS,
+                _ => PSCI_RET_NOT_SUPPORTED,
+            };
+            ctx.set_argument(ret as usize);
+            Some(Ok(AxVCpuExitReason::Nothing))
+        }
+        Some(PSCI_FN_MIGRATE_INFO_TYPE) => {
+            ctx.set_argument(PSCI_TOS_NOT_PRESENT_MP as usize);
+            Some(Ok(AxVCpuExitReason::Nothing))
+        }
+        Some(PSCI_FN_AFFINITY_INFO) => {
+            // Only the affinity of single PEs is supported, as KVM does.
+            if ctx.gpr[2] != 0 {
+                ctx.set_argument(PSCI_RET_INVALID_PARAMETERS as usize);
+                return Some(Ok(AxVCpuExitReason::Nothing));
+            }
+            if state.psci.is_self(ctx.gpr[1]) {
+                ctx.set_argument(PSCI_AFFINITY_ON as usize);
+                return Some(Ok(AxVCpuExitReason::Nothing));
+            }
+            Some(Ok(AxVCpuExitReason::Hypercall {
+                nr: fn_,
+                args: [ctx.gpr[1], ctx.gpr[2], 0, 0, 0, 0],
+            }))
+        }
+        Some(PSCI_FN_CPU_OFF) => Some(Ok(AxVCpuExitReason::CpuDown { _state: ctx.gpr[1] })),
+        Some(PSCI_FN_CPU_ON) if state.psci.is_self(ctx.gpr[1]) => {
+            ctx.set_argument(PSCI_RET_ALREADY_ON as usize);
+            Some(Ok(AxVCpuExitReason::Nothing))
+        }
+        Some(PSCI_FN_CPU_ON) => Some(Ok(AxVCpuExitReason::CpuUp {
+            target_cpu: ctx.gpr[1],
+            entry_point: GuestPhysAddr::from(ctx.gpr[2] as usize),
+            arg: ctx.gpr[3],
+        })),
+        // A null type is `HIBERNATE_OFF`, as KVM does.
+        Some(PSCI_FN_SYSTEM_OFF2)
+            if !matches!(ctx.gpr[1], 0 | PSCI_OFF_TYPE_HIBERNATE_OFF) || ctx.gpr[2] != 0 =>
+        {
+            ctx.set_argument(PSCI_RET_INVALID_PARAMETERS as usize);
+            Some(Ok(AxVCpuExitReason::Nothing))
+        }
+        Some(PSCI_FN_SYSTEM_OFF) | Some(PSCI_FN_SYSTEM_OFF2) => {
+            Some(Ok(system_event(ctx, state, SystemEvent::Off)))
+        }
+        Some(PSCI_FN_SYSTEM_RESET) | Some(PSCI_FN_SYSTEM_RESET2) => {
+            Some(Ok(system_event(ctx, state, SystemEvent::Reset)))
+        }
+        Some(PSCI_FN_STAT_RESIDENCY) | Some(PSCI_FN_STAT_COUNT) => {
+            let stat = if state.psci.is_self(ctx.gpr[1]) {
+                state.psci.stat(ctx.gpr[2] as u32)
+            } else {
+                PowerStateStat::default()
+            };
+            let ret = if fn_offset == Some(PSCI_FN_STAT_COUNT) {
+                stat.count
+            } else {
+                ticks_to_us(stat.residency_ticks)
+            };
+            ctx.set_argument(ret as usize);
+            Some(Ok(AxVCpuExitReason::Nothing))
+        }
+        Some(_) => {
+            if count_event(&mut state.stats.unsupported_psci) {
+                warn!(
+                    "Unsupported psci function {fn_:#x} @pc {:#x} ({} times)",
+                    ctx.exception_pc(),
+                    state.stats.unsupported_psci
+                );
+            }
+            ctx.set_argument(PSCI_RET_NOT_SUPPORTED as usize);
+            Some(Ok(AxVCpuExitReason::Nothing))
+        }
+        None => None,
+    }
+}
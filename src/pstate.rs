@@ -0,0 +1,147 @@
+//! Preservation of the host PSTATE bits across a guest run.
+//!
+//! The guest PSTATE, including PAN, UAO, DIT and SSBS, is entirely held in `SPSR_EL2` when the
+//! guest traps, which is saved into and restored from [`TrapFrame::spsr`] by exception.S, so it
+//! is preserved as is across VM exits.
+//!
+//! The host side is different: the VM exit is an exception taken to EL2, which leaves PSTATE.DIT
+//! unchanged (i.e. as set by the guest), sets PSTATE.SSBS to `SCTLR_EL2.DSSBS`, and may change
+//! PAN and UAO. The control flow then returns to `run()` through a plain `ret`, so nothing
+//! reinstates the values the host had before entering the guest. [`HostPstate`] saves them
+//! before the guest runs and restores them right after it exits.
+//!
+//! The interrupt masks follow an explicit contract instead, see [`HostDaif`].
+//!
+//! [`TrapFrame::spsr`]: crate::TrapFrame
+
+use aarch64_cpu::registers::{DAIF, Readable, Writeable};
+use spin::Once;
+
+use crate::cpu_feature::{has_feat_dit, has_feat_pan, has_feat_ssbs2, has_feat_uao};
+
+const PSTATE_PAN: u8 = 1 << 0;
+const PSTATE_UAO: u8 = 1 << 1;
+const PSTATE_DIT: u8 = 1 << 2;
+const PSTATE_SSBS: u8 = 1 << 3;
+
+/// `DAIF.D`, `DAIF.A`, `DAIF.I` and `DAIF.F`, bits [9:6].
+const DAIF_ALL: u64 = 0b1111 << 6;
+
+/// The PSTATE bits which are accessible on the host, detected once.
+static PSTATE_FEATURES: Once<u8> = Once::new();
+
+fn pstate_features() -> u8 {
+    *PSTATE_FEATURES.call_once(|| {
+        let mut features = 0;
+        if has_feat_pan() {
+            features |= PSTATE_PAN;
+        }
+        if has_feat_uao() {
+            features |= PSTATE_UAO;
+        }
+        if has_feat_dit() {
+            features |= PSTATE_DIT;
+        }
+        if has_feat_ssbs2() {
+            features |= PSTATE_SSBS;
+        }
+        features
+    })
+}
+
+/// The host PSTATE bits which are not reinstated by a VM exit.
+///
+/// The registers are accessed through their generic encodings, so that no target feature
+/// is required from the assembler:
+/// - PAN: `S3_0_C4_C2_3`,
+/// - UAO: `S3_0_C4_C2_4`,
+/// - DIT: `S3_3_C4_C2_5`,
+/// - SSBS: `S3_3_C4_C2_6`.
+#[derive(Debug, Clone, Copy)]
+pub struct HostPstate {
+    features: u8,
+    pan: u64,
+    uao: u64,
+    dit: u64,
+    ssbs: u64,
+}
+
+impl HostPstate {
+    /// Saves the current PSTATE bits.
+    pub fn save() -> Self {
+        let features = pstate_features();
+        let mut pstate = Self {
+            features,
+            pan: 0,
+            uao: 0,
+            dit: 0,
+            ssbs: 0,
+        };
+        unsafe {
+            if features & PSTATE_PAN != 0 {
+                core::arch::asm!("mrs {}, S3_0_C4_C2_3", out(reg) pstate.pan);
+            }
+            if features & PSTATE_UAO != 0 {
+                core::arch::asm!("mrs {}, S3_0_C4_C2_4", out(reg) pstate.uao);
+            }
+            if features & PSTATE_DIT != 0 {
+                core::arch::asm!("mrs {}, S3_3_C4_C2_5", out(reg) pstate.dit);
+            }
+            if features & PSTATE_SSBS != 0 {
+                core::arch::asm!("mrs {}, S3_3_C4_C2_6", out(reg) pstate.ssbs);
+            }
+        }
+        pstate
+    }
+
+    /// Restores the saved PSTATE bits.
+    pub fn restore(&self) {
+        unsafe {
+            if self.features & PSTATE_PAN != 0 {
+                core::arch::asm!("msr S3_0_C4_C2_3, {}", in(reg) self.pan);
+            }
+            if self.features & PSTATE_UAO != 0 {
+                core::arch::asm!("msr S3_0_C4_C2_4, {}", in(reg) self.uao);
+            }
+            if self.features & PSTATE_DIT != 0 {
+                core::arch::asm!("msr S3_3_C4_C2_5, {}", in(reg) self.dit);
+            }
+            if self.features & PSTATE_SSBS != 0 {
+                core::arch::asm!("msr S3_3_C4_C2_6, {}", in(reg) self.ssbs);
+            }
+        }
+    }
+}
+
+/// The host interrupt masks around `run()`.
+///
+/// Whatever the masks of the caller, `run()` masks all the exceptions (`DAIF`) on entry and
+/// enters the guest with them masked, the guest running with its own masks from `SPSR_EL2`.
+/// The VM exits are exceptions to EL2, which mask them all again. The masks of the caller are
+/// reinstated while a synchronous exit is handled only if
+/// [`Aarch64VCpuSetupConfig::host_irqs_in_exits`] is set, and always when `run()` returns.
+///
+/// [`Aarch64VCpuSetupConfig::host_irqs_in_exits`]: crate::Aarch64VCpuSetupConfig::host_irqs_in_exits
+#[derive(Debug, Clone, Copy)]
+pub struct HostDaif(u64);
+
+impl HostDaif {
+    /// Saves the masks of the caller and masks all the exceptions.
+    pub fn save_and_mask() -> Self {
+        let daif = DAIF.get();
+        Self::mask();
+        Self(daif)
+    }
+
+    /// Masks all the exceptions, before entering the guest.
+    pub fn mask() {
+        unsafe { core::arch::asm!("msr daifset, #0xf") };
+    }
+
+    /// Reinstates the masks of the caller, which are saved again by the next [`save_and_mask`].
+    ///
+    /// [`save_and_mask`]: Self::save_and_mask
+    pub fn restore(&self) {
+        DAIF.set(self.0 & DAIF_ALL);
+    }
+}
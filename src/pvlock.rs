@@ -0,0 +1,59 @@
+//! The paravirtual "vCPU is preempted" interface, letting the spinlocks of the guest avoid
+//! spinning on the lock holders which are not running.
+//!
+//! It follows the KVM PV lock interface, in the vendor specific hypervisor service range: the
+//! guest discovers it with `PV_LOCK_FEATURES`, then registers a state area of each vCPU with
+//! `PV_LOCK_PREEMPTED`, passing its IPA in `x1`. The area is 64 bytes long and aligned, its
+//! first little-endian 64-bit word is the preempted flag, set while the vCPU is unbound from
+//! its physical CPU (`unbind()`) and cleared when it is bound again (`bind()`).
+
+use axaddrspace::GuestPhysAddr;
+use axvcpu::AxVCpuExitReason;
+
+use crate::TrapFrame;
+use crate::smc::SMCCC_RET_NOT_SUPPORTED;
+
+/// `PV_LOCK_FEATURES`, returns whether the function in `x1` is supported.
+const PV_LOCK_FEATURES: u32 = 0xc600_0020;
+/// `PV_LOCK_PREEMPTED`, registers the state area at the IPA in `x1`.
+const PV_LOCK_PREEMPTED: u32 = 0xc600_0021;
+
+const PV_LOCK_RET_SUCCESS: u64 = 0;
+const PV_LOCK_RET_INVALID_PARAMETER: u64 = -3i64 as u64;
+
+/// The size and alignment of the state area.
+const PV_LOCK_STATE_SIZE: usize = 64;
+
+/// The per-vCPU state of the interface.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PvLockState {
+    /// The IPA of the state area registered by the guest.
+    pub area: Option<GuestPhysAddr>,
+}
+
+/// Handles the `PV_LOCK_*` calls of the guest, through `HVC` or `SMC`.
+///
+/// Returns `None` if the call is not one of them.
+pub fn handle_pvlock_call(
+    ctx: &mut TrapFrame,
+    state: &mut PvLockState,
+) -> Option<AxVCpuExitReason> {
+    let ret = match ctx.gpr[0] as u32 {
+        PV_LOCK_FEATURES => match ctx.gpr[1] as u32 {
+            PV_LOCK_FEATURES | PV_LOCK_PREEMPTED => PV_LOCK_RET_SUCCESS,
+            _ => SMCCC_RET_NOT_SUPPORTED,
+        },
+        PV_LOCK_PREEMPTED => {
+            let ipa = ctx.gpr[1] as usize;
+            if ipa & (PV_LOCK_STATE_SIZE - 1) != 0 {
+                PV_LOCK_RET_INVALID_PARAMETER
+            } else {
+                state.area = Some(GuestPhysAddr::from(ipa));
+                PV_LOCK_RET_SUCCESS
+            }
+        }
+        _ => return None,
+    };
+    ctx.set_argument(ret as usize);
+    Some(AxVCpuExitReason::Nothing)
+}
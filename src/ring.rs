@@ -0,0 +1,148 @@
+//! Batched delivery of the MMIO write exits through a ring shared with the VMM, with the
+//! experimental `exit-ring` feature.
+//!
+//! The MMIO writes of the guest need no answer, so they can be posted: when the producer of a
+//! ring is attached with [`Aarch64VCpu::set_exit_ring`], they are written into it and the guest is
+//! re-entered right away. `run()` returns [`AxVCpuExitReason::Nothing`] once the ring is
+//! full, and the other exits as usual. The VMM must process the pending writes before any
+//! exit returned by `run()`, so that they keep their order with the accesses needing an
+//! answer, e.g. a read of the status register of the device.
+//!
+//! The writes are only processed when `run()` returns, so their side effects, e.g. the
+//! interrupts raised by the device, are delayed until the next exit of any kind, usually
+//! the next host timer interrupt.
+//!
+//! [`Aarch64VCpu::set_exit_ring`]: crate::Aarch64VCpu::set_exit_ring
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::AccessWidth;
+use axvcpu::AxVCpuExitReason;
+
+/// The number of entries of an [`ExitRing`].
+pub const EXIT_RING_ENTRIES: usize = 64;
+
+/// A posted MMIO write of the guest, see [`ExitRing`].
+#[derive(Clone, Copy, Debug)]
+pub struct ExitRingEntry {
+    /// The written IPA.
+    pub addr: GuestPhysAddr,
+    /// The width of the access.
+    pub width: AccessWidth,
+    /// The written value.
+    pub data: u64,
+}
+
+/// A ring of posted MMIO writes, produced by a vCPU and consumed by the VMM.
+///
+/// The ring has a single producer and a single consumer, handed out once by
+/// [`ExitRing::split`]: the vCPU the [`ExitRingProducer`] is attached to only pushes entries
+/// while it runs, in `run()`, and the VMM pops them with the [`ExitRingConsumer`] from any
+/// thread, e.g. from an I/O thread while the vCPU runs.
+#[derive(Debug)]
+pub struct ExitRing {
+    /// The free-running index of the next entry pushed by the vCPU.
+    head: AtomicU32,
+    /// The free-running index of the next entry popped by the VMM.
+    tail: AtomicU32,
+    entries: [UnsafeCell<Option<ExitRingEntry>>; EXIT_RING_ENTRIES],
+}
+
+// The entries are only accessed through the producer and the consumer, which are unique: the
+// ones between `tail` and `head` by the consumer, the other ones by the producer.
+unsafe impl Sync for ExitRing {}
+
+/// The producer of an [`ExitRing`], attached to the vCPU posting its MMIO writes.
+#[derive(Debug)]
+pub struct ExitRingProducer(&'static ExitRing);
+
+/// The consumer of an [`ExitRing`], popping the posted MMIO writes for the VMM.
+#[derive(Debug)]
+pub struct ExitRingConsumer(&'static ExitRing);
+
+impl ExitRing {
+    /// Creates an empty ring.
+    pub const fn new() -> Self {
+        Self {
+            head: AtomicU32::new(0),
+            tail: AtomicU32::new(0),
+            entries: [const { UnsafeCell::new(None) }; EXIT_RING_ENTRIES],
+        }
+    }
+
+    /// Returns the number of pending entries.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        head.wrapping_sub(self.tail.load(Ordering::Acquire)) as usize
+    }
+
+    /// Returns whether there is no pending entry.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether the ring is full, i.e. the next MMIO write is returned by `run()`.
+    pub fn is_full(&self) -> bool {
+        self.len() == EXIT_RING_ENTRIES
+    }
+
+    /// Splits the ring into its producer and consumer, the only handles accessing its
+    /// entries.
+    pub fn split(&'static mut self) -> (ExitRingProducer, ExitRingConsumer) {
+        let ring: &'static ExitRing = self;
+        (ExitRingProducer(ring), ExitRingConsumer(ring))
+    }
+}
+
+impl ExitRingProducer {
+    /// Returns the ring.
+    pub fn ring(&self) -> &'static ExitRing {
+        self.0
+    }
+
+    /// Pushes the exit if it is an MMIO write and the ring is not full.
+    ///
+    /// Returns the exit back if it is not posted.
+    pub(crate) fn post(&mut self, exit: AxVCpuExitReason) -> Option<AxVCpuExitReason> {
+        let AxVCpuExitReason::MmioWrite { addr, width, data } = exit else {
+            return Some(exit);
+        };
+        let ring = self.0;
+        if ring.is_full() {
+            return Some(exit);
+        }
+        let head = ring.head.load(Ordering::Relaxed);
+        let slot = &ring.entries[head as usize % EXIT_RING_ENTRIES];
+        unsafe { *slot.get() = Some(ExitRingEntry { addr, width, data }) };
+        ring.head.store(head.wrapping_add(1), Ordering::Release);
+        None
+    }
+}
+
+impl ExitRingConsumer {
+    /// Returns the ring.
+    pub fn ring(&self) -> &'static ExitRing {
+        self.0
+    }
+
+    /// Pops the oldest pending entry.
+    pub fn pop(&mut self) -> Option<ExitRingEntry> {
+        let ring = self.0;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        if ring.head.load(Ordering::Acquire) == tail {
+            return None;
+        }
+        let slot = &ring.entries[tail as usize % EXIT_RING_ENTRIES];
+        let entry = unsafe { (*slot.get()).take() };
+        ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        entry
+    }
+}
+
+impl Default for ExitRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
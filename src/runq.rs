@@ -0,0 +1,179 @@
+//! A run queue of the vCPUs bound to a physical CPU, switching between them without returning
+//! to the VMM, with the experimental `run-queue` feature.
+//!
+//! The VMM adds the vCPUs it hosts on a physical CPU to a [`RunQueue`], then runs the queue with
+//! [`RunQueue::run`] instead of running each vCPU. A switch to another vCPU of the queue is
+//! requested with [`request_vcpu_switch`], usually by the [`IrqClassifier`] of the CPU on a
+//! tick of the VMM scheduler: the running vCPU exits at its next resumed exit, i.e. right
+//! after the tick, and the queue unbinds it then binds and enters the target, in the same
+//! `run()` of the queue. The other exits are returned to the VMM with the index of their vCPU,
+//! which the next `run()` enters again unless a switch has been requested meanwhile.
+//!
+//! The queue binds and unbinds its vCPUs itself, the VMM must not bind them directly. The
+//! [`AxVCpuExitReason::Nothing`] exits are not returned while a switch is pending, so the
+//! [`ExitRing`]s of the queued vCPUs, if any, must be drained by another thread.
+//!
+//! [`IrqClassifier`]: crate::IrqClassifier
+//! [`ExitRing`]: crate::ExitRing
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axerrno::{AxResult, ax_err};
+use axvcpu::{AxArchVCpu, AxVCpuExitReason, AxVCpuHal};
+
+use crate::Aarch64VCpu;
+
+/// The maximum number of vCPUs of a [`RunQueue`].
+pub const RUN_QUEUE_CAPACITY: usize = 16;
+
+/// The switch target of the current CPU meaning that no switch is pending.
+const NO_SWITCH: usize = usize::MAX;
+
+/// The index of the vCPU the run queue of this CPU switches to, see [`request_vcpu_switch`].
+///
+/// Atomic as a request from an IRQ may land between the read and the reset of a take.
+#[percpu::def_percpu]
+static SWITCH_TARGET: AtomicUsize = AtomicUsize::new(NO_SWITCH);
+
+/// Requests the run queue running on the current CPU to switch to its vCPU `index`.
+///
+/// Can be called with the IRQs masked, e.g. from an [`IrqClassifier`], or between two `run()`
+/// of the queue. A later request replaces a pending one, and a request for an index without a
+/// vCPU is dropped when served.
+///
+/// [`IrqClassifier`]: crate::IrqClassifier
+pub fn request_vcpu_switch(index: usize) {
+    unsafe { SWITCH_TARGET.current_ref_raw() }.store(index, Ordering::Release);
+}
+
+/// Returns whether a switch is pending on the current CPU.
+pub(crate) fn switch_requested() -> bool {
+    unsafe { SWITCH_TARGET.current_ref_raw() }.load(Ordering::Acquire) != NO_SWITCH
+}
+
+/// Takes the switch pending on the current CPU, if any.
+pub(crate) fn take_switch_target() -> Option<usize> {
+    let target = unsafe { SWITCH_TARGET.current_ref_raw() }.swap(NO_SWITCH, Ordering::AcqRel);
+    (target != NO_SWITCH).then_some(target)
+}
+
+/// The vCPUs run on a physical CPU, see the module documentation.
+///
+/// The vCPUs must be set up before being added, and the queue must only be run on one CPU.
+pub struct RunQueue<'a, H: AxVCpuHal> {
+    vcpus: [Option<&'a mut Aarch64VCpu<H>>; RUN_QUEUE_CAPACITY],
+    /// The index of the vCPU run by the next `run()`.
+    current: usize,
+    /// Whether the current vCPU is bound to the CPU.
+    bound: bool,
+    /// The number of switches between vCPUs.
+    switches: u64,
+}
+
+impl<'a, H: AxVCpuHal> RunQueue<'a, H> {
+    /// Creates an empty queue.
+    pub const fn new() -> Self {
+        Self {
+            vcpus: [const { None }; RUN_QUEUE_CAPACITY],
+            current: 0,
+            bound: false,
+            switches: 0,
+        }
+    }
+
+    /// Adds `vcpu` to the queue, returning its index.
+    ///
+    /// Returns `ResourceBusy` if the queue is full.
+    pub fn add(&mut self, vcpu: &'a mut Aarch64VCpu<H>) -> AxResult<usize> {
+        let Some(index) = self.vcpus.iter().position(Option::is_none) else {
+            return ax_err!(ResourceBusy, "run queue full");
+        };
+        self.vcpus[index] = Some(vcpu);
+        Ok(index)
+    }
+
+    /// Removes the vCPU `index` from the queue, unbinding it if it is the current one.
+    ///
+    /// Returns `InvalidInput` if the queue has no vCPU `index`.
+    pub fn remove(&mut self, index: usize) -> AxResult<&'a mut Aarch64VCpu<H>> {
+        if index == self.current {
+            self.unbind()?;
+        }
+        match self.vcpus.get_mut(index).and_then(Option::take) {
+            Some(vcpu) => Ok(vcpu),
+            None => ax_err!(InvalidInput, "no such vCPU in the run queue"),
+        }
+    }
+
+    /// Returns the index of the vCPU run by the next `run()`.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Returns the number of switches between vCPUs of the queue, whether requested with
+    /// [`request_vcpu_switch`] or [`switch_to`](Self::switch_to).
+    pub fn switches(&self) -> u64 {
+        self.switches
+    }
+
+    /// Makes the vCPU `index` the one run by the next `run()`, unbinding the current one.
+    ///
+    /// Returns `InvalidInput` if the queue has no vCPU `index`.
+    pub fn switch_to(&mut self, index: usize) -> AxResult {
+        if !matches!(self.vcpus.get(index), Some(Some(_))) {
+            return ax_err!(InvalidInput, "no such vCPU in the run queue");
+        }
+        if index != self.current {
+            self.unbind()?;
+            self.current = index;
+            self.switches += 1;
+        }
+        Ok(())
+    }
+
+    /// Unbinds the current vCPU from the CPU, before the queue is dropped or moved to another
+    /// CPU. The next `run()` binds it again.
+    pub fn unbind(&mut self) -> AxResult {
+        if core::mem::take(&mut self.bound) {
+            if let Some(vcpu) = self.vcpus[self.current].as_deref_mut() {
+                vcpu.unbind()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the vCPUs of the queue, switching between them as requested with
+    /// [`request_vcpu_switch`], until one of them exits to the VMM.
+    ///
+    /// Returns the index of the vCPU and its exit, or `BadState` if the current vCPU has been
+    /// removed. The errors of the vCPUs are returned as is.
+    pub fn run(&mut self) -> AxResult<(usize, AxVCpuExitReason)> {
+        loop {
+            if let Some(target) = take_switch_target() {
+                if self.switch_to(target).is_err() {
+                    warn!("dropping the switch to vCPU {target} missing from the run queue");
+                }
+            }
+            let index = self.current;
+            let Some(vcpu) = self.vcpus[index].as_deref_mut() else {
+                return ax_err!(BadState, "no vCPU to run");
+            };
+            if !self.bound {
+                vcpu.bind()?;
+                self.bound = true;
+            }
+            let exit_reason = vcpu.run()?;
+            // The exit was only taken for the switch.
+            if matches!(exit_reason, AxVCpuExitReason::Nothing) && switch_requested() {
+                continue;
+            }
+            return Ok((index, exit_reason));
+        }
+    }
+}
+
+impl<H: AxVCpuHal> Default for RunQueue<'_, H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
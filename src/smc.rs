@@ -1,26 +1,137 @@
 use core::arch::asm;
 
+/// Number of registers used to pass arguments and results of SMCCC (v1.2) calls, x0-x17.
+pub const SMCCC_NUM_REGS: usize = 18;
+
+/// SMCCC return value for unknown or unsupported functions.
+pub const SMCCC_RET_NOT_SUPPORTED: u64 = -1i64 as u64;
+
+/// The standard SMCCC error codes, returned in `x0` by the calls which fail, see
+/// [`Aarch64VCpu::deny_hypercall`](crate::Aarch64VCpu::deny_hypercall).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmcccError {
+    /// `NOT_SUPPORTED` (-1), the function is not implemented.
+    NotSupported,
+    /// `NOT_REQUIRED` (-2), the call is not needed, e.g. a mitigation the platform doesn't
+    /// need.
+    NotRequired,
+    /// `INVALID_PARAMETER` (-3), the arguments are invalid.
+    InvalidParameter,
+}
+
+impl SmcccError {
+    /// Returns the value of `x0` for the error.
+    pub const fn code(self) -> u64 {
+        match self {
+            Self::NotSupported => SMCCC_RET_NOT_SUPPORTED,
+            Self::NotRequired => -2i64 as u64,
+            Self::InvalidParameter => -3i64 as u64,
+        }
+    }
+}
+
+/// SMCCC function ID bit [31], set for fast calls and clear for yielding calls.
+const SMCCC_FAST_CALL: u32 = 1 << 31;
+/// SMCCC function ID bit [30], set for the SMC64/HVC64 calling convention.
+pub const SMCCC_64: u32 = 1 << 30;
+
+/// SMCCC owning entity number of SiP (Silicon Provider) services.
+pub const SMCCC_OWNER_SIP: u32 = 2;
+/// SMCCC owning entity number of OEM services.
+pub const SMCCC_OWNER_OEM: u32 = 3;
+
+/// Returns the owning entity number of an SMCCC function ID, bits [29:24].
+pub const fn smccc_owner(fn_id: u32) -> u32 {
+    (fn_id >> 24) & 0x3f
+}
+
+/// Which SMCs of the guest are forwarded to EL3, if they are not handled by the vCPU itself
+/// (e.g. as psci calls).
+///
+/// Only fast calls are ever forwarded, SMCs which are not forwarded return `NOT_SUPPORTED`.
+/// The yielding calls are always rejected, whatever the policy, as the vCPU doesn't handle their
+/// preempted returns and resumes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SmcForwardPolicy {
+    /// Forward all the fast calls.
+    #[default]
+    All,
+    /// Forward nothing.
+    Nothing,
+    /// Forward the fast calls of the owning entities whose bit is set in the mask, e.g.
+    /// `1 << SMCCC_OWNER_SIP` to pass SiP services (clocks, regulators...) through to a
+    /// privileged VM.
+    Owners(u64),
+}
+
+impl SmcForwardPolicy {
+    /// Returns whether the call with function ID `fn_id` may be forwarded to EL3, never for the
+    /// yielding calls.
+    pub fn allows(&self, fn_id: u32) -> bool {
+        if fn_id & SMCCC_FAST_CALL == 0 {
+            return false;
+        }
+        match self {
+            Self::All => true,
+            Self::Nothing => false,
+            Self::Owners(mask) => mask & (1 << smccc_owner(fn_id)) != 0,
+        }
+    }
+}
+
 #[inline(never)]
-/// invoke a secure monitor call
+/// invoke a secure monitor call, passing arguments and results in x0-x17 as SMCCC v1.2 does
 /// # Safety:
 /// It is unsafe to call this function directly.
 /// The caller must ensure that
-/// x0 is defined as the SMC function number referenced in the SMC Calling Convention
+/// regs[0] is defined as the SMC function number referenced in the SMC Calling Convention
 /// than the args later must be valid for the specified SMC function.
-pub unsafe fn smc_call(x0: u64, x1: u64, x2: u64, x3: u64) -> (u64, u64, u64, u64) {
-    let r0;
-    let r1;
-    let r2;
-    let r3;
+///
+/// Older firmwares may corrupt x4-x17 (SMCCC v1.0), they are all declared as outputs so that
+/// no host state is kept in them across the call.
+pub unsafe fn smc_call(regs: &mut [u64; SMCCC_NUM_REGS]) {
     unsafe {
         asm!(
             "smc #0",
-            inout("x0") x0 => r0,
-            inout("x1") x1 => r1,
-            inout("x2") x2 => r2,
-            inout("x3") x3 => r3,
+            inout("x0") regs[0],
+            inout("x1") regs[1],
+            inout("x2") regs[2],
+            inout("x3") regs[3],
+            inout("x4") regs[4],
+            inout("x5") regs[5],
+            inout("x6") regs[6],
+            inout("x7") regs[7],
+            inout("x8") regs[8],
+            inout("x9") regs[9],
+            inout("x10") regs[10],
+            inout("x11") regs[11],
+            inout("x12") regs[12],
+            inout("x13") regs[13],
+            inout("x14") regs[14],
+            inout("x15") regs[15],
+            inout("x16") regs[16],
+            inout("x17") regs[17],
             options(nomem, nostack)
         );
     }
-    (r0, r1, r2, r3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yielding_calls_are_not_forwarded() {
+        // A SiP fast call, then the same function as a yielding call.
+        let fast = SMCCC_FAST_CALL | (SMCCC_OWNER_SIP << 24) | 0x10;
+        let yielding = fast & !SMCCC_FAST_CALL;
+        for policy in [
+            SmcForwardPolicy::All,
+            SmcForwardPolicy::Owners(1 << SMCCC_OWNER_SIP),
+        ] {
+            assert!(policy.allows(fast));
+            assert!(!policy.allows(yielding));
+        }
+        assert!(!SmcForwardPolicy::Nothing.allows(fast));
+    }
 }
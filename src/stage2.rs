@@ -0,0 +1,202 @@
+//! Software walk of the stage-2 translation tables of the guest.
+
+use axaddrspace::{AxMmHal, GuestPhysAddr, HostPhysAddr};
+use axerrno::{AxResult, ax_err};
+
+/// `VTTBR_EL2.BADDR`, bits [47:1].
+pub const VTTBR_BADDR_MASK: u64 = 0x0000_ffff_ffff_fffe;
+/// The output address of the table, block and page descriptors, bits [47:12].
+const DESC_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+/// Descriptor bit [0], set for valid descriptors.
+const DESC_VALID: u64 = 1 << 0;
+/// Descriptor bit [1], set for table descriptors at levels 0 to 2 and page descriptors at
+/// level 3, clear for block descriptors.
+const DESC_TABLE_OR_PAGE: u64 = 1 << 1;
+/// The `MemAttr` field of the block and page descriptors, bits [5:2].
+const DESC_MEMATTR_SHIFT: u32 = 2;
+const DESC_MEMATTR_MASK: u64 = 0b1111 << DESC_MEMATTR_SHIFT;
+/// `S2AP[0]` of the block and page descriptors, bit [6], set if the guest may read.
+const DESC_S2AP_READ: u64 = 1 << 6;
+/// `S2AP[1]` of the block and page descriptors, bit [7], set if the guest may write.
+const DESC_S2AP_WRITE: u64 = 1 << 7;
+
+/// Memory types of stage-2 mappings, see [`Aarch64VCpu::stage2_memattr`].
+///
+/// [`Aarch64VCpu::stage2_memattr`]: crate::Aarch64VCpu::stage2_memattr
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage2MemAttr {
+    /// Device-nGnRnE memory.
+    DeviceNGnRnE,
+    /// Device-nGnRE memory, the usual type of device MMIO regions.
+    DeviceNGnRE,
+    /// Normal Non-cacheable memory.
+    NormalNonCacheable,
+    /// Normal Inner and Outer Write-Back Cacheable memory.
+    NormalWriteBack,
+}
+
+impl Stage2MemAttr {
+    /// Returns the `MemAttr` field encoding of the type, which depends on whether stage-2
+    /// forced write-back (`HCR_EL2.FWB`) is enabled.
+    ///
+    /// Without FWB, the stricter of the stage-1 and stage-2 types applies. With FWB, the
+    /// Normal types are forced over the stage-1 ones. In both cases, the Device types are
+    /// forced whatever the guest maps the IPA as.
+    pub const fn encoding(self, fwb: bool) -> u64 {
+        match (self, fwb) {
+            (Self::DeviceNGnRnE, _) => 0b0000,
+            (Self::DeviceNGnRE, _) => 0b0001,
+            (Self::NormalNonCacheable, false) => 0b0101,
+            (Self::NormalNonCacheable, true) => 0b0101,
+            (Self::NormalWriteBack, false) => 0b1111,
+            (Self::NormalWriteBack, true) => 0b0110,
+        }
+    }
+}
+
+/// Finds the leaf descriptor mapping `ipa` in the stage-2 tables of `vttbr_el2`, in the
+/// format set up by the vCPU (4KiB granule, 48-bit IPA space, starting at level 0).
+///
+/// Returns the descriptor and its level, `None` if `ipa` is not mapped.
+fn walk<M: AxMmHal>(vttbr_el2: u64, ipa: u64) -> Option<(*mut u64, usize)> {
+    let mut table = vttbr_el2 & VTTBR_BADDR_MASK;
+
+    for level in 0..4 {
+        let entry = table + ((ipa >> level_shift(level)) & 0x1ff) * 8;
+        let entry = M::phys_to_virt(HostPhysAddr::from(entry as usize)).as_usize() as *mut u64;
+        let desc = unsafe { entry.read_volatile() };
+        if desc & DESC_VALID == 0 {
+            return None;
+        }
+
+        let is_table_or_page = desc & DESC_TABLE_OR_PAGE != 0;
+        if level == 3 || !is_table_or_page {
+            // There are no level 0 blocks with the 4KiB granule, nor level 3 blocks.
+            if (level == 0 || level == 3) && !is_table_or_page {
+                return None;
+            }
+            return Some((entry, level));
+        }
+        table = desc & DESC_ADDR_MASK;
+    }
+    None
+}
+
+/// Returns the shift of the IPA bits indexing the tables of `level`, i.e. the log2 of the size
+/// mapped by an entry.
+pub const fn level_shift(level: usize) -> u32 {
+    39 - 9 * level as u32
+}
+
+/// Translates `ipa` with the stage-2 tables of `vttbr_el2`.
+///
+/// Returns `None` if `ipa` is not mapped.
+pub fn translate_ipa<M: AxMmHal>(vttbr_el2: u64, ipa: GuestPhysAddr) -> Option<HostPhysAddr> {
+    translate_leaf::<M>(vttbr_el2, ipa).map(|(hpa, _)| hpa)
+}
+
+/// Translates `ipa` with the stage-2 tables of `vttbr_el2` for an access of the VMM on behalf
+/// of the guest, e.g. to a hypercall buffer, which must be allowed to the guest itself.
+///
+/// `fwb` tells whether stage-2 forced write-back is enabled, which changes the `MemAttr`
+/// encoding. Returns `InvalidInput` if `ipa` is not mapped or not mapped as Normal memory, and
+/// `PermissionDenied` if the stage-2 permissions deny the guest the access.
+pub fn translate_ipa_access<M: AxMmHal>(
+    vttbr_el2: u64,
+    ipa: GuestPhysAddr,
+    write: bool,
+    fwb: bool,
+) -> AxResult<HostPhysAddr> {
+    let Some((hpa, desc)) = translate_leaf::<M>(vttbr_el2, ipa) else {
+        return ax_err!(InvalidInput, "guest memory not mapped");
+    };
+    let memattr = (desc & DESC_MEMATTR_MASK) >> DESC_MEMATTR_SHIFT;
+    // Device types are MemAttr 0b00xx without FWB, 0bx0xx with FWB.
+    let device = if fwb {
+        memattr & 0b0100 == 0
+    } else {
+        memattr & 0b1100 == 0
+    };
+    if device {
+        return ax_err!(InvalidInput, "guest memory not mapped as Normal memory");
+    }
+    let allowed = if write {
+        DESC_S2AP_WRITE
+    } else {
+        DESC_S2AP_READ
+    };
+    if desc & allowed == 0 {
+        return ax_err!(PermissionDenied, "stage-2 permissions deny the access");
+    }
+    Ok(hpa)
+}
+
+/// Returns the output address of `ipa` and the leaf descriptor of its mapping, `None` if not
+/// mapped.
+fn translate_leaf<M: AxMmHal>(vttbr_el2: u64, ipa: GuestPhysAddr) -> Option<(HostPhysAddr, u64)> {
+    let ipa = ipa.as_usize() as u64;
+    let (entry, level) = walk::<M>(vttbr_el2, ipa)?;
+    // The tables are only read, the VMM owns them.
+    let desc = unsafe { entry.read_volatile() };
+    let offset_mask = (1u64 << level_shift(level)) - 1;
+    let hpa = (desc & DESC_ADDR_MASK & !offset_mask) | (ipa & offset_mask);
+    Some((HostPhysAddr::from(hpa as usize), desc))
+}
+
+/// Replaces the `MemAttr` field of the stage-2 mappings of `[start, start + size)` with
+/// `memattr`, following the break-before-make sequence.
+///
+/// `flush` invalidates the TLB entries of an IPA range once its mapping has been invalidated.
+/// The range must be fully mapped, by blocks and pages wholly inside it, otherwise nothing is
+/// changed and `InvalidInput` is returned.
+///
+/// # Safety
+///
+/// Accesses of the guest to the range while its mappings are invalid fault, the caller must
+/// make sure no vCPU of the VM runs meanwhile.
+pub unsafe fn set_memattr<M: AxMmHal>(
+    vttbr_el2: u64,
+    start: GuestPhysAddr,
+    size: usize,
+    memattr: u64,
+    mut flush: impl FnMut(GuestPhysAddr, usize),
+) -> AxResult {
+    let start = start.as_usize() as u64;
+    let end = start + size as u64;
+
+    // Returns the leaf descriptor mapping `ipa` and the size it maps, if it lies wholly in the
+    // range.
+    let leaf = |ipa: u64| {
+        let (entry, level) = walk::<M>(vttbr_el2, ipa)?;
+        let mapped = 1u64 << level_shift(level);
+        (ipa & (mapped - 1) == 0 && end - ipa >= mapped).then_some((entry, mapped))
+    };
+
+    let mut ipa = start;
+    while ipa < end {
+        let Some((_, mapped)) = leaf(ipa) else {
+            return ax_err!(
+                InvalidInput,
+                "the range is not mapped by whole stage-2 blocks and pages"
+            );
+        };
+        ipa += mapped;
+    }
+
+    let mut ipa = start;
+    while ipa < end {
+        let Some((entry, mapped)) = leaf(ipa) else {
+            break;
+        };
+        unsafe {
+            let desc = entry.read_volatile();
+            entry.write_volatile(desc & !DESC_VALID);
+            flush(GuestPhysAddr::from(ipa as usize), mapped as usize);
+            let desc = (desc & !DESC_MEMATTR_MASK) | (memattr << DESC_MEMATTR_SHIFT);
+            entry.write_volatile(desc);
+        }
+        ipa += mapped;
+    }
+    unsafe { core::arch::asm!("dsb ishst", "isb") };
+    Ok(())
+}
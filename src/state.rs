@@ -0,0 +1,483 @@
+//! The versioned, self-describing format of the register state of a vCPU, exported and
+//! imported for migration, see [`Aarch64VCpu::export_state`] and
+//! [`Aarch64VCpu::import_state`].
+//!
+//! [`VCPU_STATE_DESCRIPTOR`] enumerates exactly the registers the vCPU saves and restores, with
+//! their IDs and sizes. The exported state is made of:
+//!
+//! - a header of three little-endian 32-bit words: the magic `"AVCS"`, the format version and
+//!   the number of records,
+//! - a record per register: its ID and size in bytes (32 bits each), then its value, all
+//!   little-endian.
+//!
+//! The state of the emulated devices and paravirtual interfaces (psci statistics, DCC, PMU
+//! ...) is not part of it, the VMM sets them up again on the destination.
+//!
+//! [`Aarch64VCpu::export_state`]: crate::Aarch64VCpu::export_state
+//! [`Aarch64VCpu::import_state`]: crate::Aarch64VCpu::import_state
+
+use axaddrspace::device::SysRegAddr;
+use axerrno::{AxResult, ax_err};
+
+use crate::TrapFrame;
+use crate::context_frame::{GuestSystemRegisters, validate_guest_pstate};
+use crate::sysreg::{
+    SCTLR_EL1_RES1, SYSREG_ACTLR_EL1, SYSREG_AFSR0_EL1, SYSREG_AFSR1_EL1, SYSREG_AMAIR_EL1,
+    SYSREG_CNTKCTL_EL1, SYSREG_CNTP_CTL_EL0, SYSREG_CNTP_CVAL_EL0, SYSREG_CNTV_CTL_EL0,
+    SYSREG_CNTV_CVAL_EL0, SYSREG_CNTVOFF_EL2, SYSREG_CONTEXTIDR_EL1, SYSREG_CPACR_EL1,
+    SYSREG_CSSELR_EL1, SYSREG_ELR_EL1, SYSREG_ESR_EL1, SYSREG_FAR_EL1, SYSREG_MAIR_EL1,
+    SYSREG_MDSCR_EL1, SYSREG_PAR_EL1, SYSREG_SCTLR_EL1, SYSREG_SP_EL1, SYSREG_SPSR_ABT,
+    SYSREG_SPSR_EL1, SYSREG_SPSR_FIQ, SYSREG_SPSR_IRQ, SYSREG_SPSR_UND, SYSREG_TCR_EL1,
+    SYSREG_TPIDR_EL0, SYSREG_TPIDR_EL1, SYSREG_TPIDRRO_EL0, SYSREG_TTBR0_EL1, SYSREG_TTBR1_EL1,
+    SYSREG_VBAR_EL1,
+};
+
+/// The version of the state format, bumped whenever registers are added or resized.
+///
+/// Version 2 adds the EL1 physical timer, `CSSELR_EL1`, `AFSR0_EL1`, `AFSR1_EL1`, `MDSCR_EL1`
+/// and the banked SPSRs of an AArch32 EL1.
+pub const VCPU_STATE_VERSION: u32 = 2;
+
+/// The magic word starting an exported state.
+const VCPU_STATE_MAGIC: u32 = u32::from_le_bytes(*b"AVCS");
+/// The size of the header and of a record header, in bytes.
+const VCPU_STATE_HEADER_SIZE: usize = 12;
+const VCPU_STATE_RECORD_HEADER_SIZE: usize = 8;
+
+/// The IDs of `SP_EL0`, the PC and `PSTATE`, following the ones of `x0` to `x30` (0 to 30).
+const VCPU_STATE_SP_EL0: u32 = 31;
+const VCPU_STATE_PC: u32 = 32;
+const VCPU_STATE_PSTATE: u32 = 33;
+/// Set in the IDs of the system registers, the rest being their [`SysRegAddr`].
+const VCPU_STATE_SYSREG: u32 = 1 << 31;
+
+/// A register of the vCPU state, see [`VCPU_STATE_DESCRIPTOR`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VCpuStateReg {
+    /// The ID of the register: 0 to 30 for `x0` to `x30`, 31 for `SP_EL0`, 32 for the PC and
+    /// 33 for `PSTATE`. The system registers have bit 31 set, the other bits are their
+    /// [`SysRegAddr`].
+    pub id: u32,
+    /// The size of the value saved by the vCPU, in bytes.
+    pub size: u32,
+    /// The name of the register, for diagnostics.
+    pub name: &'static str,
+}
+
+const fn core_reg(id: u32, name: &'static str) -> VCpuStateReg {
+    VCpuStateReg { id, size: 8, name }
+}
+
+const fn sys_reg(addr: SysRegAddr, size: u32, name: &'static str) -> VCpuStateReg {
+    VCpuStateReg {
+        id: VCPU_STATE_SYSREG | addr.0 as u32,
+        size,
+        name,
+    }
+}
+
+/// The registers of the vCPU state, in export order.
+const VCPU_STATE_REGS: &[VCpuStateReg] = &[
+    core_reg(0, "x0"),
+    core_reg(1, "x1"),
+    core_reg(2, "x2"),
+    core_reg(3, "x3"),
+    core_reg(4, "x4"),
+    core_reg(5, "x5"),
+    core_reg(6, "x6"),
+    core_reg(7, "x7"),
+    core_reg(8, "x8"),
+    core_reg(9, "x9"),
+    core_reg(10, "x10"),
+    core_reg(11, "x11"),
+    core_reg(12, "x12"),
+    core_reg(13, "x13"),
+    core_reg(14, "x14"),
+    core_reg(15, "x15"),
+    core_reg(16, "x16"),
+    core_reg(17, "x17"),
+    core_reg(18, "x18"),
+    core_reg(19, "x19"),
+    core_reg(20, "x20"),
+    core_reg(21, "x21"),
+    core_reg(22, "x22"),
+    core_reg(23, "x23"),
+    core_reg(24, "x24"),
+    core_reg(25, "x25"),
+    core_reg(26, "x26"),
+    core_reg(27, "x27"),
+    core_reg(28, "x28"),
+    core_reg(29, "x29"),
+    core_reg(30, "x30"),
+    core_reg(VCPU_STATE_SP_EL0, "sp_el0"),
+    core_reg(VCPU_STATE_PC, "pc"),
+    core_reg(VCPU_STATE_PSTATE, "pstate"),
+    sys_reg(SYSREG_SP_EL1, 8, "sp_el1"),
+    sys_reg(SYSREG_ELR_EL1, 8, "elr_el1"),
+    sys_reg(SYSREG_SPSR_EL1, 4, "spsr_el1"),
+    sys_reg(SYSREG_SCTLR_EL1, 4, "sctlr_el1"),
+    sys_reg(SYSREG_ACTLR_EL1, 8, "actlr_el1"),
+    sys_reg(SYSREG_CPACR_EL1, 4, "cpacr_el1"),
+    sys_reg(SYSREG_TTBR0_EL1, 8, "ttbr0_el1"),
+    sys_reg(SYSREG_TTBR1_EL1, 8, "ttbr1_el1"),
+    sys_reg(SYSREG_TCR_EL1, 8, "tcr_el1"),
+    sys_reg(SYSREG_ESR_EL1, 4, "esr_el1"),
+    sys_reg(SYSREG_FAR_EL1, 8, "far_el1"),
+    sys_reg(SYSREG_PAR_EL1, 8, "par_el1"),
+    sys_reg(SYSREG_MAIR_EL1, 8, "mair_el1"),
+    sys_reg(SYSREG_AMAIR_EL1, 8, "amair_el1"),
+    sys_reg(SYSREG_VBAR_EL1, 8, "vbar_el1"),
+    sys_reg(SYSREG_CONTEXTIDR_EL1, 4, "contextidr_el1"),
+    sys_reg(SYSREG_TPIDR_EL0, 8, "tpidr_el0"),
+    sys_reg(SYSREG_TPIDR_EL1, 8, "tpidr_el1"),
+    sys_reg(SYSREG_TPIDRRO_EL0, 8, "tpidrro_el0"),
+    sys_reg(SYSREG_CNTKCTL_EL1, 4, "cntkctl_el1"),
+    sys_reg(SYSREG_CNTV_CTL_EL0, 4, "cntv_ctl_el0"),
+    sys_reg(SYSREG_CNTV_CVAL_EL0, 8, "cntv_cval_el0"),
+    sys_reg(SYSREG_CNTVOFF_EL2, 8, "cntvoff_el2"),
+    sys_reg(SYSREG_CNTP_CTL_EL0, 4, "cntp_ctl_el0"),
+    sys_reg(SYSREG_CNTP_CVAL_EL0, 8, "cntp_cval_el0"),
+    sys_reg(SYSREG_CSSELR_EL1, 4, "csselr_el1"),
+    sys_reg(SYSREG_AFSR0_EL1, 4, "afsr0_el1"),
+    sys_reg(SYSREG_AFSR1_EL1, 4, "afsr1_el1"),
+    sys_reg(SYSREG_MDSCR_EL1, 4, "mdscr_el1"),
+    // Zero, and ignored when imported, if the crate is built without the `aarch32` feature.
+    sys_reg(SYSREG_SPSR_ABT, 4, "spsr_abt"),
+    sys_reg(SYSREG_SPSR_UND, 4, "spsr_und"),
+    sys_reg(SYSREG_SPSR_IRQ, 4, "spsr_irq"),
+    sys_reg(SYSREG_SPSR_FIQ, 4, "spsr_fiq"),
+];
+
+/// Describes the register state of a vCPU of a given format version.
+#[derive(Clone, Copy, Debug)]
+pub struct VCpuStateDescriptor {
+    /// The format version, see [`VCPU_STATE_VERSION`].
+    pub version: u32,
+    /// The registers of the state, in export order.
+    pub regs: &'static [VCpuStateReg],
+}
+
+impl VCpuStateDescriptor {
+    /// Returns the register `id`, if it is part of the state.
+    pub fn reg(&self, id: u32) -> Option<&VCpuStateReg> {
+        self.regs.iter().find(|reg| reg.id == id)
+    }
+
+    /// Returns the size of the exported state, in bytes.
+    pub fn export_size(&self) -> usize {
+        let records = self.regs.iter();
+        VCPU_STATE_HEADER_SIZE
+            + records
+                .map(|reg| VCPU_STATE_RECORD_HEADER_SIZE + reg.size as usize)
+                .sum::<usize>()
+    }
+}
+
+/// The register state of the vCPUs of this version of the crate.
+pub const VCPU_STATE_DESCRIPTOR: VCpuStateDescriptor = VCpuStateDescriptor {
+    version: VCPU_STATE_VERSION,
+    regs: VCPU_STATE_REGS,
+};
+
+/// A difference between the register state of a vCPU and the architectural reset state, see
+/// [`Aarch64VCpu::reset_state_deviations`](crate::Aarch64VCpu::reset_state_deviations).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResetStateDeviation {
+    /// The name of the register, as in [`VCPU_STATE_DESCRIPTOR`].
+    pub reg: &'static str,
+    /// The bits of the register whose reset value is defined by the architecture.
+    pub mask: u64,
+    /// The architectural reset value of these bits.
+    pub expected: u64,
+    /// The value of the register in the vCPU.
+    pub actual: u64,
+}
+
+/// The architecturally defined reset values, as `(id, mask, expected)`.
+///
+/// The fields whose reset value is UNKNOWN are left out, whatever the vCPU sets them to.
+const RESET_CHECKS: &[(u32, u64, u64)] = &[
+    // EL1h with all the exceptions masked.
+    (VCPU_STATE_PSTATE, 0x3df, 0x3c5),
+    // The MMU (M), alignment checking (A, stage-1 faults), the caches (C, I) and WXN are
+    // disabled, EL1 and EL0 are little-endian (EE, E0E), the RES1 bits are set.
+    (
+        VCPU_STATE_SYSREG | SYSREG_SCTLR_EL1.0 as u32,
+        0b111 | (1 << 12) | (1 << 19) | (0b11 << 24) | SCTLR_EL1_RES1,
+        SCTLR_EL1_RES1,
+    ),
+    // The virtual timer is disabled (ENABLE).
+    (VCPU_STATE_SYSREG | SYSREG_CNTV_CTL_EL0.0 as u32, 0b1, 0),
+    // No event stream is generated (EVNTEN).
+    (VCPU_STATE_SYSREG | SYSREG_CNTKCTL_EL1.0 as u32, 1 << 2, 0),
+];
+
+/// Returns the differences between the state held in `ctx` and `regs` and the architectural
+/// reset state.
+pub fn reset_state_deviations<'a>(
+    ctx: &'a TrapFrame,
+    regs: &'a GuestSystemRegisters,
+) -> impl Iterator<Item = ResetStateDeviation> + 'a {
+    RESET_CHECKS
+        .iter()
+        .filter_map(move |&(id, mask, expected)| {
+            let actual = read_reg(ctx, regs, id)?;
+            let reg = VCPU_STATE_DESCRIPTOR.reg(id)?.name;
+            (actual & mask != expected).then_some(ResetStateDeviation {
+                reg,
+                mask,
+                expected,
+                actual,
+            })
+        })
+}
+
+fn read_reg(ctx: &TrapFrame, regs: &GuestSystemRegisters, id: u32) -> Option<u64> {
+    match id {
+        0..=30 => Some(ctx.gpr[id as usize]),
+        VCPU_STATE_SP_EL0 => Some(ctx.sp_el0),
+        VCPU_STATE_PC => Some(ctx.elr),
+        VCPU_STATE_PSTATE => Some(ctx.spsr),
+        _ => regs.migrated_sysreg(SysRegAddr::new((id & !VCPU_STATE_SYSREG) as usize)),
+    }
+}
+
+fn write_reg(ctx: &mut TrapFrame, regs: &mut GuestSystemRegisters, id: u32, value: u64) {
+    match id {
+        0..=30 => ctx.gpr[id as usize] = value,
+        VCPU_STATE_SP_EL0 => ctx.sp_el0 = value,
+        VCPU_STATE_PC => ctx.elr = value,
+        VCPU_STATE_PSTATE => ctx.spsr = value,
+        _ => {
+            regs.set_migrated_sysreg(SysRegAddr::new((id & !VCPU_STATE_SYSREG) as usize), value);
+        }
+    }
+}
+
+/// The magic word starting a [`VCpuSnapshot`], `"AVSN"`.
+pub const VCPU_SNAPSHOT_MAGIC: u32 = u32::from_le_bytes(*b"AVSN");
+
+/// A checkpoint of the whole state of a stopped vCPU, see
+/// [`Aarch64VCpu::save_state`](crate::Aarch64VCpu::save_state).
+///
+/// Unlike the exported state, the layout is fixed for a [`VCPU_STATE_VERSION`], whatever the
+/// features the crate is built with, so the snapshot can be copied around as plain bytes
+/// between the builds of a version, e.g. to checkpoint a guest or migrate it between hosts.
+/// The fields of the features the crate is built without are zero.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct VCpuSnapshot {
+    /// [`VCPU_SNAPSHOT_MAGIC`].
+    pub magic: u32,
+    /// [`VCPU_STATE_VERSION`].
+    pub version: u32,
+    /// The values of the registers of [`VCPU_STATE_DESCRIPTOR`], in its order.
+    pub regs: [u64; VCPU_STATE_REGS.len()],
+    /// Zero, the explicit padding of the header and `regs` to a multiple of 16 bytes, the
+    /// alignment of `fp_q`.
+    pub _reserved: [u64; VCPU_SNAPSHOT_PADDING],
+    /// `Q0`-`Q31` of the guest.
+    pub fp_q: [u128; 32],
+    /// `FPCR` of the guest.
+    pub fpcr: u64,
+    /// `FPSR` of the guest.
+    pub fpsr: u64,
+    /// The virtual IRQ and FIQ lines asserted by the VMM, `HCR_EL2.VI` (bit 7) and `HCR_EL2.VF`
+    /// (bit 6), and the pending virtual SError, `HCR_EL2.VSE` (bit 8). The syndrome of the
+    /// latter is not saved, it is IMPLEMENTATION DEFINED once restored.
+    pub virq_lines: u64,
+    /// Whether an interrupt has been injected since the guest was last entered, 0 or 1.
+    pub irq_pending: u64,
+    /// `ICH_HCR_EL2` of the vCPU, 0 if it doesn't use the virtual CPU interface.
+    pub ich_hcr: u64,
+    /// `ICH_VMCR_EL2` of the vCPU.
+    pub ich_vmcr: u64,
+    /// `ICH_AP0R<n>_EL2` of the vCPU.
+    pub ich_ap0r: [u64; 4],
+    /// `ICH_AP1R<n>_EL2` of the vCPU.
+    pub ich_ap1r: [u64; 4],
+    /// `ICH_LR<n>_EL2` of the vCPU, the interrupts injected in the list registers.
+    pub ich_lrs: [u64; 16],
+}
+
+/// The number of words padding the header and the registers of a [`VCpuSnapshot`].
+const VCPU_SNAPSHOT_PADDING: usize = (VCPU_STATE_REGS.len() + 1) % 2;
+
+// The layout has no implicit padding, which would be left uninitialized in the copies.
+const _: () = {
+    use core::mem::{offset_of, size_of};
+    assert!(offset_of!(VCpuSnapshot, fp_q).is_multiple_of(16));
+    assert!(
+        offset_of!(VCpuSnapshot, fp_q)
+            == offset_of!(VCpuSnapshot, _reserved) + VCPU_SNAPSHOT_PADDING * 8
+    );
+    assert!(size_of::<VCpuSnapshot>() == offset_of!(VCpuSnapshot, ich_lrs) + 16 * 8);
+};
+
+impl VCpuSnapshot {
+    /// Returns an empty snapshot of the current version.
+    pub(crate) const fn new() -> Self {
+        Self {
+            magic: VCPU_SNAPSHOT_MAGIC,
+            version: VCPU_STATE_VERSION,
+            regs: [0; VCPU_STATE_REGS.len()],
+            _reserved: [0; VCPU_SNAPSHOT_PADDING],
+            fp_q: [0; 32],
+            fpcr: 0,
+            fpsr: 0,
+            virq_lines: 0,
+            irq_pending: 0,
+            ich_hcr: 0,
+            ich_vmcr: 0,
+            ich_ap0r: [0; 4],
+            ich_ap1r: [0; 4],
+            ich_lrs: [0; 16],
+        }
+    }
+
+    /// Returns `InvalidData` if this is not a snapshot, or `Unsupported` if it is not one of
+    /// the current version.
+    pub(crate) fn check(&self) -> AxResult {
+        if self.magic != VCPU_SNAPSHOT_MAGIC {
+            return ax_err!(InvalidData, "not a vCPU snapshot");
+        }
+        if self.version != VCPU_STATE_VERSION {
+            warn!(
+                "vCPU snapshot version {} instead of {VCPU_STATE_VERSION}",
+                self.version
+            );
+            return ax_err!(Unsupported, "vCPU snapshot of another version");
+        }
+        Ok(())
+    }
+}
+
+/// Saves the registers held in `ctx` and `regs` to `snapshot`.
+pub fn snapshot_regs(ctx: &TrapFrame, regs: &GuestSystemRegisters, snapshot: &mut VCpuSnapshot) {
+    for (value, reg) in snapshot.regs.iter_mut().zip(VCPU_STATE_REGS) {
+        *value = read_reg(ctx, regs, reg.id).unwrap_or_default();
+    }
+}
+
+/// Checks that the registers saved to `snapshot` can be restored, i.e. that the guest EL1,
+/// AArch32 one if `aarch32_el1`, can be entered with its `PSTATE`.
+pub fn check_snapshot_regs(snapshot: &VCpuSnapshot, aarch32_el1: bool) -> AxResult {
+    let pstate = VCPU_STATE_REGS
+        .iter()
+        .position(|reg| reg.id == VCPU_STATE_PSTATE)
+        .map(|n| snapshot.regs[n])
+        .unwrap_or_default();
+    validate_guest_pstate(pstate, aarch32_el1)
+}
+
+/// Restores the registers saved to `snapshot` into `ctx` and `regs`.
+pub fn restore_snapshot_regs(
+    ctx: &mut TrapFrame,
+    regs: &mut GuestSystemRegisters,
+    snapshot: &VCpuSnapshot,
+) {
+    for (value, reg) in snapshot.regs.iter().zip(VCPU_STATE_REGS) {
+        write_reg(ctx, regs, reg.id, *value);
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Exports the state held in `ctx` and `regs` to `buf`, returning its size.
+pub fn export_state(
+    ctx: &TrapFrame,
+    regs: &GuestSystemRegisters,
+    buf: &mut [u8],
+) -> AxResult<usize> {
+    let size = VCPU_STATE_DESCRIPTOR.export_size();
+    let Some(buf) = buf.get_mut(..size) else {
+        return ax_err!(InvalidInput, "the buffer is too small for the vCPU state");
+    };
+
+    let mut offset = 0;
+    let mut put = |bytes: &[u8]| {
+        buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+        offset += bytes.len();
+    };
+    put(&VCPU_STATE_MAGIC.to_le_bytes());
+    put(&VCPU_STATE_VERSION.to_le_bytes());
+    put(&(VCPU_STATE_REGS.len() as u32).to_le_bytes());
+    for reg in VCPU_STATE_REGS {
+        let value = read_reg(ctx, regs, reg.id)
+            .unwrap_or_default()
+            .to_le_bytes();
+        put(&reg.id.to_le_bytes());
+        put(&reg.size.to_le_bytes());
+        put(&value[..reg.size as usize]);
+    }
+    Ok(size)
+}
+
+/// Imports the state exported to `buf` into `ctx` and `regs`.
+///
+/// The whole state is checked before anything is imported: a state of a later version,
+/// holding a register this version doesn't know about, or with another size, is rejected.
+/// The registers missing from a state of an earlier version keep their current values. A
+/// `PSTATE` the guest can't be entered with, see `validate_guest_pstate`, is rejected too.
+pub fn import_state(
+    ctx: &mut TrapFrame,
+    regs: &mut GuestSystemRegisters,
+    buf: &[u8],
+    aarch32_el1: bool,
+) -> AxResult {
+    let (Some(magic), Some(version), Some(count)) =
+        (read_u32(buf, 0), read_u32(buf, 4), read_u32(buf, 8))
+    else {
+        return ax_err!(InvalidData, "truncated vCPU state header");
+    };
+    if magic != VCPU_STATE_MAGIC {
+        return ax_err!(InvalidData, "not a vCPU state");
+    }
+    if version > VCPU_STATE_VERSION {
+        warn!("vCPU state version {version} is newer than {VCPU_STATE_VERSION}");
+        return ax_err!(Unsupported, "vCPU state of a later version");
+    }
+
+    // Returns the records of the state, as `(id, value)` pairs.
+    let records = || {
+        let mut offset = VCPU_STATE_HEADER_SIZE;
+        (0..count).map(move |_| {
+            let (Some(id), Some(size)) = (read_u32(buf, offset), read_u32(buf, offset + 4)) else {
+                return ax_err!(InvalidData, "truncated vCPU state record");
+            };
+            offset += VCPU_STATE_RECORD_HEADER_SIZE;
+            let Some(reg) = VCPU_STATE_DESCRIPTOR.reg(id) else {
+                warn!("Unknown register {id:#x} in the vCPU state");
+                return ax_err!(Unsupported, "unknown register in the vCPU state");
+            };
+            if reg.size != size {
+                warn!(
+                    "Register {} is {size} bytes long in the vCPU state",
+                    reg.name
+                );
+                return ax_err!(InvalidData, "register size mismatch in the vCPU state");
+            }
+            let Some(bytes) = buf.get(offset..offset + size as usize) else {
+                return ax_err!(InvalidData, "truncated vCPU state record");
+            };
+            offset += size as usize;
+            let mut value = [0; 8];
+            value[..bytes.len()].copy_from_slice(bytes);
+            Ok((id, u64::from_le_bytes(value)))
+        })
+    };
+
+    for record in records() {
+        let (id, value) = record?;
+        if id == VCPU_STATE_PSTATE {
+            validate_guest_pstate(value, aarch32_el1)?;
+        }
+    }
+    for (id, value) in records().flatten() {
+        write_reg(ctx, regs, id, value);
+    }
+    Ok(())
+}
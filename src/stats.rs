@@ -0,0 +1,68 @@
+//! Per-vCPU event counters and rate limiting of the related diagnostics.
+
+/// Number of occurrences of an event that are always logged, before rate limiting kicks in.
+const LOG_BURST: u64 = 8;
+
+/// Counters of guest misbehaviour events of a vCPU, see [`Aarch64VCpu::stats`].
+///
+/// The warnings about these events are rate limited, so that a misbehaving guest can't flood
+/// the host log, the counters are always accurate though.
+///
+/// [`Aarch64VCpu::stats`]: crate::Aarch64VCpu::stats
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Aarch64VCpuStats {
+    /// Reads of the write-only system registers the vCPU emulates, i.e. `ICC_SGI1R_EL1`, which
+    /// are RAZ.
+    ///
+    /// Neither the other registers the vCPU emulates as RAZ/WI by design, nor the accesses
+    /// left to the VMM, nor the ones injecting an Undefined Instruction exception are counted.
+    pub unhandled_sysreg: u64,
+    /// Accesses to IMPLEMENTATION DEFINED registers that are ignored by the vCPU (RAZ/WI).
+    pub imp_def_sysreg: u64,
+    /// PSCI calls to functions which are not supported.
+    pub unsupported_psci: u64,
+    /// SMCs which are not allowed to be forwarded to EL3.
+    pub denied_smc: u64,
+    /// MMIO accesses ignored by the VMM, see [`Aarch64VCpu::note_ignored_mmio`].
+    ///
+    /// [`Aarch64VCpu::note_ignored_mmio`]: crate::Aarch64VCpu::note_ignored_mmio
+    pub ignored_mmio: u64,
+    /// Power offs requested by the guest through psci.
+    pub system_offs: u64,
+    /// Resets requested by the guest through psci, a high rate of them is the sign of a
+    /// crash/reboot loop.
+    pub system_resets: u64,
+    /// `WFE` spin loops of the guest turned into yields, see
+    /// [`Aarch64VCpuSetupConfig::wfe_spin`].
+    ///
+    /// [`Aarch64VCpuSetupConfig::wfe_spin`]: crate::Aarch64VCpuSetupConfig::wfe_spin
+    pub wfe_yields: u64,
+    /// `WFI`s of the guest reported as halts, see [`Aarch64VCpuSetupConfig::trap_wfi`].
+    ///
+    /// [`Aarch64VCpuSetupConfig::trap_wfi`]: crate::Aarch64VCpuSetupConfig::trap_wfi
+    pub wfi_halts: u64,
+    /// Cache maintenance instructions to the PoU of the guest, trapped when its code changes
+    /// are tracked, see [`Aarch64VCpuSetupConfig::code_maintenance`].
+    ///
+    /// [`Aarch64VCpuSetupConfig::code_maintenance`]: crate::Aarch64VCpuSetupConfig::code_maintenance
+    pub code_maintenance: u64,
+    /// Accesses of a guest hypervisor to the EL2 generic timers, answered with an Undefined
+    /// Instruction exception.
+    pub el2_timer_undef: u64,
+    /// Cache maintenance instructions of the guest to unmapped IPAs, e.g. dcache flushes over
+    /// MMIO windows, skipped rather than reported as MMIO accesses.
+    pub skipped_cache_maintenance: u64,
+    /// Physical SErrors taken while the guest ran, see [`GuestSError`].
+    ///
+    /// [`GuestSError`]: crate::GuestSError
+    pub serrors: u64,
+}
+
+/// Bumps an event counter and returns whether this occurrence of the event should be logged.
+///
+/// The first [`LOG_BURST`] occurrences are logged, then only the ones whose count is a power
+/// of two.
+pub(crate) fn count_event(counter: &mut u64) -> bool {
+    *counter = counter.saturating_add(1);
+    *counter <= LOG_BURST || counter.is_power_of_two()
+}
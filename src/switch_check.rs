@@ -0,0 +1,108 @@
+//! Validation of the world switch invariants at each guest entry and exit, with the
+//! `switch-checks` feature.
+//!
+//! A regression of the stack handling or of the structure layouts the world switch relies on
+//! otherwise corrupts memory far from its cause. The checks panic on the first violated
+//! invariant instead, at the entry or exit breaking it.
+
+use core::arch::asm;
+use core::fmt;
+
+use aarch64_cpu::registers::{DAIF, Readable, SP_EL0, VBAR_EL2};
+
+unsafe extern "C" {
+    fn exception_vector_base_vcpu();
+}
+
+/// `DAIF.I` and `DAIF.F`, bits [7] and [6].
+const DAIF_IF: u64 = 0b11 << 6;
+/// `DAIF.D`, `DAIF.A`, `DAIF.I` and `DAIF.F`, bits [9:6], all set by the exceptions taken to
+/// EL2.
+const DAIF_ALL: u64 = 0b1111 << 6;
+/// The size of the host context pushed by `save_regs_to_stack!` in `run_guest()`.
+const HOST_CONTEXT_SIZE: u64 = 12 * 8;
+
+/// Reads the stack pointer, of the caller as the function is inlined.
+#[inline(always)]
+fn current_sp() -> u64 {
+    let sp: u64;
+    unsafe { asm!("mov {}, sp", out(reg) sp) };
+    sp
+}
+
+#[track_caller]
+fn check(holds: bool, invariant: &str, found: fmt::Arguments) {
+    if !holds {
+        panic!("world switch invariant violated: {invariant}, found {found}");
+    }
+}
+
+fn check_vbar() {
+    let vbar = VBAR_EL2.get();
+    check(
+        vbar == exception_vector_base_vcpu as usize as u64,
+        "VBAR_EL2 is the vector table of the vCPU",
+        format_args!("VBAR_EL2 {vbar:#x}"),
+    );
+}
+
+/// The host state captured when entering the guest, checked when it exits.
+pub struct SwitchSnapshot {
+    sp: u64,
+    sp_el0: u64,
+}
+
+impl SwitchSnapshot {
+    /// Checks the invariants of a guest entry and captures the host state, before the host
+    /// `SP_EL0` is saved.
+    #[inline(always)]
+    pub fn entry() -> Self {
+        check_vbar();
+        let daif = DAIF.get();
+        check(
+            daif & DAIF_IF == DAIF_IF,
+            "IRQs and FIQs are masked across the world switch",
+            format_args!("DAIF {daif:#x}"),
+        );
+        Self {
+            sp: current_sp(),
+            sp_el0: SP_EL0.get(),
+        }
+    }
+
+    /// Checks the invariants of a guest exit, right after `run_guest()` has returned.
+    ///
+    /// `host_stack_top` is the host stack pointer saved by `run_guest()`, which must be just
+    /// below the stack frame of `run()`.
+    #[inline(always)]
+    pub fn exit(&self, host_stack_top: u64) {
+        let sp = current_sp();
+        check(
+            sp == self.sp,
+            "the host stack pointer is restored",
+            format_args!("sp {sp:#x} instead of {:#x}", self.sp),
+        );
+        check(
+            host_stack_top + HOST_CONTEXT_SIZE == self.sp,
+            "the host context is saved at the top of the host stack",
+            format_args!("host_stack_top {host_stack_top:#x}, sp {:#x}", self.sp),
+        );
+        check_vbar();
+        let daif = DAIF.get();
+        check(
+            daif & DAIF_ALL == DAIF_ALL,
+            "the exit is taken as an exception to EL2",
+            format_args!("DAIF {daif:#x}"),
+        );
+    }
+
+    /// Checks that the host `SP_EL0` has been restored, once the exit has been handled.
+    pub fn host_sp_el0_restored(&self) {
+        let sp_el0 = SP_EL0.get();
+        check(
+            sp_el0 == self.sp_el0,
+            "the host SP_EL0 round-trips",
+            format_args!("SP_EL0 {sp_el0:#x} instead of {:#x}", self.sp_el0),
+        );
+    }
+}
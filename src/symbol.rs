@@ -0,0 +1,63 @@
+//! Annotation of guest addresses with the symbols of the guest, in the diagnostics of the vCPU.
+
+use core::fmt;
+
+/// A symbol of the guest, see [`GuestSymbolizer`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestSymbol<'a> {
+    /// The name of the symbol.
+    pub name: &'a str,
+    /// The offset of the address in the symbol.
+    pub offset: u64,
+}
+
+/// Resolves a guest virtual address to the symbol containing it, e.g. from the symbol map of
+/// the guest kernel, see [`Aarch64VCpu::set_guest_symbolizer`].
+///
+/// It is called on failure paths, including panics, so it must neither block nor panic.
+///
+/// [`Aarch64VCpu::set_guest_symbolizer`]: crate::Aarch64VCpu::set_guest_symbolizer
+pub type GuestSymbolizer = &'static (dyn Fn(u64) -> Option<GuestSymbol<'static>> + Send + Sync);
+
+/// The symbolizer of a vCPU, if the VMM has registered one.
+#[derive(Clone, Copy, Default)]
+pub struct Symbolizer(pub Option<GuestSymbolizer>);
+
+impl Symbolizer {
+    /// Returns `addr`, to be displayed with its symbol.
+    pub fn addr(self, addr: u64) -> SymbolizedAddr {
+        SymbolizedAddr {
+            addr,
+            symbolizer: self,
+        }
+    }
+}
+
+impl fmt::Debug for Symbolizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Symbolizer")
+            .field(&self.0.map(|_| ".."))
+            .finish()
+    }
+}
+
+/// A guest virtual address, displayed as `0xffff800080012345 <do_idle+0x24>` if its symbol is
+/// known.
+pub struct SymbolizedAddr {
+    addr: u64,
+    symbolizer: Symbolizer,
+}
+
+impl fmt::Display for SymbolizedAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.addr)?;
+        match self
+            .symbolizer
+            .0
+            .and_then(|symbolizer| symbolizer(self.addr))
+        {
+            Some(symbol) => write!(f, " <{}+{:#x}>", symbol.name, symbol.offset),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,497 @@
+//! Encodings and in-crate emulation of trapped system registers.
+
+use axaddrspace::device::SysRegAddr;
+
+/// Builds the [`SysRegAddr`] of a system register from its `MRS`/`MSR` encoding.
+///
+/// The numbering follows the ISS layout of trapped `MSR`/`MRS` instructions, formatted as
+/// `<op0><op2><op1><CRn>00000<CRm>0`.
+pub const fn sysreg_addr(op0: usize, op1: usize, crn: usize, crm: usize, op2: usize) -> SysRegAddr {
+    SysRegAddr::new((op0 << 20) | (op2 << 17) | (op1 << 14) | (crn << 10) | (crm << 1))
+}
+
+/// Splits a [`SysRegAddr`] back into its `(op0, op1, CRn, CRm, op2)` encoding, the inverse of
+/// [`sysreg_addr`].
+pub const fn sysreg_encoding(addr: SysRegAddr) -> (usize, usize, usize, usize, usize) {
+    let addr = addr.0;
+    (
+        (addr >> 20) & 0b11,
+        (addr >> 14) & 0b111,
+        (addr >> 10) & 0b1111,
+        (addr >> 1) & 0b1111,
+        (addr >> 17) & 0b111,
+    )
+}
+
+/// CTR_EL0, Cache Type Register.
+pub const SYSREG_CTR_EL0: SysRegAddr = sysreg_addr(3, 3, 0, 0, 1);
+/// CCSIDR_EL1, Current Cache Size ID Register.
+pub const SYSREG_CCSIDR_EL1: SysRegAddr = sysreg_addr(3, 1, 0, 0, 0);
+/// CLIDR_EL1, Cache Level ID Register.
+pub const SYSREG_CLIDR_EL1: SysRegAddr = sysreg_addr(3, 1, 0, 0, 1);
+/// CCSIDR2_EL1, Current Cache Size ID Register 2 (FEAT_CCIDX).
+pub const SYSREG_CCSIDR2_EL1: SysRegAddr = sysreg_addr(3, 1, 0, 0, 2);
+/// CSSELR_EL1, Cache Size Selection Register.
+pub const SYSREG_CSSELR_EL1: SysRegAddr = sysreg_addr(3, 2, 0, 0, 0);
+/// SCTLR_EL1, System Control Register (EL1).
+pub const SYSREG_SCTLR_EL1: SysRegAddr = sysreg_addr(3, 0, 1, 0, 0);
+/// ACTLR_EL1, Auxiliary Control Register (EL1).
+pub const SYSREG_ACTLR_EL1: SysRegAddr = sysreg_addr(3, 0, 1, 0, 1);
+/// CPACR_EL1, Architectural Feature Access Control Register.
+pub const SYSREG_CPACR_EL1: SysRegAddr = sysreg_addr(3, 0, 1, 0, 2);
+/// TTBR0_EL1, Translation Table Base Register 0 (EL1).
+pub const SYSREG_TTBR0_EL1: SysRegAddr = sysreg_addr(3, 0, 2, 0, 0);
+/// TTBR1_EL1, Translation Table Base Register 1 (EL1).
+pub const SYSREG_TTBR1_EL1: SysRegAddr = sysreg_addr(3, 0, 2, 0, 1);
+/// TCR_EL1, Translation Control Register (EL1).
+pub const SYSREG_TCR_EL1: SysRegAddr = sysreg_addr(3, 0, 2, 0, 2);
+/// SPSR_EL1, Saved Program Status Register (EL1).
+pub const SYSREG_SPSR_EL1: SysRegAddr = sysreg_addr(3, 0, 4, 0, 0);
+/// ELR_EL1, Exception Link Register (EL1).
+pub const SYSREG_ELR_EL1: SysRegAddr = sysreg_addr(3, 0, 4, 0, 1);
+/// SP_EL1, Stack Pointer (EL1).
+pub const SYSREG_SP_EL1: SysRegAddr = sysreg_addr(3, 4, 4, 1, 0);
+/// SPSR_irq, Saved Program Status Register (IRQ mode), of an AArch32 EL1.
+pub const SYSREG_SPSR_IRQ: SysRegAddr = sysreg_addr(3, 4, 4, 3, 0);
+/// SPSR_abt, Saved Program Status Register (Abort mode), of an AArch32 EL1.
+pub const SYSREG_SPSR_ABT: SysRegAddr = sysreg_addr(3, 4, 4, 3, 1);
+/// SPSR_und, Saved Program Status Register (Undefined mode), of an AArch32 EL1.
+pub const SYSREG_SPSR_UND: SysRegAddr = sysreg_addr(3, 4, 4, 3, 2);
+/// SPSR_fiq, Saved Program Status Register (FIQ mode), of an AArch32 EL1.
+pub const SYSREG_SPSR_FIQ: SysRegAddr = sysreg_addr(3, 4, 4, 3, 3);
+/// AFSR0_EL1, Auxiliary Fault Status Register 0 (EL1).
+pub const SYSREG_AFSR0_EL1: SysRegAddr = sysreg_addr(3, 0, 5, 1, 0);
+/// AFSR1_EL1, Auxiliary Fault Status Register 1 (EL1).
+pub const SYSREG_AFSR1_EL1: SysRegAddr = sysreg_addr(3, 0, 5, 1, 1);
+/// ESR_EL1, Exception Syndrome Register (EL1).
+pub const SYSREG_ESR_EL1: SysRegAddr = sysreg_addr(3, 0, 5, 2, 0);
+/// FAR_EL1, Fault Address Register (EL1).
+pub const SYSREG_FAR_EL1: SysRegAddr = sysreg_addr(3, 0, 6, 0, 0);
+/// PAR_EL1, Physical Address Register.
+pub const SYSREG_PAR_EL1: SysRegAddr = sysreg_addr(3, 0, 7, 4, 0);
+/// VBAR_EL1, Vector Base Address Register (EL1).
+pub const SYSREG_VBAR_EL1: SysRegAddr = sysreg_addr(3, 0, 12, 0, 0);
+/// MAIR_EL1, Memory Attribute Indirection Register (EL1).
+pub const SYSREG_MAIR_EL1: SysRegAddr = sysreg_addr(3, 0, 10, 2, 0);
+/// AMAIR_EL1, Auxiliary Memory Attribute Indirection Register (EL1).
+pub const SYSREG_AMAIR_EL1: SysRegAddr = sysreg_addr(3, 0, 10, 3, 0);
+/// CONTEXTIDR_EL1, Context ID Register (EL1).
+pub const SYSREG_CONTEXTIDR_EL1: SysRegAddr = sysreg_addr(3, 0, 13, 0, 1);
+/// TPIDR_EL0, EL0 Read/Write Software Thread ID Register.
+pub const SYSREG_TPIDR_EL0: SysRegAddr = sysreg_addr(3, 3, 13, 0, 2);
+/// TPIDRRO_EL0, EL0 Read-Only Software Thread ID Register.
+pub const SYSREG_TPIDRRO_EL0: SysRegAddr = sysreg_addr(3, 3, 13, 0, 3);
+/// TPIDR_EL1, EL1 Software Thread ID Register.
+pub const SYSREG_TPIDR_EL1: SysRegAddr = sysreg_addr(3, 0, 13, 0, 4);
+/// CNTKCTL_EL1, Counter-timer Kernel Control Register.
+pub const SYSREG_CNTKCTL_EL1: SysRegAddr = sysreg_addr(3, 0, 14, 1, 0);
+/// CNTP_CTL_EL0, Counter-timer Physical Timer Control Register.
+pub const SYSREG_CNTP_CTL_EL0: SysRegAddr = sysreg_addr(3, 3, 14, 2, 1);
+/// CNTP_CVAL_EL0, Counter-timer Physical Timer CompareValue Register.
+pub const SYSREG_CNTP_CVAL_EL0: SysRegAddr = sysreg_addr(3, 3, 14, 2, 2);
+/// CNTV_CTL_EL0, Counter-timer Virtual Timer Control Register.
+pub const SYSREG_CNTV_CTL_EL0: SysRegAddr = sysreg_addr(3, 3, 14, 3, 1);
+/// CNTV_CVAL_EL0, Counter-timer Virtual Timer CompareValue Register.
+pub const SYSREG_CNTV_CVAL_EL0: SysRegAddr = sysreg_addr(3, 3, 14, 3, 2);
+/// CNTVOFF_EL2, Counter-timer Virtual Offset Register.
+pub const SYSREG_CNTVOFF_EL2: SysRegAddr = sysreg_addr(3, 4, 14, 0, 3);
+/// DC ZVA, Data Cache Zero by VA, trapped as a system register write of the VA.
+pub const SYSREG_DC_ZVA: SysRegAddr = sysreg_addr(1, 3, 7, 4, 1);
+/// IC IALLUIS, Instruction Cache Invalidate All to PoU, Inner Shareable, trapped as a system
+/// register write.
+pub const SYSREG_IC_IALLUIS: SysRegAddr = sysreg_addr(1, 0, 7, 1, 0);
+/// IC IALLU, Instruction Cache Invalidate All to PoU, trapped as a system register write.
+pub const SYSREG_IC_IALLU: SysRegAddr = sysreg_addr(1, 0, 7, 5, 0);
+/// IC IVAU, Instruction Cache Invalidate by VA to PoU, trapped as a system register write of
+/// the VA.
+pub const SYSREG_IC_IVAU: SysRegAddr = sysreg_addr(1, 3, 7, 5, 1);
+/// DC CVAU, Data Cache Clean by VA to PoU, trapped as a system register write of the VA.
+pub const SYSREG_DC_CVAU: SysRegAddr = sysreg_addr(1, 3, 7, 11, 1);
+/// LORSA_EL1, LORegion Start Address (EL1).
+pub const SYSREG_LORSA_EL1: SysRegAddr = sysreg_addr(3, 0, 10, 4, 0);
+/// LOREA_EL1, LORegion End Address (EL1).
+pub const SYSREG_LOREA_EL1: SysRegAddr = sysreg_addr(3, 0, 10, 4, 1);
+/// LORN_EL1, LORegion Number (EL1).
+pub const SYSREG_LORN_EL1: SysRegAddr = sysreg_addr(3, 0, 10, 4, 2);
+/// LORC_EL1, LORegion Control (EL1).
+pub const SYSREG_LORC_EL1: SysRegAddr = sysreg_addr(3, 0, 10, 4, 3);
+/// LORID_EL1, LORegionID (EL1).
+pub const SYSREG_LORID_EL1: SysRegAddr = sysreg_addr(3, 0, 10, 4, 7);
+/// MDCCINT_EL1, Monitor DCC Interrupt Enable Register.
+pub const SYSREG_MDCCINT_EL1: SysRegAddr = sysreg_addr(2, 0, 0, 2, 0);
+/// MDSCR_EL1, Monitor Debug System Control Register.
+pub const SYSREG_MDSCR_EL1: SysRegAddr = sysreg_addr(2, 0, 0, 2, 2);
+/// MDCCSR_EL0, Monitor DCC Status Register.
+pub const SYSREG_MDCCSR_EL0: SysRegAddr = sysreg_addr(2, 3, 0, 1, 0);
+/// DBGDTR_EL0, Debug Data Transfer Register, half-duplex.
+pub const SYSREG_DBGDTR_EL0: SysRegAddr = sysreg_addr(2, 3, 0, 4, 0);
+/// DBGDTRRX_EL0, Debug Data Transfer Register, Receive, accessed by `MRS`.
+pub const SYSREG_DBGDTRRX_EL0: SysRegAddr = sysreg_addr(2, 3, 0, 5, 0);
+/// DBGDTRTX_EL0, Debug Data Transfer Register, Transmit, accessed by `MSR`.
+///
+/// It shares its encoding with [`SYSREG_DBGDTRRX_EL0`], the direction of the access tells
+/// them apart.
+pub const SYSREG_DBGDTRTX_EL0: SysRegAddr = sysreg_addr(2, 3, 0, 5, 0);
+/// MPAMIDR_EL1, MPAM ID Register (EL1).
+pub const SYSREG_MPAMIDR_EL1: SysRegAddr = sysreg_addr(3, 0, 10, 4, 4);
+/// MPAM1_EL1, MPAM1 Register (EL1).
+pub const SYSREG_MPAM1_EL1: SysRegAddr = sysreg_addr(3, 0, 10, 5, 0);
+/// MPAM0_EL1, MPAM0 Register (EL1).
+pub const SYSREG_MPAM0_EL1: SysRegAddr = sysreg_addr(3, 0, 10, 5, 1);
+/// TRFCR_EL1, Trace Filter Control Register (EL1).
+pub const SYSREG_TRFCR_EL1: SysRegAddr = sysreg_addr(3, 0, 1, 2, 1);
+/// TRBIDR_EL1, Trace Buffer ID Register.
+pub const SYSREG_TRBIDR_EL1: SysRegAddr = sysreg_addr(3, 0, 9, 11, 7);
+/// PMCR_EL0, Performance Monitors Control Register.
+#[cfg(feature = "vpmu")]
+pub const SYSREG_PMCR_EL0: SysRegAddr = sysreg_addr(3, 3, 9, 12, 0);
+/// PMCNTENSET_EL0, Performance Monitors Count Enable Set Register.
+#[cfg(feature = "vpmu")]
+pub const SYSREG_PMCNTENSET_EL0: SysRegAddr = sysreg_addr(3, 3, 9, 12, 1);
+/// PMCNTENCLR_EL0, Performance Monitors Count Enable Clear Register.
+#[cfg(feature = "vpmu")]
+pub const SYSREG_PMCNTENCLR_EL0: SysRegAddr = sysreg_addr(3, 3, 9, 12, 2);
+/// PMOVSCLR_EL0, Performance Monitors Overflow Flag Status Clear Register.
+#[cfg(feature = "vpmu")]
+pub const SYSREG_PMOVSCLR_EL0: SysRegAddr = sysreg_addr(3, 3, 9, 12, 3);
+/// PMSELR_EL0, Performance Monitors Event Counter Selection Register.
+#[cfg(feature = "vpmu")]
+pub const SYSREG_PMSELR_EL0: SysRegAddr = sysreg_addr(3, 3, 9, 12, 5);
+/// PMCCNTR_EL0, Performance Monitors Cycle Count Register.
+#[cfg(feature = "vpmu")]
+pub const SYSREG_PMCCNTR_EL0: SysRegAddr = sysreg_addr(3, 3, 9, 13, 0);
+/// PMXEVTYPER_EL0, Performance Monitors Selected Event Type Register.
+#[cfg(feature = "vpmu")]
+pub const SYSREG_PMXEVTYPER_EL0: SysRegAddr = sysreg_addr(3, 3, 9, 13, 1);
+/// PMUSERENR_EL0, Performance Monitors User Enable Register.
+#[cfg(feature = "vpmu")]
+pub const SYSREG_PMUSERENR_EL0: SysRegAddr = sysreg_addr(3, 3, 9, 14, 0);
+/// PMOVSSET_EL0, Performance Monitors Overflow Flag Status Set Register.
+#[cfg(feature = "vpmu")]
+pub const SYSREG_PMOVSSET_EL0: SysRegAddr = sysreg_addr(3, 3, 9, 14, 3);
+/// PMCCFILTR_EL0, Performance Monitors Cycle Count Filter Register.
+#[cfg(feature = "vpmu")]
+pub const SYSREG_PMCCFILTR_EL0: SysRegAddr = sysreg_addr(3, 3, 14, 15, 7);
+/// ICC_SGI1R_EL1, Interrupt Controller Software Generated Interrupt Group 1 Register.
+pub const SYSREG_ICC_SGI1R_EL1: SysRegAddr = sysreg_addr(3, 0, 12, 11, 5);
+
+/// The bits of `SCTLR_EL1` which are RES1 in Armv8.0: EOS, TSCXT, EIS, SPAN, nTLSMD and
+/// LSMAOE. They keep the Armv8.0 behaviour when set on later architecture versions.
+pub const SCTLR_EL1_RES1: u64 =
+    (1 << 11) | (1 << 20) | (1 << 22) | (1 << 23) | (1 << 28) | (1 << 29);
+
+/// Returns whether `addr` is one of the FEAT_LOR registers, trapped by `HCR_EL2.TLOR`.
+///
+/// They are emulated as RAZ/WI, i.e. no LORegion is implemented (`LORID_EL1` reads as 0) and
+/// the guest can't change the ordering of host memory.
+pub const fn is_lor_sysreg(addr: SysRegAddr) -> bool {
+    matches!(
+        addr,
+        SYSREG_LORSA_EL1 | SYSREG_LOREA_EL1 | SYSREG_LORN_EL1 | SYSREG_LORC_EL1 | SYSREG_LORID_EL1
+    )
+}
+
+/// Returns whether `addr` is one of the MPAM registers of the guest, trapped by `MPAM2_EL2`.
+///
+/// They are emulated as RAZ/WI, i.e. the guest sees no PARTID beyond the default one and
+/// can't change the partition its traffic is tagged with.
+pub const fn is_mpam_sysreg(addr: SysRegAddr) -> bool {
+    matches!(
+        addr,
+        SYSREG_MPAMIDR_EL1 | SYSREG_MPAM1_EL1 | SYSREG_MPAM0_EL1
+    )
+}
+
+/// Returns whether `addr` is one of the FEAT_TRBE registers (`TRB*_EL1`), trapped when
+/// `MDCR_EL2.E2TB` makes EL2 the owner of the trace buffer.
+pub const fn is_trbe_sysreg(addr: SysRegAddr) -> bool {
+    let (op0, op1, crn, crm, _) = sysreg_encoding(addr);
+    op0 == 3 && op1 == 0 && crn == 9 && crm == 11
+}
+
+/// Emulates the read of a FEAT_TRBE register by a guest the trace buffer is not available to.
+///
+/// `TRBIDR_EL1` reports that programming the trace buffer is not allowed (`P`, bit [4]), which
+/// makes guests (Linux included) leave it alone even if `ID_AA64DFR0_EL1` advertises it. The
+/// other registers are RAZ/WI.
+pub const fn trbe_sysreg_read(addr: SysRegAddr) -> u64 {
+    const TRBIDR_EL1_P: u64 = 1 << 4;
+    match addr {
+        SYSREG_TRBIDR_EL1 => TRBIDR_EL1_P,
+        _ => 0,
+    }
+}
+
+/// Returns whether `addr` is one of the PMUv3 registers trapped by `MDCR_EL2.TPM`: the
+/// `PM*_EL0` ones, `PMINTENSET_EL1`, `PMINTENCLR_EL1` and `PMMIR_EL1`, and the event counters
+/// and types.
+#[cfg(feature = "vpmu")]
+pub const fn is_pmu_sysreg(addr: SysRegAddr) -> bool {
+    let (op0, op1, crn, crm, _) = sysreg_encoding(addr);
+    op0 == 3
+        && ((crn == 9 && ((op1 == 3 && crm >= 12) || (op1 == 0 && crm == 14)))
+            || (crn == 14 && op1 == 3 && crm >= 8))
+}
+
+/// Returns whether `addr` is in the IMPLEMENTATION DEFINED register space, i.e. is encoded with
+/// `op0` = 3 and `CRn` = 11 or 15 (`S3_<op1>_C11_*` and `S3_<op1>_C15_*`), trapped by
+/// `HCR_EL2.TIDCP`.
+pub const fn is_imp_def_sysreg(addr: SysRegAddr) -> bool {
+    let (op0, _, crn, _, _) = sysreg_encoding(addr);
+    op0 == 3 && (crn == 11 || crn == 15)
+}
+
+/// Returns whether `addr` is one of the registers of the EL2 generic timers, the `CNTHP_*`,
+/// `CNTHV_*`, `CNTHPS_*` and `CNTHVS_*` ones, i.e. is encoded with `op0` = 3, `op1` = 4,
+/// `CRn` = 14 and `CRm` = 2 to 5. `CNTHCTL_EL2` and `CNTVOFF_EL2` are not timers.
+pub const fn is_el2_timer_sysreg(addr: SysRegAddr) -> bool {
+    let (op0, op1, crn, crm, _) = sysreg_encoding(addr);
+    op0 == 3 && op1 == 4 && crn == 14 && matches!(crm, 2..=5)
+}
+
+/// How the trapped accesses of the guest to the IMPLEMENTATION DEFINED registers are handled,
+/// see [`Aarch64VCpuSetupConfig::imp_def_sysreg`].
+///
+/// [`Aarch64VCpuSetupConfig::imp_def_sysreg`]: crate::Aarch64VCpuSetupConfig::imp_def_sysreg
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ImpDefSysRegPolicy {
+    /// The registers are RAZ/WI, the accesses are counted in
+    /// [`Aarch64VCpuStats::imp_def_sysreg`](crate::Aarch64VCpuStats::imp_def_sysreg) and
+    /// logged.
+    #[default]
+    RazWi,
+    /// The accesses are reported to the VMM, as any other unknown register.
+    Report,
+}
+
+/// How the cache maintenance to the PoU of the guest is handled, which is how it makes the code
+/// it writes visible to instruction fetches, see
+/// [`Aarch64VCpuSetupConfig::code_maintenance`].
+///
+/// Only the maintenance instructions can be trapped, the `ISB`s completing the sequences
+/// can't.
+///
+/// [`Aarch64VCpuSetupConfig::code_maintenance`]: crate::Aarch64VCpuSetupConfig::code_maintenance
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CodeMaintenancePolicy {
+    /// The maintenance is not trapped.
+    #[default]
+    NotTrapped,
+    /// `IC IALLUIS`, `IC IALLU`, `IC IVAU` and `DC CVAU` are trapped (`HCR_EL2.TPU`),
+    /// performed by the vCPU and counted in
+    /// [`Aarch64VCpuStats::code_maintenance`](crate::Aarch64VCpuStats::code_maintenance).
+    Track,
+    /// The maintenance is trapped and performed as with [`CodeMaintenancePolicy::Track`], then
+    /// reported as an [`AxVCpuExitReason::SysRegWrite`] to [`SYSREG_IC_IALLUIS`],
+    /// [`SYSREG_IC_IALLU`], [`SYSREG_IC_IVAU`] or [`SYSREG_DC_CVAU`], with the virtual address
+    /// as the value, e.g. for the VMM to reinstate its software breakpoints.
+    ///
+    /// [`AxVCpuExitReason::SysRegWrite`]: axvcpu::AxVCpuExitReason::SysRegWrite
+    Report,
+}
+
+/// The emulation of a trapped system register by the VMM, see [`SysRegEntry`].
+#[derive(Clone, Copy)]
+pub enum SysRegEmulation {
+    /// Reads return the value and writes are ignored, e.g. for an ID register.
+    Constant(u64),
+    /// Reads return zero and writes are ignored.
+    RazWi,
+    /// The access is reported to the VMM as an [`AxVCpuExitReason::SysRegRead`] or
+    /// [`AxVCpuExitReason::SysRegWrite`], bypassing the emulation of the vCPU itself.
+    ///
+    /// [`AxVCpuExitReason::SysRegRead`]: axvcpu::AxVCpuExitReason::SysRegRead
+    /// [`AxVCpuExitReason::SysRegWrite`]: axvcpu::AxVCpuExitReason::SysRegWrite
+    Report,
+    /// The handler is called in EL2 with the written value, or `None` for a read, and returns
+    /// the value read, ignored for a write, or `None` to report the access to the VMM.
+    Handler(&'static (dyn Fn(SysRegAddr, Option<u64>) -> Option<u64> + Send + Sync)),
+}
+
+impl core::fmt::Debug for SysRegEmulation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Constant(value) => f.debug_tuple("Constant").field(value).finish(),
+            Self::RazWi => f.write_str("RazWi"),
+            Self::Report => f.write_str("Report"),
+            Self::Handler(_) => f.write_str("Handler(..)"),
+        }
+    }
+}
+
+/// An entry of the system register dispatch table of a vCPU, see
+/// [`Aarch64VCpuSetupConfig::sysreg_table`].
+///
+/// [`Aarch64VCpuSetupConfig::sysreg_table`]: crate::Aarch64VCpuSetupConfig::sysreg_table
+#[derive(Clone, Copy, Debug)]
+pub struct SysRegEntry {
+    /// The register, built from its `(op0, op1, CRn, CRm, op2)` encoding by [`sysreg_addr`].
+    pub addr: SysRegAddr,
+    /// How its trapped accesses are emulated.
+    pub emulation: SysRegEmulation,
+}
+
+/// Emulates an access to `addr` with the entry of `table` for it, the written value being
+/// `write`, if any.
+///
+/// Returns `None` if the access is not emulated, `Some(None)` if it must be reported to the
+/// VMM, and `Some(Some(value))` with the value read otherwise.
+pub fn sysreg_table_access(
+    table: &[SysRegEntry],
+    addr: SysRegAddr,
+    write: Option<u64>,
+) -> Option<Option<u64>> {
+    let entry = table.iter().find(|entry| entry.addr == addr)?;
+    Some(match entry.emulation {
+        SysRegEmulation::Constant(value) => Some(value),
+        SysRegEmulation::RazWi => Some(0),
+        SysRegEmulation::Report => None,
+        SysRegEmulation::Handler(handler) => handler(addr, write),
+    })
+}
+
+/// Returns whether `addr` is one of the cache maintenance instructions to the PoU trapped by
+/// `HCR_EL2.TPU`.
+pub const fn is_pou_maintenance(addr: SysRegAddr) -> bool {
+    matches!(
+        addr,
+        SYSREG_IC_IALLUIS | SYSREG_IC_IALLU | SYSREG_IC_IVAU | SYSREG_DC_CVAU
+    )
+}
+
+/// Returns whether `addr` is a debug system register, i.e. is encoded with `op0` = 2.
+///
+/// These are the registers trapped by `MDCR_EL2.TDA`, except the trace ones which are trapped
+/// by `CPTR_EL2.TTA` instead.
+pub const fn is_debug_sysreg(addr: SysRegAddr) -> bool {
+    sysreg_encoding(addr).0 == 2
+}
+
+/// Reads the Cache Type Register of the current CPU.
+pub fn host_ctr_el0() -> u64 {
+    let val: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, CTR_EL0", out(reg) val);
+    }
+    val
+}
+
+/// Sanitizes the `CTR_EL0` value requested for a guest against the one of the host, so that
+/// the guest never skips cache maintenance the hardware actually requires:
+///
+/// - IDC and DIC are only advertised if the host advertises them too,
+/// - the minimum line sizes (IminLine, DminLine, TminLine) are the smallest ones,
+/// - the granules (ERG, CWG) are the largest ones, 0 meaning "not provided" is the largest,
+/// - the other fields (L1Ip, RES1 bits) are the host ones.
+pub fn sanitize_ctr_el0(requested: u64, host: u64) -> u64 {
+    const IDC_DIC: u64 = 0b11 << 28;
+
+    let field = |val: u64, shift: u32, mask: u64| (val >> shift) & mask;
+    let min_field = |shift: u32, mask: u64| {
+        field(requested, shift, mask).min(field(host, shift, mask)) << shift
+    };
+    let max_field = |shift: u32| {
+        let (req, cur) = (field(requested, shift, 0xf), field(host, shift, 0xf));
+        if req == 0 || cur == 0 {
+            0
+        } else {
+            req.max(cur) << shift
+        }
+    };
+
+    let fields_mask = 0xf | (0xf << 16) | (0xf << 20) | (0xf << 24) | IDC_DIC | (0x3f << 32);
+    (host & !fields_mask)
+        | min_field(0, 0xf) // IminLine
+        | min_field(16, 0xf) // DminLine
+        | max_field(20) // ERG
+        | max_field(24) // CWG
+        | (requested & host & IDC_DIC)
+        | min_field(32, 0x3f) // TminLine
+}
+
+/// The virtual cache geometry presented to the guest when the cache identification registers
+/// are trapped (`HCR_EL2.TID2`).
+///
+/// Like KVM does, the geometry is synthesized from `CTR_EL0` rather than copied from the
+/// current CPU, so that it is the same on all the cores of a heterogeneous (big.LITTLE) host:
+/// a single level of separate instruction and data caches, with 1 set and 1 way each, and the
+/// minimum line sizes from `CTR_EL0`. The points of unification are consistent with
+/// `CTR_EL0.IDC`. Software using set/way maintenance still walks the whole (single line)
+/// cache, and software computing line sizes uses the minimum ones.
+#[derive(Clone, Copy, Debug)]
+pub struct VirtCacheTopology {
+    ctr_el0: u64,
+    csselr_el1: u64,
+}
+
+impl VirtCacheTopology {
+    /// CLIDR_EL1: Ctype1 = separate instruction and data caches, LoC = 1.
+    const CLIDR_EL1: u64 = 0b011 | (1 << 24);
+    /// CLIDR_EL1: LoUIS = LoUU = 1, only if cleaning to the PoU is required (`CTR_EL0.IDC` = 0).
+    const CLIDR_EL1_LOU: u64 = (1 << 21) | (1 << 27);
+    /// The writable bits of CSSELR_EL1: TnD, Level and InD.
+    const CSSELR_EL1_MASK: u64 = 0b11111;
+
+    /// Creates the topology presented with the given `CTR_EL0` value.
+    pub const fn new(ctr_el0: u64) -> Self {
+        Self {
+            ctr_el0,
+            csselr_el1: 0,
+        }
+    }
+
+    /// The synthesized CLIDR_EL1.
+    fn clidr_el1(&self) -> u64 {
+        const CTR_EL0_IDC: u64 = 1 << 28;
+        if self.ctr_el0 & CTR_EL0_IDC != 0 {
+            Self::CLIDR_EL1
+        } else {
+            Self::CLIDR_EL1 | Self::CLIDR_EL1_LOU
+        }
+    }
+
+    /// CCSIDR_EL1 of the cache currently selected by CSSELR_EL1.
+    ///
+    /// Only the line size is non-zero, which has the same layout with and without FEAT_CCIDX.
+    fn ccsidr_el1(&self) -> u64 {
+        // CSSELR_EL1.Level selects L1.
+        if self.csselr_el1 & 0b1110 != 0 {
+            return 0;
+        }
+        // The line sizes in CTR_EL0 are log2 of the number of words.
+        let min_line = if self.csselr_el1 & 0b1 != 0 {
+            self.ctr_el0 & 0xf // IminLine
+        } else {
+            (self.ctr_el0 >> 16) & 0xf // DminLine
+        };
+        // CCSIDR_EL1.LineSize is log2 of the number of bytes, minus 4.
+        min_line.saturating_sub(2)
+    }
+
+    /// Emulates a read of a cache identification register.
+    ///
+    /// Returns `None` if `addr` is not one of them.
+    pub fn read(&self, addr: SysRegAddr) -> Option<u64> {
+        match addr {
+            SYSREG_CTR_EL0 => Some(self.ctr_el0),
+            SYSREG_CLIDR_EL1 => Some(self.clidr_el1()),
+            SYSREG_CCSIDR_EL1 => Some(self.ccsidr_el1()),
+            SYSREG_CCSIDR2_EL1 => Some(0),
+            SYSREG_CSSELR_EL1 => Some(self.csselr_el1),
+            _ => None,
+        }
+    }
+
+    /// Emulates a write to a cache identification register, only CSSELR_EL1 is writable.
+    ///
+    /// Returns `false` if `addr` is not one of them.
+    pub fn write(&mut self, addr: SysRegAddr, value: u64) -> bool {
+        match addr {
+            SYSREG_CSSELR_EL1 => {
+                self.csselr_el1 = value & Self::CSSELR_EL1_MASK;
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! A tiny bare-metal guest exercising the world switch end-to-end, see `harness/`.
+//!
+//! The guest runs at EL1 with the MMU off and is position independent, it can be copied at any
+//! IPA mapped by stage-2. It performs, in order:
+//!
+//! 1. a hypercall [`TEST_GUEST_HVC_HELLO`], with `CurrentEL` in `x1`,
+//! 2. a 32-bit MMIO write of [`TEST_GUEST_MMIO_VALUE`] to [`TEST_GUEST_MMIO_ADDR`],
+//! 3. a 32-bit MMIO read from [`TEST_GUEST_MMIO_ADDR`] + 4,
+//! 4. a hypercall [`TEST_GUEST_HVC_ECHO`], with the value read by step 3 in `x1`,
+//! 5. a `WFI`, woken up by the virtual timer (PPI 27) it has armed,
+//! 6. a psci `SYSTEM_OFF` through `HVC`, then it spins forever.
+//!
+//! The IPA [`TEST_GUEST_MMIO_ADDR`] must not be mapped by stage-2.
+
+/// The hypercall number of the first hypercall of the test guest.
+pub const TEST_GUEST_HVC_HELLO: u64 = 0x4156_4350_0001;
+/// The hypercall number of the hypercall echoing the value of the MMIO read.
+pub const TEST_GUEST_HVC_ECHO: u64 = 0x4156_4350_0002;
+/// The IPA the test guest performs its MMIO accesses at.
+pub const TEST_GUEST_MMIO_ADDR: usize = 0x0a00_0000;
+/// The value the test guest writes to [`TEST_GUEST_MMIO_ADDR`].
+pub const TEST_GUEST_MMIO_VALUE: u32 = 0xdead_beef;
+/// The number of virtual timer ticks the test guest waits for in `WFI`.
+const TEST_GUEST_TIMER_TICKS: u64 = 0x1000;
+
+core::arch::global_asm!(
+    "
+    .section .rodata.arm_vcpu_test_guest, \"a\"
+    .balign 4
+    .global arm_vcpu_test_guest_start
+arm_vcpu_test_guest_start:
+    mrs     x1, CurrentEL
+    ldr     x0, ={hvc_hello}
+    hvc     #0
+
+    ldr     x2, ={mmio_addr}
+    ldr     w1, ={mmio_value}
+    str     w1, [x2]
+    ldr     w3, [x2, #4]
+    ldr     x0, ={hvc_echo}
+    mov     x1, x3
+    hvc     #0
+
+    ldr     x4, ={timer_ticks}
+    msr     cntv_tval_el0, x4
+    mov     x4, #1                  // CNTV_CTL_EL0.ENABLE, interrupt not masked.
+    msr     cntv_ctl_el0, x4
+    isb
+    wfi
+    msr     cntv_ctl_el0, xzr
+
+    ldr     x0, =0x84000008         // PSCI_SYSTEM_OFF
+    hvc     #0
+1:  b       1b
+
+    .ltorg
+    .global arm_vcpu_test_guest_end
+arm_vcpu_test_guest_end:
+    ",
+    hvc_hello = const TEST_GUEST_HVC_HELLO,
+    hvc_echo = const TEST_GUEST_HVC_ECHO,
+    mmio_addr = const TEST_GUEST_MMIO_ADDR,
+    mmio_value = const TEST_GUEST_MMIO_VALUE,
+    timer_ticks = const TEST_GUEST_TIMER_TICKS,
+);
+
+unsafe extern "C" {
+    fn arm_vcpu_test_guest_start();
+    fn arm_vcpu_test_guest_end();
+}
+
+/// Returns the code of the test guest, to be copied to the guest memory and entered at its
+/// first byte.
+pub fn test_guest_image() -> &'static [u8] {
+    let start = arm_vcpu_test_guest_start as usize;
+    let end = arm_vcpu_test_guest_end as usize;
+    unsafe { core::slice::from_raw_parts(start as *const u8, end - start) }
+}
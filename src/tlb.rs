@@ -0,0 +1,201 @@
+//! Stage-2 TLB maintenance, and emulation of the TLB maintenance instructions of the guest.
+//!
+//! All the invalidations here operate on the VMID currently programmed in `VTTBR_EL2`, the
+//! callers are responsible for loading the right one first.
+
+use core::arch::asm;
+
+use axaddrspace::GuestPhysAddr;
+use axaddrspace::device::SysRegAddr;
+
+use crate::errata::{ErratumWorkaround, needs_workaround};
+use crate::sysreg::sysreg_encoding;
+
+/// Size of the translation granule used by stage-2, see `init_vm_context` in vcpu.rs.
+const STAGE2_PAGE_SIZE: usize = 0x1000;
+
+/// Above this number of pages, invalidating the whole VMID is cheaper than invalidating the
+/// IPAs one by one.
+const MAX_TLBI_PAGES: usize = 512;
+
+/// The domain a TLB invalidation is performed on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlbScope {
+    /// Only invalidate the TLBs of the current physical CPU.
+    ///
+    /// Only correct if the VM has never run on any other physical CPU, e.g. it is pinned.
+    Local,
+    /// Broadcast the invalidation to all the physical CPUs in the inner shareable domain.
+    InnerShareable,
+}
+
+/// Invalidates the stage-2 translations of the IPA range `[start, start + size)` for the
+/// current VMID, including the trailing barriers.
+///
+/// As the TLBs may hold combined stage-1 and stage-2 entries which cannot be invalidated by
+/// IPA, all the stage-1 entries of the VMID are invalidated too, as the architecture requires.
+///
+/// # Safety
+///
+/// The caller must make sure `VTTBR_EL2` holds the VMID of the VM the range belongs to.
+pub unsafe fn flush_ipa_range(start: GuestPhysAddr, size: usize, scope: TlbScope) {
+    // The range may start and end in the middle of pages, all the pages it overlaps are
+    // invalidated.
+    let end = (start.as_usize() + size).next_multiple_of(STAGE2_PAGE_SIZE);
+    let start = start.as_usize() & !(STAGE2_PAGE_SIZE - 1);
+    let pages = (end - start) / STAGE2_PAGE_SIZE;
+    let repeat = needs_workaround(ErratumWorkaround::RepeatTlbi);
+
+    unsafe {
+        if pages > MAX_TLBI_PAGES {
+            flush_vmid(scope);
+            return;
+        }
+
+        // Make sure the page table updates are visible to the table walker.
+        match scope {
+            TlbScope::Local => asm!("dsb nshst"),
+            TlbScope::InnerShareable => asm!("dsb ishst"),
+        }
+
+        for page in 0..pages {
+            // The operand holds IPA[47:12] in its bits [35:0].
+            let operand = ((start + page * STAGE2_PAGE_SIZE) >> 12) as u64;
+            match scope {
+                TlbScope::Local => asm!("tlbi ipas2e1, {0}", in(reg) operand),
+                TlbScope::InnerShareable => asm!("tlbi ipas2e1is, {0}", in(reg) operand),
+            }
+        }
+
+        match scope {
+            TlbScope::Local => asm!("dsb nsh", "tlbi vmalle1", "dsb nsh", "isb"),
+            TlbScope::InnerShareable => asm!("dsb ish", "tlbi vmalle1is", "dsb ish", "isb"),
+        }
+        if repeat {
+            match scope {
+                TlbScope::Local => asm!("tlbi vmalle1", "dsb nsh", "isb"),
+                TlbScope::InnerShareable => asm!("tlbi vmalle1is", "dsb ish", "isb"),
+            }
+        }
+    }
+}
+
+/// Invalidates all the stage-1 and stage-2 translations of the current VMID, including the
+/// trailing barriers.
+///
+/// # Safety
+///
+/// The caller must make sure `VTTBR_EL2` holds the VMID to invalidate.
+pub unsafe fn flush_vmid(scope: TlbScope) {
+    unsafe {
+        match scope {
+            TlbScope::Local => asm!("dsb nshst", "tlbi vmalls12e1", "dsb nsh", "isb"),
+            TlbScope::InnerShareable => asm!("dsb ishst", "tlbi vmalls12e1is", "dsb ish", "isb"),
+        }
+        if needs_workaround(ErratumWorkaround::RepeatTlbi) {
+            match scope {
+                TlbScope::Local => asm!("tlbi vmalls12e1", "dsb nsh", "isb"),
+                TlbScope::InnerShareable => asm!("tlbi vmalls12e1is", "dsb ish", "isb"),
+            }
+        }
+    }
+}
+
+/// Invalidates the EL1 translations of all the VMIDs on all the physical CPUs, including the
+/// trailing barriers.
+pub fn flush_all_vmids() {
+    unsafe {
+        asm!("dsb ishst", "tlbi alle1is", "dsb ish", "isb");
+        if needs_workaround(ErratumWorkaround::RepeatTlbi) {
+            asm!("tlbi alle1is", "dsb ish", "isb");
+        }
+    }
+}
+
+/// Invalidates the stage-1 translations of the current VMID and the instruction cache on the
+/// current physical CPU, as another vCPU of the VM, or of a VM with the same VMID, may have
+/// left entries the local maintenance of the guest on its own physical CPU didn't reach.
+///
+/// # Safety
+///
+/// The caller must make sure `VTTBR_EL2` holds the VMID of the vCPU about to be entered.
+pub unsafe fn flush_local_guest_context() {
+    unsafe {
+        asm!("dsb nshst", "tlbi vmalle1", "ic iallu", "dsb nsh", "isb");
+        if needs_workaround(ErratumWorkaround::RepeatTlbi) {
+            asm!("tlbi vmalle1", "dsb nsh", "isb");
+        }
+    }
+}
+
+/// Performs an EL1 TLB maintenance instruction of the guest trapped by `HCR_EL2.TTLB`, on
+/// behalf of the guest and for the current VMID.
+///
+/// All the operations are performed with `scope`, the local ones too, as they are broadcast by
+/// `HCR_EL2.FB` when not trapped. The range operations (FEAT_TLBIRANGE) are widened to the
+/// whole ASID, or to the whole VMID if they apply to all ASIDs. The barriers issued by the guest
+/// around the instruction also wait for the operations performed here, including the repeated
+/// ones of the [`RepeatTlbi`](ErratumWorkaround::RepeatTlbi) workaround.
+///
+/// Returns `false` if `addr` is not an EL1 TLB maintenance instruction.
+///
+/// # Safety
+///
+/// The caller must make sure `VTTBR_EL2` holds the VMID of the guest, and `HCR_EL2` its
+/// configuration.
+pub unsafe fn emulate_guest_tlbi(addr: SysRegAddr, operand: u64, scope: TlbScope) -> bool {
+    const TLBI_ASID_MASK: u64 = 0xffff << 48;
+
+    let (op0, op1, crn, crm, op2) = sysreg_encoding(addr);
+    if (op0, op1, crn) != (1, 0, 8) {
+        return false;
+    }
+    let (scope, range) = match crm {
+        // Local, inner and outer shareable.
+        7 | 3 | 1 => (scope, false),
+        6 | 2 | 5 => (scope, true),
+        _ => return false,
+    };
+    let (op2, operand) = match (range, op2) {
+        (false, _) => (op2, operand),
+        // RVAE1, RVALE1: invalidate the ASID.
+        (true, 1 | 5) => (2, operand & TLBI_ASID_MASK),
+        // RVAAE1, RVAALE1: invalidate the VMID.
+        (true, 3 | 7) => (0, 0),
+        (true, _) => return false,
+    };
+
+    trace!("Guest TLBI {addr:?} {operand:#x} performed with {scope:?}");
+
+    let passes = if needs_workaround(ErratumWorkaround::RepeatTlbi) {
+        2
+    } else {
+        1
+    };
+    for pass in 0..passes {
+        unsafe {
+            if pass > 0 {
+                match scope {
+                    TlbScope::Local => asm!("dsb nsh"),
+                    TlbScope::InnerShareable => asm!("dsb ish"),
+                }
+            }
+            match (op2, scope) {
+                (0, TlbScope::Local) => asm!("tlbi vmalle1"),
+                (0, TlbScope::InnerShareable) => asm!("tlbi vmalle1is"),
+                (1, TlbScope::Local) => asm!("tlbi vae1, {}", in(reg) operand),
+                (1, TlbScope::InnerShareable) => asm!("tlbi vae1is, {}", in(reg) operand),
+                (2, TlbScope::Local) => asm!("tlbi aside1, {}", in(reg) operand),
+                (2, TlbScope::InnerShareable) => asm!("tlbi aside1is, {}", in(reg) operand),
+                (3, TlbScope::Local) => asm!("tlbi vaae1, {}", in(reg) operand),
+                (3, TlbScope::InnerShareable) => asm!("tlbi vaae1is, {}", in(reg) operand),
+                (5, TlbScope::Local) => asm!("tlbi vale1, {}", in(reg) operand),
+                (5, TlbScope::InnerShareable) => asm!("tlbi vale1is, {}", in(reg) operand),
+                (7, TlbScope::Local) => asm!("tlbi vaale1, {}", in(reg) operand),
+                (7, TlbScope::InnerShareable) => asm!("tlbi vaale1is, {}", in(reg) operand),
+                _ => return false,
+            }
+        }
+    }
+    true
+}
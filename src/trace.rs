@@ -0,0 +1,79 @@
+//! Structured trace events of the vCPUs, delivered to a backend registered by the host.
+//!
+//! The events are timestamped with the physical counter, so that a host forwarding them to a
+//! shared ring or a monitoring VM can correlate them with the guest and host activity.
+
+use aarch64_cpu::registers::{CNTPCT_EL0, Readable};
+use axerrno::{AxResult, ax_err};
+use spin::Once;
+
+use crate::exception::TrapKind;
+
+/// A trace event of a vCPU, see [`VCpuTraceBackend`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VCpuTraceEvent {
+    /// The guest is entered at `pc`.
+    Entry {
+        /// The PC the guest resumes at.
+        pc: u64,
+    },
+    /// The guest has exited to EL2.
+    Exit {
+        /// The kind of the exception the exit is caused by.
+        kind: TrapKind,
+        /// The value of `ESR_EL2`, only meaningful for synchronous exceptions.
+        esr: u64,
+    },
+    /// A virtual interrupt is injected into the guest.
+    Inject {
+        /// The interrupt number.
+        vector: u8,
+    },
+    /// The guest sends an SGI to other vCPUs, which the VMM delivers by kicking them.
+    Kick {
+        /// The SGI number.
+        intid: u64,
+        /// The affinity of the targets, as in [`AxVCpuExitReason::SendIPI`].
+        ///
+        /// [`AxVCpuExitReason::SendIPI`]: axvcpu::AxVCpuExitReason::SendIPI
+        target_cpu: u64,
+        /// The target list within the affinity.
+        target_list: u64,
+        /// Whether the SGI targets all the vCPUs but the sender instead.
+        send_to_all: bool,
+    },
+}
+
+/// The host backend receiving the trace events of all the vCPUs, see [`set_trace_backend`].
+///
+/// It is called on the hot path of the vCPUs, with the guest context already saved, and must
+/// neither block nor allocate.
+pub trait VCpuTraceBackend: Send + Sync {
+    /// Records `event` of the vCPU identified by `mpidr`, at the physical counter value
+    /// `timestamp`.
+    fn record(&self, mpidr: u64, timestamp: u64, event: VCpuTraceEvent);
+}
+
+static TRACE_BACKEND: Once<&'static dyn VCpuTraceBackend> = Once::new();
+
+/// Registers the backend receiving the trace events of the vCPUs, once.
+///
+/// Returns `AlreadyExists` if a backend has already been registered.
+pub fn set_trace_backend(backend: &'static dyn VCpuTraceBackend) -> AxResult {
+    let mut registered = false;
+    TRACE_BACKEND.call_once(|| {
+        registered = true;
+        backend
+    });
+    if !registered {
+        return ax_err!(AlreadyExists, "a trace backend is already registered");
+    }
+    Ok(())
+}
+
+/// Delivers `event` of the vCPU `mpidr` to the backend, if any.
+pub fn trace_event(mpidr: u64, event: VCpuTraceEvent) {
+    if let Some(backend) = TRACE_BACKEND.get() {
+        backend.record(mpidr, CNTPCT_EL0.get(), event);
+    }
+}
@@ -0,0 +1,79 @@
+//! Tracking of the exceptions taken by EL2 itself, e.g. the host IRQs taken while a VM exit
+//! is handled with the host IRQs unmasked, which nest on the host stack.
+//!
+//! Each of them saves its trap frame on the stack of the code it interrupts, below the frame
+//! of the exception it may itself interrupt. The frames are recorded per CPU, innermost last,
+//! so that the nesting is bounded instead of overflowing the stack, and so that a panic in an
+//! EL2 exception handler logs the whole chain of interrupted contexts.
+
+use crate::TrapFrame;
+use crate::exception::TrapKind;
+use crate::pcpu::last_entered_vcpu;
+
+/// The maximum nesting depth of the EL2 exceptions, panicking beyond.
+pub const MAX_NESTED_TRAPS: usize = 8;
+
+/// The EL2 exceptions being handled on a CPU, innermost last.
+struct NestedTraps {
+    depth: usize,
+    /// The address of the trap frame and the kind of each exception.
+    traps: [(usize, TrapKind); MAX_NESTED_TRAPS],
+}
+
+#[percpu::def_percpu]
+static NESTED_TRAPS: NestedTraps = NestedTraps {
+    depth: 0,
+    traps: [(0, TrapKind::Synchronous); MAX_NESTED_TRAPS],
+};
+
+/// Returns the number of EL2 exceptions being handled on the current CPU, 0 outside of the
+/// EL2 exception handlers.
+pub fn nested_trap_depth() -> usize {
+    unsafe { NESTED_TRAPS.current_ref_raw() }.depth
+}
+
+/// Records an EL2 exception of `kind` whose trap frame is `tf` for as long as it lives, see
+/// the module documentation.
+pub(crate) struct NestedTrapGuard(());
+
+impl NestedTrapGuard {
+    /// Records the exception, the IRQs being masked.
+    ///
+    /// Panics if [`MAX_NESTED_TRAPS`] exceptions are already being handled.
+    pub(crate) fn enter(tf: &TrapFrame, kind: TrapKind) -> Self {
+        let nested = unsafe { NESTED_TRAPS.current_ref_mut_raw() };
+        if nested.depth == MAX_NESTED_TRAPS {
+            dump_nested_traps();
+            panic!(
+                "too many nested EL2 exceptions, {kind:?} taken @{:#x}",
+                tf.elr
+            );
+        }
+        nested.traps[nested.depth] = (tf as *const TrapFrame as usize, kind);
+        nested.depth += 1;
+        Self(())
+    }
+}
+
+impl Drop for NestedTrapGuard {
+    fn drop(&mut self) {
+        let nested = unsafe { NESTED_TRAPS.current_ref_mut_raw() };
+        nested.depth -= 1;
+    }
+}
+
+/// Logs the trap frames of the EL2 exceptions being handled on the current CPU, outermost
+/// first, e.g. before a panic.
+pub(crate) fn dump_nested_traps() {
+    let nested = unsafe { NESTED_TRAPS.current_ref_raw() };
+    error!(
+        "{} nested EL2 exception(s), last entered vCPU @{:#x}",
+        nested.depth,
+        last_entered_vcpu()
+    );
+    for (n, &(frame, kind)) in nested.traps[..nested.depth].iter().enumerate() {
+        // The frames of the interrupted contexts are live on the stack until they return.
+        let tf = unsafe { &*(frame as *const TrapFrame) };
+        error!("#{n}: {kind:?} @{:#x}, frame @{frame:#x}:\n{tf}", tf.elr);
+    }
+}
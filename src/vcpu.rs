@@ -1,14 +1,168 @@
 use core::marker::PhantomData;
 
 use aarch64_cpu::registers::*;
-use axaddrspace::{GuestPhysAddr, HostPhysAddr, device::SysRegAddr};
-use axerrno::AxResult;
+use axaddrspace::device::{AccessWidth, SysRegAddr};
+use axaddrspace::{AxMmHal, GuestPhysAddr, HostPhysAddr};
+use axerrno::{AxResult, ax_err};
 use axvcpu::{AxArchVCpu, AxVCpuExitReason, AxVCpuHal};
 
 use crate::TrapFrame;
-use crate::context_frame::GuestSystemRegisters;
-use crate::exception::{TrapKind, handle_exception_sync};
-use crate::exception_utils::exception_class_value;
+use crate::context_frame::{Aarch64El1State, GuestSystemRegisters, validate_guest_pstate};
+#[cfg(feature = "aarch32")]
+use crate::cpu_feature::has_aarch32_el1;
+#[cfg(feature = "nested")]
+use crate::cpu_feature::has_feat_nv2;
+#[cfg(feature = "vgic")]
+use crate::cpu_feature::has_gicv3_sysregs;
+use crate::cpu_feature::{
+    has_feat_fgt, has_feat_lor, has_feat_mpam, has_feat_pmuv3, has_feat_pmuv3p1, has_feat_pmuv3p5,
+    has_feat_ras, has_feat_s2fwb, has_feat_trbe, has_feat_trf, host_parange,
+};
+use crate::dcc::{DccBackend, VirtDcc};
+#[cfg(feature = "aarch32")]
+use crate::decode::is_t32_wide;
+use crate::decode::{DataAbortAccess, DecodedMmioInsn, ExclusiveAccess, Writeback};
+use crate::errata::{ErratumWorkaround, needs_workaround};
+use crate::exception::{DcIvacPolicy, ExceptionState, TrapKind, handle_exception_sync, mmio_exit};
+use crate::exception_utils::{exception_class_value, translate_guest_va, try_translate_guest_va};
+#[cfg(feature = "exit-latency")]
+use crate::exit::Aarch64ExitTimestamps;
+use crate::exit::{Aarch64ExitInfo, Aarch64ExitMask, GuestSError, MaskableExit, UnhandledExit};
+use crate::fpsimd::{CPTR_EL2_TFP, FpSimdState};
+use crate::gpr::{ExitGprs, loaded_value, truncate};
+use crate::hcbuf::{GuestBuffer, HYPERCALL_BUFFER_MAX};
+use crate::heartbeat::GuestHeartbeat;
+use crate::hvstats::GuestHvStats;
+use crate::idreg::VirtIdRegs;
+use crate::mmu::Aarch64GuestMmu;
+use crate::mpam::{MpamPartition, load_guest_mpam};
+#[cfg(feature = "nested")]
+use crate::nv2::VncrPage;
+use crate::pcpu::{
+    IrqTarget, clear_resident_vcpu, irq_classifier, set_resident_vcpu, swap_last_entered_vcpu,
+};
+#[cfg(feature = "vpmu")]
+use crate::pmu::{CycleCounterFuzz, VirtPmu};
+use crate::pmu_filter::FilteredPmu;
+use crate::policy::{ExitPolicy, PolicyExit, PolicyOutcome};
+use crate::power_mmio::{PowerMmioReg, power_mmio_event};
+use crate::psci::{ResetStormLimit, SystemEvent, system_event};
+use crate::pstate::{HostDaif, HostPstate};
+use crate::pvlock::PvLockState;
+#[cfg(feature = "exit-ring")]
+use crate::ring::ExitRingProducer;
+#[cfg(feature = "run-queue")]
+use crate::runq::switch_requested;
+use crate::smc::{SmcForwardPolicy, SmcccError};
+use crate::stage2::{
+    Stage2MemAttr, VTTBR_BADDR_MASK, set_memattr, translate_ipa, translate_ipa_access,
+};
+use crate::state::{self, ResetStateDeviation, VCpuSnapshot};
+use crate::stats::{Aarch64VCpuStats, count_event};
+#[cfg(feature = "switch-checks")]
+use crate::switch_check::SwitchSnapshot;
+use crate::symbol::{GuestSymbolizer, Symbolizer};
+#[cfg(feature = "vpmu")]
+use crate::sysreg::is_pmu_sysreg;
+use crate::sysreg::{
+    CodeMaintenancePolicy, ImpDefSysRegPolicy, SCTLR_EL1_RES1, SYSREG_CSSELR_EL1, SYSREG_DC_CVAU,
+    SYSREG_FAR_EL1, SYSREG_ICC_SGI1R_EL1, SYSREG_MDSCR_EL1, SYSREG_TRFCR_EL1, SysRegEntry,
+    VirtCacheTopology, host_ctr_el0, is_el2_timer_sysreg, is_imp_def_sysreg, is_lor_sysreg,
+    is_mpam_sysreg, is_pou_maintenance, is_trbe_sysreg, sanitize_ctr_el0, sysreg_encoding,
+    sysreg_table_access, trbe_sysreg_read,
+};
+use crate::tlb::{
+    TlbScope, emulate_guest_tlbi, flush_all_vmids, flush_ipa_range, flush_local_guest_context,
+    flush_vmid,
+};
+#[cfg(feature = "tracing")]
+use crate::trace::{VCpuTraceEvent, trace_event};
+#[cfg(feature = "vgic")]
+use crate::vgic::{IrqLatencyStats, VirqState};
+use crate::vmid::{VTTBR_VMID_SHIFT, activate_vmid, allocate_vmid, vmid_of};
+use crate::vmstate::Aarch64VmArchState;
+use crate::watch::WatchedSysReg;
+use crate::wfe::{WfeSpinDetector, WfeSpinPolicy};
+
+/// `HCR_EL2.VM`, bit [0], enables stage-2 translation.
+const HCR_EL2_VM: u64 = 1 << 0;
+/// `HCR_EL2.FWB`, bit [46], forced write-back of stage-2 memory attributes (FEAT_S2FWB).
+const HCR_EL2_FWB: u64 = 1 << 46;
+/// `HCR_EL2.TID2`, bit [17], traps the cache identification registers.
+const HCR_EL2_TID2: u64 = 1 << 17;
+/// `HCR_EL2.TID3`, bit [18], traps the reads of the feature ID registers.
+const HCR_EL2_TID3: u64 = 1 << 18;
+/// `HCR_EL2.TPU`, bit [24], traps the cache maintenance instructions to the PoU.
+const HCR_EL2_TPU: u64 = 1 << 24;
+/// `HCR_EL2.TDZ`, bit [28], traps `DC ZVA`.
+const HCR_EL2_TDZ: u64 = 1 << 28;
+/// `HCR_EL2.TIDCP`, bit [20], traps the IMPLEMENTATION DEFINED registers.
+const HCR_EL2_TIDCP: u64 = 1 << 20;
+/// `HCR_EL2.TLOR`, bit [35], traps the FEAT_LOR registers.
+const HCR_EL2_TLOR: u64 = 1 << 35;
+/// `HCR_EL2.TTLB`, bit [25], traps the EL1 TLB maintenance instructions.
+const HCR_EL2_TTLB: u64 = 1 << 25;
+/// `HCR_EL2.TVM`, bit [26], traps the writes to the virtual memory control registers.
+const HCR_EL2_TVM: u64 = 1 << 26;
+/// `HCR_EL2.VI` and `HCR_EL2.VF`, bits [7] and [6], assert the virtual IRQ and FIQ lines.
+const HCR_EL2_VI_VF: u64 = (1 << 7) | (1 << 6);
+/// `HCR_EL2.VSE`, bit [8], makes a virtual SError pending.
+const HCR_EL2_VSE: u64 = 1 << 8;
+/// `HCR_EL2.FB`, bit [9], broadcasts the EL1 TLB and instruction cache maintenance of the
+/// guest to the inner shareable domain.
+const HCR_EL2_FB: u64 = 1 << 9;
+/// `HCR_EL2.AMO`, bit [5], routes the physical SErrors to EL2 and enables the virtual ones.
+const HCR_EL2_AMO: u64 = 1 << 5;
+/// The bits of `VSESR_EL2`, the `IDS` bit and the `ISS` of the virtual SErrors.
+const VSESR_EL2_MASK: u64 = (1 << 25) - 1;
+/// The `PSTATE` an AArch32 EL1 guest starts with: the Supervisor mode (`M[4:0]` = 0b10011)
+/// with asynchronous aborts, IRQs and FIQs masked (`A`, `I` and `F`, bits [8:6]).
+const AARCH32_SVC_PSTATE: u64 = 0b1_0011 | (0b111 << 6);
+/// `SPSR.T`, bit [5], set in the Thumb state of AArch32.
+const SPSR_T: u64 = 1 << 5;
+/// `HCR_EL2.TWI`, bit [13], traps `WFI`.
+const HCR_EL2_TWI: u64 = 1 << 13;
+/// `HCR_EL2.TWE`, bit [14], traps `WFE`.
+const HCR_EL2_TWE: u64 = 1 << 14;
+/// `HCR_EL2.NV`, bit [42], runs the guest hypervisor at EL1, reading EL2 from `CurrentEL`.
+#[cfg(feature = "nested")]
+const HCR_EL2_NV: u64 = 1 << 42;
+/// `HCR_EL2.NV2`, bit [45], redirects its EL2 register accesses to the page at `VNCR_EL2`.
+#[cfg(feature = "nested")]
+const HCR_EL2_NV2: u64 = 1 << 45;
+/// `HFGWTR_EL2.VBAR_EL1`, bit [38], traps the writes to `VBAR_EL1` (FEAT_FGT).
+const HFGWTR_EL2_VBAR_EL1: u64 = 1 << 38;
+/// The negative polarity bits of `HFGWTR_EL2`, bits [63:59], [57:51] and [49] (`nAMAIR2_EL1`
+/// ... `nACCDATA_EL1`), which trap the writes to their registers when clear and are set to
+/// trap nothing. They are RES0 on the hosts without the features of the registers.
+const HFGWTR_EL2_NMASK: u64 = 0xfbfa_0000_0000_0000;
+/// `MDCR_EL2.TDA`, bit [9], traps the debug registers, including the DCC ones.
+const MDCR_EL2_TDA: u64 = 1 << 9;
+/// `MDCR_EL2.TPM`, bit [6], traps the PMU registers.
+const MDCR_EL2_TPM: u64 = 1 << 6;
+/// `MDCR_EL2.HPMD`, bit [17], stops the event counters accessible to EL1 from counting at EL2.
+const MDCR_EL2_HPMD: u64 = 1 << 17;
+/// `MDCR_EL2.HCCD`, bit [23], stops the cycle counter from counting at EL2.
+const MDCR_EL2_HCCD: u64 = 1 << 23;
+/// `MDCR_EL2.E2TB`, bits [25:24], 0b00 makes EL2 own the trace buffer and traps the FEAT_TRBE
+/// registers.
+const MDCR_EL2_E2TB: u64 = 0b11 << 24;
+/// `MDCR_EL2.TTRF`, bit [19], traps the accesses to `TRFCR_EL1`.
+const MDCR_EL2_TTRF: u64 = 1 << 19;
+/// `CNTKCTL_EL1.EL0VCTEN`, bit [1], EL0 access to the virtual counter.
+const CNTKCTL_EL1_EL0VCTEN: u64 = 1 << 1;
+/// `CNTKCTL_EL1.EVNTEN`, bit [2], `EVNTDIR`, bit [3], and `EVNTI`, bits [7:4], the event stream
+/// of the virtual counter.
+const CNTKCTL_EL1_EVNT_MASK: u64 = 0xfc;
+/// `CNTKCTL_EL1.EVNTEN`, bit [2].
+const CNTKCTL_EL1_EVNTEN: u64 = 1 << 2;
+/// `CNTKCTL_EL1.EVNTI`, bits [7:4].
+const CNTKCTL_EL1_EVNTI_SHIFT: u32 = 4;
+/// `SCTLR_EL1.nTWI` and `nTWE`, bits [16] and [18], don't trap the `WFI`s and `WFE`s of EL0
+/// to EL1.
+const SCTLR_EL1_NTWI_NTWE: u64 = (1 << 16) | (1 << 18);
+/// The default `SCTLR_EL1` of the guest, see [`Aarch64VCpuSetupConfig::sctlr_el1`].
+const SCTLR_EL1_RESET: u64 = SCTLR_EL1_RES1 | SCTLR_EL1_NTWI_NTWE;
 
 #[percpu::def_percpu]
 static HOST_SP_EL0: u64 = 0;
@@ -42,12 +196,149 @@ pub struct Aarch64VCpu<H: AxVCpuHal> {
     // DO NOT add anything before or between them unless you do know what you are doing!
     ctx: TrapFrame,
     host_stack_top: u64,
+    /// The value of `CNTPCT_EL0` when the exception vector has saved the guest context, written
+    /// by `vmexit_trampoline` right after `host_stack_top`.
+    #[cfg(feature = "exit-latency")]
+    exit_vector_time: u64,
     guest_system_regs: GuestSystemRegisters,
     /// The MPIDR_EL1 value for the vCPU.
     mpidr: u64,
+    /// The ID of the VM of the vCPU, sharing its VMID.
+    vm_id: usize,
+    /// The state shared with the other vCPUs of the VM, if any.
+    vm_state: Option<&'static Aarch64VmArchState>,
+    /// The VMID of the VM, tagged with its generation, see [`allocate_vmid`].
+    vmid: u64,
+    /// Whether the TLB entries of the VMID must be invalidated on the next entry, as its
+    /// stage-2 root or VMID changed since the last one.
+    stage2_flush_pending: bool,
+    /// The exception handling state, including the counters of guest misbehaviour events.
+    exception_state: ExceptionState,
+    /// The value of `CNTPCT_EL0` when the guest was last entered.
+    last_entry: u64,
+    /// Whether the entry point has been set.
+    entry_set: bool,
+    /// Whether the configuration has been validated, by the first `run()` after the setup or
+    /// a restore.
+    validated: bool,
+    /// Information about the last VM exit.
+    last_exit: Option<Aarch64ExitInfo>,
+    /// The timestamps of the exit path of the last VM exit, until the guest is re-entered.
+    #[cfg(feature = "exit-latency")]
+    exit_timestamps: Option<Aarch64ExitTimestamps>,
+    /// The timestamps of the exit path of the last VM exit the guest has been re-entered after.
+    #[cfg(feature = "exit-latency")]
+    last_exit_timestamps: Option<Aarch64ExitTimestamps>,
+    /// The VM exits handled in EL2 instead of being returned by `run()`.
+    exit_mask: Aarch64ExitMask,
+    /// Whether an interrupt has been injected since the guest was last entered.
+    irq_pending: bool,
+    /// The syndrome of the pending virtual SError, see [`Aarch64VCpu::inject_serror`].
+    vsesr_el2: u64,
+    /// The `PSTATE` the guest starts with, restored by [`Aarch64VCpu::reset`].
+    reset_pstate: u64,
+    /// The EL1 system registers the guest starts with, restored by [`Aarch64VCpu::reset`].
+    reset_el1_state: Aarch64El1State,
+    /// The cache geometry presented to the guest, if the cache identification registers are
+    /// trapped.
+    cache_topology: Option<VirtCacheTopology>,
+    /// The feature ID registers presented to the guest, if they are trapped.
+    id_regs: Option<VirtIdRegs>,
+    /// The emulated Debug Communications Channel, if the debug registers are trapped.
+    dcc: Option<VirtDcc>,
+    /// The scope the broadcast TLB maintenance instructions of the guest are performed with,
+    /// if they are trapped.
+    tlbi_scope: Option<TlbScope>,
+    /// The mirror of the stage-1 MMU configuration of the guest, if its writes are trapped.
+    guest_mmu: Option<Aarch64GuestMmu>,
+    /// The set of [`WatchedSysReg::bit`]s of the registers whose writes are reported.
+    sysreg_watch: u32,
+    /// The subset of `sysreg_watch` which is only reported once.
+    sysreg_watch_once: u32,
+    /// The fine-grained write traps of the guest, only loaded if the host implements FEAT_FGT.
+    hfgwtr_el2: u64,
+    /// The guest `TRFCR_EL1`, `None` if the host doesn't implement FEAT_TRF.
+    trfcr_el1: Option<u64>,
+    /// Whether the guest can use self-hosted tracing, i.e. owns its `TRFCR_EL1`.
+    self_hosted_trace: bool,
+    /// The MPAM partition the traffic of the guest is tagged with, `None` if the host doesn't
+    /// implement FEAT_MPAM.
+    mpam_partition: Option<MpamPartition>,
+    /// The cycle counter only virtual PMU, if enabled.
+    #[cfg(feature = "vpmu")]
+    vpmu: Option<VirtPmu>,
+    /// The host PMU left to the guest, if its registers are trapped to keep its counters from
+    /// counting at EL2.
+    filtered_pmu: Option<FilteredPmu>,
+    /// How the trapped accesses to the IMPLEMENTATION DEFINED registers are handled.
+    imp_def_sysreg: ImpDefSysRegPolicy,
+    /// How the cache maintenance to the PoU is handled.
+    code_maintenance: CodeMaintenancePolicy,
+    /// The power and reset registers translated into psci system events.
+    power_mmio: &'static [PowerMmioReg],
+    /// Whether the host interrupt masks are reinstated while the synchronous exits are handled.
+    host_irqs_in_exits: bool,
+    /// Whether the guest EL0 is denied AArch32 execution.
+    deny_aarch32_el0: bool,
+    /// Whether the guest EL1 runs in AArch32 state, always `false` without the `aarch32`
+    /// feature.
+    aarch32_el1: bool,
+    /// The system registers emulated as configured by the VMM.
+    sysreg_table: &'static [SysRegEntry],
+    /// The FP/SIMD registers of the guest, while they are not loaded.
+    guest_fp: FpSimdState,
+    /// The FP/SIMD registers of the host, while the ones of the guest are loaded.
+    host_fp: FpSimdState,
+    /// Whether the FP/SIMD registers of the guest are loaded, since its first access in the
+    /// current `run()`.
+    guest_fp_loaded: bool,
+    /// The PC of the instruction of the last exit, and the PSTATE it has trapped with for the
+    /// IT state of a T32 guest, if it is an MMIO one which has not been replayed.
+    mmio_pc: Option<(usize, u64)>,
+    /// The mask of the GPRs modified by the VMM since the last exit, see
+    /// [`Aarch64VCpu::modified_gprs`].
+    gprs_modified: u32,
+    /// The MMIO exits left to return for the last emulated instruction.
+    split_mmio: Option<SplitMmio>,
+    /// The exit to be returned by the next `run()` without entering the guest.
+    #[cfg(feature = "synthetic-exit")]
+    synthetic_exit: Option<AxVCpuExitReason>,
+    /// The producer of the ring the MMIO writes are posted to, if attached.
+    #[cfg(feature = "exit-ring")]
+    exit_ring: Option<ExitRingProducer>,
+    /// The page of the virtual EL2 registers of the guest hypervisor, if attached.
+    #[cfg(feature = "nested")]
+    vncr_page: Option<&'static mut VncrPage>,
     _phantom: PhantomData<H>,
 }
 
+/// The MMIO exits left to return for an instruction accessing several registers or a block,
+/// which are emulated one by one.
+#[derive(Debug)]
+enum SplitMmio {
+    /// The access of the second register of a pair.
+    Exit(AxVCpuExitReason),
+    /// The `size`-byte zero writes of `DC ZVA` from `addr`.
+    Zero {
+        addr: usize,
+        size: usize,
+        remaining: usize,
+    },
+}
+
+/// Returns the MMIO exit of a zero write of `size` (4 or 8) bytes at `addr`, for `DC ZVA`.
+fn zero_write(addr: usize, size: usize) -> AxVCpuExitReason {
+    AxVCpuExitReason::MmioWrite {
+        addr: GuestPhysAddr::from(addr),
+        width: if size == 8 {
+            AccessWidth::Qword
+        } else {
+            AccessWidth::Dword
+        },
+        data: 0,
+    }
+}
+
 /// Configuration for creating a new `Aarch64VCpu`
 #[derive(Clone, Debug, Default)]
 pub struct Aarch64VCpuCreateConfig {
@@ -58,164 +349,2326 @@ pub struct Aarch64VCpuCreateConfig {
     pub mpidr_el1: u64,
     /// The address of the device tree blob.
     pub dtb_addr: usize,
+    /// Should the guest EL1 run in AArch32 state, e.g. for a 32-bit RTOS?
+    ///
+    /// The guest then starts in the Supervisor mode with the asynchronous exceptions masked,
+    /// in the Thumb state if bit 0 of its entry point is set, like on a `BX`, and its EL0 can
+    /// only run AArch32 too (`HCR_EL2.RW` is cleared). The AArch32 only EL1 registers
+    /// (`DACR`, `IFSR`, `FPEXC` and the banked SPSRs) are switched with the other ones, but not
+    /// part of the snapshots of [`Aarch64VCpu::save_state`].
+    ///
+    /// The MMIO accesses of the A32 and T32 loads and stores are reported like the A64 ones,
+    /// with the registers in their AArch64 view, `x0`-`x30`. The trapped system register
+    /// accesses (`MCR`, `MRC`...) are reported as unhandled exits. The trapped instructions
+    /// failing their condition code check are skipped, and the instructions skipped in an IT
+    /// block advance its state. The exceptions injected into the guest are taken in the
+    /// Undefined or Abort mode. The setup returns
+    /// `Unsupported` if the host EL1 can't run AArch32.
+    #[cfg(feature = "aarch32")]
+    pub aarch32_el1: bool,
+    /// The state shared with the other vCPUs of the VM, see [`Aarch64VmArchState`].
+    ///
+    /// Without it, the VMID is still shared through the ID of the VM, but the `VTCR_EL2` and
+    /// the virtual counter offset are the ones of the vCPU. With it, the `CNTVOFF_EL2` of the
+    /// imported states and snapshots is overridden by the one of the VM at the next entry.
+    /// `new()` returns `InvalidInput` if it belongs to another VM.
+    pub vm_state: Option<&'static Aarch64VmArchState>,
 }
 
 /// Configuration for setting up a new `Aarch64VCpu`
 #[derive(Clone, Debug, Default)]
 pub struct Aarch64VCpuSetupConfig {
     /// Should the hypervisor passthrough interrupts to the guest?
+    ///
+    /// This is the mode of static partitioning, with one guest per physical CPU: physical IRQs
+    /// and FIQs are taken by the guest directly (`HCR_EL2.IMO` and `FMO` clear), the guest
+    /// drives the GIC CPU interface itself and the vCPU only exits on synchronous exceptions,
+    /// which keeps the interrupt latency of real-time guests to the bare-metal one. No GIC
+    /// virtualization is involved, `inject_interrupt` fails with `BadState`.
+    ///
+    /// The host must have enabled the EL1 accesses to the system register interface of the
+    /// GIC (`ICC_SRE_EL2.Enable`), and must not rely on receiving interrupts on this CPU while
+    /// the guest runs. It is usually combined with
+    /// [`passthrough_timer`](Self::passthrough_timer).
     pub passthrough_interrupt: bool,
     /// Should the hypervisor passthrough timers to the guest?
     pub passthrough_timer: bool,
+    /// Should the guest have access to the physical counter (`CNTHCTL_EL2.EL1PCTEN`) even if
+    /// the timers are not passed through?
+    ///
+    /// Reads of `CNTPCT_EL0` are reported as [`AxVCpuExitReason::SysRegRead`] otherwise, the
+    /// virtual counter is never trapped.
+    pub passthrough_counter: bool,
+    /// The `CNTKCTL_EL1` value the guest starts with, which controls the accesses of its EL0
+    /// to the counters and timers. Subsequent writes of the guest are preserved.
+    ///
+    /// Defaults to `EL0VCTEN` only: EL0 can read the virtual counter, as used by the
+    /// `clock_gettime` fast path of the Linux vDSO, but not the physical one nor the timers.
+    /// The EL0 accesses to the physical counter still depend on
+    /// [`passthrough_counter`](Self::passthrough_counter).
+    pub cntkctl_el1: Option<u64>,
+    /// Should the latencies of the interrupts injected with [`Aarch64VCpu::inject_irq`] be
+    /// measured? See [`IrqLatencyStats`].
+    ///
+    /// The measurement costs a maintenance interrupt, thus an exit, at the acknowledgment and
+    /// at the completion of each interrupt.
+    #[cfg(feature = "vgic")]
+    pub measure_irq_latency: bool,
+    /// The event stream of the virtual counter the guest starts with, overriding the one of
+    /// [`cntkctl_el1`](Self::cntkctl_el1), for the guests bounding their `WFE` waits with it
+    /// without enabling it themselves.
+    ///
+    /// `Some(n)` generates an event at each transition of the bit `n` (0 to 15) of the virtual
+    /// counter (`CNTKCTL_EL1.EVNTEN` and `EVNTI`), see [`Aarch64VCpu::set_event_stream`]. The
+    /// event stream of the host, in `CNTHCTL_EL2`, is kept across the guest entries either way.
+    pub event_stream: Option<u8>,
+    /// The `SCTLR_EL1` value the guest starts with, e.g. for a big-endian guest (`EE`).
+    ///
+    /// Defaults to the architectural reset value: the MMU, the caches and alignment checking
+    /// are disabled and the guest is little-endian, the RES1 bits of Armv8.0 are set. The
+    /// fields whose reset value is UNKNOWN are 0, except `nTWI` and `nTWE` which are set, so
+    /// that the `WFI`s and `WFE`s of EL0 don't trap to EL1. An `SCTLR_EL1` in
+    /// [`el1_state`](Self::el1_state) takes precedence.
+    ///
+    /// Only the lower 32 bits are used, the upper ones are not context switched.
+    pub sctlr_el1: Option<u64>,
+    /// Should stage-2 translation force the memory attributes (`HCR_EL2.FWB`)?
+    ///
+    /// Only takes effect if the host implements FEAT_S2FWB, see
+    /// [`Aarch64VCpu::stage2_fwb_enabled`]. Note that the stage-2 `MemAttr` encoding changes
+    /// when it is enabled, the stage-2 page table must be built accordingly.
+    pub stage2_fwb: bool,
+    /// Should the guest run with stage-2 translation disabled (`HCR_EL2.VM` clear), seeing the
+    /// host physical memory directly?
+    ///
+    /// This suits a fully trusted control VM (like the root cell of Jailhouse) only: its
+    /// accesses to memory and devices never trap, so there is no MMIO emulation nor
+    /// containment. `HVC`, `SMC`, the trapped system registers and the interrupts still exit.
+    /// `VTTBR_EL2` then only provides the VMID tagging the TLB entries of the guest, the page
+    /// table root passed to `set_ept_root()` may be 0, and the stage-2 accessors of the vCPU
+    /// ([`Aarch64VCpu::force_stage2_device`]) return `Unsupported`.
+    pub disable_stage2: bool,
+    /// Should the cache identification registers be trapped (`HCR_EL2.TID2`), presenting the
+    /// guest a cache geometry which is the same on all the host cores?
+    ///
+    /// See [`virt_ctr_el0`](Self::virt_ctr_el0) for the line sizes.
+    pub virt_cache_topology: bool,
+    /// The `CTR_EL0` value presented to the guest, which should hold the minimum line sizes
+    /// over all the host cores. Setting it implies [`virt_cache_topology`].
+    ///
+    /// It is sanitized against the value of the CPU `setup()` is called on: IDC/DIC are only
+    /// kept if that CPU has them, line sizes are the smallest ones, granules the largest ones.
+    /// Defaults to the value of that CPU.
+    ///
+    /// [`virt_cache_topology`]: Self::virt_cache_topology
+    pub virt_ctr_el0: Option<u64>,
+    /// Should the reads of the feature ID registers be trapped (`HCR_EL2.TID3`), presenting
+    /// the guest the values set with [`Aarch64VCpu::set_id_reg`]?
+    ///
+    /// They default to the values of the CPU `setup()` is called on, without the SVE, SME,
+    /// MPAM and trace buffer features, which the vCPU doesn't virtualize.
+    pub virt_id_regs: bool,
+    /// Should `DC ZVA` be trapped (`HCR_EL2.TDZ`)?
+    ///
+    /// The guest then reads `DCZID_EL0.DZP` as 1, i.e. `DC ZVA` is prohibited, which can be used
+    /// when the block size of the host can't be honored. `DC ZVA` instructions executed anyway
+    /// are reported as [`AxVCpuExitReason::SysRegWrite`] to [`SYSREG_DC_ZVA`], with the
+    /// virtual address as the value.
+    ///
+    /// [`SYSREG_DC_ZVA`]: crate::SYSREG_DC_ZVA
+    pub trap_dc_zva: bool,
+    /// How the cache maintenance making the code changes of the guest visible to instruction
+    /// fetches is handled, not trapped by default.
+    pub code_maintenance: CodeMaintenancePolicy,
+    /// Which SMCs of the guest are forwarded to EL3, if not handled by the vCPU itself.
+    pub smc_forward: SmcForwardPolicy,
+    /// The EL1 system register state the guest starts with, instead of the cold boot one.
+    ///
+    /// Together with [`initial_pstate`](Self::initial_pstate), the general-purpose registers
+    /// (`set_gpr`) and the entry point (`set_entry`), it allows resuming a guest from a snapshot
+    /// taken in the middle of its execution.
+    pub el1_state: Option<Aarch64El1State>,
+    /// The PSTATE the guest starts with, defaults to EL1h with all exceptions masked.
+    ///
+    /// Its `M[4:0]` field must be EL1h, EL1t or EL0t, or an AArch32 mode other than Hyp and
+    /// Monitor for an AArch32 EL1 (`aarch32_el1` of the creation configuration), `setup()`
+    /// returns `InvalidInput` otherwise.
+    pub initial_pstate: Option<u64>,
+    /// Should the Debug Communications Channel be emulated (`MDCR_EL2.TDA`), and where does the
+    /// output of the guest go?
+    ///
+    /// Guests using `earlycon=dcc` or `hvc_dcc` hang on a DCC nobody drains otherwise. The
+    /// hardware debug features (breakpoints, watchpoints) are then unavailable to the guest,
+    /// their registers read as zero and ignore writes.
+    pub dcc: Option<DccBackend>,
+    /// Should the TLB maintenance instructions of the guest be trapped (`HCR_EL2.TTLB`), and
+    /// which scope are they performed with, the local ones included?
+    ///
+    /// [`TlbScope::Local`] keeps a guest whose vCPUs never leave their physical CPUs from
+    /// stalling all the cores with broadcast invalidations. The instructions are always
+    /// performed for the VMID of the guest, at the cost of an exit each.
+    pub trap_tlbi: Option<TlbScope>,
+    /// Should the writes to the virtual memory control registers be trapped (`HCR_EL2.TVM`),
+    /// maintaining a mirror of the stage-1 MMU configuration of the guest?
+    ///
+    /// See [`Aarch64VCpu::guest_mmu`]. Guests write these registers rarely, mostly at boot and
+    /// on some context switches.
+    pub track_guest_mmu: bool,
+    /// The VM exits handled with a default policy instead of being returned by `run()`,
+    /// nothing is masked by default.
+    pub exit_mask: Aarch64ExitMask,
+    /// The rate of guest resets beyond which they are reported as
+    /// [`SystemEvent::ResetStorm`], none by default.
+    pub reset_storm_limit: Option<ResetStormLimit>,
+    /// The MPAM partition the memory traffic of the guest is tagged with, on hosts
+    /// implementing FEAT_MPAM, defaults to the default partition (PARTID 0).
+    ///
+    /// MPAM is hidden from the guest in any case: its MPAM registers are RAZ/WI.
+    pub mpam_partition: Option<MpamPartition>,
+    /// Can the guest use self-hosted tracing, on hosts implementing FEAT_TRF?
+    ///
+    /// If so, its `TRFCR_EL1` is context switched, which suits a designated debug VM. Otherwise
+    /// `TRFCR_EL1` is RAZ/WI (`MDCR_EL2.TTRF`) and tracing is prohibited at EL1 and EL0 while
+    /// the guest runs, so that no trace data of it leaks to another VM.
+    pub self_hosted_trace: bool,
+    /// Should the `WFE` spin loops of the guest be turned into yields to the VMM
+    /// (`HCR_EL2.TWE`), and after how many `WFE`s at the same PC?
+    ///
+    /// The yields are reported as [`AxVCpuExitReason::Nothing`], the VMM should then schedule
+    /// the other vCPUs of its physical CPU, see [`Aarch64VCpuStats::wfe_yields`]. They can be
+    /// masked with [`MaskableExit::Wfe`].
+    pub wfe_spin: Option<WfeSpinPolicy>,
+    /// Should the `WFI`s of the guest be trapped (`HCR_EL2.TWI`) and reported as
+    /// [`AxVCpuExitReason::Halt`], so that the VMM deschedules the idle vCPU until an
+    /// interrupt is pending for it?
+    ///
+    /// Otherwise the physical CPU waits in the guest, which suits partitioned CPUs.
+    pub trap_wfi: bool,
+    /// Should the paravirtual "vCPU is preempted" interface be offered to the guest?
+    ///
+    /// The guest registers a state area through `HVC` or `SMC`, whose preempted flag is set
+    /// and cleared by `unbind()` and `bind()`, so the VMM must call them when the vCPU is
+    /// scheduled out and in. The area must be mapped by stage-2 at these times.
+    pub pv_preempted: bool,
+    /// Should the guest only be offered the PMU cycle counter, on hosts implementing PMUv3?
+    ///
+    /// If so, the PMU registers are trapped (`MDCR_EL2.TPM`) and the guest sees a PMU with no
+    /// event counter, and a cycle counter of its own, only counting while it runs at EL1 and
+    /// EL0. The host must not use the cycle counter itself. Otherwise the PMU is left to the
+    /// guest as configured by the host (`MDCR_EL2.HPMN`), its counters being kept from
+    /// counting at EL2, by `MDCR_EL2.HPMD` and `HCCD` on the hosts implementing FEAT_PMUv3p5,
+    /// and by trapping the PMU registers and clearing the EL2 filter bits the guest writes on
+    /// the others.
+    #[cfg(feature = "vpmu")]
+    pub pmu_cycle_counter: bool,
+    /// Should the cycle counter values read by the guest be quantized and fuzzed, mitigating
+    /// the cross-VM timing side channels on shared cores?
+    ///
+    /// This implies [`pmu_cycle_counter`](Self::pmu_cycle_counter). The generic timer
+    /// counters are left alone, their resolution is much lower.
+    #[cfg(feature = "vpmu")]
+    pub pmu_cycle_fuzz: Option<CycleCounterFuzz>,
+    /// Should the diagnostic `HV_STATS` hypercall be offered to the guest, letting it query
+    /// its own exit counts and stolen time?
+    pub hv_stats_hypercall: bool,
+    /// Should the `HEARTBEAT` hypercall be offered to the guest, letting the VMM detect a hung
+    /// guest, see [`Aarch64VCpu::ticks_since_heartbeat`]?
+    pub heartbeat_hypercall: bool,
+    /// How the `DC IVAC`s of the guest to the pages it can only read are handled, upgraded to
+    /// clean and invalidate by default.
+    pub dc_ivac_readonly: DcIvacPolicy,
+    /// How the trapped accesses of the guest to the IMPLEMENTATION DEFINED registers are
+    /// handled, RAZ/WI by default.
+    ///
+    /// Vendor kernels access them, e.g. to tune the core, and the accesses trapped by the
+    /// implementation specific controls of `ACTLR_EL2` would otherwise be unknown to the VMM.
+    pub imp_def_sysreg: ImpDefSysRegPolicy,
+    /// Should all the accesses of the guest to the IMPLEMENTATION DEFINED registers be trapped
+    /// (`HCR_EL2.TIDCP`), and handled as [`imp_def_sysreg`](Self::imp_def_sysreg)?
+    ///
+    /// This keeps the guest from reconfiguring the physical core it shares with other VMs.
+    pub trap_imp_def_sysreg: bool,
+    /// The power and reset registers of the platform, whose guest writes are reported as psci
+    /// system events, see [`PowerMmioReg`].
+    pub power_mmio: &'static [PowerMmioReg],
+    /// Should the host interrupt masks be reinstated while the synchronous VM exits are handled
+    /// in `run()`, letting the host take its IRQs between the guest runs, see [`HostDaif`]?
+    ///
+    /// The host IRQ handler then runs with the EL1 registers of the guest loaded, it must
+    /// neither reschedule nor touch the vCPU. Otherwise, the exits are handled with all the
+    /// exceptions masked, and the host takes its IRQs once `run()` returns.
+    pub host_irqs_in_exits: bool,
+    /// Should the guest EL0 be denied AArch32 execution, for a pure 64-bit environment?
+    ///
+    /// The architecture has no control trapping the exception returns of the guest EL1 to an
+    /// AArch32 EL0, so the denial is enforced at the first exit taken from AArch32 EL0, at the
+    /// latest the next host timer interrupt. The exit is reported as an
+    /// [`AxVCpuExitReason::FailEntry`], with its [`UnhandledExit::aarch32`] set.
+    ///
+    /// Otherwise the exits from AArch32 EL0 are handled like the AArch64 ones.
+    pub deny_aarch32_el0: bool,
+    /// The system registers emulated as configured by the VMM, looked up by encoding before
+    /// the emulation of the vCPU itself, see [`SysRegEntry`].
+    ///
+    /// Only the accesses trapped by the configuration of the vCPU reach the table, e.g. the
+    /// cache identification registers with [`virt_cache_topology`](Self::virt_cache_topology)
+    /// or the IMPLEMENTATION DEFINED ones with
+    /// [`trap_imp_def_sysreg`](Self::trap_imp_def_sysreg).
+    pub sysreg_table: &'static [SysRegEntry],
 }
 
 impl<H: AxVCpuHal> axvcpu::AxArchVCpu for Aarch64VCpu<H> {
     type CreateConfig = Aarch64VCpuCreateConfig;
 
-    type SetupConfig = Aarch64VCpuSetupConfig;
+    type SetupConfig = Aarch64VCpuSetupConfig;
+
+    fn new(vm_id: usize, _vcpu_id: usize, config: Self::CreateConfig) -> AxResult<Self> {
+        if config.vm_state.is_some_and(|vm| vm.vm_id() != vm_id) {
+            return ax_err!(InvalidInput, "the VM state belongs to another VM");
+        }
+        let mut ctx = TrapFrame::default();
+        ctx.set_argument(config.dtb_addr);
+
+        Ok(Self {
+            ctx,
+            host_stack_top: 0,
+            #[cfg(feature = "exit-latency")]
+            exit_vector_time: 0,
+            guest_system_regs: GuestSystemRegisters::default(),
+            mpidr: config.mpidr_el1,
+            vm_id,
+            vm_state: config.vm_state,
+            vmid: 0,
+            stage2_flush_pending: false,
+            exception_state: ExceptionState::default(),
+            last_entry: 0,
+            entry_set: false,
+            validated: false,
+            last_exit: None,
+            #[cfg(feature = "exit-latency")]
+            exit_timestamps: None,
+            #[cfg(feature = "exit-latency")]
+            last_exit_timestamps: None,
+            exit_mask: Aarch64ExitMask::default(),
+            irq_pending: false,
+            vsesr_el2: 0,
+            reset_pstate: 0,
+            reset_el1_state: Aarch64El1State::default(),
+            cache_topology: None,
+            id_regs: None,
+            dcc: None,
+            tlbi_scope: None,
+            guest_mmu: None,
+            trfcr_el1: None,
+            self_hosted_trace: false,
+            mpam_partition: None,
+            #[cfg(feature = "vpmu")]
+            vpmu: None,
+            filtered_pmu: None,
+            imp_def_sysreg: ImpDefSysRegPolicy::RazWi,
+            code_maintenance: CodeMaintenancePolicy::NotTrapped,
+            power_mmio: &[],
+            host_irqs_in_exits: false,
+            deny_aarch32_el0: false,
+            #[cfg(feature = "aarch32")]
+            aarch32_el1: config.aarch32_el1,
+            #[cfg(not(feature = "aarch32"))]
+            aarch32_el1: false,
+            sysreg_table: &[],
+            guest_fp: FpSimdState::default(),
+            host_fp: FpSimdState::default(),
+            guest_fp_loaded: false,
+            mmio_pc: None,
+            gprs_modified: 0,
+            split_mmio: None,
+            sysreg_watch: 0,
+            sysreg_watch_once: 0,
+            hfgwtr_el2: HFGWTR_EL2_NMASK,
+            #[cfg(feature = "synthetic-exit")]
+            synthetic_exit: None,
+            #[cfg(feature = "exit-ring")]
+            exit_ring: None,
+            #[cfg(feature = "nested")]
+            vncr_page: None,
+            _phantom: PhantomData,
+        })
+    }
+
+    fn setup(&mut self, config: Self::SetupConfig) -> AxResult {
+        #[cfg(feature = "aarch32")]
+        if self.aarch32_el1 && !has_aarch32_el1() {
+            return ax_err!(Unsupported, "the host EL1 can't run AArch32");
+        }
+        if let Some(pstate) = config.initial_pstate {
+            validate_guest_pstate(pstate, self.aarch32_el1)?;
+        }
+        self.init_hv(config);
+        self.vmid = match self.vm_state {
+            Some(vm) => vm.vmid()?,
+            None => allocate_vmid(self.vm_id)?,
+        };
+        self.load_vmid();
+        Ok(())
+    }
+
+    fn set_entry(&mut self, entry: GuestPhysAddr) -> AxResult {
+        debug!("set vcpu entry:{entry:?}");
+        self.set_entry_pc(entry);
+        self.entry_set = true;
+        Ok(())
+    }
+
+    fn set_ept_root(&mut self, ept_root: HostPhysAddr) -> AxResult {
+        debug!("set vcpu ept root:{ept_root:#x}");
+        let prev_vttbr = self.guest_system_regs.vttbr_el2;
+        self.guest_system_regs.vttbr_el2 = ept_root.as_usize() as u64;
+        self.load_vmid();
+        // The entries of the previous root are tagged with the same VMID.
+        if self.guest_system_regs.vttbr_el2 != prev_vttbr {
+            self.stage2_flush_pending = true;
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> AxResult<AxVCpuExitReason> {
+        #[cfg(feature = "synthetic-exit")]
+        if let Some(exit_reason) = self.synthetic_exit.take() {
+            return Ok(exit_reason);
+        }
+
+        if !self.validated {
+            self.validate_config()?;
+            self.validated = true;
+        }
+
+        if self.gprs_modified != 0 {
+            trace!(
+                "vCPU {:#x} GPRs modified by the VMM: {:#x}",
+                self.mpidr, self.gprs_modified
+            );
+        }
+
+        let host_daif = HostDaif::save_and_mask();
+        loop {
+            HostDaif::mask();
+            // The other accesses of an instruction emulated by several MMIO exits are returned
+            // before the guest is entered again.
+            let exit_reason = match self.next_split_mmio() {
+                Some(exit_reason) => exit_reason,
+                None => match self.enter_guest(&host_daif) {
+                    Ok(exit_reason) => exit_reason,
+                    Err(err) => {
+                        self.unload_guest_fp();
+                        host_daif.restore();
+                        return Err(err);
+                    }
+                },
+            };
+            let el = self.ctx.exception_level() as u8;
+            let resume = core::mem::take(&mut self.exception_state.resume);
+            // A masked yield resumes the guest, as a `WFE` outside of a spin loop.
+            let wfe_yield = core::mem::take(&mut self.exception_state.wfe_yield);
+            let resume =
+                resume || (wfe_yield && self.exit_mask.is_masked_from(MaskableExit::Wfe, el));
+            // The run queue of the CPU switches to another vCPU.
+            #[cfg(feature = "run-queue")]
+            let resume = resume && !switch_requested();
+            if resume {
+                continue;
+            }
+            let exit_reason =
+                match self
+                    .exception_state
+                    .policies
+                    .apply(None, exit_reason, &mut self.ctx)
+                {
+                    PolicyOutcome::Allowed { exit, .. } => exit,
+                    PolicyOutcome::Denied => continue,
+                };
+            #[cfg(feature = "tracing")]
+            if let AxVCpuExitReason::SendIPI {
+                target_cpu,
+                target_cpu_aux,
+                send_to_all,
+                vector,
+                ..
+            } = exit_reason
+            {
+                trace_event(
+                    self.mpidr,
+                    VCpuTraceEvent::Kick {
+                        intid: vector,
+                        target_cpu,
+                        target_list: target_cpu_aux,
+                        send_to_all,
+                    },
+                );
+            }
+            if !self.exit_mask.handle(&exit_reason, el, &mut self.ctx) {
+                #[cfg(feature = "exit-ring")]
+                let exit_reason = match &mut self.exit_ring {
+                    Some(producer) => match producer.post(exit_reason) {
+                        Some(exit_reason) => exit_reason,
+                        None if producer.ring().is_full() => AxVCpuExitReason::Nothing,
+                        None => continue,
+                    },
+                    None => exit_reason,
+                };
+                self.unload_guest_fp();
+                host_daif.restore();
+                #[cfg(feature = "exit-latency")]
+                if let Some(timestamps) = &mut self.exit_timestamps {
+                    timestamps.vmm_return = CNTPCT_EL0.get();
+                }
+                return Ok(exit_reason);
+            }
+        }
+    }
+
+    fn bind(&mut self) -> AxResult {
+        self.set_pv_preempted(false);
+        set_resident_vcpu(self as *const Self as usize, self.pv_preempted_flag());
+        Ok(())
+    }
+
+    fn unbind(&mut self) -> AxResult {
+        self.set_pv_preempted(true);
+        clear_resident_vcpu(self as *const Self as usize);
+        Ok(())
+    }
+
+    fn set_gpr(&mut self, idx: usize, val: usize) {
+        self.exit_gprs().set(idx, val as u64);
+    }
+
+    fn inject_interrupt(&mut self, vector: usize) -> AxResult {
+        // Virtual IRQs are only signaled to the guest if `HCR_EL2.IMO` is set, which is not the
+        // case if interrupts are passed through.
+        if !self.virtual_irq_enabled() {
+            return ax_err!(BadState, "virtual IRQs are disabled for this vCPU");
+        }
+        // The list registers of the vCPU hold the interrupt until the guest takes it, wherever
+        // the vCPU runs next.
+        #[cfg(feature = "vgic")]
+        if self.guest_system_regs.vgic.enabled() {
+            let Ok(intid) = u32::try_from(vector) else {
+                return ax_err!(InvalidInput, "interrupt vector out of range");
+            };
+            self.inject_irq(intid)?;
+            self.irq_pending = true;
+            return Ok(());
+        }
+        // Don't silently inject another interrupt than the requested one.
+        let Ok(vector) = u8::try_from(vector) else {
+            return ax_err!(InvalidInput, "interrupt vector out of range");
+        };
+
+        axvisor_api::arch::hardware_inject_virtual_interrupt(vector);
+        self.irq_pending = true;
+        #[cfg(feature = "tracing")]
+        trace_event(self.mpidr, VCpuTraceEvent::Inject { vector });
+        Ok(())
+    }
+
+    fn set_return_value(&mut self, val: usize) {
+        // Return value is stored in x0.
+        self.exit_gprs().set(0, val as u64);
+    }
+}
+
+impl<H: AxVCpuHal> Aarch64VCpu<H> {
+    /// Makes the virtual interrupt `intid` pending for the guest through a list register of the
+    /// GICv3 virtual CPU interface, saved and loaded with the other registers of the guest.
+    ///
+    /// `inject_interrupt` injects through it when the virtual CPU interface is used, and with
+    /// `hardware_inject_virtual_interrupt` of `axvisor_api` otherwise. Unlike the latter, any
+    /// INTID can be injected, several interrupts can be pending at once, and the guest
+    /// acknowledges them through its own `ICC_IAR1_EL1`. Returns
+    /// `BadState` if the virtual CPU interface is not used by the vCPU, i.e. if interrupts are
+    /// passed through or the host has no GICv3, and `ResourceBusy` if all the list registers
+    /// are in use. With an [`Aarch64VmArchState`], an SPI disabled at its distributor is not
+    /// injected, and `BadState` returned.
+    #[cfg(feature = "vgic")]
+    pub fn inject_irq(&mut self, intid: u32) -> AxResult {
+        if !self.guest_system_regs.vgic.enabled() {
+            return ax_err!(
+                BadState,
+                "the GICv3 virtual CPU interface is not used by this vCPU"
+            );
+        }
+        if self.vm_state.is_some_and(|vm| !vm.spi_enabled(intid)) {
+            return ax_err!(BadState, "the SPI is disabled at the distributor");
+        }
+        self.guest_system_regs.vgic.inject(intid)
+    }
+
+    /// Returns the interrupts injected with [`inject_irq`](Self::inject_irq) the guest hasn't
+    /// completed yet, as their INTID and state at the last exit, e.g. for a device backend to
+    /// check whether the guest has acknowledged its level-triggered interrupt.
+    ///
+    /// Nothing is returned if the virtual CPU interface is not used by the vCPU.
+    #[cfg(feature = "vgic")]
+    pub fn pending_irqs(&self) -> impl Iterator<Item = (u32, VirqState)> + '_ {
+        let vgic = &self.guest_system_regs.vgic;
+        vgic.enabled().then(|| vgic.irqs()).into_iter().flatten()
+    }
+
+    /// Rescinds the interrupt `intid` injected with [`inject_irq`](Self::inject_irq), e.g. when
+    /// a level-triggered line is deasserted or its device is removed, returning whether it was
+    /// still pending.
+    ///
+    /// The guest never sees a rescinded interrupt it hasn't acknowledged yet. One it is already
+    /// handling stays active until it completes it, see [`VirqState`]. Returns `BadState` if
+    /// the virtual CPU interface is not used by the vCPU.
+    #[cfg(feature = "vgic")]
+    pub fn rescind_irq(&mut self, intid: u32) -> AxResult<bool> {
+        if !self.guest_system_regs.vgic.enabled() {
+            return ax_err!(
+                BadState,
+                "the GICv3 virtual CPU interface is not used by this vCPU"
+            );
+        }
+        Ok(self.guest_system_regs.vgic.rescind(intid))
+    }
+
+    /// Returns the latencies of the interrupts injected with [`inject_irq`](Self::inject_irq)
+    /// measured so far, `None` if they are not, see
+    /// [`Aarch64VCpuSetupConfig::measure_irq_latency`].
+    ///
+    /// The statistics of the vCPUs of a VM can be combined with [`IrqLatencyStats::merge`].
+    #[cfg(feature = "vgic")]
+    pub fn irq_latency_stats(&self) -> Option<IrqLatencyStats> {
+        self.guest_system_regs.vgic.latency_stats()
+    }
+
+    /// Clears the interrupt latencies measured so far.
+    #[cfg(feature = "vgic")]
+    pub fn clear_irq_latency_stats(&mut self) {
+        self.guest_system_regs.vgic.clear_latency_stats();
+    }
+
+    /// Sets the value of a feature ID register read by the guest, e.g. of
+    /// [`SYSREG_ID_AA64ISAR1_EL1`](crate::SYSREG_ID_AA64ISAR1_EL1) with the PAC fields cleared.
+    ///
+    /// The VMM should only lower the feature fields from the values of the host, the guest
+    /// would otherwise use features the hardware doesn't have. Returns `BadState` if the ID
+    /// registers are not trapped, see [`Aarch64VCpuSetupConfig::virt_id_regs`], and
+    /// `InvalidInput` if `addr` is not in the trapped space (`S3_0_C0_C<1-7>_<op2>`).
+    pub fn set_id_reg(&mut self, addr: SysRegAddr, value: u64) -> AxResult {
+        let Some(id_regs) = &mut self.id_regs else {
+            return ax_err!(BadState, "the ID registers are not trapped");
+        };
+        if !id_regs.set(addr, value) {
+            return ax_err!(InvalidInput, "not a trapped ID register");
+        }
+        Ok(())
+    }
+
+    /// Returns the value of a feature ID register read by the guest, or `None` if the ID
+    /// registers are not trapped or `addr` is not one of them.
+    pub fn id_reg(&self, addr: SysRegAddr) -> Option<u64> {
+        self.id_regs.as_ref()?.read(addr)
+    }
+
+    /// Returns whether stage-2 forced write-back (`HCR_EL2.FWB`) is active for this vCPU.
+    ///
+    /// If so, the VMM can skip cache clean/invalidate when transferring pages to/from the guest.
+    pub fn stage2_fwb_enabled(&self) -> bool {
+        self.guest_system_regs.hcr_el2 & HCR_EL2_FWB != 0
+    }
+
+    /// Registers the callback annotating the guest addresses in the diagnostics of this vCPU
+    /// with the symbols of the guest, or removes it.
+    pub fn set_guest_symbolizer(&mut self, symbolizer: Option<GuestSymbolizer>) {
+        self.exception_state.symbolizer = Symbolizer(symbolizer);
+    }
+
+    /// Logs the state of the guest, the PC, LR and `ELR_EL1` annotated with their symbols, e.g.
+    /// when the VMM gives up on a crashed guest.
+    pub fn dump_state(&self) {
+        let symbolizer = self.exception_state.symbolizer;
+        let el1 = self.guest_system_regs.el1_state();
+        error!("vCPU {:#x} state:", self.mpidr);
+        error!("  pc: {}", symbolizer.addr(self.ctx.exception_pc() as u64));
+        error!("  lr: {}", symbolizer.addr(self.ctx.gpr[30]));
+        error!(
+            "  elr_el1: {}, esr_el1: {:#x}, far_el1: {:#x}",
+            symbolizer.addr(el1.elr_el1),
+            el1.esr_el1,
+            el1.far_el1
+        );
+        if let Some(exit) = &self.last_exit {
+            error!("  last exit: {:?}, esr_el2: {:#x}", exit.kind, exit.esr);
+        }
+        error!("{}", self.ctx);
+    }
+
+    /// Returns the general-purpose registers `x0` to `x30` of the guest.
+    pub fn get_gprs(&self) -> &[u64; 31] {
+        &self.ctx.gpr
+    }
+
+    /// Replaces the general-purpose registers `x0` to `x30` of the guest.
+    pub fn set_gprs(&mut self, gprs: &[u64; 31]) {
+        self.update_gprs(gprs);
+    }
+
+    /// Completes an [`AxVCpuExitReason::MmioRead`] with the `width` value `value` read from
+    /// the device, written into the guest register `reg` of `reg_width` as the load
+    /// instruction would, with the `signed_ext` of the exit as `sign_extend`.
+    ///
+    /// The bits of `value` above `width` are ignored. Writes to `xzr` (`reg` 31) are ignored.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reg` is above 31.
+    pub fn set_gpr_sized(
+        &mut self,
+        reg: usize,
+        value: u64,
+        width: AccessWidth,
+        reg_width: AccessWidth,
+        sign_extend: bool,
+    ) {
+        let value = loaded_value(value, width, reg_width, sign_extend);
+        self.exit_gprs().set(reg, value);
+    }
+
+    /// Returns the low `width` of the guest register `reg`, e.g. the data of an access of
+    /// `width` stored from it, 0 for `xzr` (`reg` 31).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reg` is above 31.
+    pub fn gpr_sized(&self, reg: usize, width: AccessWidth) -> u64 {
+        truncate(self.ctx.gpr(reg) as u64, width)
+    }
+
+    /// Replaces the general-purpose registers `x0` to `x30` of the guest, returning the mask
+    /// of the registers whose value has changed (bit `n` for `xn`).
+    ///
+    /// Debuggers and migration code can use the mask to only log or transfer the changes.
+    pub fn update_gprs(&mut self, gprs: &[u64; 31]) -> u32 {
+        let mut changed = 0;
+        for (n, (gpr, new)) in self.ctx.gpr.iter_mut().zip(gprs).enumerate() {
+            if *gpr != *new {
+                *gpr = *new;
+                changed |= 1 << n;
+            }
+        }
+        self.gprs_modified |= changed;
+        changed
+    }
+
+    /// Returns a writable view of the general-purpose registers of the guest, recording the
+    /// ones the VMM modifies until the next exit.
+    pub fn exit_gprs(&mut self) -> ExitGprs<'_> {
+        ExitGprs::new(&mut self.ctx, &mut self.gprs_modified)
+    }
+
+    /// Returns the mask of the general-purpose registers modified since the last exit (bit `n`
+    /// for `xn`), through [`Aarch64VCpu::exit_gprs`], `set_gpr()`, `set_return_value()`,
+    /// [`Aarch64VCpu::set_gprs`] or [`Aarch64VCpu::update_gprs`].
+    ///
+    /// The registers modified by the vCPU itself, e.g. when it completes an exit in EL2, are not
+    /// recorded.
+    pub fn modified_gprs(&self) -> u32 {
+        self.gprs_modified
+    }
+
+    /// Returns the stack pointer of the guest EL0 (`SP_EL0`).
+    pub fn sp_el0(&self) -> u64 {
+        self.ctx.sp_el0
+    }
+
+    /// Sets the stack pointer of the guest EL0 (`SP_EL0`), loaded at the next entry.
+    pub fn set_sp_el0(&mut self, sp_el0: u64) {
+        self.ctx.sp_el0 = sp_el0;
+    }
+
+    /// Returns the EL1 and EL0 system register state of the guest as of the last exit, e.g.
+    /// `SP_EL1`, `ELR_EL1`, `SPSR_EL1`, `TPIDR_EL0`, `TPIDR_EL1` and `TTBR0/1_EL1`.
+    pub fn el1_state(&self) -> Aarch64El1State {
+        self.guest_system_regs.el1_state()
+    }
+
+    /// Replaces the EL1 and EL0 system register state of the guest, loaded at the next entry,
+    /// e.g. for a debugger to modify it between exits.
+    ///
+    /// Registers which are 32-bit wide are truncated. To change a single register, modify the
+    /// state returned by [`el1_state`](Self::el1_state).
+    pub fn set_el1_state(&mut self, state: &Aarch64El1State) {
+        self.guest_system_regs.set_el1_state(state);
+        if let Some(mmu) = &mut self.guest_mmu {
+            mmu.sync(state);
+        }
+    }
+
+    /// Exports the register state of the vCPU to `buf` for migration, in the format described
+    /// by [`VCPU_STATE_DESCRIPTOR`](crate::VCPU_STATE_DESCRIPTOR), returning its size.
+    ///
+    /// Returns `InvalidInput` if `buf` is smaller than
+    /// [`VCpuStateDescriptor::export_size`](crate::VCpuStateDescriptor::export_size).
+    pub fn export_state(&self, buf: &mut [u8]) -> AxResult<usize> {
+        state::export_state(&self.ctx, &self.migrated_system_regs(), buf)
+    }
+
+    /// Compares the register state of the guest with the architectural reset state, to be
+    /// called after `setup()` and before the first run.
+    ///
+    /// Only the fields with an architecturally defined reset value are checked, e.g. the
+    /// `SCTLR_EL1` MMU, cache and endianness controls, but not `nTWI`. The deviations come from
+    /// the setup configuration (`initial_pstate`, `sctlr_el1`, `el1_state` ...), and explain
+    /// most of the boot differences of a guest with other hypervisors.
+    pub fn reset_state_deviations(&self) -> impl Iterator<Item = ResetStateDeviation> + '_ {
+        state::reset_state_deviations(&self.ctx, &self.guest_system_regs)
+    }
+
+    /// Imports a register state exported by [`export_state`](Self::export_state), possibly
+    /// by another version of the crate.
+    ///
+    /// Nothing is imported if the state is incompatible with this version, see
+    /// [`VCPU_STATE_DESCRIPTOR`](crate::VCPU_STATE_DESCRIPTOR), or if its `PSTATE` is not a
+    /// mode of the guest EL1, like [`initial_pstate`](Aarch64VCpuSetupConfig::initial_pstate).
+    /// With an [`Aarch64VmArchState`], the imported `CNTVOFF_EL2` becomes the virtual counter
+    /// offset of the whole VM, see
+    /// [`set_virtual_counter_offset`](Self::set_virtual_counter_offset).
+    pub fn import_state(&mut self, buf: &[u8]) -> AxResult {
+        self.guest_system_regs.cntvoff_el2 = self.virtual_counter_offset();
+        state::import_state(
+            &mut self.ctx,
+            &mut self.guest_system_regs,
+            buf,
+            self.aarch32_el1,
+        )?;
+        self.set_virtual_counter_offset(self.guest_system_regs.cntvoff_el2);
+        self.restore_emulated_sysregs();
+        if let Some(mmu) = &mut self.guest_mmu {
+            mmu.sync(&self.guest_system_regs.el1_state());
+        }
+        Ok(())
+    }
+
+    /// Resets the stopped vCPU as on a warm reset of the PE, e.g. for a psci `CPU_ON` or
+    /// `SYSTEM_RESET` handled by the VMM, without recreating it.
+    ///
+    /// The guest restarts at `entry` with `context_id` in `x0`, the other GPRs cleared, and
+    /// the `PSTATE` it was set up with. Its EL1 system registers get their reset values, the
+    /// ones from the setup configuration (`sctlr_el1`, `cntkctl_el1`...) included, its timers
+    /// are disabled, its FP/SIMD registers cleared and its pending interrupts dropped. The
+    /// paravirtual state registered by the guest, i.e. its PV lock state area, heartbeat, exit
+    /// statistics and psci suspend state, is dropped too. The EL2 configuration of the vCPU,
+    /// i.e. its traps, stage-2 and `CNTVOFF_EL2`, is kept.
+    ///
+    /// Returns `BadState` if the vCPU has not been set up.
+    pub fn reset(&mut self, entry: GuestPhysAddr, context_id: u64) -> AxResult {
+        if self.guest_system_regs.vtcr_el2 == 0 {
+            return ax_err!(BadState, "the vCPU has not been set up");
+        }
+        self.ctx = TrapFrame::default();
+        self.ctx.spsr = self.reset_pstate;
+        self.ctx.gpr[0] = context_id;
+        self.set_entry_pc(entry);
+        self.entry_set = true;
+        self.mmio_pc = None;
+        self.split_mmio = None;
+
+        let regs = &mut self.guest_system_regs;
+        regs.set_el1_state(&self.reset_el1_state);
+        regs.reset_timers();
+        #[cfg(feature = "aarch32")]
+        regs.reset_aarch32_regs();
+        regs.hcr_el2 &= !(HCR_EL2_VI_VF | HCR_EL2_VSE);
+        #[cfg(feature = "vgic")]
+        regs.vgic.reset();
+        self.irq_pending = false;
+        self.guest_fp = FpSimdState::default();
+        if let Some(mmu) = &mut self.guest_mmu {
+            mmu.sync(&self.reset_el1_state);
+        }
+
+        let state = &mut self.exception_state;
+        if let Some(pvlock) = &mut state.pvlock {
+            *pvlock = PvLockState::default();
+        }
+        if let Some(heartbeat) = &mut state.heartbeat {
+            *heartbeat = GuestHeartbeat::default();
+        }
+        if let Some(stats) = &mut state.hv_stats {
+            *stats = GuestHvStats::default();
+        }
+        state.psci.reset_guest_state();
+        Ok(())
+    }
+
+    /// Boots the vCPU targeted by a psci `CPU_ON` of another vCPU of the VM, reported as an
+    /// [`AxVCpuExitReason::CpuUp`], at its `entry_point` with its `arg` as context ID.
+    ///
+    /// The vCPU may have never run yet, or have been turned off by `CPU_OFF`. Its state is
+    /// reset like [`reset`](Self::reset) does, then set up as psci requires whatever the setup
+    /// configuration: AArch64 EL1h with all the exceptions masked, or the Supervisor mode for
+    /// an AArch32 EL1 (see [`Aarch64VCpuCreateConfig::aarch32_el1`]), the MMU and the caches off.
+    /// The VMM then runs it, and completes the call of the calling vCPU with
+    /// `set_return_value()`, 0 (`SUCCESS`) once booted.
+    ///
+    /// Returns `InvalidInput` if `entry` is misaligned or not mapped, for the VMM to
+    /// return `INVALID_ADDRESS` (-9) to the calling vCPU, and `BadState` if the vCPU has not
+    /// been set up.
+    pub fn boot_secondary(&mut self, entry: GuestPhysAddr, context_id: u64) -> AxResult {
+        /// `SCTLR_EL1.M`, `C` and `I`, bits [0], [2] and [12].
+        const SCTLR_EL1_MMU_CACHES: u32 = (1 << 0) | (1 << 2) | (1 << 12);
+
+        // Bit 0 of an AArch32 entry point selects the T32 state, then it is 2-byte aligned.
+        let misaligned = if self.aarch32_el1 {
+            entry.as_usize() & 0b11 == 0b10
+        } else {
+            entry.as_usize() % 4 != 0
+        };
+        let pc = GuestPhysAddr::from(entry.as_usize() & !1);
+        if misaligned || self.translate_ipa(pc).is_none() {
+            return ax_err!(InvalidInput, "invalid CPU_ON entry point");
+        }
+        self.reset(entry, context_id)?;
+        self.ctx.spsr = if self.aarch32_el1 {
+            AARCH32_SVC_PSTATE
+        } else {
+            TrapFrame::default().spsr
+        };
+        self.set_entry_pc(entry);
+        self.guest_system_regs.sctlr_el1 &= !SCTLR_EL1_MMU_CACHES;
+        if let Some(mmu) = &mut self.guest_mmu {
+            mmu.sync(&self.guest_system_regs.el1_state());
+        }
+        Ok(())
+    }
+
+    /// Takes a checkpoint of the whole state of the stopped vCPU: its registers, its FP/SIMD
+    /// registers and its pending interrupts, see [`VCpuSnapshot`].
+    ///
+    /// The state of the emulated devices and paravirtual interfaces is not part of it, like
+    /// for [`export_state`](Self::export_state).
+    pub fn save_state(&self) -> VCpuSnapshot {
+        let mut snapshot = VCpuSnapshot::new();
+        state::snapshot_regs(&self.ctx, &self.migrated_system_regs(), &mut snapshot);
+        // The guest FP/SIMD registers are unloaded when `run()` returns.
+        snapshot.fp_q = self.guest_fp.q;
+        snapshot.fpcr = self.guest_fp.fpcr;
+        snapshot.fpsr = self.guest_fp.fpsr;
+        snapshot.virq_lines = self.guest_system_regs.hcr_el2 & (HCR_EL2_VI_VF | HCR_EL2_VSE);
+        snapshot.irq_pending = self.irq_pending as u64;
+        #[cfg(feature = "vgic")]
+        self.guest_system_regs.vgic.save_to(&mut snapshot);
+        snapshot
+    }
+
+    /// Restores a checkpoint taken by [`save_state`](Self::save_state), by the same version of
+    /// the crate, into the stopped vCPU.
+    ///
+    /// Returns `InvalidData` if `snapshot` is not a snapshot, `Unsupported` if it is one of
+    /// another version, uses the virtual CPU interface without the `vgic` feature or more list
+    /// registers than the host implements, and `InvalidInput` if its `PSTATE` is not a mode of
+    /// the guest EL1, like [`initial_pstate`](Aarch64VCpuSetupConfig::initial_pstate). Nothing
+    /// is restored then. The virtual counter offset is restored like by
+    /// [`import_state`](Self::import_state).
+    pub fn restore_state(&mut self, snapshot: &VCpuSnapshot) -> AxResult {
+        snapshot.check()?;
+        #[cfg(not(feature = "vgic"))]
+        if snapshot.ich_hcr != 0 {
+            return ax_err!(Unsupported, "vCPU snapshot using the vgic feature");
+        }
+        #[cfg(feature = "vgic")]
+        self.guest_system_regs.vgic.check_snapshot(snapshot)?;
+        state::check_snapshot_regs(snapshot, self.aarch32_el1)?;
+        state::restore_snapshot_regs(&mut self.ctx, &mut self.guest_system_regs, snapshot);
+        self.set_virtual_counter_offset(self.guest_system_regs.cntvoff_el2);
+        self.restore_emulated_sysregs();
+        self.guest_fp.q = snapshot.fp_q;
+        self.guest_fp.fpcr = snapshot.fpcr;
+        self.guest_fp.fpsr = snapshot.fpsr;
+        let virq_lines = HCR_EL2_VI_VF | HCR_EL2_VSE;
+        self.guest_system_regs.hcr_el2 =
+            (self.guest_system_regs.hcr_el2 & !virq_lines) | (snapshot.virq_lines & virq_lines);
+        self.vsesr_el2 = 0;
+        self.irq_pending = snapshot.irq_pending != 0;
+        #[cfg(feature = "vgic")]
+        self.guest_system_regs.vgic.restore_from(snapshot);
+        if let Some(mmu) = &mut self.guest_mmu {
+            mmu.sync(&self.guest_system_regs.el1_state());
+        }
+        // The restored configuration is validated by the next `run()`.
+        self.validated = false;
+        Ok(())
+    }
+
+    /// Returns the stage-2 descriptor `MemAttr` field (bits [5:2]) of `attr` for this vCPU, whose
+    /// encoding depends on whether [stage-2 FWB](Self::stage2_fwb_enabled) is enabled.
+    pub fn stage2_memattr(&self, attr: Stage2MemAttr) -> u64 {
+        attr.encoding(self.stage2_fwb_enabled())
+    }
+
+    /// Forces Device-nGnRE semantics on the IPA range `[start, start + size)`, whatever the
+    /// guest maps it as, by rewriting the `MemAttr` of its stage-2 mappings.
+    ///
+    /// This keeps a guest from mapping e.g. the registers or the non-coherent DMA buffers of a
+    /// passthrough device as Normal cacheable memory, with or without stage-2 FWB. The range
+    /// must be mapped by stage-2 blocks and pages wholly inside it, mappings created later must
+    /// use [`stage2_memattr`](Self::stage2_memattr) themselves.
+    ///
+    /// The vCPUs of the VM must not run meanwhile, the mappings are briefly invalid.
+    pub fn force_stage2_device(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        scope: TlbScope,
+    ) -> AxResult {
+        if !self.stage2_enabled() {
+            return ax_err!(Unsupported, "stage-2 translation is disabled");
+        }
+        let memattr = self.stage2_memattr(Stage2MemAttr::DeviceNGnRE);
+        unsafe {
+            set_memattr::<H::MmHal>(
+                self.guest_system_regs.vttbr_el2,
+                start,
+                size,
+                memattr,
+                |ipa, size| self.flush_stage2_ipa_range(ipa, size, scope),
+            )
+        }
+    }
+
+    /// Invalidates the stage-2 TLB entries of the IPA range `[start, start + size)` of this
+    /// vCPU's VM, e.g. after the range is unmapped.
+    ///
+    /// [`TlbScope::Local`] can be used to avoid system-wide TLBI traffic if the VM is known to
+    /// be pinned to the current physical CPU.
+    pub fn flush_stage2_ipa_range(&self, start: GuestPhysAddr, size: usize, scope: TlbScope) {
+        let prev_vttbr = VTTBR_EL2.get();
+        VTTBR_EL2.set(self.guest_system_regs.vttbr_el2);
+        unsafe {
+            core::arch::asm!("isb");
+            flush_ipa_range(start, size, scope);
+        }
+        VTTBR_EL2.set(prev_vttbr);
+        unsafe { core::arch::asm!("isb") };
+    }
+
+    /// Invalidates the stage-2 TLB entries of the page of `ipa` of this vCPU's VM, e.g. after
+    /// its mapping is changed, see [`Self::flush_stage2_ipa_range`].
+    pub fn flush_stage2_ipa(&self, ipa: GuestPhysAddr, scope: TlbScope) {
+        self.flush_stage2_ipa_range(ipa, 1, scope);
+    }
+
+    /// Invalidates all the TLB entries of the VMID of this vCPU's VM, e.g. after large changes
+    /// of its stage-2 page tables.
+    pub fn flush_stage2_vmid(&self, scope: TlbScope) {
+        let prev_vttbr = VTTBR_EL2.get();
+        VTTBR_EL2.set(self.guest_system_regs.vttbr_el2);
+        unsafe {
+            core::arch::asm!("isb");
+            flush_vmid(scope);
+        }
+        VTTBR_EL2.set(prev_vttbr);
+        unsafe { core::arch::asm!("isb") };
+    }
+
+    /// Invalidates the TLB entries of all the VMs on all the physical CPUs.
+    ///
+    /// The vCPUs no longer flush the TLBs on each entry, only when their stage-2 root or VMID
+    /// changes, so a VMM changing the stage-2 page tables must invalidate the affected entries
+    /// with this or the narrower [`Self::flush_stage2_ipa`] and [`Self::flush_stage2_vmid`].
+    pub fn flush_stage2_all() {
+        flush_all_vmids();
+    }
+
+    /// Makes the image the VMM has written to the guest memory `[start, start + size)`, e.g. a
+    /// kernel, visible to the guest booting with its MMU and caches off, before its first entry.
+    ///
+    /// The data cache lines of the range are cleaned and invalidated to the PoC, unless stage-2
+    /// FWB forces the cacheable attributes on the guest accesses anyway, and the instruction
+    /// caches are invalidated, unless the host has `CTR_EL0.DIC`, so that no stale data or
+    /// instructions from the previous use of the memory are read once the guest enables its
+    /// caches.
+    ///
+    /// Returns `InvalidInput` if an IPA of the range is not mapped, after the maintenance of
+    /// the mapped part.
+    pub fn prepare_guest_image(&self, start: GuestPhysAddr, size: usize) -> AxResult {
+        const PAGE_SIZE: usize = 0x1000;
+        /// `CTR_EL0.DminLine`, bits [19:16], log2 of the line size in words.
+        const CTR_EL0_DMINLINE_SHIFT: u64 = 16;
+        /// `CTR_EL0.DIC`, bit [29].
+        const CTR_EL0_DIC: u64 = 1 << 29;
+
+        let ctr_el0 = host_ctr_el0();
+        let line_size = 4 << ((ctr_el0 >> CTR_EL0_DMINLINE_SHIFT) & 0xf);
+        let mut unmapped = false;
+        if !self.stage2_fwb_enabled() {
+            let end = start.as_usize() + size;
+            let mut ipa = start.as_usize() & !(line_size - 1);
+            while ipa < end {
+                let page_end = ((ipa & !(PAGE_SIZE - 1)) + PAGE_SIZE).min(end);
+                let Some(hpa) = self.translate_ipa(GuestPhysAddr::from(ipa)) else {
+                    unmapped = true;
+                    ipa = page_end;
+                    continue;
+                };
+                let mut va = H::MmHal::phys_to_virt(hpa).as_usize();
+                while ipa < page_end {
+                    unsafe { core::arch::asm!("dc civac, {}", in(reg) va) };
+                    ipa += line_size;
+                    va += line_size;
+                }
+            }
+            unsafe { core::arch::asm!("dsb sy") };
+        }
+        if ctr_el0 & CTR_EL0_DIC == 0 {
+            // The EL2 VAs don't map the guest code, invalidate the whole instruction cache.
+            unsafe { core::arch::asm!("ic ialluis", "dsb ish", "isb") };
+        }
+        if unmapped {
+            return ax_err!(InvalidInput, "the image range is not fully mapped");
+        }
+        Ok(())
+    }
+
+    /// Sets the offset of the virtual counter of the guest from the physical one
+    /// (`CNTVOFF_EL2`), e.g. to hide the time the VM was paused or to restore it after a
+    /// migration. It is reset to 0 by `setup()`.
+    ///
+    /// With an [`Aarch64VmArchState`], the offset is the one of the whole VM, the same as
+    /// [`Aarch64VmArchState::set_virtual_counter_offset`], and it is kept by `setup()`.
+    pub fn set_virtual_counter_offset(&mut self, offset: u64) {
+        self.guest_system_regs.cntvoff_el2 = offset;
+        if let Some(vm) = self.vm_state {
+            vm.set_virtual_counter_offset(offset);
+        }
+    }
+
+    /// Enables the event stream of the virtual counter of the guest, with an event at each
+    /// transition of its bit `trigger` (`CNTKCTL_EL1.EVNTI`, truncated to 4 bits), or disables
+    /// it with `None`.
+    ///
+    /// The guest can reprogram it through `CNTKCTL_EL1` afterwards.
+    pub fn set_event_stream(&mut self, trigger: Option<u8>) {
+        let event_stream = trigger.map_or(0, |trigger| {
+            CNTKCTL_EL1_EVNTEN | ((trigger as u64 & 0xf) << CNTKCTL_EL1_EVNTI_SHIFT)
+        });
+        let cntkctl_el1 = &mut self.guest_system_regs.cntkctl_el1;
+        *cntkctl_el1 = ((*cntkctl_el1 as u64 & !CNTKCTL_EL1_EVNT_MASK) | event_stream) as u32;
+    }
+
+    /// Returns the offset of the virtual counter of the guest from the physical one.
+    pub fn virtual_counter_offset(&self) -> u64 {
+        self.vm_state
+            .map_or(self.guest_system_regs.cntvoff_el2, |vm| {
+                vm.virtual_counter_offset()
+            })
+    }
+
+    /// Returns the physical counter value the virtual timer of the guest fires at, if it is
+    /// armed, so that the VMM can wake the vCPU up then while it is descheduled.
+    pub fn vtimer_deadline(&self) -> Option<u64> {
+        self.guest_system_regs
+            .vtimer_deadline(self.virtual_counter_offset())
+    }
+
+    /// Returns whether the virtual timer of the guest has expired while the vCPU was not
+    /// running, the VMM should then inject the virtual timer PPI (INTID 27) before entering it
+    /// again.
+    pub fn vtimer_pending(&self) -> bool {
+        self.vtimer_deadline()
+            .is_some_and(|deadline| CNTPCT_EL0.get() >= deadline)
+    }
+
+    /// Returns the value of the physical counter (`CNTPCT_EL0`) when the guest was last entered.
+    pub fn last_entry_time(&self) -> u64 {
+        self.last_entry
+    }
+
+    /// Returns the last system power event requested by the guest, which tells apart the
+    /// power offs from the resets reported as [`AxVCpuExitReason::SystemDown`].
+    pub fn last_system_event(&self) -> Option<SystemEvent> {
+        self.exception_state.psci.last_system_event
+    }
+
+    /// Completes the [`AxVCpuExitReason::Hypercall`] the guest has last exited with by
+    /// returning `error` in `x0`, for the hypercalls the VMM rejects.
+    ///
+    /// The guest resumes after the `HVC` or `SMC`, from AArch64 or AArch32, at the next `run()`
+    /// and sees a standard SMCCC error instead of its own arguments. Returns `BadState` if the
+    /// last exit is not a hypercall.
+    pub fn deny_hypercall(&mut self, error: SmcccError) -> AxResult {
+        let is_hypercall = self.last_exit.is_some_and(|exit| {
+            exit.kind == TrapKind::Synchronous
+                && matches!(
+                    ESR_EL2::EC.read_as_enum(exit.esr),
+                    Some(
+                        ESR_EL2::EC::Value::HVC64
+                            | ESR_EL2::EC::Value::HVC32
+                            | ESR_EL2::EC::Value::SMC64
+                            | ESR_EL2::EC::Value::SMC32
+                    )
+                )
+        });
+        if !is_hypercall {
+            return ax_err!(BadState, "the last VM exit is not a hypercall");
+        }
+        self.ctx.set_argument(error.code() as usize);
+        Ok(())
+    }
+
+    /// Returns the guest buffer the [`AxVCpuExitReason::Hypercall`] the guest has last exited
+    /// with passes in its arguments `index` (the IPA) and `index + 1` (the length), see
+    /// [`GuestBuffer`].
+    ///
+    /// Returns `BadState` if the last exit is not a hypercall, and `InvalidInput` if `index`
+    /// is above 4, the buffer is longer than [`HYPERCALL_BUFFER_MAX`] or any part of it is not
+    /// mapped.
+    pub fn hypercall_buffer(&self, index: usize) -> AxResult<GuestBuffer> {
+        let is_hvc = self.last_exit.is_some_and(|exit| {
+            exit.kind == TrapKind::Synchronous
+                && ESR_EL2::EC.read_as_enum(exit.esr) == Some(ESR_EL2::EC::Value::HVC64)
+        });
+        if !is_hvc {
+            return ax_err!(BadState, "the last VM exit is not a hypercall");
+        }
+        if index > 4 {
+            return ax_err!(
+                InvalidInput,
+                "no hypercall argument after the buffer address"
+            );
+        }
+        // The arguments start at x1, x0 is the hypercall number.
+        let buffer = GuestBuffer::new(
+            GuestPhysAddr::from(self.ctx.gpr[index + 1] as usize),
+            self.ctx.gpr[index + 2] as usize,
+        )?;
+        if buffer
+            .chunks(0, buffer.len())
+            .any(|(ipa, _)| self.translate_ipa(ipa).is_none())
+        {
+            return ax_err!(InvalidInput, "hypercall buffer not mapped");
+        }
+        Ok(buffer)
+    }
+
+    /// Copies the bytes of the guest `buffer` from `offset` into `dst`, returning the number
+    /// of bytes copied, less than the length of `dst` at the end of the buffer.
+    ///
+    /// Returns `InvalidInput` if a page of the buffer got unmapped or is not Normal memory, and
+    /// `PermissionDenied` if the guest may not read it, after copying the bytes before it.
+    pub fn read_guest_buffer(
+        &self,
+        buffer: &GuestBuffer,
+        offset: usize,
+        dst: &mut [u8],
+    ) -> AxResult<usize> {
+        let mut copied = 0;
+        for (ipa, size) in buffer.chunks(offset, dst.len()) {
+            let hpa = self.translate_buffer_ipa(ipa, false)?;
+            let src = H::MmHal::phys_to_virt(hpa).as_usize() as *const u8;
+            for (n, byte) in dst[copied..copied + size].iter_mut().enumerate() {
+                // The guest memory may be written concurrently by other vCPUs.
+                *byte = unsafe { src.add(n).read_volatile() };
+            }
+            copied += size;
+        }
+        Ok(copied)
+    }
+
+    /// Copies `src` into the guest `buffer` from `offset`, e.g. the results of the hypercall,
+    /// returning the number of bytes copied, less than the length of `src` at the end of the
+    /// buffer.
+    ///
+    /// Returns `InvalidInput` if a page of the buffer got unmapped or is not Normal memory, and
+    /// `PermissionDenied` if the guest may not write it, after copying the bytes before it.
+    pub fn write_guest_buffer(
+        &self,
+        buffer: &GuestBuffer,
+        offset: usize,
+        src: &[u8],
+    ) -> AxResult<usize> {
+        let mut copied = 0;
+        for (ipa, size) in buffer.chunks(offset, src.len()) {
+            let hpa = self.translate_buffer_ipa(ipa, true)?;
+            let dst = H::MmHal::phys_to_virt(hpa).as_usize() as *mut u8;
+            for (n, byte) in src[copied..copied + size].iter().enumerate() {
+                unsafe { dst.add(n).write_volatile(*byte) };
+            }
+            copied += size;
+        }
+        Ok(copied)
+    }
+
+    /// Registers a security policy deciding on the exits of `kinds`, invoked after the already
+    /// registered ones, see [`ExitPolicy`].
+    ///
+    /// The policies run before the exit mask, so the exits they let through can still be
+    /// masked. Up to 4 policies can be registered, `NoMemory` is returned beyond.
+    pub fn add_exit_policy(
+        &mut self,
+        kinds: &[PolicyExit],
+        policy: &'static dyn ExitPolicy,
+    ) -> AxResult {
+        self.exception_state.policies.add(kinds, policy)
+    }
+
+    /// Unregisters all the security policies of the vCPU.
+    pub fn clear_exit_policies(&mut self) {
+        self.exception_state.policies.clear();
+    }
+
+    /// Makes the guest execute again the instruction of the [`AxVCpuExitReason::MmioRead`] or
+    /// [`AxVCpuExitReason::MmioWrite`] it has last exited with, instead of resuming after it.
+    ///
+    /// This suits the VMM installing a mapping of the IPA instead of emulating the access,
+    /// in particular for the exclusive sequences, see [`Aarch64ExitInfo::exclusive`]. A value
+    /// set meanwhile in the destination register is overwritten by the replayed load. Returns
+    /// `BadState` if the last exit is not an MMIO one, if it is already replayed, or if its
+    /// instruction has been emulated from its decoding and updates its base register or
+    /// makes several accesses.
+    pub fn replay_mmio_access(&mut self) -> AxResult {
+        let Some((pc, spsr)) = self.mmio_pc.take() else {
+            return ax_err!(BadState, "the last VM exit is not an MMIO access");
+        };
+        self.ctx.set_exception_pc(pc);
+        self.ctx.spsr = spsr;
+        Ok(())
+    }
+
+    /// Returns the physical counter ticks elapsed since the last `HEARTBEAT` call of the guest,
+    /// see [`Aarch64VCpuSetupConfig::heartbeat_hypercall`].
+    ///
+    /// Returns `None` if the hypercall is not offered or the guest hasn't called it yet, like
+    /// a watchdog which is only armed by its first ping.
+    pub fn ticks_since_heartbeat(&self) -> Option<u64> {
+        self.exception_state
+            .heartbeat
+            .as_ref()
+            .and_then(GuestHeartbeat::ticks_since)
+    }
+
+    /// Returns information about the last VM exit, `None` if the guest has never run.
+    pub fn last_exit(&self) -> Option<&Aarch64ExitInfo> {
+        self.last_exit.as_ref()
+    }
+
+    /// Returns the timestamps of the exit path of the last VM exit the guest has been
+    /// re-entered after, including the exits handled by the vCPU itself.
+    ///
+    /// The timestamps of the exit returned by a `run()` are thus available after the next
+    /// `run()` has re-entered the guest, e.g. for accounting the latency of each exit in a
+    /// histogram right after `run()` returns.
+    #[cfg(feature = "exit-latency")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "exit-latency")))]
+    pub fn last_exit_timestamps(&self) -> Option<&Aarch64ExitTimestamps> {
+        self.last_exit_timestamps.as_ref()
+    }
+
+    /// Returns whether an interrupt is pending for the guest, i.e. one has been injected since
+    /// the guest was last entered, or a virtual IRQ/FIQ is asserted through `HCR_EL2`.
+    pub fn has_pending_interrupt(&self) -> bool {
+        self.irq_pending || self.guest_system_regs.hcr_el2 & HCR_EL2_VI_VF != 0
+    }
+
+    /// Makes a virtual SError (asynchronous abort) pending for the guest, taken once it
+    /// unmasks them (`PSTATE.A`), e.g. to reflect into it a contained RAS error the host
+    /// observed on its behalf, see [`GuestSError`].
+    ///
+    /// The guest reads `syndrome` as the `IDS` bit and `ISS` of its `ESR_EL1`, bits [24:0],
+    /// or an IMPLEMENTATION DEFINED syndrome if it is `None`. Returns `Unsupported` if a
+    /// syndrome is given but the host doesn't implement FEAT_RAS, `InvalidInput` if it has
+    /// other bits set, and `BadState` if another virtual SError is still pending.
+    pub fn inject_serror(&mut self, syndrome: Option<u64>) -> AxResult {
+        if self.serror_pending() {
+            return ax_err!(BadState, "a virtual SError is already pending");
+        }
+        if let Some(syndrome) = syndrome {
+            if !has_feat_ras() {
+                return ax_err!(Unsupported, "virtual SError syndromes require FEAT_RAS");
+            }
+            if syndrome & !VSESR_EL2_MASK != 0 {
+                return ax_err!(InvalidInput, "invalid virtual SError syndrome");
+            }
+        }
+        self.vsesr_el2 = syndrome.unwrap_or(0);
+        self.guest_system_regs.hcr_el2 |= HCR_EL2_VSE;
+        Ok(())
+    }
+
+    /// Returns whether a virtual SError injected with [`inject_serror`](Self::inject_serror)
+    /// is still pending, the guest having kept them masked since.
+    pub fn serror_pending(&self) -> bool {
+        self.guest_system_regs.hcr_el2 & HCR_EL2_VSE != 0
+    }
+
+    /// Returns whether virtual IRQs are enabled (`HCR_EL2.IMO`), i.e. whether interrupts can be
+    /// injected with `inject_interrupt`.
+    pub fn virtual_irq_enabled(&self) -> bool {
+        self.guest_system_regs.hcr_el2 & HCR_EL2::IMO::EnableVirtualIRQ.value != 0
+    }
+
+    /// Replaces the set of VM exits handled in EL2 with a default policy instead of being
+    /// returned by `run()`, see [`Aarch64VCpuSetupConfig::exit_mask`].
+    pub fn set_exit_mask(&mut self, mask: Aarch64ExitMask) {
+        self.exit_mask = mask;
+    }
+
+    /// Returns the mirror of the stage-1 MMU configuration of the guest, if it is tracked, see
+    /// [`Aarch64VCpuSetupConfig::track_guest_mmu`].
+    pub fn guest_mmu(&self) -> Option<&Aarch64GuestMmu> {
+        self.guest_mmu.as_ref()
+    }
+
+    /// Starts or stops reporting the writes of the guest to `reg`.
+    ///
+    /// Watched writes are reported as [`AxVCpuExitReason::SysRegWrite`] once they have been
+    /// performed, the VMM only has to resume the vCPU. Watching [`WatchedSysReg::VbarEl1`]
+    /// requires FEAT_FGT.
+    pub fn watch_sysreg_writes(&mut self, reg: WatchedSysReg, watch: bool) -> AxResult {
+        if reg == WatchedSysReg::VbarEl1 && !has_feat_fgt() {
+            return ax_err!(Unsupported, "watching VBAR_EL1 requires FEAT_FGT");
+        }
+        if watch {
+            self.sysreg_watch |= reg.bit();
+        } else {
+            self.sysreg_watch &= !reg.bit();
+        }
+        self.sysreg_watch_once &= !reg.bit();
+        self.update_sysreg_write_traps();
+        Ok(())
+    }
+
+    /// Reports the next write of the guest to `reg` only, then stops watching it.
+    ///
+    /// Watching [`WatchedSysReg::VbarEl1`] this way before the first run gives attestation
+    /// logic a hook point when the guest kernel installs its exception vectors, before any
+    /// interrupt is taken: it can measure the guest kernel code then.
+    pub fn watch_sysreg_write_once(&mut self, reg: WatchedSysReg) -> AxResult {
+        self.watch_sysreg_writes(reg, true)?;
+        self.sysreg_watch_once |= reg.bit();
+        Ok(())
+    }
+
+    /// Makes `data` available to the guest in the receive register of the emulated Debug
+    /// Communications Channel, see [`Aarch64VCpuSetupConfig::dcc`].
+    ///
+    /// Returns `false` if DCC emulation is disabled or the guest has not read the previous data
+    /// yet.
+    pub fn dcc_push_rx(&mut self, data: u32) -> bool {
+        self.dcc.as_mut().is_some_and(|dcc| dcc.push_rx(data))
+    }
+
+    /// Returns the counters of guest misbehaviour events of this vCPU.
+    pub fn stats(&self) -> &Aarch64VCpuStats {
+        &self.exception_state.stats
+    }
+
+    /// Records an MMIO access of the guest which is ignored by the VMM, e.g. to an unbacked
+    /// region, logging it with the same rate limiting as the vCPU's own diagnostics.
+    pub fn note_ignored_mmio(&mut self, addr: GuestPhysAddr, write: bool) {
+        if count_event(&mut self.exception_state.stats.ignored_mmio) {
+            warn!(
+                "Ignored MMIO {} @{:?}, pc {:#x} ({} times)",
+                if write { "write" } else { "read" },
+                addr,
+                self.ctx.exception_pc(),
+                self.exception_state.stats.ignored_mmio
+            );
+        }
+    }
+
+    /// Makes the next `run()` return `exit_reason` without entering the guest.
+    ///
+    /// This is meant for testing the exit handling logic of a VMM (MMIO, hypercalls, IRQs...)
+    /// without bringing up a guest image. Guest registers are left untouched, so the exit can
+    /// be completed the same way as a real one, e.g. with `set_gpr` for an MMIO read.
+    #[cfg(feature = "synthetic-exit")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "synthetic-exit")))]
+    pub fn inject_synthetic_exit(&mut self, exit_reason: AxVCpuExitReason) {
+        self.synthetic_exit = Some(exit_reason);
+    }
+
+    /// Attaches the producer of the ring the MMIO writes of the guest are posted to, or
+    /// detaches it, returning the producer attached before, see [`ExitRing`].
+    ///
+    /// The entries left in a detached ring are still to be processed by the VMM.
+    ///
+    /// [`ExitRing`]: crate::ExitRing
+    #[cfg(feature = "exit-ring")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "exit-ring")))]
+    pub fn set_exit_ring(
+        &mut self,
+        producer: Option<ExitRingProducer>,
+    ) -> Option<ExitRingProducer> {
+        core::mem::replace(&mut self.exit_ring, producer)
+    }
+
+    /// Attaches the page the virtual EL2 registers of the guest hypervisor are stored in, or
+    /// detaches it, see [`VncrPage`].
+    ///
+    /// The guest runs its hypervisor at EL1 as a virtual EL2 while a page is attached. Returns
+    /// `Unsupported` if the host doesn't implement FEAT_NV2.
+    #[cfg(feature = "nested")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "nested")))]
+    pub fn set_vncr_page(&mut self, page: Option<&'static mut VncrPage>) -> AxResult {
+        if page.is_some() && !has_feat_nv2() {
+            return ax_err!(Unsupported, "FEAT_NV2 is not implemented");
+        }
+        if page.is_some() {
+            self.guest_system_regs.hcr_el2 |= HCR_EL2_NV | HCR_EL2_NV2;
+        } else {
+            self.guest_system_regs.hcr_el2 &= !(HCR_EL2_NV | HCR_EL2_NV2);
+        }
+        self.vncr_page = page;
+        Ok(())
+    }
+
+    /// Returns the attached page of the virtual EL2 registers of the guest hypervisor, for the
+    /// VMM to supply and observe their values while the vCPU is stopped.
+    #[cfg(feature = "nested")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "nested")))]
+    pub fn vncr_page(&mut self) -> Option<&mut VncrPage> {
+        self.vncr_page.as_deref_mut()
+    }
+}
+
+// Private function
+impl<H: AxVCpuHal> Aarch64VCpu<H> {
+    fn init_hv(&mut self, config: Aarch64VCpuSetupConfig) {
+        self.ctx.spsr = setup_pstate(self.aarch32_el1, self.ctx.spsr);
+        if let Some(pstate) = config.initial_pstate {
+            self.ctx.spsr = pstate;
+        }
+        self.reset_pstate = self.ctx.spsr;
+        self.init_vm_context(config);
+    }
+
+    /// Init guest context. Also set some el2 register value.
+    fn init_vm_context(&mut self, config: Aarch64VCpuSetupConfig) {
+        self.exception_state.smc_forward = config.smc_forward;
+        self.exit_mask = config.exit_mask;
+
+        // CNTHCTL_EL2.modify(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
+        self.guest_system_regs.cntvoff_el2 =
+            self.vm_state.map_or(0, |vm| vm.virtual_counter_offset());
+        self.guest_system_regs.cntkctl_el1 =
+            config.cntkctl_el1.unwrap_or(CNTKCTL_EL1_EL0VCTEN) as u32;
+        if config.event_stream.is_some() {
+            self.set_event_stream(config.event_stream);
+        }
+        self.guest_system_regs.cnthctl_el2 = if config.passthrough_timer {
+            (CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET).into()
+        } else if config.passthrough_counter {
+            (CNTHCTL_EL2::EL1PCEN::CLEAR + CNTHCTL_EL2::EL1PCTEN::SET).into()
+        } else {
+            (CNTHCTL_EL2::EL1PCEN::CLEAR + CNTHCTL_EL2::EL1PCTEN::CLEAR).into()
+        };
+
+        self.guest_system_regs.sctlr_el1 = config.sctlr_el1.unwrap_or(SCTLR_EL1_RESET) as u32;
+        self.guest_system_regs.pmcr_el0 = 0;
+
+        // use 3 level ept paging
+        // - 4KiB granule (TG0)
+        // - 39-bit address space (T0_SZ)
+        // - start at level 1 (SL0)
+        // self.guest_system_regs.vtcr_el2 = (VTCR_EL2::PS::PA_40B_1TB
+        //     + VTCR_EL2::TG0::Granule4KB
+        //     + VTCR_EL2::SH0::Inner
+        //     + VTCR_EL2::ORGN0::NormalWBRAWA
+        //     + VTCR_EL2::IRGN0::NormalWBRAWA
+        //     + VTCR_EL2::SL0.val(0b01)
+        //     + VTCR_EL2::T0SZ.val(64 - 39))
+        // .into();
+
+        // use 4 level ept paging
+        // - 4KiB granule (TG0)
+        // - 48-bit address space (T0_SZ)
+        // - start at level 0 (SL0)
+        // - output addresses in the PA range of the host, up to 48 bits with this granule (PS)
+        const VTCR_EL2_PS_48B: u64 = 0b101;
+        self.guest_system_regs.vtcr_el2 = (VTCR_EL2::PS.val(host_parange().min(VTCR_EL2_PS_48B))
+            + VTCR_EL2::TG0::Granule4KB
+            + VTCR_EL2::SH0::Inner
+            + VTCR_EL2::ORGN0::NormalWBRAWA
+            + VTCR_EL2::IRGN0::NormalWBRAWA
+            + VTCR_EL2::SL0.val(0b10) // 0b10 means start at level 0
+            + VTCR_EL2::T0SZ.val(64 - 48))
+        .into();
+        if let Some(vm) = self.vm_state {
+            self.guest_system_regs.vtcr_el2 = vm.vtcr_el2(self.guest_system_regs.vtcr_el2);
+        }
+
+        let mut hcr_el2 = HCR_EL2::VM::Enable
+            + HCR_EL2::RW::EL1IsAarch64
+            + HCR_EL2::TSC::EnableTrapEl1SmcToEl2
+            + HCR_EL2::RW::EL1IsAarch64;
+
+        if !config.passthrough_interrupt {
+            // Set HCR_EL2.IMO will trap IRQs to EL2 while enabling virtual IRQs.
+            //
+            // We must choose one of the two:
+            // - Enable virtual IRQs and trap physical IRQs to EL2.
+            // - Disable virtual IRQs and pass through physical IRQs to EL1.
+            hcr_el2 += HCR_EL2::IMO::EnableVirtualIRQ + HCR_EL2::FMO::EnableVirtualFIQ;
+            #[cfg(feature = "vgic")]
+            if has_gicv3_sysregs() {
+                self.guest_system_regs.vgic.enable();
+                if config.measure_irq_latency {
+                    self.guest_system_regs.vgic.measure_latency();
+                }
+            }
+        }
+        // Otherwise the guest owns the GIC CPU interface: FIQs are passed through as well, so
+        // the only exits left are the synchronous ones and the SErrors, and the `ICC_*`
+        // accesses of the guest (including SGI generation) are not trapped.
+
+        let mut hcr_el2: u64 = hcr_el2.into();
+        // The physical SErrors are reported to the VMM, which reflects the contained ones into
+        // the guest as virtual SErrors.
+        hcr_el2 |= HCR_EL2_AMO;
+        // A vCPU migrated back to a physical CPU must not find the entries its local
+        // maintenance on another one didn't reach.
+        hcr_el2 |= HCR_EL2_FB;
+        if self.aarch32_el1 {
+            hcr_el2 &= !HCR_EL2::RW::EL1IsAarch64.value;
+        }
+        if config.disable_stage2 {
+            hcr_el2 &= !HCR_EL2_VM;
+        }
+        if config.stage2_fwb && has_feat_s2fwb() {
+            // Stage-2 forces write-back cacheable memory attributes, so no cache maintenance is
+            // needed when pages are transferred between the host and the guest.
+            hcr_el2 |= HCR_EL2_FWB;
+        }
+        if config.virt_cache_topology || config.virt_ctr_el0.is_some() {
+            let host_ctr_el0 = host_ctr_el0();
+            let ctr_el0 =
+                sanitize_ctr_el0(config.virt_ctr_el0.unwrap_or(host_ctr_el0), host_ctr_el0);
+            self.cache_topology = Some(VirtCacheTopology::new(ctr_el0));
+            hcr_el2 |= HCR_EL2_TID2;
+        }
+        if config.virt_id_regs {
+            self.id_regs = Some(VirtIdRegs::from_host());
+            hcr_el2 |= HCR_EL2_TID3;
+        }
+        self.code_maintenance = config.code_maintenance;
+        self.power_mmio = config.power_mmio;
+        self.host_irqs_in_exits = config.host_irqs_in_exits;
+        self.deny_aarch32_el0 = config.deny_aarch32_el0;
+        self.sysreg_table = config.sysreg_table;
+        if config.code_maintenance != CodeMaintenancePolicy::NotTrapped {
+            hcr_el2 |= HCR_EL2_TPU;
+        }
+        if config.trap_dc_zva {
+            hcr_el2 |= HCR_EL2_TDZ;
+        }
+        if let Some(scope) = config.trap_tlbi {
+            self.tlbi_scope = Some(scope);
+            hcr_el2 |= HCR_EL2_TTLB;
+        }
+        self.exception_state.wfe_spin = config.wfe_spin.map(WfeSpinDetector::new);
+        self.exception_state.pvlock = config.pv_preempted.then(PvLockState::default);
+        self.exception_state.dc_ivac = config.dc_ivac_readonly;
+        self.exception_state.hv_stats = config.hv_stats_hypercall.then(GuestHvStats::default);
+        self.exception_state.heartbeat = config.heartbeat_hypercall.then(GuestHeartbeat::default);
+        if self.exception_state.wfe_spin.is_some() {
+            hcr_el2 |= HCR_EL2_TWE;
+        }
+        if config.trap_wfi {
+            hcr_el2 |= HCR_EL2_TWI;
+        }
+        if has_feat_mpam() {
+            self.mpam_partition = Some(config.mpam_partition.unwrap_or_default());
+        }
+        self.imp_def_sysreg = config.imp_def_sysreg;
+        if config.trap_imp_def_sysreg {
+            hcr_el2 |= HCR_EL2_TIDCP;
+        }
+        if has_feat_lor() {
+            // The LOR registers are emulated as RAZ/WI, see `is_lor_sysreg`.
+            hcr_el2 |= HCR_EL2_TLOR;
+        }
+
+        #[cfg(feature = "nested")]
+        if self.vncr_page.is_some() {
+            hcr_el2 |= HCR_EL2_NV | HCR_EL2_NV2;
+        }
+
+        self.guest_system_regs.hcr_el2 = hcr_el2;
+
+        // Keep the host MDCR_EL2 configuration, HPMN in particular.
+        let mut mdcr_el2: u64;
+        unsafe {
+            core::arch::asm!("mrs {}, MDCR_EL2", out(reg) mdcr_el2);
+        }
+        if let Some(backend) = config.dcc {
+            self.dcc = Some(VirtDcc::new(backend));
+            mdcr_el2 |= MDCR_EL2_TDA;
+        }
+        if has_feat_trf() {
+            // Tracing at EL1 and EL0 is prohibited at reset.
+            self.trfcr_el1 = Some(0);
+            self.self_hosted_trace = config.self_hosted_trace;
+            if !config.self_hosted_trace {
+                mdcr_el2 |= MDCR_EL2_TTRF;
+            }
+        }
+        #[cfg(feature = "vpmu")]
+        if (config.pmu_cycle_counter || config.pmu_cycle_fuzz.is_some()) && has_feat_pmuv3() {
+            self.vpmu = Some(VirtPmu::new(config.pmu_cycle_fuzz));
+            mdcr_el2 |= MDCR_EL2_TPM;
+        }
+        #[cfg(feature = "vpmu")]
+        let vpmu_enabled = self.vpmu.is_some();
+        #[cfg(not(feature = "vpmu"))]
+        let vpmu_enabled = false;
+        if !vpmu_enabled {
+            // The counters the guest programs must not count the hypervisor execution, whatever
+            // their `NSH` filter bit.
+            if has_feat_pmuv3p1() {
+                mdcr_el2 |= MDCR_EL2_HPMD;
+            }
+            if has_feat_pmuv3p5() {
+                mdcr_el2 |= MDCR_EL2_HCCD;
+            } else if has_feat_pmuv3() {
+                // Neither the event counters without FEAT_PMUv3p1 nor the cycle counter can be
+                // stopped at EL2, so the guest must not set their `NSH` filter bit.
+                self.filtered_pmu = Some(FilteredPmu::new(mdcr_el2));
+                mdcr_el2 |= MDCR_EL2_TPM;
+            }
+        }
+        if has_feat_trbe() {
+            // The guest must not point the trace buffer to host memory, see `is_trbe_sysreg`.
+            mdcr_el2 &= !MDCR_EL2_E2TB;
+        }
+        self.guest_system_regs.mdcr_el2 = mdcr_el2;
+
+        // Set VMPIDR_EL2, which provides the value of the Virtualization Multiprocessor ID.
+        // This is the value returned by Non-secure EL1 reads of MPIDR.
+        let mut vmpidr = 1 << 31;
+        // Note: mind CPU cluster here.
+        vmpidr |= self.mpidr;
+        self.guest_system_regs.vmpidr_el2 = vmpidr;
+        self.exception_state.psci.mpidr = vmpidr;
+        self.exception_state.psci.reset_storm_limit = config.reset_storm_limit;
+
+        // A snapshot the guest resumes from is not the state it restarts with.
+        self.reset_el1_state = self.guest_system_regs.el1_state();
+        if let Some(el1_state) = &config.el1_state {
+            self.guest_system_regs.set_el1_state(el1_state);
+        }
+        if config.track_guest_mmu {
+            self.guest_mmu = Some(Aarch64GuestMmu::new(&self.guest_system_regs.el1_state()));
+        }
+        self.update_sysreg_write_traps();
+    }
+
+    /// Checks that the vCPU is fully configured before it is first entered, a half-configured
+    /// vCPU would fault in the world switch or loop on stage-2 faults otherwise.
+    ///
+    /// With the `vgic` feature, the virtual CPU interface must be enabled if the virtual IRQs
+    /// are on a GICv3 host. The distributor and the CPU interface of the host are left to the
+    /// VMM.
+    fn validate_config(&self) -> AxResult {
+        let regs = &self.guest_system_regs;
+        if regs.vtcr_el2 == 0 {
+            return ax_err!(BadState, "the vCPU has not been set up");
+        }
+        if !self.entry_set {
+            return ax_err!(BadState, "the entry point of the vCPU has not been set");
+        }
+        if self.stage2_enabled() && regs.vttbr_el2 & VTTBR_BADDR_MASK == 0 {
+            return ax_err!(BadState, "the stage-2 page table root has not been set");
+        }
+        // E.g. a snapshot of a vCPU with the interrupts passed through has been restored.
+        #[cfg(feature = "vgic")]
+        if self.virtual_irq_enabled() && has_gicv3_sysregs() && !regs.vgic.enabled() {
+            return ax_err!(
+                BadState,
+                "virtual IRQs are enabled but the GICv3 virtual CPU interface is not configured"
+            );
+        }
+
+        // The vCPU may have been set up on a CPU of another type.
+        let ps = VTCR_EL2::PS.read(regs.vtcr_el2);
+        let parange = host_parange();
+        if ps > parange {
+            error!("VTCR_EL2.PS {ps:#b} exceeds the PA range of the host {parange:#b}");
+            return ax_err!(
+                Unsupported,
+                "the stage-2 output size exceeds the PA range of the host"
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns whether the guest runs with stage-2 translation, see
+    /// [`Aarch64VCpuSetupConfig::disable_stage2`].
+    fn stage2_enabled(&self) -> bool {
+        self.guest_system_regs.hcr_el2 & HCR_EL2_VM != 0
+    }
+
+    /// Returns whether the guest runs a guest hypervisor, see [`VncrPage`].
+    fn nested_guest(&self) -> bool {
+        #[cfg(feature = "nested")]
+        if self.vncr_page.is_some() {
+            return true;
+        }
+        false
+    }
+
+    /// Makes the guest take an Undefined Instruction exception for the trapped 32-bit
+    /// instruction it has exited with, the PC being already past it.
+    fn inject_undef(&mut self) {
+        /// `ESR_ELx.IL`, bit [25], the instruction is 32-bit, with an EC of 0 (Unknown reason).
+        const ESR_UNDEF: u64 = 1 << 25;
+        let pc = self.ctx.exception_pc() as u64 - 4;
+        self.guest_system_regs
+            .inject_el1_sync(&mut self.ctx, ESR_UNDEF, pc);
+    }
+
+    /// Makes the guest take the Data Abort of its trapped cache maintenance instruction to the
+    /// virtual address `va`, which faulted at stage 1 with the fault status `fst`, the PC being
+    /// already past the instruction.
+    fn inject_cache_maintenance_abort(&mut self, va: u64, fst: u64) {
+        /// `ESR_ELx.IL`, bit [25], the instruction is 32-bit.
+        const ESR_IL: u64 = 1 << 25;
+        /// `ESR_ELx.ISS.CM`, bit [8], and `WnR`, bit [6], always set for the cache maintenance.
+        const ESR_DA_CM_WNR: u64 = (1 << 8) | (1 << 6);
+        // A Data Abort from a lower EL for EL0, from the current EL for EL1.
+        let ec = if self.ctx.spsr & 0b1111 == 0 {
+            0x24
+        } else {
+            0x25
+        };
+        let pc = self.ctx.exception_pc() as u64 - 4;
+        self.guest_system_regs
+            .set_migrated_sysreg(SYSREG_FAR_EL1, va);
+        self.guest_system_regs.inject_el1_sync(
+            &mut self.ctx,
+            (ec << 26) | ESR_IL | ESR_DA_CM_WNR | fst,
+            pc,
+        );
+    }
+
+    /// Returns the saved system registers of the guest with the virtual counter offset of its
+    /// VM, which may have changed since its last entry.
+    fn migrated_system_regs(&self) -> GuestSystemRegisters {
+        let mut regs = self.guest_system_regs;
+        regs.cntvoff_el2 = self.virtual_counter_offset();
+        // The emulated registers are not the ones of the hardware.
+        if let Some(csselr_el1) = self
+            .cache_topology
+            .and_then(|topology| topology.read(SYSREG_CSSELR_EL1))
+        {
+            regs.set_migrated_sysreg(SYSREG_CSSELR_EL1, csselr_el1);
+        }
+        if let Some(dcc) = &self.dcc {
+            regs.set_migrated_sysreg(SYSREG_MDSCR_EL1, dcc.mdscr_el1());
+        }
+        regs
+    }
+
+    /// Loads the migrated registers just restored into `guest_system_regs` into their
+    /// emulation, if they are emulated.
+    fn restore_emulated_sysregs(&mut self) {
+        let regs = &self.guest_system_regs;
+        if let (Some(topology), Some(csselr_el1)) = (
+            &mut self.cache_topology,
+            regs.migrated_sysreg(SYSREG_CSSELR_EL1),
+        ) {
+            topology.write(SYSREG_CSSELR_EL1, csselr_el1);
+        }
+        if let (Some(dcc), Some(mdscr_el1)) =
+            (&mut self.dcc, regs.migrated_sysreg(SYSREG_MDSCR_EL1))
+        {
+            dcc.write(SYSREG_MDSCR_EL1, mdscr_el1, self.mpidr);
+        }
+    }
+
+    /// Encodes the VMID of the vCPU into its `VTTBR_EL2`.
+    fn load_vmid(&mut self) {
+        const VTTBR_VMID_MASK: u64 = 0xff << VTTBR_VMID_SHIFT;
+        let vttbr_el2 = &mut self.guest_system_regs.vttbr_el2;
+        *vttbr_el2 = (*vttbr_el2 & !VTTBR_VMID_MASK) | (vmid_of(self.vmid) << VTTBR_VMID_SHIFT);
+    }
+
+    /// Translates the guest physical address `ipa`, identity mapped if stage-2 translation is
+    /// disabled.
+    fn translate_ipa(&self, ipa: GuestPhysAddr) -> Option<HostPhysAddr> {
+        if !self.stage2_enabled() {
+            return Some(HostPhysAddr::from(ipa.as_usize()));
+        }
+        translate_ipa::<H::MmHal>(self.guest_system_regs.vttbr_el2, ipa)
+    }
+
+    /// Translates the IPA of a hypercall buffer for a read or a `write` of the VMM, which the
+    /// stage-2 mapping must allow to the guest, see [`translate_ipa_access`].
+    fn translate_buffer_ipa(&self, ipa: GuestPhysAddr, write: bool) -> AxResult<HostPhysAddr> {
+        if !self.stage2_enabled() {
+            return Ok(HostPhysAddr::from(ipa.as_usize()));
+        }
+        translate_ipa_access::<H::MmHal>(
+            self.guest_system_regs.vttbr_el2,
+            ipa,
+            write,
+            self.stage2_fwb_enabled(),
+        )
+    }
+
+    /// Updates the preempted flag of the paravirtual state area registered by the guest, if
+    /// any.
+    fn set_pv_preempted(&self, preempted: bool) {
+        let Some(flag) = self.pv_preempted_flag() else {
+            return;
+        };
+        // The area is 64-byte aligned guest memory, the guest only ever reads the flag.
+        unsafe { flag.write_volatile((preempted as u64).to_le()) };
+    }
+
+    /// Returns the host address of the PV `preempted` flag, if the guest has registered one.
+    fn pv_preempted_flag(&self) -> Option<*mut u64> {
+        let area = self.exception_state.pvlock.and_then(|pv| pv.area)?;
+        let Some(hpa) = self.translate_ipa(area) else {
+            warn!(
+                "PV lock state area {area:?} of vCPU {:#x} is not mapped",
+                self.mpidr
+            );
+            return None;
+        };
+        Some(H::MmHal::phys_to_virt(hpa).as_usize() as *mut u64)
+    }
+
+    /// Saves the FP/SIMD registers of the host and loads the ones of the guest, on its first
+    /// access.
+    fn load_guest_fp(&mut self) {
+        if !self.guest_fp_loaded {
+            unsafe {
+                self.host_fp.save();
+                self.guest_fp.restore();
+            }
+            self.guest_fp_loaded = true;
+        }
+    }
+
+    /// Saves the FP/SIMD registers of the guest and loads back the ones of the host, if the
+    /// guest has used them, before returning to the VMM.
+    fn unload_guest_fp(&mut self) {
+        if core::mem::take(&mut self.guest_fp_loaded) {
+            unsafe {
+                self.guest_fp.save();
+                self.host_fp.restore();
+            }
+        }
+    }
+
+    /// Cleans and invalidates the data cache line of the guest IPA `ipa` to the PoC, completing
+    /// a `DC IVAC` upgraded by [`DcIvacPolicy::CleanInvalidate`].
+    fn clean_invalidate_guest_line(&self, ipa: GuestPhysAddr) {
+        let Some(hpa) = self.translate_ipa(ipa) else {
+            return;
+        };
+        // The maintenance by VA works on the whole line containing the address, whatever the
+        // mapping it goes through.
+        let va = H::MmHal::phys_to_virt(hpa).as_usize();
+        unsafe { core::arch::asm!("dc civac, {}", "dsb sy", in(reg) va) };
+    }
+
+    /// Performs the trapped cache maintenance to the PoU `addr` of the guest, by the guest
+    /// virtual address `va`.
+    ///
+    /// A `DC CVAU` to an address the guest can't translate makes it take the data abort it
+    /// would have taken without the trap, returning `false`.
+    fn perform_pou_maintenance(&mut self, addr: SysRegAddr, va: u64) -> bool {
+        if addr == SYSREG_DC_CVAU {
+            // Only a read permission is needed, like for the guest itself.
+            let ipa = match try_translate_guest_va(va as usize) {
+                Ok(ipa) => ipa,
+                Err(Some(fst)) => {
+                    self.inject_cache_maintenance_abort(va, fst);
+                    return false;
+                }
+                // The maintenance of an address not mapped by stage 2 is skipped, there is no
+                // memory to maintain.
+                Err(None) => return true,
+            };
+            let Some(hpa) = self.translate_ipa(ipa) else {
+                return true;
+            };
+            let va = H::MmHal::phys_to_virt(hpa).as_usize();
+            unsafe { core::arch::asm!("dc cvau, {}", "dsb ish", in(reg) va) };
+        } else {
+            // The EL2 VAs don't map the guest code, invalidate the whole instruction cache,
+            // on all the physical CPUs the vCPU may have run on.
+            unsafe { core::arch::asm!("ic ialluis", "dsb ish") };
+        }
+        true
+    }
+
+    /// Reads the A64 or A32 instruction of the guest at the virtual address `pc`, `None` if
+    /// the guest can't read it.
+    fn read_guest_insn(&self, pc: usize) -> Option<u32> {
+        let hpa = translate_guest_va(pc).and_then(|ipa| self.translate_ipa(ipa))?;
+        let va = H::MmHal::phys_to_virt(hpa).as_usize() as *const u32;
+        // The instructions are 4-byte aligned, thus within a page, and always little-endian.
+        Some(u32::from_le(unsafe { va.read_volatile() }))
+    }
+
+    /// Reads the halfword of the guest at the virtual address `va`, for its T32 instructions,
+    /// `None` if the guest can't read it.
+    #[cfg(feature = "aarch32")]
+    fn read_guest_halfword(&self, va: usize) -> Option<u16> {
+        let hpa = translate_guest_va(va).and_then(|ipa| self.translate_ipa(ipa))?;
+        let va = H::MmHal::phys_to_virt(hpa).as_usize() as *const u16;
+        Some(u16::from_le(unsafe { va.read_volatile() }))
+    }
 
-    fn new(_vm_id: usize, _vcpu_id: usize, config: Self::CreateConfig) -> AxResult<Self> {
-        let mut ctx = TrapFrame::default();
-        ctx.set_argument(config.dtb_addr);
+    /// Reads and decodes the load or store of the guest at `pc`, returning the instruction,
+    /// the first halfword of the 16-bit T32 ones, and its decoding with the registers in their
+    /// AArch64 view.
+    ///
+    /// The A32 and T32 instructions are only decoded with the `aarch32` feature.
+    fn decode_guest_mmio_insn(&self, pc: usize) -> (Option<u32>, Option<DecodedMmioInsn>) {
+        if self.ctx.is_aarch32() {
+            #[cfg(feature = "aarch32")]
+            return self.decode_guest_aarch32_insn(pc);
+            #[cfg(not(feature = "aarch32"))]
+            return (None, None);
+        }
+        let insn = self.read_guest_insn(pc);
+        (insn, insn.and_then(DecodedMmioInsn::decode))
+    }
 
-        Ok(Self {
-            ctx,
-            host_stack_top: 0,
-            guest_system_regs: GuestSystemRegisters::default(),
-            mpidr: config.mpidr_el1,
-            _phantom: PhantomData,
-        })
+    /// Reads and decodes the A32 or T32 load or store of the guest at `pc`, see
+    /// [`decode_guest_mmio_insn`](Self::decode_guest_mmio_insn).
+    #[cfg(feature = "aarch32")]
+    fn decode_guest_aarch32_insn(&self, pc: usize) -> (Option<u32>, Option<DecodedMmioInsn>) {
+        let thumb = self.ctx.spsr & SPSR_T != 0;
+        let insn = if thumb {
+            match self.read_guest_halfword(pc) {
+                // The halfwords may be in different pages.
+                Some(hw1) if is_t32_wide(hw1) => self
+                    .read_guest_halfword(pc + 2)
+                    .map(|hw2| ((hw1 as u32) << 16) | hw2 as u32),
+                hw1 => return (hw1.map(u32::from), None),
+            }
+        } else {
+            self.read_guest_insn(pc)
+        };
+        let decoded = if thumb {
+            insn.and_then(DecodedMmioInsn::decode_t32)
+        } else {
+            insn.and_then(DecodedMmioInsn::decode_a32)
+        };
+        (
+            insn,
+            decoded.and_then(|decoded| decoded.to_aarch64_view(self.ctx.spsr)),
+        )
     }
 
-    fn setup(&mut self, config: Self::SetupConfig) -> AxResult {
-        self.init_hv(config);
-        Ok(())
+    /// Returns the guest register `reg`, 31 being the stack pointer selected by `PSTATE.SP`.
+    fn guest_reg_or_sp(&self, reg: usize) -> u64 {
+        match reg {
+            0..=30 => self.ctx.gpr[reg],
+            // `SPSR_EL2.M[0]`, set in EL1h.
+            _ if self.ctx.spsr & 1 != 0 => self.guest_system_regs.sp_el1,
+            _ => self.ctx.sp_el0,
+        }
     }
 
-    fn set_entry(&mut self, entry: GuestPhysAddr) -> AxResult {
-        debug!("set vcpu entry:{entry:?}");
-        self.set_elr(entry.as_usize());
-        Ok(())
+    /// Applies the base register update of an emulated load or store.
+    fn apply_writeback(&mut self, writeback: Writeback) {
+        let mut value = self
+            .guest_reg_or_sp(writeback.base)
+            .wrapping_add_signed(writeback.offset);
+        if self.ctx.is_aarch32() {
+            value = value as u32 as u64;
+        }
+        match writeback.base {
+            0..=30 => self.ctx.gpr[writeback.base] = value,
+            _ if self.ctx.spsr & 1 != 0 => self.guest_system_regs.sp_el1 = value,
+            _ => self.ctx.sp_el0 = value,
+        }
     }
 
-    fn set_ept_root(&mut self, ept_root: HostPhysAddr) -> AxResult {
-        debug!("set vcpu ept root:{ept_root:#x}");
-        self.guest_system_regs.vttbr_el2 = ept_root.as_usize() as u64;
-        Ok(())
+    /// Emulates a data abort at `ipa` (the VA `far`) with no valid instruction syndrome, by
+    /// decoding its instruction.
+    ///
+    /// Returns the MMIO exit of the (first) access, and whether the instruction can be
+    /// replayed. The exits of the other accesses of the pairs and of `DC ZVA` are returned by
+    /// the next `run()`s, the base register is updated as soon as the instruction is decoded.
+    ///
+    /// The instructions which can't be decoded, and the pairs crossing a page, are reported
+    /// as an [`UnhandledExit`] instead, the PC of the guest not being advanced.
+    fn emulate_undecoded_abort(
+        &mut self,
+        ipa: GuestPhysAddr,
+        far: usize,
+    ) -> (AxVCpuExitReason, bool) {
+        const PAGE_MASK: usize = !0xfff;
+
+        let pc = self.ctx.exception_pc();
+        let (insn, decoded) = self.decode_guest_mmio_insn(pc);
+        let Some(decoded) = decoded else {
+            error!(
+                "Core data abort not handleable {:#x}, instruction {:x?} @pc {}",
+                ipa,
+                insn,
+                self.exception_state.symbolizer.addr(pc as u64)
+            );
+            return (self.report_unhandled(TrapKind::Synchronous), true);
+        };
+
+        let emulated = match decoded {
+            DecodedMmioInsn::Single { access, writeback } => {
+                let exit = mmio_exit(&self.ctx, ipa, &access);
+                if let Some(writeback) = writeback {
+                    self.apply_writeback(writeback);
+                }
+                (exit, writeback.is_none())
+            }
+            DecodedMmioInsn::Pair {
+                access,
+                reg2,
+                base,
+                offset,
+                writeback,
+            } => {
+                let size = if access.width == AccessWidth::Qword {
+                    8
+                } else {
+                    4
+                };
+                // The fault may be on either register, find the IPA of the first one.
+                let start = self.guest_reg_or_sp(base).wrapping_add_signed(offset) as usize;
+                let delta = far.wrapping_sub(start);
+                let first = ipa.as_usize().wrapping_sub(delta);
+                let last = first.wrapping_add(2 * size - 1);
+                if delta >= 2 * size
+                    || first & PAGE_MASK != ipa.as_usize() & PAGE_MASK
+                    || last & PAGE_MASK != ipa.as_usize() & PAGE_MASK
+                {
+                    error!(
+                        "Core data abort not handleable {:#x}, pair crossing a page @pc {}",
+                        ipa,
+                        self.exception_state.symbolizer.addr(pc as u64)
+                    );
+                    return (self.report_unhandled(TrapKind::Synchronous), true);
+                }
+                let exit = mmio_exit(&self.ctx, GuestPhysAddr::from(first), &access);
+                let access2 = DataAbortAccess {
+                    reg: reg2,
+                    ..access
+                };
+                let exit2 = mmio_exit(&self.ctx, GuestPhysAddr::from(first + size), &access2);
+                self.split_mmio = Some(SplitMmio::Exit(exit2));
+                if let Some(writeback) = writeback {
+                    self.apply_writeback(writeback);
+                }
+                (exit, false)
+            }
+            DecodedMmioInsn::DcZva => {
+                let dczid_el0: u64;
+                unsafe { core::arch::asm!("mrs {}, dczid_el0", out(reg) dczid_el0) };
+                // `DCZID_EL0.BS`, bits [3:0], the log2 of the block size in words.
+                let block = 4usize << (dczid_el0 & 0xf);
+                // The block is zeroed with 8-byte writes, but the 4-byte one.
+                let size = block.min(8);
+                let start = ipa.as_usize() & !(block - 1);
+                if block > size {
+                    self.split_mmio = Some(SplitMmio::Zero {
+                        addr: start + size,
+                        size,
+                        remaining: block / size - 1,
+                    });
+                }
+                (zero_write(start, size), false)
+            }
+        };
+        // The decoded loads and stores are 32-bit instructions, the T32 ones included.
+        self.ctx.skip_instruction(4);
+        emulated
     }
 
-    fn run(&mut self) -> AxResult<AxVCpuExitReason> {
+    /// Enters the guest until its next VM exit, and handles the exit.
+    fn enter_guest(&mut self, host_daif: &HostDaif) -> AxResult<AxVCpuExitReason> {
+        // The vCPU is resumed if it was suspended through psci.
+        self.exception_state.psci.resume();
+
+        #[cfg(feature = "tracing")]
+        trace_event(self.mpidr, VCpuTraceEvent::Entry { pc: self.ctx.elr });
+
+        // The VM exit doesn't reinstate the host PAN, UAO, DIT and SSBS bits.
+        let host_pstate = HostPstate::save();
+        #[cfg(feature = "switch-checks")]
+        let snapshot = SwitchSnapshot::entry();
+
+        // The VMID changes if a rollover has happened since the last entry. Activated before
+        // anything of the host is saved, so that an error leaves the host context as is.
+        let vmid = activate_vmid(self.vm_id, self.vmid)?;
+        if vmid != self.vmid {
+            self.vmid = vmid;
+            if let Some(vm) = self.vm_state {
+                vm.update_vmid(vmid);
+            }
+            self.stage2_flush_pending = true;
+        }
+
         // Run guest.
         let exit_reson = unsafe {
             // Save host SP_EL0 to the ctx becase it's used as current task ptr.
             // This has to be done before vm system regs are restored.
             save_host_sp_el0();
+            self.load_vmid();
+            if let Some(vm) = self.vm_state {
+                self.guest_system_regs.cntvoff_el2 = vm.virtual_counter_offset();
+            }
             self.restore_vm_system_regs();
+            self.last_entry = CNTPCT_EL0.get();
+            if let (Some(stats), Some(exit)) = (&mut self.exception_state.hv_stats, &self.last_exit)
+            {
+                stats.stolen_ticks += self.last_entry.wrapping_sub(exit.timestamp);
+            }
+            #[cfg(feature = "exit-latency")]
+            if let Some(mut timestamps) = self.exit_timestamps.take() {
+                timestamps.reentry = self.last_entry;
+                self.last_exit_timestamps = Some(timestamps);
+            }
+            self.irq_pending = false;
             self.run_guest()
         };
+        #[cfg(feature = "switch-checks")]
+        snapshot.exit(self.host_stack_top);
 
-        let trap_kind = TrapKind::try_from(exit_reson as u8).expect("Invalid TrapKind");
-        self.vmexit_handler(trap_kind)
-    }
+        host_pstate.restore();
+        // The FP/SIMD accesses of EL2 are trapped too.
+        unsafe { core::arch::asm!("msr cptr_el2, xzr", "isb") };
 
-    fn bind(&mut self) -> AxResult {
-        Ok(())
+        let trap_kind = TrapKind::try_from(exit_reson as u8).expect("Invalid TrapKind");
+        let exit_reason = self.vmexit_handler(trap_kind, host_daif)?;
+        #[cfg(feature = "switch-checks")]
+        snapshot.host_sp_el0_restored();
+        Ok(exit_reason)
     }
 
-    fn unbind(&mut self) -> AxResult {
-        Ok(())
+    /// Returns the next exit of the accesses left by `emulate_undecoded_abort`, if any,
+    /// handled as an exit of the guest by `vmexit_handler`.
+    fn next_split_mmio(&mut self) -> Option<AxVCpuExitReason> {
+        let exit = match self.split_mmio.take()? {
+            SplitMmio::Exit(exit) => exit,
+            SplitMmio::Zero {
+                addr,
+                size,
+                remaining,
+            } => {
+                if remaining > 1 {
+                    self.split_mmio = Some(SplitMmio::Zero {
+                        addr: addr + size,
+                        size,
+                        remaining: remaining - 1,
+                    });
+                }
+                zero_write(addr, size)
+            }
+        };
+        self.gprs_modified = 0;
+        if let AxVCpuExitReason::MmioWrite { addr, data, .. } = exit {
+            if let Some(event) = power_mmio_event(self.power_mmio, addr, data) {
+                return Some(system_event(&self.ctx, &mut self.exception_state, event));
+            }
+        }
+        Some(exit)
     }
 
-    fn set_gpr(&mut self, idx: usize, val: usize) {
-        self.ctx.set_gpr(idx, val);
+    /// Records the exception of the exit the vCPU doesn't handle and returns the exit reporting
+    /// it, see [`UnhandledExit`].
+    fn report_unhandled(&mut self, kind: TrapKind) -> AxVCpuExitReason {
+        let unhandled = UnhandledExit::capture(kind, &self.ctx);
+        self.exception_state.unhandled = Some(unhandled);
+        unhandled.exit_reason()
     }
 
-    fn inject_interrupt(&mut self, vector: usize) -> AxResult {
-        axvisor_api::arch::hardware_inject_virtual_interrupt(vector as u8);
-        Ok(())
-    }
+    /// Handles a physical IRQ taken while the guest runs.
+    ///
+    /// The interrupt is acknowledged by the HAL (`irq_fetch`, e.g. reading `ICC_IAR1_EL1`) and
+    /// its INTID reported as [`AxVCpuExitReason::ExternalInterrupt`]: the host then handles
+    /// and completes (EOIs) it before entering the guest again. An IRQ withdrawn meanwhile reads
+    /// as the spurious INTID, it has nothing to complete and the guest is resumed right away.
+    /// The guest is also resumed right away if the [`IrqClassifier`](crate::IrqClassifier) of
+    /// the CPU, if any, has handled the IRQ as one of the host.
+    fn handle_irq_exit(&mut self) -> AxVCpuExitReason {
+        /// The INTID read from `ICC_IAR1_EL1` or `GICC_IAR` if no interrupt is pending.
+        const SPURIOUS_INTID: usize = 1023;
 
-    fn set_return_value(&mut self, val: usize) {
-        // Return value is stored in x0.
-        self.ctx.set_argument(val);
+        let vector = H::irq_fetch();
+        if vector == SPURIOUS_INTID {
+            self.exception_state.resume = true;
+            return AxVCpuExitReason::Nothing;
+        }
+        if let Some(classifier) = irq_classifier() {
+            if classifier.classify(vector) == IrqTarget::Host {
+                self.exception_state.resume = true;
+                return AxVCpuExitReason::Nothing;
+            }
+        }
+        AxVCpuExitReason::ExternalInterrupt {
+            vector: vector as _,
+        }
     }
-}
 
-// Private function
-impl<H: AxVCpuHal> Aarch64VCpu<H> {
-    fn init_hv(&mut self, config: Aarch64VCpuSetupConfig) {
-        self.ctx.spsr = (SPSR_EL1::M::EL1h
-            + SPSR_EL1::I::Masked
-            + SPSR_EL1::F::Masked
-            + SPSR_EL1::A::Masked
-            + SPSR_EL1::D::Masked)
-            .value;
-        self.init_vm_context(config);
+    /// Handles a physical SError taken while the guest runs, reporting it to the VMM, see
+    /// [`GuestSError`].
+    fn handle_serror_exit(&mut self) -> AxVCpuExitReason {
+        let serror = GuestSError::capture(&self.ctx);
+        if count_event(&mut self.exception_state.stats.serrors) {
+            warn!(
+                "vCPU {:#x} took an SError @pc {:#x}, esr {:#x}",
+                self.mpidr, serror.elr, serror.esr
+            );
+        }
+        if let Some(exit) = &mut self.last_exit {
+            exit.serror = Some(serror);
+        }
+        serror.exit_reason()
     }
 
-    /// Init guest context. Also set some el2 register value.
-    fn init_vm_context(&mut self, config: Aarch64VCpuSetupConfig) {
-        // CNTHCTL_EL2.modify(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
-        self.guest_system_regs.cntvoff_el2 = 0;
-        self.guest_system_regs.cntkctl_el1 = 0;
-        self.guest_system_regs.cnthctl_el2 = if config.passthrough_timer {
-            (CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET).into()
+    /// Records the faulting instruction of an MMIO exit, at the PC and with the PSTATE of
+    /// `trapped`, the PC of the guest being already advanced.
+    fn note_mmio_access(&mut self, trapped: (usize, u64)) {
+        let (pc, _) = trapped;
+        self.mmio_pc = Some(trapped);
+        let exclusive = if self.ctx.is_aarch32() {
+            None
         } else {
-            (CNTHCTL_EL2::EL1PCEN::CLEAR + CNTHCTL_EL2::EL1PCTEN::CLEAR).into()
+            self.read_guest_insn(pc).and_then(ExclusiveAccess::decode)
         };
-
-        self.guest_system_regs.sctlr_el1 = 0x30C50830;
-        self.guest_system_regs.pmcr_el0 = 0;
-
-        // use 3 level ept paging
-        // - 4KiB granule (TG0)
-        // - 39-bit address space (T0_SZ)
-        // - start at level 1 (SL0)
-        // self.guest_system_regs.vtcr_el2 = (VTCR_EL2::PS::PA_40B_1TB
-        //     + VTCR_EL2::TG0::Granule4KB
-        //     + VTCR_EL2::SH0::Inner
-        //     + VTCR_EL2::ORGN0::NormalWBRAWA
-        //     + VTCR_EL2::IRGN0::NormalWBRAWA
-        //     + VTCR_EL2::SL0.val(0b01)
-        //     + VTCR_EL2::T0SZ.val(64 - 39))
-        // .into();
-
-        // use 4 level ept paging
-        // - 4KiB granule (TG0)
-        // - 48-bit address space (T0_SZ)
-        // - start at level 0 (SL0)
-        self.guest_system_regs.vtcr_el2 = (VTCR_EL2::PS::PA_48B_256TB
-            + VTCR_EL2::TG0::Granule4KB
-            + VTCR_EL2::SH0::Inner
-            + VTCR_EL2::ORGN0::NormalWBRAWA
-            + VTCR_EL2::IRGN0::NormalWBRAWA
-            + VTCR_EL2::SL0.val(0b10) // 0b10 means start at level 0
-            + VTCR_EL2::T0SZ.val(64 - 48))
-        .into();
-
-        let mut hcr_el2 = HCR_EL2::VM::Enable
-            + HCR_EL2::RW::EL1IsAarch64
-            + HCR_EL2::FMO::EnableVirtualFIQ
-            + HCR_EL2::TSC::EnableTrapEl1SmcToEl2
-            + HCR_EL2::RW::EL1IsAarch64;
-
-        if !config.passthrough_interrupt {
-            // Set HCR_EL2.IMO will trap IRQs to EL2 while enabling virtual IRQs.
-            //
-            // We must choose one of the two:
-            // - Enable virtual IRQs and trap physical IRQs to EL2.
-            // - Disable virtual IRQs and pass through physical IRQs to EL1.
-            hcr_el2 += HCR_EL2::IMO::EnableVirtualIRQ;
+        if let Some(ExclusiveAccess::Store { status_reg }) = exclusive {
+            // Reports success if the VMM completes the store.
+            if status_reg != 31 {
+                self.ctx.set_gpr(status_reg, 0);
+            }
         }
+        if let Some(exit) = &mut self.last_exit {
+            exit.exclusive = exclusive;
+            // Not set for the accesses emulated from their instruction, which are no acquires
+            // or releases.
+            exit.acquire_release = DataAbortAccess::decode(ESR_EL2::ISS.read(exit.esr) as usize)
+                .is_some_and(|access| access.acquire_release);
+        }
+    }
 
-        self.guest_system_regs.hcr_el2 = hcr_el2.into();
-
-        // Set VMPIDR_EL2, which provides the value of the Virtualization Multiprocessor ID.
-        // This is the value returned by Non-secure EL1 reads of MPIDR.
-        let mut vmpidr = 1 << 31;
-        // Note: mind CPU cluster here.
-        vmpidr |= self.mpidr;
-        self.guest_system_regs.vmpidr_el2 = vmpidr;
+    /// Traps the writes to the registers needed by the MMU tracking and the watched ones.
+    fn update_sysreg_write_traps(&mut self) {
+        if self.guest_mmu.is_some() || self.sysreg_watch & WatchedSysReg::TVM_MASK != 0 {
+            self.guest_system_regs.hcr_el2 |= HCR_EL2_TVM;
+        } else {
+            self.guest_system_regs.hcr_el2 &= !HCR_EL2_TVM;
+        }
+        if self.sysreg_watch & WatchedSysReg::VbarEl1.bit() != 0 {
+            self.hfgwtr_el2 |= HFGWTR_EL2_VBAR_EL1;
+        } else {
+            self.hfgwtr_el2 &= !HFGWTR_EL2_VBAR_EL1;
+        }
     }
 
     /// Set exception return pc
@@ -223,6 +2676,18 @@ impl<H: AxVCpuHal> Aarch64VCpu<H> {
         self.ctx.set_exception_pc(elr);
     }
 
+    /// Sets the PC the guest starts at, switching an AArch32 guest to the Thumb state if bit 0
+    /// of `entry` is set, like an interworking branch.
+    fn set_entry_pc(&mut self, entry: GuestPhysAddr) {
+        let entry = entry.as_usize();
+        if self.aarch32_el1 {
+            self.ctx.spsr = (self.ctx.spsr & !SPSR_T) | ((entry as u64 & 1) << 5);
+            self.set_elr(entry & !1);
+        } else {
+            self.set_elr(entry);
+        }
+    }
+
     /// Get general purpose register
     #[allow(unused)]
     fn get_gpr(&self, idx: usize) {
@@ -277,20 +2742,61 @@ impl<H: AxVCpuHal> Aarch64VCpu<H> {
     unsafe fn restore_vm_system_regs(&mut self) {
         unsafe {
             // load system regs
-            core::arch::asm!(
-                "
-                mov x3, xzr           // Trap nothing from EL1 to El2.
-                msr cptr_el2, x3"
-            );
+            // Trap nothing from EL1 to EL2, but the FP/SIMD accesses until the guest registers
+            // are loaded.
+            let cptr_el2 = if self.guest_fp_loaded {
+                0
+            } else {
+                CPTR_EL2_TFP
+            };
+            #[cfg(feature = "aarch32")]
+            self.guest_system_regs.restore_fpexc32();
+            core::arch::asm!("msr cptr_el2, {}", in(reg) cptr_el2);
+            if needs_workaround(ErratumWorkaround::SpeculativeAt) {
+                // Speculative translations of the EL1 registers loaded below must use the
+                // stage-2 context of the guest.
+                core::arch::asm!(
+                    "msr VTTBR_EL2, {}",
+                    "isb",
+                    in(reg) self.guest_system_regs.vttbr_el2
+                );
+            }
             self.guest_system_regs.restore();
-            core::arch::asm!(
-                "
-                ic  iallu
-                tlbi	alle2
-                tlbi	alle1         // Flush tlb
-                dsb	nsh
-                isb"
-            );
+            // Only read by the guest with a virtual SError pending, so left over from another
+            // vCPU otherwise.
+            if self.serror_pending() && has_feat_ras() {
+                core::arch::asm!("msr S3_4_C5_C2_3, {}", in(reg) self.vsesr_el2); // VSESR_EL2
+            }
+            if let Some(partition) = self.mpam_partition {
+                load_guest_mpam(partition);
+            }
+            if let Some(trfcr_el1) = self.trfcr_el1 {
+                // Also loaded if the guest can't use it, overriding the one of the previous vCPU.
+                core::arch::asm!("msr S3_0_C1_C2_1, {}", in(reg) trfcr_el1); // TRFCR_EL1
+            }
+            #[cfg(feature = "vpmu")]
+            if let Some(vpmu) = &mut self.vpmu {
+                vpmu.load();
+            }
+            #[cfg(feature = "nested")]
+            if let Some(page) = &self.vncr_page {
+                core::arch::asm!("msr S3_4_C2_C2_0, {}", in(reg) page.base()); // VNCR_EL2
+            }
+            if has_feat_fgt() {
+                // Always loaded, so that traps don't leak from other vCPUs.
+                core::arch::asm!("msr HFGWTR_EL2, {}", in(reg) self.hfgwtr_el2);
+            }
+            // The TLB entries are tagged with the VMID loaded above, they stay valid across
+            // the entries and exits unless the stage-2 context of the vCPU changed, or another
+            // vCPU ran on this physical CPU meanwhile. The maintenance of the guest itself is
+            // broadcast (`HCR_EL2.FB`), so it reaches the CPUs the vCPU ran on before.
+            if core::mem::take(&mut self.stage2_flush_pending) {
+                flush_vmid(TlbScope::InnerShareable);
+            }
+            let vcpu = self as *const Self as usize;
+            if swap_last_entered_vcpu(vcpu) != vcpu {
+                flush_local_guest_context();
+            }
         }
     }
 
@@ -302,17 +2808,61 @@ impl<H: AxVCpuHal> Aarch64VCpu<H> {
     /// Returns:
     /// - [`AxVCpuExitReason`]: a wrappered VM-Exit reason needed to be handled by the hypervisor.
     ///
-    /// This function may panic for unhandled exceptions.
-    fn vmexit_handler(&mut self, exit_reason: TrapKind) -> AxResult<AxVCpuExitReason> {
+    /// The exceptions the vCPU has no handler for are reported as an [`UnhandledExit`] instead
+    /// of panicking, see [`Aarch64ExitInfo::unhandled`].
+    fn vmexit_handler(
+        &mut self,
+        exit_reason: TrapKind,
+        host_daif: &HostDaif,
+    ) -> AxResult<AxVCpuExitReason> {
         trace!(
             "Aarch64VCpu vmexit_handler() esr:{:#x} ctx:{:#x?}",
             exception_class_value(),
             self.ctx
         );
 
+        let timestamp = CNTPCT_EL0.get();
+        self.last_exit = Some(Aarch64ExitInfo {
+            kind: exit_reason,
+            esr: ESR_EL2.get(),
+            timestamp,
+            el: self.ctx.exception_level() as u8,
+            stage2_fault: None,
+            exclusive: None,
+            acquire_release: false,
+            unhandled: None,
+            serror: None,
+        });
+        self.mmio_pc = None;
+        self.gprs_modified = 0;
+        #[cfg(feature = "nested")]
+        if let Some(page) = &mut self.vncr_page {
+            page.track_dirty();
+        }
+        if let Some(stats) = &mut self.exception_state.hv_stats {
+            stats.record_exit(exit_reason);
+        }
+        #[cfg(feature = "exit-latency")]
+        {
+            self.exit_timestamps = Some(Aarch64ExitTimestamps {
+                vector: self.exit_vector_time,
+                handler: timestamp,
+                ..Default::default()
+            });
+        }
+
         unsafe {
             // Store guest system regs
             self.guest_system_regs.store();
+            if self.self_hosted_trace {
+                let trfcr_el1: u64;
+                core::arch::asm!("mrs {}, S3_0_C1_C2_1", out(reg) trfcr_el1); // TRFCR_EL1
+                self.trfcr_el1 = Some(trfcr_el1);
+            }
+            #[cfg(feature = "vpmu")]
+            if let Some(vpmu) = &mut self.vpmu {
+                vpmu.save();
+            }
 
             // Store guest `SP_EL0` into the `Aarch64VCpu` struct,
             // which will be restored when the guest is resumed in `exception_return_el2`.
@@ -322,14 +2872,72 @@ impl<H: AxVCpuHal> Aarch64VCpu<H> {
             // This has to be done after guest's SP_EL0 is stored by `ext_regs_store`.
             restore_host_sp_el0();
         }
+        // The host can take its IRQs from here, as its context is back. The IRQ exits are
+        // handled masked, the host would otherwise take the IRQ reported to the VMM.
+        if self.host_irqs_in_exits && exit_reason == TrapKind::Synchronous {
+            host_daif.restore();
+        }
+
+        #[cfg(feature = "tracing")]
+        trace_event(
+            self.mpidr,
+            VCpuTraceEvent::Exit {
+                kind: exit_reason,
+                esr: ESR_EL2.get(),
+            },
+        );
 
-        let result = match exit_reason {
-            TrapKind::Synchronous => handle_exception_sync(&mut self.ctx),
-            TrapKind::Irq => Ok(AxVCpuExitReason::ExternalInterrupt {
-                vector: H::irq_fetch() as _,
-            }),
-            _ => panic!("Unhandled exception {:?}", exit_reason),
+        // The instruction the guest has trapped on, before it is skipped.
+        let trapped = (self.ctx.exception_pc(), self.ctx.spsr);
+        let mut result = match exit_reason {
+            // Denied before the IRQ is acknowledged, the host takes it once `run()` returns.
+            _ if self.deny_aarch32_el0
+                && self.ctx.is_aarch32()
+                && self.ctx.exception_level() == 0 =>
+            {
+                warn!(
+                    "vCPU {:#x} denied AArch32 EL0 execution @pc {:#x}",
+                    self.mpidr, self.ctx.elr
+                );
+                Ok(self.report_unhandled(exit_reason))
+            }
+            TrapKind::Synchronous => {
+                handle_exception_sync(&mut self.ctx, &mut self.exception_state)
+            }
+            TrapKind::Irq => Ok(self.handle_irq_exit()),
+            TrapKind::SError => Ok(self.handle_serror_exit()),
+            _ => {
+                error!("Unhandled exception {:?} ctx:{}", exit_reason, self.ctx);
+                Ok(self.report_unhandled(exit_reason))
+            }
         };
+        if core::mem::take(&mut self.exception_state.fp_access) {
+            self.load_guest_fp();
+        }
+        if let Some(ipa) = self.exception_state.dc_civac.take() {
+            self.clean_invalidate_guest_line(ipa);
+        }
+        let mut replayable = true;
+        if let Some((ipa, far)) = self.exception_state.undecoded_abort.take() {
+            let (exit, simple) = self.emulate_undecoded_abort(ipa, far);
+            result = Ok(exit);
+            replayable = simple;
+        }
+        if let Some(exit) = &mut self.last_exit {
+            exit.stage2_fault = self.exception_state.stage2_fault.take();
+            exit.unhandled = self.exception_state.unhandled.take();
+        }
+        if let Ok(AxVCpuExitReason::MmioWrite { addr, data, .. }) = result {
+            if let Some(event) = power_mmio_event(self.power_mmio, addr, data) {
+                result = Ok(system_event(&self.ctx, &mut self.exception_state, event));
+            }
+        }
+        if let Ok(AxVCpuExitReason::MmioRead { .. } | AxVCpuExitReason::MmioWrite { .. }) = result {
+            self.note_mmio_access(trapped);
+            if !replayable {
+                self.mmio_pc = None;
+            }
+        }
 
         match result {
             Ok(AxVCpuExitReason::SysRegRead { addr, reg }) => {
@@ -364,7 +2972,172 @@ impl<H: AxVCpuHal> Aarch64VCpu<H> {
         value: u64,
         reg: usize,
     ) -> AxResult<Option<AxVCpuExitReason>> {
-        const SYSREG_ICC_SGI1R_EL1: SysRegAddr = SysRegAddr::new(0x3A_3016); // ICC_SGI1R_EL1
+        if let Some(access) = sysreg_table_access(self.sysreg_table, addr, write.then_some(value)) {
+            let Some(val) = access else {
+                return Ok(None);
+            };
+            if !write {
+                self.ctx.set_gpr(reg, val as usize);
+            }
+            return Ok(Some(AxVCpuExitReason::Nothing));
+        }
+
+        if let (Some(id_regs), false) = (&self.id_regs, write) {
+            if let Some(val) = id_regs.read(addr) {
+                self.ctx.set_gpr(reg, val as usize);
+                return Ok(Some(AxVCpuExitReason::Nothing));
+            }
+        }
+
+        if let Some(topology) = &mut self.cache_topology {
+            if write {
+                if topology.write(addr, value) {
+                    return Ok(Some(AxVCpuExitReason::Nothing));
+                }
+            } else if let Some(val) = topology.read(addr) {
+                self.ctx.set_gpr(reg, val as usize);
+                return Ok(Some(AxVCpuExitReason::Nothing));
+            }
+        }
+
+        if let Some(dcc) = &mut self.dcc {
+            if write {
+                if dcc.write(addr, value, self.mpidr) {
+                    return Ok(Some(AxVCpuExitReason::Nothing));
+                }
+            } else if let Some(val) = dcc.read(addr) {
+                self.ctx.set_gpr(reg, val as usize);
+                return Ok(Some(AxVCpuExitReason::Nothing));
+            }
+        }
+
+        #[cfg(feature = "vpmu")]
+        if let Some(vpmu) = &mut self.vpmu {
+            // `M[3:0]` is 0 for EL0t, and for the AArch32 User mode.
+            let el0 = self.ctx.spsr & 0b1111 == 0;
+            if el0 && is_pmu_sysreg(addr) && !vpmu.el0_access_allowed(addr, write) {
+                self.inject_undef();
+                return Ok(Some(AxVCpuExitReason::Nothing));
+            }
+            if write {
+                if vpmu.write(addr, value) {
+                    return Ok(Some(AxVCpuExitReason::Nothing));
+                }
+            } else if let Some(val) = vpmu.read(addr) {
+                self.ctx.set_gpr(reg, val as usize);
+                return Ok(Some(AxVCpuExitReason::Nothing));
+            }
+        }
+
+        if let Some(pmu) = &self.filtered_pmu {
+            if write {
+                if unsafe { pmu.write(addr, value) } {
+                    return Ok(Some(AxVCpuExitReason::Nothing));
+                }
+            } else if let Some(val) = unsafe { pmu.read(addr) } {
+                self.ctx.set_gpr(reg, val as usize);
+                return Ok(Some(AxVCpuExitReason::Nothing));
+            }
+        }
+
+        if let (Some(scope), true) = (self.tlbi_scope, write) {
+            // The guest VMID and configuration are still loaded.
+            if unsafe { emulate_guest_tlbi(addr, value, scope) } {
+                return Ok(Some(AxVCpuExitReason::Nothing));
+            }
+        }
+
+        // These writes only trap for the MMU tracking or the watched registers.
+        if write && self.guest_system_regs.write_vm_control(addr, value) {
+            if let Some(mmu) = &mut self.guest_mmu {
+                mmu.sync(&self.guest_system_regs.el1_state());
+            }
+            let Some(watched) = WatchedSysReg::from_addr(addr)
+                .map(WatchedSysReg::bit)
+                .filter(|bit| self.sysreg_watch & bit != 0)
+            else {
+                return Ok(Some(AxVCpuExitReason::Nothing));
+            };
+            if self.sysreg_watch_once & watched != 0 {
+                self.sysreg_watch &= !watched;
+                self.sysreg_watch_once &= !watched;
+                self.update_sysreg_write_traps();
+            }
+            // Reported to the VMM, the write is already performed.
+            return Ok(None);
+        }
+
+        if addr == SYSREG_TRFCR_EL1 && !self.self_hosted_trace {
+            // Only trapped if the guest can't use self-hosted tracing.
+            if !write {
+                self.ctx.set_gpr(reg, 0);
+            }
+            return Ok(Some(AxVCpuExitReason::Nothing));
+        }
+
+        if is_trbe_sysreg(addr) {
+            if !write {
+                self.ctx.set_gpr(reg, trbe_sysreg_read(addr) as usize);
+            }
+            return Ok(Some(AxVCpuExitReason::Nothing));
+        }
+
+        if is_lor_sysreg(addr) || (self.mpam_partition.is_some() && is_mpam_sysreg(addr)) {
+            if !write {
+                self.ctx.set_gpr(reg, 0);
+            }
+            return Ok(Some(AxVCpuExitReason::Nothing));
+        }
+
+        if write && is_pou_maintenance(addr) {
+            if !self.perform_pou_maintenance(addr, value) {
+                return Ok(Some(AxVCpuExitReason::Nothing));
+            }
+            let stats = &mut self.exception_state.stats;
+            stats.code_maintenance = stats.code_maintenance.saturating_add(1);
+            if self.code_maintenance == CodeMaintenancePolicy::Report {
+                // Reported to the VMM, the maintenance is already performed.
+                return Ok(None);
+            }
+            return Ok(Some(AxVCpuExitReason::Nothing));
+        }
+
+        if is_el2_timer_sysreg(addr) && self.nested_guest() {
+            // Only a guest hypervisor traps them (`HCR_EL2.NV`), the accesses of the other guests
+            // are UNDEFINED at EL1 without trapping. No EL2 timers are emulated for it, its VHE
+            // kernel uses the EL1 ones through their `_EL0` names, so a timer-probing loop gets
+            // an UNDEF instead of exiting to the VMM at each iteration.
+            let stats = &mut self.exception_state.stats;
+            if count_event(&mut stats.el2_timer_undef) {
+                let (op0, op1, crn, crm, op2) = sysreg_encoding(addr);
+                warn!(
+                    "vCPU {:#x} EL2 timer S{op0}_{op1}_C{crn}_C{crm}_{op2} {}, UNDEF injected ({} times)",
+                    self.mpidr,
+                    if write { "write" } else { "read" },
+                    stats.el2_timer_undef
+                );
+            }
+            self.inject_undef();
+            return Ok(Some(AxVCpuExitReason::Nothing));
+        }
+
+        if is_imp_def_sysreg(addr) && self.imp_def_sysreg == ImpDefSysRegPolicy::RazWi {
+            if count_event(&mut self.exception_state.stats.imp_def_sysreg) {
+                let (op0, op1, crn, crm, op2) = sysreg_encoding(addr);
+                // The PC is already past the 32-bit MRS/MSR.
+                let pc = self.ctx.exception_pc() as u64 - 4;
+                warn!(
+                    "arm_vcpu IMP DEF S{op0}_{op1}_C{crn}_C{crm}_{op2} {} at {}, treated as RAZ/WI ({} times)",
+                    if write { "write" } else { "read" },
+                    self.exception_state.symbolizer.addr(pc),
+                    self.exception_state.stats.imp_def_sysreg
+                );
+            }
+            if !write {
+                self.ctx.set_gpr(reg, 0);
+            }
+            return Ok(Some(AxVCpuExitReason::Nothing));
+        }
 
         match (addr, write) {
             (SYSREG_ICC_SGI1R_EL1, true) => {
@@ -407,6 +3180,12 @@ impl<H: AxVCpuHal> Aarch64VCpu<H> {
             }
             (SYSREG_ICC_SGI1R_EL1, false) => {
                 // ICC_SGI1R_EL1 is WO, we take it as RAZ.
+                if count_event(&mut self.exception_state.stats.unhandled_sysreg) {
+                    warn!(
+                        "arm_vcpu ICC_SGI1R_EL1 read, treated as RAZ ({} times)",
+                        self.exception_state.stats.unhandled_sysreg
+                    );
+                }
                 self.set_gpr(reg, 0);
                 Ok(Some(AxVCpuExitReason::Nothing))
             }
@@ -418,3 +3197,37 @@ impl<H: AxVCpuHal> Aarch64VCpu<H> {
         }
     }
 }
+
+/// Returns the `PSTATE` the guest starts with by default, before the one of the setup
+/// configuration if any: AArch64 EL1h with all the exceptions masked, or the Supervisor mode
+/// for an AArch32 EL1, keeping the Thumb state `spsr` holds for the entry point set before.
+fn setup_pstate(aarch32_el1: bool, spsr: u64) -> u64 {
+    if aarch32_el1 {
+        AARCH32_SVC_PSTATE | (spsr & SPSR_T)
+    } else {
+        (SPSR_EL1::M::EL1h
+            + SPSR_EL1::I::Masked
+            + SPSR_EL1::F::Masked
+            + SPSR_EL1::A::Masked
+            + SPSR_EL1::D::Masked)
+            .value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thumb_entry_survives_setup() {
+        // The state `set_entry_pc` leaves for an odd AArch32 entry point.
+        let entry = 0x4000_0001u64;
+        let spsr = (entry & 1) << 5;
+
+        let pstate = setup_pstate(true, spsr);
+        assert_eq!(pstate & 0b1_1111, 0b1_0011);
+        assert_ne!(pstate & SPSR_T, 0);
+        assert_eq!(setup_pstate(true, 0) & SPSR_T, 0);
+        assert_eq!(setup_pstate(false, spsr) & SPSR_T, 0);
+    }
+}
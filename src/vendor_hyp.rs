@@ -0,0 +1,80 @@
+//! The SMCCC vendor specific hypervisor service calls identifying the hypervisor, with which
+//! a guest can check that the paravirtual hypercalls of the vCPU are offered before using them.
+//!
+//! All of them are fast calls in the vendor specific hypervisor service range (owning entity
+//! 6), through `HVC` or `SMC`:
+//!
+//! - `CALL_UID` (`0x8600_ff01`) returns the UUID of the ArceOS hypervisor in `w0`-`w3`,
+//!   [`ARCEOS_HYP_UUID`] as the bytes of `w0` then `w1`..., in little-endian order,
+//! - `REVISION` (`0x8600_ff03`) returns the revision of the interface, 1.0, in `w0` and `w1`,
+//! - `FEATURES` (`0x8600_0000`) returns in `x0` the bitmap of the paravirtual services offered
+//!   to the guest, see the `VENDOR_HYP_FEATURE_*` bits, configured by the VMM.
+
+use axvcpu::AxVCpuExitReason;
+
+use crate::TrapFrame;
+use crate::exception::ExceptionState;
+use crate::smc::SMCCC_64;
+
+/// `VENDOR_HYP_CALL_UID`, returns the UUID of the hypervisor.
+const VENDOR_HYP_CALL_UID: u32 = 0x8600_ff01;
+/// `VENDOR_HYP_REVISION`, returns the revision of the interface.
+const VENDOR_HYP_REVISION: u32 = 0x8600_ff03;
+/// `VENDOR_HYP_FEATURES`, returns the bitmap of the offered services.
+const VENDOR_HYP_FEATURES: u32 = 0x8600_0000;
+
+/// The revision of the interface, major then minor.
+const VENDOR_HYP_REVISION_VALUE: (u64, u64) = (1, 0);
+
+/// The UUID of the ArceOS hypervisor returned by `CALL_UID`,
+/// `c2a2f0b8-7d5e-4e4f-9b1f-3a7d6c5e1f20`.
+pub const ARCEOS_HYP_UUID: [u8; 16] = [
+    0xc2, 0xa2, 0xf0, 0xb8, 0x7d, 0x5e, 0x4e, 0x4f, 0x9b, 0x1f, 0x3a, 0x7d, 0x6c, 0x5e, 0x1f, 0x20,
+];
+
+/// The `FEATURES` call itself, always set.
+pub const VENDOR_HYP_FEATURE_FEATURES: u64 = 1 << 0;
+/// The paravirtual "vCPU is preempted" interface (`0xc600_0020` and `0xc600_0021`).
+pub const VENDOR_HYP_FEATURE_PV_LOCK: u64 = 1 << 1;
+/// The `HV_STATS` hypercall (`0xc600_0030`).
+pub const VENDOR_HYP_FEATURE_HV_STATS: u64 = 1 << 2;
+/// The `HEARTBEAT` hypercall (`0xc600_0040`).
+pub const VENDOR_HYP_FEATURE_HEARTBEAT: u64 = 1 << 3;
+
+/// Returns the bitmap of the services offered to the guest of `state`.
+fn features(state: &ExceptionState) -> u64 {
+    let mut features = VENDOR_HYP_FEATURE_FEATURES;
+    if state.pvlock.is_some() {
+        features |= VENDOR_HYP_FEATURE_PV_LOCK;
+    }
+    if state.hv_stats.is_some() {
+        features |= VENDOR_HYP_FEATURE_HV_STATS;
+    }
+    if state.heartbeat.is_some() {
+        features |= VENDOR_HYP_FEATURE_HEARTBEAT;
+    }
+    features
+}
+
+/// Handles the vendor specific hypervisor service calls of the guest, through `HVC` or `SMC`.
+///
+/// Returns `None` if the call is not one.
+pub fn handle_vendor_hyp_call(
+    ctx: &mut TrapFrame,
+    state: &ExceptionState,
+) -> Option<AxVCpuExitReason> {
+    // The calls are the same with both calling conventions.
+    match ctx.gpr[0] as u32 & !SMCCC_64 {
+        VENDOR_HYP_CALL_UID => {
+            for (reg, word) in ctx.gpr[..4].iter_mut().zip(ARCEOS_HYP_UUID.chunks_exact(4)) {
+                *reg = u32::from_le_bytes([word[0], word[1], word[2], word[3]]) as u64;
+            }
+        }
+        VENDOR_HYP_REVISION => {
+            (ctx.gpr[0], ctx.gpr[1]) = VENDOR_HYP_REVISION_VALUE;
+        }
+        VENDOR_HYP_FEATURES => ctx.gpr[0] = features(state),
+        _ => return None,
+    }
+    Some(AxVCpuExitReason::Nothing)
+}
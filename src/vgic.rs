@@ -0,0 +1,452 @@
+//! GICv3 virtual interrupt injection through the list registers of the GIC virtualization
+//! extensions (`ICH_*_EL2`), with the `vgic` feature.
+//!
+//! The virtual CPU interface of a vCPU, i.e. its list registers, active priorities, `ICH_HCR_EL2`
+//! and `ICH_VMCR_EL2`, is part of its [`GuestSystemRegisters`], saved at each exit and loaded at
+//! each entry. The guest acknowledges and completes the injected interrupts through its
+//! `ICC_*_EL1` registers without trapping, see [`Aarch64VCpu::inject_irq`].
+//!
+//! With [`Aarch64VCpuSetupConfig::measure_irq_latency`], the injected interrupts are
+//! timestamped, and the exits following their acknowledgment and completion by the guest are
+//! made prompt with maintenance interrupts (`ICH_HCR_EL2.NPIE` and `ICH_LR<n>_EL2.EOI`), which
+//! the host must have enabled. The latencies are measured at these exits, in ticks of the
+//! physical counter, see [`IrqLatencyStats`].
+//!
+//! [`GuestSystemRegisters`]: crate::context_frame::GuestSystemRegisters
+//! [`Aarch64VCpuSetupConfig::measure_irq_latency`]: crate::Aarch64VCpuSetupConfig::measure_irq_latency
+//! [`Aarch64VCpu::inject_irq`]: crate::Aarch64VCpu::inject_irq
+
+use core::arch::asm;
+
+use aarch64_cpu::registers::{CNTPCT_EL0, Readable};
+use axerrno::{AxResult, ax_err};
+
+use crate::state::VCpuSnapshot;
+
+/// The maximum number of list registers (`ICH_VTR_EL2.ListRegs`).
+const MAX_LIST_REGS: usize = 16;
+/// The maximum number of active priorities registers per group.
+const MAX_APR_REGS: usize = 4;
+
+/// `ICH_HCR_EL2.En`, bit [0], enables the virtual CPU interface.
+const ICH_HCR_EL2_EN: u64 = 1 << 0;
+/// `ICH_HCR_EL2.NPIE`, bit [3], signals a maintenance interrupt while no list register holds
+/// a pending interrupt.
+const ICH_HCR_EL2_NPIE: u64 = 1 << 3;
+
+/// `ICH_LR<n>_EL2.State`, bits [63:62].
+const ICH_LR_STATE_MASK: u64 = 0b11 << 62;
+/// The pending state of a list register.
+const ICH_LR_STATE_PENDING: u64 = 0b01 << 62;
+/// The active state of a list register.
+const ICH_LR_STATE_ACTIVE: u64 = 0b10 << 62;
+/// `ICH_LR<n>_EL2.EOI`, bit [41], signals a maintenance interrupt once the interrupt is
+/// completed.
+const ICH_LR_EOI: u64 = 1 << 41;
+/// `ICH_LR<n>_EL2.Group`, bit [60], set for Group 1 interrupts.
+const ICH_LR_GROUP1: u64 = 1 << 60;
+/// `ICH_LR<n>_EL2.Priority`, bits [55:48].
+const ICH_LR_PRIORITY_SHIFT: u32 = 48;
+/// `ICH_LR<n>_EL2.vINTID`, bits [31:0].
+const ICH_LR_VINTID_MASK: u64 = 0xffff_ffff;
+
+/// The priority of the injected interrupts, the default one of Linux.
+const VIRQ_PRIORITY: u64 = 0xa0;
+/// The largest INTID which is not special, the SPIs and PPIs/SGIs below it.
+const MAX_INTID: u32 = 1019;
+
+/// Generates the accessors of the system registers indexed by `n`, which are distinct
+/// instructions.
+macro_rules! indexed_sysreg {
+    ($read:ident, $write:ident, [$($n:literal => $reg:literal),* $(,)?]) => {
+        fn $read(n: usize) -> u64 {
+            let value: u64;
+            match n {
+                $($n => unsafe { asm!(concat!("mrs {}, ", $reg), out(reg) value) },)*
+                _ => unreachable!(),
+            }
+            value
+        }
+
+        fn $write(n: usize, value: u64) {
+            match n {
+                $($n => unsafe { asm!(concat!("msr ", $reg, ", {}"), in(reg) value) },)*
+                _ => unreachable!(),
+            }
+        }
+    };
+}
+
+// `ICH_LR<n>_EL2`.
+indexed_sysreg!(read_lr, write_lr, [
+    0 => "S3_4_C12_C12_0", 1 => "S3_4_C12_C12_1", 2 => "S3_4_C12_C12_2", 3 => "S3_4_C12_C12_3",
+    4 => "S3_4_C12_C12_4", 5 => "S3_4_C12_C12_5", 6 => "S3_4_C12_C12_6", 7 => "S3_4_C12_C12_7",
+    8 => "S3_4_C12_C13_0", 9 => "S3_4_C12_C13_1", 10 => "S3_4_C12_C13_2", 11 => "S3_4_C12_C13_3",
+    12 => "S3_4_C12_C13_4", 13 => "S3_4_C12_C13_5", 14 => "S3_4_C12_C13_6", 15 => "S3_4_C12_C13_7",
+]);
+// `ICH_AP0R<n>_EL2`.
+indexed_sysreg!(read_ap0r, write_ap0r, [
+    0 => "S3_4_C12_C8_0", 1 => "S3_4_C12_C8_1", 2 => "S3_4_C12_C8_2", 3 => "S3_4_C12_C8_3",
+]);
+// `ICH_AP1R<n>_EL2`.
+indexed_sysreg!(read_ap1r, write_ap1r, [
+    0 => "S3_4_C12_C9_0", 1 => "S3_4_C12_C9_1", 2 => "S3_4_C12_C9_2", 3 => "S3_4_C12_C9_3",
+]);
+
+/// Reads `ICH_VTR_EL2`, describing the virtual CPU interface of the host.
+fn ich_vtr_el2() -> u64 {
+    let value: u64;
+    unsafe { asm!("mrs {}, S3_4_C12_C11_1", out(reg) value) };
+    value
+}
+
+/// Returns the number of list registers and of active priorities registers per group.
+fn vgic_regs() -> (usize, usize) {
+    let vtr = ich_vtr_el2();
+    let list_regs = (vtr & 0x1f) as usize + 1;
+    // `ICH_VTR_EL2.PREbits`, bits [28:26], the number of preemption bits minus one.
+    let apr_regs = match (vtr >> 26) & 0b111 {
+        4 => 1,
+        5 => 2,
+        _ => 4,
+    };
+    (list_regs.min(MAX_LIST_REGS), apr_regs.min(MAX_APR_REGS))
+}
+
+/// Latency samples of the guest interrupt handling, in ticks of the physical counter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySamples {
+    /// The number of samples.
+    pub count: u64,
+    /// The sum of the samples.
+    pub total_ticks: u64,
+    /// The smallest sample, 0 if there is none.
+    pub min_ticks: u64,
+    /// The largest sample.
+    pub max_ticks: u64,
+}
+
+impl LatencySamples {
+    fn record(&mut self, ticks: u64) {
+        self.min_ticks = if self.count == 0 {
+            ticks
+        } else {
+            self.min_ticks.min(ticks)
+        };
+        self.max_ticks = self.max_ticks.max(ticks);
+        self.total_ticks = self.total_ticks.saturating_add(ticks);
+        self.count += 1;
+    }
+
+    /// Returns the mean of the samples, 0 if there is none.
+    pub fn mean_ticks(&self) -> u64 {
+        self.total_ticks.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// Adds the samples of `other`, e.g. of the other vCPUs of the VM.
+    pub fn merge(&mut self, other: &Self) {
+        if other.count == 0 {
+            return;
+        }
+        self.min_ticks = if self.count == 0 {
+            other.min_ticks
+        } else {
+            self.min_ticks.min(other.min_ticks)
+        };
+        self.max_ticks = self.max_ticks.max(other.max_ticks);
+        self.total_ticks = self.total_ticks.saturating_add(other.total_ticks);
+        self.count += other.count;
+    }
+}
+
+/// The latencies of the interrupts injected with [`Aarch64VCpu::inject_irq`], from their
+/// injection, as measured at the first exit after each event.
+///
+/// [`Aarch64VCpu::inject_irq`]: crate::Aarch64VCpu::inject_irq
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IrqLatencyStats {
+    /// Until the guest acknowledges the interrupt.
+    pub acknowledge: LatencySamples,
+    /// Until the guest completes the interrupt (EOI and deactivation).
+    pub completion: LatencySamples,
+}
+
+impl IrqLatencyStats {
+    /// Adds the samples of `other`, e.g. of the other vCPUs of the VM.
+    pub fn merge(&mut self, other: &Self) {
+        self.acknowledge.merge(&other.acknowledge);
+        self.completion.merge(&other.completion);
+    }
+}
+
+/// The state of an interrupt injected into the guest and not completed yet, as held by its
+/// list register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VirqState {
+    /// Not acknowledged by the guest yet.
+    Pending,
+    /// Acknowledged by the guest, which is handling it.
+    Active,
+    /// Acknowledged by the guest, and injected again meanwhile.
+    PendingActive,
+}
+
+impl VirqState {
+    /// Returns the state of the list register `lr`, `None` if it is free.
+    fn of(lr: u64) -> Option<Self> {
+        match lr & ICH_LR_STATE_MASK {
+            0 => None,
+            ICH_LR_STATE_PENDING => Some(Self::Pending),
+            ICH_LR_STATE_ACTIVE => Some(Self::Active),
+            _ => Some(Self::PendingActive),
+        }
+    }
+}
+
+/// The state of the virtual CPU interface of a vCPU.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VgicState {
+    /// `ICH_HCR_EL2`, 0 if the virtual CPU interface is not used by the vCPU.
+    hcr: u64,
+    /// `ICH_VMCR_EL2`, the guest view of `ICC_PMR_EL1`, `ICC_BPR*_EL1` and `ICC_IGRPEN*_EL1`.
+    vmcr: u64,
+    /// `ICH_AP0R<n>_EL2`.
+    ap0r: [u64; MAX_APR_REGS],
+    /// `ICH_AP1R<n>_EL2`.
+    ap1r: [u64; MAX_APR_REGS],
+    /// `ICH_LR<n>_EL2`.
+    lrs: [u64; MAX_LIST_REGS],
+    /// The latencies measured so far, if they are.
+    latency: Option<IrqLatencyStats>,
+    /// The physical counter at the injection of the interrupt of each list register, if its
+    /// latency is measured and it is not completed yet.
+    injected_at: [Option<u64>; MAX_LIST_REGS],
+    /// The list registers whose interrupt is acknowledged, one bit each.
+    acknowledged: u16,
+}
+
+impl VgicState {
+    /// Enables the virtual CPU interface for the vCPU.
+    pub fn enable(&mut self) {
+        self.hcr |= ICH_HCR_EL2_EN;
+    }
+
+    /// Starts measuring the latencies of the injected interrupts.
+    pub fn measure_latency(&mut self) {
+        self.latency = Some(IrqLatencyStats::default());
+    }
+
+    /// Returns the latencies measured so far, if they are.
+    pub fn latency_stats(&self) -> Option<IrqLatencyStats> {
+        self.latency
+    }
+
+    /// Clears the latencies measured so far.
+    pub fn clear_latency_stats(&mut self) {
+        if let Some(stats) = &mut self.latency {
+            *stats = IrqLatencyStats::default();
+        }
+    }
+
+    /// Returns whether the virtual CPU interface is enabled for the vCPU.
+    pub fn enabled(&self) -> bool {
+        self.hcr & ICH_HCR_EL2_EN != 0
+    }
+
+    /// Saves the virtual CPU interface after the guest exits, and disables it, so that its
+    /// interrupts are not signaled to the next vCPU of the physical CPU.
+    pub unsafe fn store(&mut self) {
+        if !self.enabled() {
+            return;
+        }
+        let (list_regs, apr_regs) = vgic_regs();
+        unsafe {
+            asm!("mrs {}, S3_4_C12_C11_0", out(reg) self.hcr); // ICH_HCR_EL2
+            asm!("mrs {}, S3_4_C12_C11_7", out(reg) self.vmcr); // ICH_VMCR_EL2
+            asm!("msr S3_4_C12_C11_0, xzr");
+        }
+        for n in 0..apr_regs {
+            self.ap0r[n] = read_ap0r(n);
+            self.ap1r[n] = read_ap1r(n);
+        }
+        for (n, lr) in self.lrs.iter_mut().enumerate().take(list_regs) {
+            *lr = read_lr(n);
+        }
+        if self.latency.is_some() {
+            self.track_latency(list_regs);
+        }
+    }
+
+    /// Records the latencies of the interrupts acknowledged or completed since they were
+    /// injected or last checked.
+    fn track_latency(&mut self, list_regs: usize) {
+        let Some(stats) = &mut self.latency else {
+            return;
+        };
+        let now = CNTPCT_EL0.get();
+        for n in 0..list_regs {
+            let Some(injected_at) = self.injected_at[n] else {
+                continue;
+            };
+            let state = self.lrs[n] & ICH_LR_STATE_MASK;
+            if state != ICH_LR_STATE_PENDING && self.acknowledged & (1 << n) == 0 {
+                stats.acknowledge.record(now.wrapping_sub(injected_at));
+                self.acknowledged |= 1 << n;
+            }
+            if state == 0 {
+                stats.completion.record(now.wrapping_sub(injected_at));
+                self.injected_at[n] = None;
+                // Its maintenance interrupt is no longer signaled once reloaded.
+                self.lrs[n] = 0;
+            }
+        }
+        let pending = self.lrs[..list_regs]
+            .iter()
+            .any(|lr| *lr & ICH_LR_STATE_MASK == ICH_LR_STATE_PENDING);
+        if !pending {
+            // Signaled as long as no interrupt is pending otherwise.
+            self.hcr &= !ICH_HCR_EL2_NPIE;
+        }
+    }
+
+    /// Loads the virtual CPU interface before the guest is entered.
+    pub unsafe fn restore(&self) {
+        if !self.enabled() {
+            return;
+        }
+        let (list_regs, apr_regs) = vgic_regs();
+        for n in 0..apr_regs {
+            write_ap0r(n, self.ap0r[n]);
+            write_ap1r(n, self.ap1r[n]);
+        }
+        for (n, lr) in self.lrs.iter().enumerate().take(list_regs) {
+            write_lr(n, *lr);
+        }
+        unsafe {
+            asm!("msr S3_4_C12_C11_7, {}", in(reg) self.vmcr); // ICH_VMCR_EL2
+            asm!("msr S3_4_C12_C11_0, {}", in(reg) self.hcr); // ICH_HCR_EL2
+        }
+    }
+
+    /// Drops the injected interrupts and resets the guest view of the interface, as on a reset
+    /// of the PE, keeping the interface enabled if it is.
+    pub fn reset(&mut self) {
+        *self = Self {
+            hcr: self.hcr & ICH_HCR_EL2_EN,
+            latency: self.latency,
+            ..Self::default()
+        };
+    }
+
+    /// Saves the state to `snapshot`, the list registers above the ones of the host are zero.
+    pub fn save_to(&self, snapshot: &mut VCpuSnapshot) {
+        snapshot.ich_hcr = self.hcr;
+        snapshot.ich_vmcr = self.vmcr;
+        snapshot.ich_ap0r = self.ap0r;
+        snapshot.ich_ap1r = self.ap1r;
+        snapshot.ich_lrs = self.lrs;
+    }
+
+    /// Returns `Unsupported` if `snapshot` holds interrupts in more list registers than the
+    /// host implements.
+    pub fn check_snapshot(&self, snapshot: &VCpuSnapshot) -> AxResult {
+        let (list_regs, _) = vgic_regs();
+        if snapshot.ich_lrs[list_regs..]
+            .iter()
+            .any(|lr| *lr & ICH_LR_STATE_MASK != 0)
+        {
+            return ax_err!(
+                Unsupported,
+                "vCPU snapshot using more list registers than the host implements"
+            );
+        }
+        Ok(())
+    }
+
+    /// Restores the state saved to `snapshot`.
+    pub fn restore_from(&mut self, snapshot: &VCpuSnapshot) {
+        self.hcr = snapshot.ich_hcr;
+        self.vmcr = snapshot.ich_vmcr;
+        self.ap0r = snapshot.ich_ap0r;
+        self.ap1r = snapshot.ich_ap1r;
+        self.lrs = snapshot.ich_lrs;
+        // The latencies are not measured across snapshots.
+        self.injected_at = [None; MAX_LIST_REGS];
+        self.acknowledged = 0;
+    }
+
+    /// Returns the INTIDs of the interrupts held by the list registers, with their state.
+    pub fn irqs(&self) -> impl Iterator<Item = (u32, VirqState)> + '_ {
+        let (list_regs, _) = vgic_regs();
+        self.lrs[..list_regs].iter().filter_map(|lr| {
+            VirqState::of(*lr).map(|state| ((*lr & ICH_LR_VINTID_MASK) as u32, state))
+        })
+    }
+
+    /// Withdraws the pending state of the interrupt `intid`, returns whether it was pending.
+    ///
+    /// An interrupt the guest has acknowledged stays active until the guest completes it.
+    pub fn rescind(&mut self, intid: u32) -> bool {
+        let (list_regs, _) = vgic_regs();
+        let mut rescinded = false;
+        for n in 0..list_regs {
+            let lr = self.lrs[n];
+            if lr & ICH_LR_VINTID_MASK != intid as u64 || lr & ICH_LR_STATE_PENDING == 0 {
+                continue;
+            }
+            rescinded = true;
+            if lr & ICH_LR_STATE_ACTIVE != 0 {
+                self.lrs[n] &= !ICH_LR_STATE_PENDING;
+            } else {
+                // Never acknowledged, it has no latency either.
+                self.lrs[n] = 0;
+                self.injected_at[n] = None;
+            }
+        }
+        if !self.lrs[..list_regs]
+            .iter()
+            .any(|lr| *lr & ICH_LR_STATE_PENDING != 0)
+        {
+            self.hcr &= !ICH_HCR_EL2_NPIE;
+        }
+        rescinded
+    }
+
+    /// Makes the Group 1 interrupt `intid` pending for the guest, in a free list register.
+    ///
+    /// An interrupt the guest is still handling becomes pending and active in its list
+    /// register, the list registers never hold the same INTID twice.
+    ///
+    /// Returns `InvalidInput` for the special INTIDs, and `ResourceBusy` if all the list
+    /// registers hold interrupts the guest hasn't completed yet.
+    pub fn inject(&mut self, intid: u32) -> AxResult {
+        if intid > MAX_INTID {
+            return ax_err!(InvalidInput, "special or out of range INTID");
+        }
+        let (list_regs, _) = vgic_regs();
+        let lrs = &mut self.lrs[..list_regs];
+        if let Some(lr) = lrs
+            .iter_mut()
+            .find(|lr| **lr & ICH_LR_STATE_MASK != 0 && **lr & ICH_LR_VINTID_MASK == intid as u64)
+        {
+            // An active one becomes pending and active, a pending one stays so: the interrupts
+            // are level-like once in a list register.
+            *lr |= ICH_LR_STATE_PENDING;
+            return Ok(());
+        }
+        let Some(n) = lrs.iter().position(|lr| *lr & ICH_LR_STATE_MASK == 0) else {
+            return ax_err!(ResourceBusy, "no free list register");
+        };
+        lrs[n] = ICH_LR_STATE_PENDING
+            | ICH_LR_GROUP1
+            | (VIRQ_PRIORITY << ICH_LR_PRIORITY_SHIFT)
+            | intid as u64;
+        if self.latency.is_some() {
+            lrs[n] |= ICH_LR_EOI;
+            self.hcr |= ICH_HCR_EL2_NPIE;
+            self.injected_at[n] = Some(CNTPCT_EL0.get());
+            self.acknowledged &= !(1 << n);
+        }
+        Ok(())
+    }
+}
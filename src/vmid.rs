@@ -0,0 +1,178 @@
+//! Allocation of the VMIDs tagging the stage-2 TLB entries of each VM.
+//!
+//! The VMIDs are 8-bit wide, VMID 0 is never allocated. All the vCPUs of a VM share its VMID,
+//! allocated on the setup of its first vCPU. Once all of them are, a new generation starts
+//! (rollover): the TLBs are invalidated for all the VMIDs, and the VMs get a new VMID on their
+//! next entry, except the ones a physical CPU may still run a vCPU with, which are kept.
+//!
+//! The VMID of a vCPU is tagged with the generation it is allocated in, and checked against the
+//! current generation at each entry, without locking unless a rollover happened meanwhile.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use aarch64_cpu::registers::{Readable, VTTBR_EL2, Writeable};
+use axerrno::{AxResult, ax_err};
+use spin::Mutex;
+
+use crate::pcpu::current_cpu_id;
+use crate::pstate::HostDaif;
+use crate::tlb::{TlbScope, flush_all_vmids, flush_vmid};
+
+/// The width of the VMIDs, `VTCR_EL2.VS` is left cleared.
+const VMID_BITS: u32 = 8;
+/// The number of VMIDs of a generation, including the unallocated VMID 0.
+const NUM_VMIDS: usize = 1 << VMID_BITS;
+/// The mask of the VMID of a tagged VMID.
+const VMID_MASK: u64 = NUM_VMIDS as u64 - 1;
+/// The maximum number of physical CPUs running vCPUs, whose active VMIDs are tracked.
+///
+/// A rollover keeps up to one VMID per CPU, at least half of the others are then free.
+pub const VMID_MAX_CPUS: usize = 128;
+const _: () = assert!(VMID_MAX_CPUS < NUM_VMIDS - 1);
+/// `VTTBR_EL2.VMID`, bits [55:48].
+pub const VTTBR_VMID_SHIFT: u32 = 48;
+
+/// The current generation, starting at 1 so that 0 is never a valid tagged VMID.
+static GENERATION: AtomicU64 = AtomicU64::new(1 << VMID_BITS);
+
+/// The tagged VMID each physical CPU runs a vCPU with, 0 once a rollover has reserved it.
+static ACTIVE_VMIDS: [AtomicU64; VMID_MAX_CPUS] = [const { AtomicU64::new(0) }; VMID_MAX_CPUS];
+
+static ALLOCATOR: Mutex<VmidAllocator> = Mutex::new(VmidAllocator {
+    owners: [None; NUM_VMIDS],
+    reserved: [0; VMID_MAX_CPUS],
+});
+
+struct VmidAllocator {
+    /// The VM the VMIDs are allocated to in the current generation.
+    owners: [Option<usize>; NUM_VMIDS],
+    /// The tagged VMID each physical CPU may still run a vCPU with, kept by the rollovers.
+    reserved: [u64; VMID_MAX_CPUS],
+}
+
+impl VmidAllocator {
+    /// Returns the tagged VMID of the VM `vm_id` in the current generation, allocating one if
+    /// it has none, with a rollover if all of them are allocated.
+    fn allocate(&mut self, vm_id: usize) -> AxResult<u64> {
+        let generation = GENERATION.load(Ordering::Relaxed);
+        if let Some(vmid) = self.owners.iter().position(|owner| *owner == Some(vm_id)) {
+            return Ok(generation | vmid as u64);
+        }
+        let free = match self.owners[1..].iter().position(Option::is_none) {
+            Some(free) => Some(free),
+            None => {
+                self.rollover();
+                // VMID 0 is never allocated, and the rollover keeps at most `VMID_MAX_CPUS`
+                // of the others.
+                self.owners[1..].iter().position(Option::is_none)
+            }
+        };
+        let Some(free) = free else {
+            return ax_err!(NoMemory, "no free VMID after a rollover");
+        };
+        let vmid = free + 1;
+        self.owners[vmid] = Some(vm_id);
+        Ok(GENERATION.load(Ordering::Relaxed) | vmid as u64)
+    }
+
+    /// Starts a new generation, only keeping the VMIDs the physical CPUs may still run with.
+    fn rollover(&mut self) {
+        GENERATION.fetch_add(1 << VMID_BITS, Ordering::SeqCst);
+        let mut owners = [None; NUM_VMIDS];
+        for (active, reserved) in ACTIVE_VMIDS.iter().zip(&mut self.reserved) {
+            // A CPU whose active VMID was reserved by the previous rollover still runs it.
+            let vmid = match active.swap(0, Ordering::SeqCst) {
+                0 => *reserved,
+                vmid => vmid,
+            };
+            *reserved = vmid;
+            if vmid != 0 {
+                let vmid = (vmid & VMID_MASK) as usize;
+                owners[vmid] = self.owners[vmid];
+            }
+        }
+        self.owners = owners;
+        // The VMIDs which are not kept are reused in the new generation.
+        flush_all_vmids();
+        info!("VMID rollover");
+    }
+
+    /// Returns whether `tagged` is reserved by a rollover.
+    fn is_reserved(&self, tagged: u64) -> bool {
+        self.reserved.contains(&tagged)
+    }
+}
+
+/// Returns the VMID of a tagged VMID, to be programmed into `VTTBR_EL2`.
+pub const fn vmid_of(tagged: u64) -> u64 {
+    tagged & VMID_MASK
+}
+
+/// Allocates the VMID of the VM `vm_id` for the setup of one of its vCPUs, returning it
+/// tagged with its generation.
+///
+/// The TLB entries of the VMID are invalidated, they may be left by a destroyed VM with the
+/// same `vm_id`.
+///
+/// The IRQs are masked meanwhile, as for every use of the allocator, so that a CPU doesn't
+/// spin on it while the holder is interrupted.
+pub fn allocate_vmid(vm_id: usize) -> AxResult<u64> {
+    let host_daif = HostDaif::save_and_mask();
+    let tagged = ALLOCATOR.lock().allocate(vm_id);
+    if let Ok(tagged) = tagged {
+        let prev_vttbr = VTTBR_EL2.get();
+        VTTBR_EL2.set(vmid_of(tagged) << VTTBR_VMID_SHIFT);
+        unsafe {
+            core::arch::asm!("isb");
+            flush_vmid(TlbScope::InnerShareable);
+        }
+        VTTBR_EL2.set(prev_vttbr);
+        unsafe { core::arch::asm!("isb") };
+    }
+    host_daif.restore();
+    tagged
+}
+
+/// Returns `InvalidInput` if the physical CPU `cpu_id` can't run vCPUs, as its active VMID
+/// can't be tracked.
+pub fn check_vmid_cpu(cpu_id: usize) -> AxResult {
+    if cpu_id >= VMID_MAX_CPUS {
+        return ax_err!(InvalidInput, "the CPU ID exceeds VMID_MAX_CPUS");
+    }
+    Ok(())
+}
+
+/// Returns the tagged VMID the vCPU of the VM `vm_id` with the tagged VMID `tagged` is entered
+/// with on the current physical CPU, recording it as active there.
+///
+/// Must be called with the IRQs masked, right before the entry.
+pub fn activate_vmid(vm_id: usize, tagged: u64) -> AxResult<u64> {
+    let cpu = current_cpu_id();
+    check_vmid_cpu(cpu)?;
+    let active = &ACTIVE_VMIDS[cpu];
+    // Like Linux's `check_and_switch_context`: a rollover clears the active VMIDs, so a
+    // non-zero one means that no rollover has happened since it was recorded, and the exchange
+    // fails if one clears it meanwhile. A VMID of an older generation is never recorded
+    // without the lock, where a rollover would keep it although it may be reallocated.
+    let old = active.load(Ordering::SeqCst);
+    if old != 0
+        && tagged & !VMID_MASK == GENERATION.load(Ordering::SeqCst)
+        && active
+            .compare_exchange(old, tagged, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    {
+        return Ok(tagged);
+    }
+    let mut allocator = ALLOCATOR.lock();
+    let generation = GENERATION.load(Ordering::Relaxed);
+    let tagged = if tagged & !VMID_MASK == generation {
+        tagged
+    } else if allocator.is_reserved(tagged) {
+        // Kept by the rollover, it moves to the new generation.
+        generation | vmid_of(tagged)
+    } else {
+        allocator.allocate(vm_id)?
+    };
+    active.store(tagged, Ordering::SeqCst);
+    Ok(tagged)
+}
@@ -0,0 +1,138 @@
+//! The state shared by all the vCPUs of a VM, so that the per-VM values are set once for the
+//! whole VM instead of in each vCPU, where they could drift apart.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "vgic")]
+use core::sync::atomic::AtomicU32;
+
+use axerrno::AxResult;
+
+use crate::vmid::allocate_vmid;
+
+/// The number of words of the shadow of the SPI enables, for the INTIDs 32 to 1019.
+#[cfg(feature = "vgic")]
+const SPI_ENABLE_WORDS: usize = (1024 - 32) / 32;
+
+/// The state shared by the vCPUs of a VM, referenced by each of them through
+/// [`Aarch64VCpuCreateConfig::vm_state`].
+///
+/// It holds:
+///
+/// - the VMID of the VM, allocated by the setup of its first vCPU and kept across the VMID
+///   rollovers,
+/// - its `VTCR_EL2`, computed by the setup of its first vCPU, so that all of them translate
+///   the guest memory alike even if the physical CPUs report different PA ranges,
+/// - the offset of its virtual counter (`CNTVOFF_EL2`), loaded by each vCPU at each entry,
+/// - with the `vgic` feature, the shadow of the SPI enables of the distributor emulated by
+///   the VMM, checked by [`Aarch64VCpu::inject_irq`].
+///
+/// [`Aarch64VCpuCreateConfig::vm_state`]: crate::Aarch64VCpuCreateConfig::vm_state
+/// [`Aarch64VCpu::inject_irq`]: crate::Aarch64VCpu::inject_irq
+#[derive(Debug)]
+pub struct Aarch64VmArchState {
+    vm_id: usize,
+    /// The tagged VMID, 0 until allocated.
+    vmid: AtomicU64,
+    /// `VTCR_EL2`, 0 until computed.
+    vtcr_el2: AtomicU64,
+    /// `CNTVOFF_EL2`.
+    cntvoff_el2: AtomicU64,
+    /// The SPIs enabled at the distributor, one bit per INTID from 32.
+    #[cfg(feature = "vgic")]
+    spi_enables: [AtomicU32; SPI_ENABLE_WORDS],
+}
+
+impl Aarch64VmArchState {
+    /// Creates the state of the VM `vm_id`, with a null virtual counter offset and, with the
+    /// `vgic` feature, all the SPIs enabled.
+    pub const fn new(vm_id: usize) -> Self {
+        Self {
+            vm_id,
+            vmid: AtomicU64::new(0),
+            vtcr_el2: AtomicU64::new(0),
+            cntvoff_el2: AtomicU64::new(0),
+            #[cfg(feature = "vgic")]
+            spi_enables: [const { AtomicU32::new(u32::MAX) }; SPI_ENABLE_WORDS],
+        }
+    }
+
+    /// Returns the ID of the VM.
+    pub fn vm_id(&self) -> usize {
+        self.vm_id
+    }
+
+    /// Sets the offset of the virtual counter of the VM from the physical one, taking effect
+    /// at the next entry of each of its vCPUs.
+    pub fn set_virtual_counter_offset(&self, offset: u64) {
+        self.cntvoff_el2.store(offset, Ordering::Relaxed);
+    }
+
+    /// Returns the offset of the virtual counter of the VM from the physical one.
+    pub fn virtual_counter_offset(&self) -> u64 {
+        self.cntvoff_el2.load(Ordering::Relaxed)
+    }
+
+    /// Records whether the SPI `intid` is enabled at the distributor, as emulated by the VMM.
+    ///
+    /// Returns `false` if `intid` is not an SPI.
+    #[cfg(feature = "vgic")]
+    pub fn set_spi_enabled(&self, intid: u32, enabled: bool) -> bool {
+        let Some((word, bit)) = spi_bit(intid) else {
+            return false;
+        };
+        if enabled {
+            self.spi_enables[word].fetch_or(bit, Ordering::Relaxed);
+        } else {
+            self.spi_enables[word].fetch_and(!bit, Ordering::Relaxed);
+        }
+        true
+    }
+
+    /// Returns whether `intid` can be injected, i.e. it is not an SPI disabled at the
+    /// distributor.
+    #[cfg(feature = "vgic")]
+    pub fn spi_enabled(&self, intid: u32) -> bool {
+        spi_bit(intid)
+            .is_none_or(|(word, bit)| self.spi_enables[word].load(Ordering::Relaxed) & bit != 0)
+    }
+
+    /// Returns the tagged VMID of the VM, allocating it for the setup of its first vCPU.
+    pub(crate) fn vmid(&self) -> AxResult<u64> {
+        match self.vmid.load(Ordering::Acquire) {
+            0 => {
+                // Concurrent setups get the same VMID, the allocator keeps one per VM.
+                let vmid = allocate_vmid(self.vm_id)?;
+                self.vmid.store(vmid, Ordering::Release);
+                Ok(vmid)
+            }
+            vmid => Ok(vmid),
+        }
+    }
+
+    /// Records the tagged VMID a vCPU of the VM got after a rollover.
+    pub(crate) fn update_vmid(&self, vmid: u64) {
+        self.vmid.store(vmid, Ordering::Release);
+    }
+
+    /// Returns the `VTCR_EL2` of the VM, publishing `vtcr_el2` for the setup of its first
+    /// vCPU.
+    pub(crate) fn vtcr_el2(&self, vtcr_el2: u64) -> u64 {
+        match self
+            .vtcr_el2
+            .compare_exchange(0, vtcr_el2, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => vtcr_el2,
+            Err(shared) => shared,
+        }
+    }
+}
+
+/// Returns the word and bit of the SPI `intid` in the shadow of the SPI enables.
+#[cfg(feature = "vgic")]
+const fn spi_bit(intid: u32) -> Option<(usize, u32)> {
+    match intid {
+        32..=1019 => Some((((intid - 32) / 32) as usize, 1 << (intid % 32))),
+        _ => None,
+    }
+}
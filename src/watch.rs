@@ -0,0 +1,67 @@
+//! Watching of the writes of the guest to some of its system registers, e.g. for kernel
+//! integrity monitoring.
+
+use axaddrspace::device::SysRegAddr;
+
+use crate::sysreg::{
+    SYSREG_CONTEXTIDR_EL1, SYSREG_MAIR_EL1, SYSREG_SCTLR_EL1, SYSREG_TCR_EL1, SYSREG_TTBR0_EL1,
+    SYSREG_TTBR1_EL1, SYSREG_VBAR_EL1,
+};
+
+/// A guest system register whose writes can be watched, see
+/// [`Aarch64VCpu::watch_sysreg_writes`](crate::Aarch64VCpu::watch_sysreg_writes).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchedSysReg {
+    /// SCTLR_EL1, trapped by `HCR_EL2.TVM`.
+    SctlrEl1,
+    /// TTBR0_EL1, trapped by `HCR_EL2.TVM`.
+    Ttbr0El1,
+    /// TTBR1_EL1, trapped by `HCR_EL2.TVM`.
+    Ttbr1El1,
+    /// TCR_EL1, trapped by `HCR_EL2.TVM`.
+    TcrEl1,
+    /// MAIR_EL1, trapped by `HCR_EL2.TVM`.
+    MairEl1,
+    /// CONTEXTIDR_EL1, trapped by `HCR_EL2.TVM`.
+    ContextidrEl1,
+    /// VBAR_EL1, trapped by the fine-grained traps, which requires FEAT_FGT.
+    VbarEl1,
+}
+
+impl WatchedSysReg {
+    const ALL: [Self; 7] = [
+        Self::SctlrEl1,
+        Self::Ttbr0El1,
+        Self::Ttbr1El1,
+        Self::TcrEl1,
+        Self::MairEl1,
+        Self::ContextidrEl1,
+        Self::VbarEl1,
+    ];
+
+    /// The registers trapped by `HCR_EL2.TVM`, as a mask of [`bit`](Self::bit)s.
+    pub const TVM_MASK: u32 = (1 << Self::VbarEl1 as u32) - 1;
+
+    /// The encoding of the register.
+    pub const fn addr(self) -> SysRegAddr {
+        match self {
+            Self::SctlrEl1 => SYSREG_SCTLR_EL1,
+            Self::Ttbr0El1 => SYSREG_TTBR0_EL1,
+            Self::Ttbr1El1 => SYSREG_TTBR1_EL1,
+            Self::TcrEl1 => SYSREG_TCR_EL1,
+            Self::MairEl1 => SYSREG_MAIR_EL1,
+            Self::ContextidrEl1 => SYSREG_CONTEXTIDR_EL1,
+            Self::VbarEl1 => SYSREG_VBAR_EL1,
+        }
+    }
+
+    /// Returns the watchable register encoded as `addr`, if any.
+    pub fn from_addr(addr: SysRegAddr) -> Option<Self> {
+        Self::ALL.into_iter().find(|reg| reg.addr() == addr)
+    }
+
+    /// The bit of the register in a set of watched registers.
+    pub const fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
@@ -0,0 +1,74 @@
+//! Detection of the `WFE` spin loops of the guest.
+//!
+//! Spinlocks and other busy-wait loops of the guest wait for the lock holder in `WFE`. When
+//! the holder is a sibling vCPU preempted on the same physical CPU, the `WFE`s only complete
+//! on spurious events and the spinning vCPU burns its whole time slice. When spin detection
+//! is enabled, `WFE` is trapped (`HCR_EL2.TWE`): isolated `WFE`s complete right away in EL2,
+//! and repeated ones at the same PC are turned into yields to the VMM.
+
+/// The policy turning `WFE` spin loops into yields, see
+/// [`Aarch64VCpuSetupConfig::wfe_spin`](crate::Aarch64VCpuSetupConfig::wfe_spin).
+///
+/// After the first yield of a spin loop, the number of `WFE` exits before the next one
+/// doubles, up to `max_backoff`, so that a vCPU which keeps spinning while its siblings have
+/// nothing to run doesn't bounce through the VMM on every `WFE`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WfeSpinPolicy {
+    /// The number of consecutive `WFE` exits at the same PC making a spin loop.
+    pub threshold: u32,
+    /// The maximum number of `WFE` exits between two yields of a spin loop.
+    pub max_backoff: u32,
+}
+
+impl Default for WfeSpinPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 4,
+            max_backoff: 64,
+        }
+    }
+}
+
+/// The spin loop detection state of a vCPU.
+#[derive(Clone, Copy, Debug)]
+pub struct WfeSpinDetector {
+    policy: WfeSpinPolicy,
+    /// The PC of the last trapped `WFE`.
+    pc: usize,
+    /// The number of `WFE` exits at `pc` since the last yield.
+    streak: u32,
+    /// The number of `WFE` exits at `pc` the next yield happens after.
+    backoff: u32,
+}
+
+impl WfeSpinDetector {
+    /// Creates a detector with the given policy, a `threshold` of 0 is treated as 1.
+    pub fn new(policy: WfeSpinPolicy) -> Self {
+        let threshold = policy.threshold.max(1);
+        Self {
+            policy: WfeSpinPolicy {
+                threshold,
+                max_backoff: policy.max_backoff.max(threshold),
+            },
+            pc: usize::MAX,
+            streak: 0,
+            backoff: threshold,
+        }
+    }
+
+    /// Records a trapped `WFE` at `pc`, returning whether the vCPU should yield.
+    pub fn record(&mut self, pc: usize) -> bool {
+        if pc != self.pc {
+            self.pc = pc;
+            self.streak = 0;
+            self.backoff = self.policy.threshold;
+        }
+        self.streak += 1;
+        if self.streak < self.backoff {
+            return false;
+        }
+        self.streak = 0;
+        self.backoff = self.backoff.saturating_mul(2).min(self.policy.max_backoff);
+        true
+    }
+}